@@ -46,7 +46,7 @@ impl MyMiningClient {
         let template_distribution_handler = NullSv2TemplateDistributionClientHandler;
 
         let cancellation_token = CancellationToken::new();
-        let (tx, rx) = async_channel::unbounded::<Sv2ClientEvent<'static>>();
+        let (tx, rx) = async_channel::bounded::<Sv2ClientEvent<'static>>(1024);
 
         let nominal_hashrate = measure_hashrate().await;
 
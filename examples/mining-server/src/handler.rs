@@ -1,6 +1,4 @@
 use anyhow::Result;
-use dashmap::DashMap;
-use std::sync::Arc;
 use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::B032;
 use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::U256;
 use stratum_common::roles_logic_sv2::mining_sv2::{
@@ -10,18 +8,24 @@ use stratum_common::roles_logic_sv2::mining_sv2::{
 };
 use stratum_common::roles_logic_sv2::parsers::{AnyMessage, Mining};
 use stratum_common::roles_logic_sv2::template_distribution_sv2::{NewTemplate, SetNewPrevHash};
-use sv2_services::server::service::client::Sv2MessagesToClient;
-use sv2_services::server::service::event::Sv2ServerEvent;
 use sv2_services::server::service::event::Sv2ServerEventError;
 use sv2_services::server::service::outcome::Sv2ServerOutcome;
+use sv2_services::server::service::state::Sv2ServerHandlerState;
+use sv2_services::server::service::subprotocols::mining::extranonce::Sv2ExtranonceAllocator;
 use sv2_services::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
 
 use crate::client::MyMiningServerClient;
 
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use tracing::{debug, info};
 #[derive(Debug, Clone, Default)]
 pub struct MyMiningServerHandler {
-    clients: Arc<DashMap<u32, MyMiningServerClient>>,
+    // no global state yet, only per-client state
+    state: Sv2ServerHandlerState<(), MyMiningServerClient>,
+    // unique extranonce prefixes for every channel, reclaimed when channels are closed
+    extranonce_allocator: Sv2ExtranonceAllocator,
+    next_channel_id: Arc<AtomicU32>,
 }
 
 impl Sv2MiningServerHandler for MyMiningServerHandler {
@@ -35,13 +39,14 @@ impl Sv2MiningServerHandler for MyMiningServerHandler {
 
     async fn add_client(&mut self, client_id: u32, flags: u32) {
         info!("adding client with id: {}, flags: {}", client_id, flags);
-        self.clients
-            .insert(client_id, MyMiningServerClient { _flags: flags });
+        self.state
+            .add_client(client_id, MyMiningServerClient { _flags: flags });
     }
 
     async fn remove_client(&mut self, client_id: u32) {
         info!("removing client with id: {}", client_id);
-        self.clients.remove(&client_id);
+        self.state.remove_client(client_id);
+        self.extranonce_allocator.release_client(client_id);
     }
 
     async fn handle_open_standard_mining_channel(
@@ -59,37 +64,36 @@ impl Sv2MiningServerHandler for MyMiningServerHandler {
         let target_bytes_array: [u8; 32] = target_bytes.try_into().expect("Expected 32 bytes");
         let target = U256::from(target_bytes_array);
 
-        // assigns unique extranonce prefix for the client - using client_id in first 4 bytes
-        let mut extranonce_prefix = vec![0u8; 32];
-        extranonce_prefix[0..4].copy_from_slice(&client_id.to_be_bytes());
+        // assigns a unique extranonce prefix to the channel
+        let channel_id = self.next_channel_id.fetch_add(1, Ordering::Relaxed);
+        let extranonce_prefix = self
+            .extranonce_allocator
+            .allocate_standard(client_id, channel_id)
+            .map_err(|e| Sv2ServerEventError::MiningHandlerError(format!("{e:?}")))?
+            .standard();
         info!(
-            "extranonce_prefix for client {}: 0x{}",
+            "extranonce_prefix for client {}, channel {}: 0x{}",
             client_id,
+            channel_id,
             hex::encode(&extranonce_prefix)
         );
         let extranonce_prefix: B032 = extranonce_prefix.try_into().expect("Expected 32 bytes");
 
         // todo: update some actual state on the server representing this new standard mining channel
 
-        let message = Sv2MessagesToClient {
-            client_id,
-            messages: vec![AnyMessage::Mining(
-                Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
-                    request_id,
-                    channel_id: 0,
-                    target,
-                    extranonce_prefix,
-                    group_channel_id: 0,
-                }),
-            )],
-        };
         info!(
             "sending OpenStandardMiningChannelSuccess to client with id: {}",
             client_id
         );
-        Ok(Sv2ServerOutcome::TriggerNewEvent(Box::new(
-            Sv2ServerEvent::SendMessagesToClient(Box::new(message)),
-        )))
+        Ok(Sv2ServerOutcome::Reply(vec![AnyMessage::Mining(
+            Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
+                request_id,
+                channel_id,
+                target,
+                extranonce_prefix,
+                group_channel_id: 0,
+            }),
+        )]))
     }
 
     async fn handle_open_extended_mining_channel(
@@ -112,10 +116,11 @@ impl Sv2MiningServerHandler for MyMiningServerHandler {
 
     async fn handle_close_channel(
         &self,
-        _client_id: u32,
-        _m: CloseChannel<'static>,
+        client_id: u32,
+        m: CloseChannel<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
         debug!("MyMiningServerHandler received CloseChannel");
+        self.extranonce_allocator.release(client_id, m.channel_id);
         Ok(Sv2ServerOutcome::Ok)
     }
 
@@ -32,6 +32,7 @@ impl MyMiningServer {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: config.inactivity_limit,
+            disconnect_on_protocol_violation: false,
             tcp_config,
             mining_config: Some(Sv2ServerServiceMiningConfig {
                 supported_flags: 0b0101,
@@ -14,6 +14,8 @@ use sv2_services::{
 pub struct MyConfig {
     pub server_config: Sv2ServerServiceConfig,
     pub client_config: Sv2ClientServiceConfig,
+    /// How many events each sibling inbox holds.
+    pub sibling_bus_capacity: usize,
 }
 
 impl MyConfig {
@@ -22,6 +24,7 @@ impl MyConfig {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 3600,
+            disconnect_on_protocol_violation: false,
             tcp_config: Sv2ServerTcpConfig {
                 listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3333),
                 pub_key: Secp256k1PublicKey::from_str(
@@ -62,6 +65,7 @@ impl MyConfig {
         Self {
             server_config,
             client_config,
+            sibling_bus_capacity: 1024,
         }
     }
 }
@@ -7,6 +7,7 @@ use sv2_services::{
         Sv2ClientService, subprotocols::mining::handler::NullSv2MiningClientHandler,
     },
     server::service::Sv2ServerService,
+    sibling::Sv2SiblingBus,
 };
 use template_distribution_handler::MyTemplateDistributionHandler;
 use tokio_util::sync::CancellationToken;
@@ -15,6 +16,10 @@ mod configs;
 mod mining_server_handler;
 mod template_distribution_handler;
 
+// The names the services are registered with on the sibling bus.
+pub const MINING_SERVER: &str = "mining_server";
+pub const TEMPLATE_DISTRIBUTION_CLIENT: &str = "template_distribution_client";
+
 // This example demonstrates how to use the SiblingIO feature described in the README.
 // The goal is to spawn three services:
 // 1. A Template Provider that sends new templates.
@@ -48,47 +53,33 @@ async fn main() -> anyhow::Result<()> {
     info!("Template Provider address: {:?}", tp_address);
 
     // Initialize the handlers for TemplateDistribution and MiningServer.
-    let tdc_handler = MyTemplateDistributionHandler::new(
-        config
-            .client_config
-            .template_distribution_config
-            .as_ref()
-            .unwrap()
-            .coinbase_output_constraints
-            .0,
-        config
-            .client_config
-            .template_distribution_config
-            .as_ref()
-            .unwrap()
-            .coinbase_output_constraints
-            .1,
-    );
+    let tdc_handler = MyTemplateDistributionHandler;
     let mining_handler = MyMiningServerHandler::default();
 
     let cancellation_token = CancellationToken::new();
 
     // Create the Sv2ServerService and Sv2ClientService using the handlers.
 
-    // The Sv2ServerService returns a [`Sv2SiblingServerServiceIo`] object.
-    // This object is used to send and receive requests to/from a sibling [`sv2_services::client::service::Sv2ClientService`].
-    let (
-        mut server_service,
-        sibling_server_io, // <----- SiblingIO is created here.
-    ) = Sv2ServerService::new_with_sibling_io(
+    // Both services register on a [`Sv2SiblingBus`] under a unique name.
+    // Each registration returns a SiblingIO, which is used to send and receive requests to/from any other sibling, by name.
+    let sibling_bus = Sv2SiblingBus::new(config.sibling_bus_capacity)?;
+    let server_sibling_io = sibling_bus.register_server(MINING_SERVER)?; // <----- SiblingIO is created here.
+    let client_sibling_io = sibling_bus.register_client(TEMPLATE_DISTRIBUTION_CLIENT)?;
+
+    let mut server_service = Sv2ServerService::new_from_sibling_io(
         config.server_config,
         mining_handler,
+        server_sibling_io, // <----- SiblingIO is passed here.
         cancellation_token.clone(),
     )
     .unwrap();
 
-    // Create the client service that communicates with the server using the `sibling_server_io` created above.
     let client_config = config.client_config.clone();
     let mut client_service = Sv2ClientService::new_from_sibling_io(
         client_config,
         NullSv2MiningClientHandler,
         tdc_handler,
-        sibling_server_io, // <----- SiblingIO is passed here.
+        client_sibling_io, // <----- SiblingIO is passed here.
         cancellation_token.clone(),
     )?;
 
@@ -8,40 +8,21 @@ use sv2_services::client::service::event::Sv2ClientEvent;
 use sv2_services::client::service::event::Sv2ClientEventError;
 use sv2_services::client::service::outcome::Sv2ClientOutcome;
 use sv2_services::client::service::subprotocols::template_distribution::handler::Sv2TemplateDistributionClientHandler;
-use sv2_services::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
 use sv2_services::server::service::event::Sv2ServerEvent;
 use sv2_services::server::service::subprotocols::mining::trigger::MiningServerTrigger;
 use tracing::info;
-#[derive(Debug, Clone, Default)]
-pub struct MyTemplateDistributionHandler {
-    coinbase_output_max_additional_size: u32,
-    coinbase_output_max_additional_sigops: u16,
-}
 
-impl MyTemplateDistributionHandler {
-    pub fn new(
-        coinbase_output_max_additional_size: u32,
-        coinbase_output_max_additional_sigops: u16,
-    ) -> Self {
-        Self {
-            coinbase_output_max_additional_size,
-            coinbase_output_max_additional_sigops,
-        }
-    }
-}
+use crate::MINING_SERVER;
+
+#[derive(Debug, Clone, Default)]
+pub struct MyTemplateDistributionHandler;
 
 /// Implements the `Sv2TemplateDistributionClientHandler` trait for `MyTemplateDistributionHandler`.
 /// This trait defines how the handler processes incoming template distribution events.
 impl Sv2TemplateDistributionClientHandler for MyTemplateDistributionHandler {
+    // the service sends the configured CoinbaseOutputConstraints on its own, so there is nothing to do here
     async fn start(&mut self) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        Ok(Sv2ClientOutcome::TriggerNewEvent(Box::new(
-            Sv2ClientEvent::TemplateDistributionTrigger(
-                TemplateDistributionClientTrigger::SetCoinbaseOutputConstraints(
-                    self.coinbase_output_max_additional_size,
-                    self.coinbase_output_max_additional_sigops,
-                ),
-            ),
-        )))
+        Ok(Sv2ClientOutcome::Ok)
     }
 
     async fn handle_new_template(
@@ -57,12 +38,16 @@ impl Sv2TemplateDistributionClientHandler for MyTemplateDistributionHandler {
         // The new template is forwarded to the MiningServer using a sibling event.
         // SiblingIO works by recursively triggering events. In this case, a new event of type
         // `Sv2ClientEvent::SendEventToSiblingServerService` is created and dispatched to the MiningServer.
-        // The MiningServer, being a sibling server of the TDC Handler, processes the event accordingly.
+        // The event is addressed to the name the MiningServer was registered with on the sibling bus,
+        // which processes the event accordingly.
 
         let outcome = Sv2ClientOutcome::TriggerNewEvent(Box::new(
-            Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                Sv2ServerEvent::MiningTrigger(MiningServerTrigger::NewTemplate(template)),
-            )),
+            Sv2ClientEvent::SendEventToSiblingServerService {
+                sibling: MINING_SERVER.to_string(),
+                event: Box::new(Sv2ServerEvent::MiningTrigger(
+                    MiningServerTrigger::NewTemplate(template),
+                )),
+            },
         ));
         Ok(outcome)
     }
@@ -75,9 +60,12 @@ impl Sv2TemplateDistributionClientHandler for MyTemplateDistributionHandler {
 
         // Similar to `handle_new_template`, this forwards the new previous hash to the MiningServer.
         let outcome = Sv2ClientOutcome::TriggerNewEvent(Box::new(
-            Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                Sv2ServerEvent::MiningTrigger(MiningServerTrigger::SetNewPrevHash(prev_hash)),
-            )),
+            Sv2ClientEvent::SendEventToSiblingServerService {
+                sibling: MINING_SERVER.to_string(),
+                event: Box::new(Sv2ServerEvent::MiningTrigger(
+                    MiningServerTrigger::SetNewPrevHash(prev_hash),
+                )),
+            },
         ));
         Ok(outcome)
     }
@@ -39,10 +39,7 @@ impl MyTemplateDistributionClient {
         };
 
         // Create the handler instance
-        let template_distribution_handler = MyTemplateDistributionHandler::new(
-            config.coinbase_output_max_additional_size,
-            config.coinbase_output_max_additional_sigops,
-        );
+        let template_distribution_handler = MyTemplateDistributionHandler;
 
         let cancellation_token = CancellationToken::new();
 
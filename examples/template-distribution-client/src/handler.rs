@@ -2,41 +2,18 @@ use anyhow::Result;
 use stratum_common::roles_logic_sv2::template_distribution_sv2::{
     NewTemplate, RequestTransactionDataError, RequestTransactionDataSuccess, SetNewPrevHash,
 };
-use sv2_services::client::service::event::Sv2ClientEvent;
 use sv2_services::client::service::event::Sv2ClientEventError;
 use sv2_services::client::service::outcome::Sv2ClientOutcome;
 use sv2_services::client::service::subprotocols::template_distribution::handler::Sv2TemplateDistributionClientHandler;
-use sv2_services::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
 use tracing::info;
 #[derive(Debug, Clone, Default)]
-pub struct MyTemplateDistributionHandler {
-    coinbase_output_max_additional_size: u32,
-    coinbase_output_max_additional_sigops: u16,
-}
-
-impl MyTemplateDistributionHandler {
-    pub fn new(
-        coinbase_output_max_additional_size: u32,
-        coinbase_output_max_additional_sigops: u16,
-    ) -> Self {
-        Self {
-            coinbase_output_max_additional_size,
-            coinbase_output_max_additional_sigops,
-        }
-    }
-}
+pub struct MyTemplateDistributionHandler;
 
 /// Implement the Sv2TemplateDistributionClientHandler trait for MyTemplateDistributionClient
 impl Sv2TemplateDistributionClientHandler for MyTemplateDistributionHandler {
+    // the service sends the configured CoinbaseOutputConstraints on its own, so there is nothing to do here
     async fn start(&mut self) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        Ok(Sv2ClientOutcome::TriggerNewEvent(Box::new(
-            Sv2ClientEvent::TemplateDistributionTrigger(
-                TemplateDistributionClientTrigger::SetCoinbaseOutputConstraints(
-                    self.coinbase_output_max_additional_size,
-                    self.coinbase_output_max_additional_sigops,
-                ),
-            ),
-        )))
+        Ok(Sv2ClientOutcome::Ok)
     }
 
     async fn handle_new_template(
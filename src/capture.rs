@@ -0,0 +1,385 @@
+//! Recording and replaying the Sv2 messages exchanged over a [`Sv2MessageIo`].
+//!
+//! A [`Sv2Capture`] records every message sent and received by the IOs it is attached to
+//! (see [`Sv2MessageIo::with_capture`], or `with_capture` on the services) into two files:
+//! - `<path>.bin`: the Sv2 frames (header and payload, before encryption), replayable via [`read_capture`]
+//! - `<path>.jsonl`: one human-readable JSON object per message
+//!
+//! Records from many connections can share the same capture, and are told apart by their `connection_id`
+//! (the client id on server services, the [`Protocol`] of the connection on client services).
+//!
+//! A capture is fed back into a fresh service with `replay` (e.g.: [`crate::server::service::Sv2ServerService::replay`]),
+//! which handles the inbound messages one at a time, in the order they were recorded.
+//!
+//! The binary file starts with the `SV2CAP01` magic, followed by records laid out as
+//! `timestamp_micros: u64 | connection_id: u32 | direction: u8 | frame_length: u32 | frame`, all little-endian.
+//!
+//! [`Protocol`]: stratum_common::roles_logic_sv2::common_messages_sv2::Protocol
+
+use crate::{StandardSv2MessageFrame, Sv2MessageIo};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use stratum_common::roles_logic_sv2::parsers::{AnyMessage, IsSv2Message};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+const MAGIC: &[u8; 8] = b"SV2CAP01";
+
+// the size of a Sv2 frame header
+const HEADER_SIZE: usize = 6;
+
+// the largest Sv2 frame: a header, and a payload of up to 2^24 - 1 bytes
+const MAX_FRAME_SIZE: usize = HEADER_SIZE + 0xFF_FFFF;
+
+// how many records can wait for the writer before new ones are dropped
+const WRITER_QUEUE_CAPACITY: usize = 4096;
+
+/// Whether a message was received or sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sv2CaptureDirection {
+    Inbound,
+    Outbound,
+}
+
+impl Sv2CaptureDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sv2CaptureDirection::Inbound => "inbound",
+            Sv2CaptureDirection::Outbound => "outbound",
+        }
+    }
+}
+
+/// A message read back from a capture.
+#[derive(Debug, Clone)]
+pub struct Sv2CaptureRecord {
+    /// When the message went through the IO (as microseconds since UNIX_EPOCH)
+    pub timestamp_micros: u64,
+    pub connection_id: u32,
+    pub direction: Sv2CaptureDirection,
+    pub message: AnyMessage<'static>,
+}
+
+/// A pair of capture files, shared by every IO it is attached to.
+///
+/// Clones write to the same files. Records are written and flushed by a dedicated thread, so recording never
+/// blocks the IO, and a capture survives the application crashing. Records are dropped (and logged) if the
+/// thread falls behind, call [`Sv2Capture::flush`] to wait for everything recorded so far to reach the files.
+#[derive(Debug, Clone)]
+pub struct Sv2Capture {
+    writer: mpsc::Sender<Sv2CaptureCommand>,
+}
+
+#[derive(Debug)]
+enum Sv2CaptureCommand {
+    Record { binary: Vec<u8>, json: String },
+    Flush(oneshot::Sender<std::io::Result<()>>),
+}
+
+#[derive(Debug)]
+struct Sv2CaptureFiles {
+    binary: BufWriter<File>,
+    jsonl: BufWriter<File>,
+}
+
+impl Sv2CaptureFiles {
+    fn write(&mut self, binary: &[u8], json: &str) -> std::io::Result<()> {
+        self.binary.write_all(binary)?;
+        self.jsonl.write_all(json.as_bytes())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.binary.flush()?;
+        self.jsonl.flush()
+    }
+
+    // runs until every clone of the capture is dropped, flushing whenever there is nothing left to write
+    fn run(mut self, mut commands: mpsc::Receiver<Sv2CaptureCommand>) {
+        while let Some(command) = commands.blocking_recv() {
+            match command {
+                Sv2CaptureCommand::Record { binary, json } => {
+                    if let Err(e) = self.write(&binary, &json) {
+                        error!("Failed to write record to capture: {:?}", e);
+                    }
+                    if commands.is_empty() {
+                        if let Err(e) = self.flush() {
+                            error!("Failed to flush capture: {:?}", e);
+                        }
+                    }
+                }
+                Sv2CaptureCommand::Flush(result) => {
+                    let _ = result.send(self.flush());
+                }
+            }
+        }
+        if let Err(e) = self.flush() {
+            error!("Failed to flush capture: {:?}", e);
+        }
+    }
+}
+
+impl Sv2Capture {
+    /// Creates (or truncates) `<path>.bin` and `<path>.jsonl`, and starts the thread writing to them.
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut binary = BufWriter::new(File::create(with_extension(path.as_ref(), "bin"))?);
+        let jsonl = BufWriter::new(File::create(with_extension(path.as_ref(), "jsonl"))?);
+        binary.write_all(MAGIC)?;
+        binary.flush()?;
+
+        let (writer, commands) = mpsc::channel(WRITER_QUEUE_CAPACITY);
+        let files = Sv2CaptureFiles { binary, jsonl };
+        std::thread::Builder::new()
+            .name("sv2-capture".to_string())
+            .spawn(move || files.run(commands))?;
+
+        Ok(Self { writer })
+    }
+
+    /// Waits for every message recorded so far to be written and flushed.
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let closed =
+            || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "capture writer stopped");
+        let (result_tx, result_rx) = oneshot::channel();
+        self.writer
+            .send(Sv2CaptureCommand::Flush(result_tx))
+            .await
+            .map_err(|_| closed())?;
+        result_rx.await.map_err(|_| closed())?
+    }
+
+    /// Records `message`. Failures are logged, and never affect the IO.
+    pub(crate) fn record(
+        &self,
+        connection_id: u32,
+        direction: Sv2CaptureDirection,
+        message: &AnyMessage<'static>,
+    ) {
+        let Some(frame) = encode_frame(message) else {
+            error!("Failed to record message on capture: failed to encode frame");
+            return;
+        };
+        let timestamp_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock should never go backwards")
+            .as_micros() as u64;
+
+        // timestamp, connection id, direction and frame length, followed by the frame
+        let mut binary = Vec::with_capacity(8 + 4 + 1 + 4 + frame.len());
+        binary.extend_from_slice(&timestamp_micros.to_le_bytes());
+        binary.extend_from_slice(&connection_id.to_le_bytes());
+        binary.push(match direction {
+            Sv2CaptureDirection::Inbound => 0,
+            Sv2CaptureDirection::Outbound => 1,
+        });
+        binary.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        binary.extend_from_slice(&frame);
+
+        let json = format!(
+            "{{\"timestamp_micros\":{},\"connection_id\":{},\"direction\":\"{}\",\"message_type\":\"0x{:02x}\",\"message\":\"{}\"}}\n",
+            timestamp_micros,
+            connection_id,
+            direction.as_str(),
+            message.message_type(),
+            escape_json(&format!("{:?}", message)),
+        );
+
+        match self
+            .writer
+            .try_send(Sv2CaptureCommand::Record { binary, json })
+        {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                error!("Capture writer is falling behind, dropping a record")
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                error!("Failed to record message on capture: writer stopped")
+            }
+        }
+    }
+}
+
+/// Reads every record of `<path>.bin`, in the order they were written.
+pub fn read_capture(path: impl AsRef<Path>) -> std::io::Result<Vec<Sv2CaptureRecord>> {
+    let invalid_data = |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, reason);
+
+    let mut reader = BufReader::new(File::open(with_extension(path.as_ref(), "bin"))?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a Sv2 capture"));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut timestamp_micros = [0u8; 8];
+        match reader.read_exact(&mut timestamp_micros) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let mut connection_id = [0u8; 4];
+        reader.read_exact(&mut connection_id)?;
+        let mut direction = [0u8; 1];
+        reader.read_exact(&mut direction)?;
+        let mut frame_length = [0u8; 4];
+        reader.read_exact(&mut frame_length)?;
+        let frame_length = u32::from_le_bytes(frame_length) as usize;
+        if frame_length > MAX_FRAME_SIZE {
+            return Err(invalid_data("frame too large"));
+        }
+        let mut frame = vec![0u8; frame_length];
+        reader.read_exact(&mut frame)?;
+
+        let direction = match direction[0] {
+            0 => Sv2CaptureDirection::Inbound,
+            1 => Sv2CaptureDirection::Outbound,
+            _ => return Err(invalid_data("invalid direction")),
+        };
+        let message = decode_frame(&mut frame).ok_or_else(|| invalid_data("invalid frame"))?;
+
+        records.push(Sv2CaptureRecord {
+            timestamp_micros: u64::from_le_bytes(timestamp_micros),
+            connection_id: u32::from_le_bytes(connection_id),
+            direction,
+            message,
+        });
+    }
+
+    Ok(records)
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+// Serializes `message` into a Sv2 frame, header included.
+fn encode_frame(message: &AnyMessage<'static>) -> Option<Vec<u8>> {
+    let frame: StandardSv2MessageFrame = message.clone().try_into().ok()?;
+    let mut bytes = vec![0u8; frame.encoded_length()];
+    frame.serialize(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+fn decode_frame(frame: &mut [u8]) -> Option<AnyMessage<'static>> {
+    if frame.len() < HEADER_SIZE {
+        return None;
+    }
+    let message_type = frame[2];
+    let (_, payload) = frame.split_at_mut(HEADER_SIZE);
+    let message: AnyMessage<'_> = (message_type, payload).try_into().ok()?;
+    Some(Sv2MessageIo::into_static(message))
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_json, read_capture, Sv2Capture, Sv2CaptureDirection};
+    use crate::Sv2MessageIo;
+    use stratum_common::roles_logic_sv2::common_messages_sv2::{
+        Protocol, SetupConnection, SetupConnectionSuccess,
+    };
+    use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages};
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("sv2-capture-oversized-{}", std::process::id()));
+        let mut bytes = super::MAGIC.to_vec();
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(super::with_extension(&path, "bin"), bytes).unwrap();
+
+        let error = read_capture(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let _ = std::fs::remove_file(super::with_extension(&path, "bin"));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(escape_json("a \"b\"\n\\"), "a \\\"b\\\"\\n\\\\");
+    }
+
+    #[tokio::test]
+    async fn capture_records_both_directions() {
+        let path = std::env::temp_dir().join(format!("sv2-capture-test-{}", std::process::id()));
+        let capture = Sv2Capture::create(&path).unwrap();
+
+        // a loopback IO: every message sent is received right back
+        let (tx, rx) = async_channel::unbounded();
+        let io = Sv2MessageIo::new(rx, tx, None).with_capture(capture.clone(), 7);
+
+        let setup_connection: AnyMessage<'static> =
+            AnyMessage::Common(CommonMessages::SetupConnection(SetupConnection {
+                protocol: Protocol::MiningProtocol,
+                min_version: 2,
+                max_version: 2,
+                flags: 0,
+                endpoint_host: "".to_string().try_into().unwrap(),
+                endpoint_port: 0,
+                vendor: "vendor".to_string().try_into().unwrap(),
+                hardware_version: "".to_string().try_into().unwrap(),
+                firmware: "".to_string().try_into().unwrap(),
+                device_id: "".to_string().try_into().unwrap(),
+            }));
+        let setup_connection_success = AnyMessage::Common(CommonMessages::SetupConnectionSuccess(
+            SetupConnectionSuccess {
+                used_version: 2,
+                flags: 0,
+            },
+        ));
+
+        io.send_message(setup_connection).await.unwrap();
+        io.recv_message().await.unwrap();
+        io.send_message(setup_connection_success).await.unwrap();
+        capture.flush().await.unwrap();
+
+        let records = read_capture(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|record| record.connection_id == 7));
+        assert_eq!(records[0].direction, Sv2CaptureDirection::Outbound);
+        assert_eq!(records[1].direction, Sv2CaptureDirection::Inbound);
+        assert!(matches!(
+            &records[1].message,
+            AnyMessage::Common(CommonMessages::SetupConnection(m)) if m.protocol == Protocol::MiningProtocol
+        ));
+        assert!(matches!(
+            &records[2].message,
+            AnyMessage::Common(CommonMessages::SetupConnectionSuccess(_))
+        ));
+        assert!(records[0].timestamp_micros <= records[2].timestamp_micros);
+
+        let jsonl = std::fs::read_to_string(super::with_extension(&path, "jsonl")).unwrap();
+        assert_eq!(jsonl.lines().count(), 3);
+        assert!(jsonl
+            .lines()
+            .next()
+            .unwrap()
+            .contains("\"direction\":\"outbound\""));
+
+        let _ = std::fs::remove_file(super::with_extension(&path, "bin"));
+        let _ = std::fs::remove_file(super::with_extension(&path, "jsonl"));
+    }
+}
@@ -12,7 +12,7 @@ pub enum Sv2ClientServiceError {
     FailedToStartMiningHandler,
     // FailedToStartJobDeclarationHandler,
     FailedToStartTemplateDistributionHandler,
-    NoSiblingServerServiceIo,
+    NoSiblingIo,
 }
 
 impl fmt::Display for Sv2ClientServiceError {
@@ -39,9 +39,7 @@ impl fmt::Display for Sv2ClientServiceError {
             Sv2ClientServiceError::FailedToStartTemplateDistributionHandler => {
                 write!(f, "Failed to start template distribution handler")
             }
-            Sv2ClientServiceError::NoSiblingServerServiceIo => {
-                write!(f, "No sibling server service io")
-            }
+            Sv2ClientServiceError::NoSiblingIo => write!(f, "No sibling io"),
         }
     }
 }
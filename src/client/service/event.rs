@@ -1,6 +1,7 @@
 use crate::client::service::subprotocols::mining::trigger::MiningClientTrigger;
 use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
 use crate::server::service::event::Sv2ServerEvent;
+use crate::sibling::Sv2SiblingBusError;
 use crate::Sv2MessageIoError;
 use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
 use stratum_common::roles_logic_sv2::parsers::{AnyMessage, Mining, TemplateDistribution};
@@ -15,7 +16,18 @@ pub enum Sv2ClientEvent<'a> {
     IncomingMessage(AnyMessage<'a>),
     MiningTrigger(MiningClientTrigger),
     TemplateDistributionTrigger(TemplateDistributionClientTrigger<'a>),
-    SendEventToSiblingServerService(Box<Sv2ServerEvent<'a>>),
+    /// Send an event to the sibling server service registered under `sibling` on the [`crate::sibling::Sv2SiblingBus`].
+    ///
+    /// The event is boxed to break the recursive type definition between Sv2ClientEvent and Sv2ServerEvent.
+    SendEventToSiblingServerService {
+        sibling: String,
+        event: Box<Sv2ServerEvent<'a>>,
+    },
+    /// Send an event to the sibling client service registered under `sibling` on the [`crate::sibling::Sv2SiblingBus`].
+    SendEventToSiblingClientService {
+        sibling: String,
+        event: Box<Sv2ClientEvent<'a>>,
+    },
     SendMessageToMiningServer(Box<Mining<'a>>),
     SendMessageToTemplateDistributionServer(Box<TemplateDistribution<'a>>),
     // SendMessageToJobDeclarationServer(Box<(JobDeclaration<'a>, u8)>),
@@ -23,18 +35,49 @@ pub enum Sv2ClientEvent<'a> {
     MultipleEvents(Box<Vec<Sv2ClientEvent<'a>>>),
 }
 
+impl Sv2ClientEvent<'_> {
+    /// The name of the event variant, e.g.: for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sv2ClientEvent::SetupConnectionTrigger(..) => "SetupConnectionTrigger",
+            Sv2ClientEvent::IncomingMessage(_) => "IncomingMessage",
+            Sv2ClientEvent::MiningTrigger(_) => "MiningTrigger",
+            Sv2ClientEvent::TemplateDistributionTrigger(_) => "TemplateDistributionTrigger",
+            Sv2ClientEvent::SendEventToSiblingServerService { .. } => {
+                "SendEventToSiblingServerService"
+            }
+            Sv2ClientEvent::SendEventToSiblingClientService { .. } => {
+                "SendEventToSiblingClientService"
+            }
+            Sv2ClientEvent::SendMessageToMiningServer(_) => "SendMessageToMiningServer",
+            Sv2ClientEvent::SendMessageToTemplateDistributionServer(_) => {
+                "SendMessageToTemplateDistributionServer"
+            }
+            Sv2ClientEvent::MultipleEvents(_) => "MultipleEvents",
+        }
+    }
+}
+
 /// The error type for the [`crate::client::service::Sv2ClientService`] service.
 #[derive(Debug, Clone)]
 pub enum Sv2ClientEventError {
     BadRouting,
     UnsupportedMessage,
-    UnsupportedProtocol { protocol: Protocol },
+    UnsupportedProtocol {
+        protocol: Protocol,
+    },
     IsNotConnected,
     SetupConnectionError(String),
     ConnectionError(String),
     StringConversionError(String),
-    NoSiblingServerServiceIo,
-    FailedToSendEventToSiblingServerService,
+    /// The service was not created with a [`crate::sibling::Sv2ClientSiblingIo`].
+    NoSiblingIo,
+    /// A [`crate::client::service::outcome::Sv2ClientOutcome::Reply`] or [`crate::client::service::outcome::Sv2ClientOutcome::Disconnect`]
+    /// was returned while handling an event that did not come from a server.
+    NoCurrentServer,
+    FailedToSendEventToSibling(Sv2SiblingBusError),
+    /// The service [`tokio_util::sync::CancellationToken`] was cancelled.
+    ServiceShutDown,
     U256ConversionError(String),
     MiningHandlerError(String),
     TemplateDistributionHandlerError(String),
@@ -1,8 +1,8 @@
+use crate::capture::{Sv2Capture, Sv2CaptureDirection, Sv2CaptureRecord};
 use crate::client::service::config::Sv2ClientServiceConfig;
 use crate::client::service::error::Sv2ClientServiceError;
 use crate::client::service::event::{Sv2ClientEvent, Sv2ClientEventError};
 use crate::client::service::outcome::Sv2ClientOutcome;
-use crate::client::service::sibling::Sv2SiblingServerServiceIo;
 use crate::client::service::subprotocols::mining::handler::NullSv2MiningClientHandler;
 use crate::client::service::subprotocols::mining::handler::Sv2MiningClientHandler;
 use crate::client::service::subprotocols::mining::trigger::MiningClientTrigger;
@@ -10,6 +10,12 @@ use crate::client::service::subprotocols::template_distribution::handler::NullSv
 use crate::client::service::subprotocols::template_distribution::handler::Sv2TemplateDistributionClientHandler;
 use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
 use crate::client::tcp::encrypted::Sv2EncryptedTcpClient;
+use crate::layer::{Sv2BoxFuture, Sv2Layer, Sv2LayerInner, Sv2Layers};
+use crate::metrics;
+use crate::sibling::Sv2ClientSiblingIo;
+use crate::span;
+use crate::timer::{Sv2ScheduledEvent, Sv2TimerHandle, Sv2Timers};
+use crate::ChannelUsageWarning;
 use crate::Sv2Service;
 use async_channel::Receiver;
 use std::future::Future;
@@ -21,15 +27,15 @@ use stratum_common::roles_logic_sv2::mining_sv2::{
 use stratum_common::roles_logic_sv2::parsers::{
     AnyMessage, CommonMessages, Mining, TemplateDistribution,
 };
+use stratum_common::roles_logic_sv2::template_distribution_sv2::CoinbaseOutputConstraints;
 use tokio::sync::RwLock;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
+use tracing::{debug, error, info, Instrument};
 
 pub mod config;
 pub mod error;
 pub mod event;
 pub mod outcome;
-pub mod sibling;
 pub mod subprotocols;
 
 /// A [`Sv2Service`] implementer that provides:
@@ -57,12 +63,17 @@ where
     mining_tcp_client: Arc<RwLock<Option<Sv2EncryptedTcpClient>>>,
     job_declaration_tcp_client: Arc<RwLock<Option<Sv2EncryptedTcpClient>>>,
     template_distribution_tcp_client: Arc<RwLock<Option<Sv2EncryptedTcpClient>>>,
+    // (max_additional_size, max_additional_sigops) sent to the Template Provider on every connection
+    coinbase_output_constraints: Arc<RwLock<Option<(u32, u16)>>>,
     mining_handler: M,
     // todo: add job_declaration_handler: J,
     template_distribution_handler: T,
     cancellation_token: CancellationToken,
-    sibling_server_service_io: Option<Sv2SiblingServerServiceIo>,
+    sibling_io: Option<Sv2ClientSiblingIo>,
     event_injector: Option<Receiver<Sv2ClientEvent<'static>>>,
+    timers: Sv2Timers<Sv2ClientEvent<'static>>,
+    layers: Sv2Layers<Sv2ClientEvent<'static>, Sv2ClientOutcome<'static>, Sv2ClientEventError>,
+    capture: Option<Sv2Capture>,
 }
 
 impl<M, T> Sv2ClientService<M, T>
@@ -72,7 +83,7 @@ where
 {
     /// Creates a new [`Sv2ClientService`]
     ///
-    /// No sibling server service is required.
+    /// No sibling service is required.
     pub fn new(
         config: Sv2ClientServiceConfig,
         mining_handler: M,
@@ -98,7 +109,8 @@ where
     /// Can be used for example for handler functions that are not defined in the handler trait
     /// (and therefore cannot leverage `Sv2ClientOutcome::TriggerNewEvent`) but still need to send events to the server.
     ///
-    /// Before calling this, you need to create a [`Receiver`] using [`async_channel::channel`].
+    /// Before calling this, you need to create a [`Receiver`] using [`async_channel::bounded`].
+    /// A bounded channel makes injectors wait while the service catches up, and a warning is logged when it is about to fill up.
     pub fn new_with_event_injector(
         config: Sv2ClientServiceConfig,
         mining_handler: M,
@@ -117,24 +129,24 @@ where
         Ok(sv2_client_service)
     }
 
-    /// Creates a new [`Sv2ClientService`] with a sibling server service.
+    /// Creates a new [`Sv2ClientService`] that communicates with sibling services.
     ///
-    /// The sibling server service is used to send and receive events to a sibling [`crate::server::service::Sv2ServerService`] that pairs with this client.    
+    /// Sibling services are other [`crate::server::service::Sv2ServerService`]s or [`Sv2ClientService`]s within the same application.
     ///
-    /// Before calling this, you need to create a [`Sv2SiblingServerServiceIo`] using [`crate::server::service::Sv2ServerService::new_with_sibling_io`].
+    /// Before calling this, you need to register the service on a [`crate::sibling::Sv2SiblingBus`] using [`crate::sibling::Sv2SiblingBus::register_client`].
     pub fn new_from_sibling_io(
         config: Sv2ClientServiceConfig,
         mining_handler: M,
         // todo: add job_declaration_handler: J,
         template_distribution_handler: T,
-        sibling_server_service_io: Sv2SiblingServerServiceIo,
+        sibling_io: Sv2ClientSiblingIo,
         cancellation_token: CancellationToken,
     ) -> Result<Self, Sv2ClientServiceError> {
         let sv2_client_service = Self::_new(
             config,
             mining_handler,
             template_distribution_handler,
-            Some(sibling_server_service_io),
+            Some(sibling_io),
             None,
             cancellation_token,
         )?;
@@ -146,7 +158,7 @@ where
         mining_handler: M,
         // todo: add job_declaration_handler: J,
         template_distribution_handler: T,
-        sibling_server_service_io: Sv2SiblingServerServiceIo,
+        sibling_io: Sv2ClientSiblingIo,
         event_rx: Receiver<Sv2ClientEvent<'static>>,
         cancellation_token: CancellationToken,
     ) -> Result<Self, Sv2ClientServiceError> {
@@ -154,7 +166,7 @@ where
             config,
             mining_handler,
             template_distribution_handler,
-            Some(sibling_server_service_io),
+            Some(sibling_io),
             Some(event_rx),
             cancellation_token,
         )?;
@@ -167,21 +179,30 @@ where
         mining_handler: M,
         // todo: add job_declaration_handler: J,
         template_distribution_handler: T,
-        sibling_server_service_io: Option<Sv2SiblingServerServiceIo>,
+        sibling_io: Option<Sv2ClientSiblingIo>,
         event_injector: Option<Receiver<Sv2ClientEvent<'static>>>,
         cancellation_token: CancellationToken,
     ) -> Result<Self, Sv2ClientServiceError> {
         Self::validate_protocol_handlers(&config)?;
 
+        let coinbase_output_constraints = config
+            .template_distribution_config
+            .as_ref()
+            .map(|c| c.coinbase_output_constraints);
+
         let sv2_client_service = Sv2ClientService {
             config,
             mining_tcp_client: Arc::new(RwLock::new(None)),
             job_declaration_tcp_client: Arc::new(RwLock::new(None)),
             template_distribution_tcp_client: Arc::new(RwLock::new(None)),
+            coinbase_output_constraints: Arc::new(RwLock::new(coinbase_output_constraints)),
             mining_handler,
             template_distribution_handler,
+            timers: Sv2Timers::new(cancellation_token.clone()),
+            layers: Sv2Layers::new(),
+            capture: None,
             cancellation_token,
-            sibling_server_service_io,
+            sibling_io,
             event_injector,
         };
 
@@ -259,6 +280,138 @@ where
         }
     }
 
+    /// Returns whether the service [`CancellationToken`] was cancelled.
+    pub fn is_shut_down(&self) -> bool {
+        self.cancellation_token.is_cancelled()
+    }
+
+    // Checks if the client is connected to the server of at least one of the supported protocols, without waiting.
+    //
+    // A connection whose lock is held is being set up or torn down, and counts as connected.
+    pub(crate) fn try_is_connected_to_any(&self) -> bool {
+        self.config
+            .supported_protocols()
+            .iter()
+            .any(|(protocol, _)| {
+                let tcp_client = match protocol {
+                    Protocol::MiningProtocol => &self.mining_tcp_client,
+                    Protocol::JobDeclarationProtocol => &self.job_declaration_tcp_client,
+                    Protocol::TemplateDistributionProtocol => {
+                        &self.template_distribution_tcp_client
+                    }
+                };
+                tcp_client
+                    .try_read()
+                    .map(|guard| guard.is_some())
+                    .unwrap_or(true)
+            })
+    }
+
+    /// Wraps [`Sv2Service::handle`] with `layer`, below every layer added before.
+    ///
+    /// Layers must be added before the service is started. See [`crate::layer`] for how layers are composed.
+    pub fn with_layer(
+        mut self,
+        layer: impl Sv2Layer<Sv2ClientEvent<'static>, Sv2ClientOutcome<'static>, Sv2ClientEventError>,
+    ) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Records every message exchanged with the servers into `capture`, under the [`Protocol`] of each connection.
+    ///
+    /// Must be set before the service connects. See [`crate::capture`] for the capture format.
+    pub fn with_capture(mut self, capture: Sv2Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Returns the CoinbaseOutputConstraints sent to the Template Provider, as (max_additional_size, max_additional_sigops).
+    ///
+    /// `None` if the service does not support the Template Distribution protocol.
+    pub async fn coinbase_output_constraints(&self) -> Option<(u32, u16)> {
+        *self.coinbase_output_constraints.read().await
+    }
+
+    /// Updates the CoinbaseOutputConstraints, e.g. when the coinbase payout layout changes.
+    ///
+    /// If the service is connected to the Template Provider, the new constraints are sent right away.
+    /// Either way, they are the ones sent after every (re)connection from now on.
+    pub async fn set_coinbase_output_constraints(
+        &mut self,
+        max_additional_size: u32,
+        max_additional_sigops: u16,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        if self.config.template_distribution_config.is_none() {
+            return Err(Sv2ClientEventError::UnsupportedProtocol {
+                protocol: Protocol::TemplateDistributionProtocol,
+            });
+        }
+
+        self.coinbase_output_constraints
+            .write()
+            .await
+            .replace((max_additional_size, max_additional_sigops));
+
+        if !self
+            .is_connected(Protocol::TemplateDistributionProtocol)
+            .await
+        {
+            return Ok(Sv2ClientOutcome::Ok);
+        }
+
+        self.handle(Sv2ClientEvent::TemplateDistributionTrigger(
+            TemplateDistributionClientTrigger::SetCoinbaseOutputConstraints(
+                max_additional_size,
+                max_additional_sigops,
+            ),
+        ))
+        .await
+    }
+
+    /// Feeds the inbound messages of a capture into the service, one at a time, in the order they were recorded.
+    ///
+    /// Outbound messages are skipped, since they are what the service itself produces.
+    /// Handlers that reply to the server fail with [`Sv2ClientEventError::IsNotConnected`] unless the service is connected.
+    pub async fn replay(
+        &mut self,
+        records: Vec<Sv2CaptureRecord>,
+    ) -> Vec<Result<Sv2ClientOutcome<'static>, Sv2ClientEventError>> {
+        let mut results = Vec::new();
+        for record in records {
+            if record.direction != Sv2CaptureDirection::Inbound {
+                continue;
+            }
+            results.push(
+                self.handle(Sv2ClientEvent::IncomingMessage(record.message))
+                    .await,
+            );
+        }
+        results
+    }
+
+    // Attaches the capture, if any, to a new connection.
+    fn capture_io(
+        &self,
+        mut tcp_client: Sv2EncryptedTcpClient,
+        protocol: Protocol,
+    ) -> Sv2EncryptedTcpClient {
+        if let Some(capture) = &self.capture {
+            tcp_client.io = tcp_client.io.with_capture(capture.clone(), protocol as u32);
+        }
+        tcp_client
+    }
+
+    /// Fires an event later, or periodically, until cancelled via the returned [`Sv2TimerHandle`] or the service shuts down.
+    ///
+    /// Handlers should return [`Sv2ClientOutcome::Schedule`] instead.
+    pub fn schedule(
+        &self,
+        scheduled_event: Sv2ScheduledEvent<Sv2ClientEvent<'static>>,
+    ) -> Sv2TimerHandle {
+        self.timers.schedule(scheduled_event)
+    }
+
     pub async fn start(&mut self) -> Result<(), Sv2ClientServiceError> {
         for (protocol, flags) in self.config.supported_protocols() {
             let initiate_connection_outcome = self
@@ -282,9 +435,9 @@ where
         }
 
         let mut this = self.clone();
-        if let Some(_sibling_server_service_io) = this.sibling_server_service_io.clone() {
+        if let Some(_sibling_io) = this.sibling_io.clone() {
             tokio::spawn(async move {
-                if let Err(e) = this.listen_for_events_via_sibling_server_service().await {
+                if let Err(e) = this.listen_for_events_via_sibling_io().await {
                     error!("Error listening for event: {:?}", e);
                 }
             });
@@ -302,6 +455,11 @@ where
             });
         }
 
+        let mut this = self.clone();
+        tokio::spawn(async move {
+            this.listen_for_events_via_timers().await;
+        });
+
         if !Self::has_null_handler(Protocol::MiningProtocol) {
             match self
                 .handle(Sv2ClientEvent::MiningTrigger(MiningClientTrigger::Start))
@@ -363,6 +521,7 @@ where
                                 "Failed to create TCP client".to_string(),
                             )
                         })?;
+                    let tcp_client = self.capture_io(tcp_client, Protocol::MiningProtocol);
                     self.mining_tcp_client
                         .write()
                         .await
@@ -391,6 +550,7 @@ where
                                 "Failed to create TCP client".to_string(),
                             )
                         })?;
+                    let tcp_client = self.capture_io(tcp_client, Protocol::JobDeclarationProtocol);
                     self.job_declaration_tcp_client
                         .write()
                         .await
@@ -419,6 +579,8 @@ where
                                 "Failed to create TCP client".to_string(),
                             )
                         })?;
+                    let tcp_client =
+                        self.capture_io(tcp_client, Protocol::TemplateDistributionProtocol);
                     self.template_distribution_tcp_client
                         .write()
                         .await
@@ -481,6 +643,33 @@ where
                     "SetupConnectionSuccess received: version: {}, flags: {}",
                     server_used_version, server_used_flags
                 );
+
+                // the Template Provider won't send templates until it knows how much room our coinbase outputs need,
+                // so the constraints go out as part of the connection setup (and again after every reconnect)
+                if protocol == Protocol::TemplateDistributionProtocol {
+                    if let Some((max_additional_size, max_additional_sigops)) =
+                        *self.coinbase_output_constraints.read().await
+                    {
+                        let coinbase_output_constraints =
+                            TemplateDistribution::CoinbaseOutputConstraints(
+                                CoinbaseOutputConstraints {
+                                    coinbase_output_max_additional_size: max_additional_size,
+                                    coinbase_output_max_additional_sigops: max_additional_sigops,
+                                },
+                            );
+                        tcp_client
+                            .io
+                            .send_message(AnyMessage::TemplateDistribution(
+                                coinbase_output_constraints,
+                            ))
+                            .await?;
+                        debug!(
+                            "CoinbaseOutputConstraints sent: max_additional_size: {}, max_additional_sigops: {}",
+                            max_additional_size, max_additional_sigops
+                        );
+                    }
+                }
+
                 Ok(Sv2ClientOutcome::Ok)
             }
             AnyMessage::Common(CommonMessages::SetupConnectionError(setup_connection_error)) => {
@@ -539,9 +728,15 @@ where
                                 // this is a protection from attacks where a server sends a message that it knows the client cannot handle
                                 // we simply log the error and ignore the message, without shutting down the client
                                 error!("Error handling message: {:?}, message will be ignored", e);
+                                metrics::record_event_error("client", &e);
                             }
                         }
                         Err(_) => {
+                            // the connection was closed on purpose, via Sv2ClientOutcome::Disconnect
+                            if !self.is_connected(protocol).await {
+                                debug!("Disconnected from {:?} server", protocol);
+                                break;
+                            }
                             error!("{:?} server closed the connection", protocol);
                             self.cancellation_token.cancel();
                             break;
@@ -567,35 +762,34 @@ where
         Ok(())
     }
 
-    // Listens for events from the sibling server service and triggers Service Events
-    async fn listen_for_events_via_sibling_server_service(
-        &mut self,
-    ) -> Result<(), Sv2ClientServiceError> {
-        let sibling_server_service_io = self
-            .sibling_server_service_io
+    // Listens for events from sibling services and triggers Service Events
+    async fn listen_for_events_via_sibling_io(&mut self) -> Result<(), Sv2ClientServiceError> {
+        let sibling_io = self
+            .sibling_io
             .as_ref()
-            .ok_or(Sv2ClientServiceError::NoSiblingServerServiceIo)?;
+            .ok_or(Sv2ClientServiceError::NoSiblingIo)?;
 
         let cancellation_token = self.cancellation_token.clone();
 
         loop {
             tokio::select! {
                 _ = cancellation_token.cancelled() => {
-                    debug!("Sibling server service event listener task cancelled");
+                    debug!("Sibling event listener task cancelled");
                     break;
                 }
-                result = sibling_server_service_io.recv() => {
+                result = sibling_io.recv() => {
                     match result {
                         Ok(req) => {
-                            debug!("Received event from sibling server service");
+                            debug!("Received event from sibling service");
 
                             let mut service = self.clone();
                             if let Err(e) = service.handle(*req).await {
-                                error!("Error handling event from sibling server service: {:?}", e);
+                                error!("Error handling event from sibling service: {:?}", e);
+                                metrics::record_event_error("client", &e);
                             }
                         }
                         Err(e) => {
-                            error!("Failed to receive event from sibling server service: {:?}", e);
+                            error!("Failed to receive event from sibling service: {:?}", e);
                             break;
                         }
                     }
@@ -603,8 +797,8 @@ where
             }
         }
 
-        debug!("Sibling server service event listener task ended");
-        sibling_server_service_io.shutdown();
+        debug!("Sibling event listener task ended");
+        sibling_io.shutdown();
         Ok(())
     }
 
@@ -614,6 +808,7 @@ where
         event_rx: Receiver<Sv2ClientEvent<'static>>,
     ) -> Result<(), Sv2ClientServiceError> {
         let cancellation_token = self.cancellation_token.clone();
+        let usage_warning = ChannelUsageWarning::default();
 
         loop {
             tokio::select! {
@@ -625,9 +820,15 @@ where
                     match result {
                         Ok(event) => {
                             debug!("Received event from event injector");
+                            usage_warning.observe(
+                                "Event injector channel",
+                                event_rx.len(),
+                                event_rx.capacity(),
+                            );
                             let mut service = self.clone();
                             if let Err(e) = service.handle(event.clone()).await {
                                 error!("Error handling event from event injector: {:?}", e);
+                                metrics::record_event_error("client", &e);
                             }
                         }
                         Err(e) => {
@@ -645,15 +846,72 @@ where
         Ok(())
     }
 
+    // Listens for events fired by timers and triggers Service Events
+    async fn listen_for_events_via_timers(&mut self) {
+        let timers = self.timers.clone();
+
+        while let Some(event) = timers.recv().await {
+            debug!("Timer fired event: {:?}", event);
+            let mut service = self.clone();
+            if let Err(e) = service.handle(event).await {
+                error!("Error handling event fired by timer: {:?}", e);
+                metrics::record_event_error("client", &e);
+            }
+        }
+
+        debug!("Timer event listener task ended");
+    }
+
+    // Returns the tcp client connected to the server of the given protocol, if any
+    async fn get_tcp_client(&self, protocol: Protocol) -> Option<Sv2EncryptedTcpClient> {
+        match protocol {
+            Protocol::MiningProtocol => self.mining_tcp_client.read().await.clone(),
+            Protocol::JobDeclarationProtocol => {
+                self.job_declaration_tcp_client.read().await.clone()
+            }
+            Protocol::TemplateDistributionProtocol => {
+                self.template_distribution_tcp_client.read().await.clone()
+            }
+        }
+    }
+
+    // Closes the connection to the server of the given protocol
+    //
+    // The tcp client is removed before shutting it down, so that the message listener
+    // knows the connection was closed on purpose.
+    async fn disconnect(&self, protocol: Protocol) {
+        let tcp_client = match protocol {
+            Protocol::MiningProtocol => self.mining_tcp_client.write().await.take(),
+            Protocol::JobDeclarationProtocol => {
+                self.job_declaration_tcp_client.write().await.take()
+            }
+            Protocol::TemplateDistributionProtocol => {
+                self.template_distribution_tcp_client.write().await.take()
+            }
+        };
+        if let Some(tcp_client) = tcp_client {
+            tcp_client.shutdown();
+        }
+    }
+
+    // Returns the subprotocol of a message, or None for Common messages
+    fn message_protocol(message: &AnyMessage<'_>) -> Option<Protocol> {
+        match message {
+            AnyMessage::Common(_) => None,
+            AnyMessage::Mining(_) => Some(Protocol::MiningProtocol),
+            AnyMessage::JobDeclaration(_) => Some(Protocol::JobDeclarationProtocol),
+            AnyMessage::TemplateDistribution(_) => Some(Protocol::TemplateDistributionProtocol),
+        }
+    }
+
     /// Checks if the handler for the given protocol is a null handler
     fn has_null_handler(protocol: Protocol) -> bool {
         match protocol {
             Protocol::MiningProtocol => {
                 std::any::TypeId::of::<M>() == std::any::TypeId::of::<NullSv2MiningClientHandler>()
             }
-            Protocol::JobDeclarationProtocol => {
-                todo!()
-            }
+            // todo: check job_declaration_handler once the J generic parameter is added
+            Protocol::JobDeclarationProtocol => true,
             Protocol::TemplateDistributionProtocol => {
                 std::any::TypeId::of::<T>()
                     == std::any::TypeId::of::<NullSv2TemplateDistributionClientHandler>()
@@ -662,22 +920,26 @@ where
     }
 }
 
-impl<M, T> Sv2Service for Sv2ClientService<M, T>
+impl<M, T> Sv2LayerInner<Sv2ClientEvent<'static>, Sv2ClientOutcome<'static>, Sv2ClientEventError>
+    for Sv2ClientService<M, T>
 where
     M: Sv2MiningClientHandler + Clone + Send + Sync + 'static,
     T: Sv2TemplateDistributionClientHandler + Clone + Send + Sync + 'static,
 {
-    type Event = Sv2ClientEvent<'static>;
-    type Outcome = Sv2ClientOutcome<'static>;
-    type ServiceError = Sv2ClientServiceError;
-    type EventError = Sv2ClientEventError;
-
     // we cannot use `async fn` syntax here because of the recursive calls to `self.handle`
-    fn handle(
+    fn handle_unlayered(
         &mut self,
         event: Sv2ClientEvent<'static>,
-    ) -> impl Future<Output = Result<Sv2ClientOutcome<'static>, Sv2ClientEventError>> + Send {
+    ) -> Sv2BoxFuture<'_, Result<Sv2ClientOutcome<'static>, Sv2ClientEventError>> {
         Box::pin(async move {
+            metrics::record_event("client", event.name());
+
+            // the server whose message is being handled, if any
+            let current_protocol = match &event {
+                Sv2ClientEvent::IncomingMessage(message) => Self::message_protocol(message),
+                _ => None,
+            };
+
             let outcome = match event {
                 Sv2ClientEvent::SetupConnectionTrigger(protocol, flags) => {
                     debug!("Sv2ClientService received a trigger event for initiating a connection under the {:?} protocol", protocol);
@@ -931,7 +1193,11 @@ where
                             }
                         }
                         AnyMessage::JobDeclaration(_job_declaration_message) => {
-                            todo!()
+                            // todo: route to job_declaration_handler once the J generic parameter is added
+                            error!("Sv2ClientService received a JobDeclaration message, but no job declaration handler is configured");
+                            Err(Sv2ClientEventError::UnsupportedProtocol {
+                                protocol: Protocol::JobDeclarationProtocol,
+                            })
                         }
                     }
                 }
@@ -1075,6 +1341,10 @@ where
                             max_additional_sigops,
                         ) => {
                             debug!("Sv2ClientService received a trigger event for sending CoinbaseOutputConstraints");
+                            self.coinbase_output_constraints
+                                .write()
+                                .await
+                                .replace((max_additional_size, max_additional_sigops));
                             self.template_distribution_handler
                                 .set_coinbase_output_constraints(
                                     max_additional_size,
@@ -1096,18 +1366,39 @@ where
                         }
                     }
                 }
-                Sv2ClientEvent::SendEventToSiblingServerService(event) => {
-                    debug!("Sv2ClientService received a SendEventToSiblingServerService event");
-                    match self.sibling_server_service_io {
+                Sv2ClientEvent::SendEventToSiblingServerService { sibling, event } => {
+                    debug!(
+                        "Sv2ClientService received a SendEventToSiblingServerService event for {}",
+                        sibling
+                    );
+                    match self.sibling_io {
+                        Some(ref io) => {
+                            io.send_to_server(&sibling, *event.clone())
+                                .await
+                                .map_err(Sv2ClientEventError::FailedToSendEventToSibling)?;
+                            Ok(Sv2ClientOutcome::Ok)
+                        }
+                        None => {
+                            error!("No sibling io on Sv2ClientService");
+                            Err(Sv2ClientEventError::NoSiblingIo)
+                        }
+                    }
+                }
+                Sv2ClientEvent::SendEventToSiblingClientService { sibling, event } => {
+                    debug!(
+                        "Sv2ClientService received a SendEventToSiblingClientService event for {}",
+                        sibling
+                    );
+                    match self.sibling_io {
                         Some(ref io) => {
-                            io.send(*event.clone()).map_err(|_| {
-                                Sv2ClientEventError::FailedToSendEventToSiblingServerService
-                            })?;
+                            io.send_to_client(&sibling, *event.clone())
+                                .await
+                                .map_err(Sv2ClientEventError::FailedToSendEventToSibling)?;
                             Ok(Sv2ClientOutcome::Ok)
                         }
                         None => {
-                            error!("No sibling server service on Sv2ClientService");
-                            Err(Sv2ClientEventError::NoSiblingServerServiceIo)
+                            error!("No sibling io on Sv2ClientService");
+                            Err(Sv2ClientEventError::NoSiblingIo)
                         }
                     }
                 }
@@ -1202,14 +1493,55 @@ where
                 }
             };
 
-            // allows for recursive chaining of events
-            if let Ok(Sv2ClientOutcome::TriggerNewEvent(event)) = outcome {
-                self.handle(*event).await
-            } else {
-                outcome
+            match outcome {
+                // allows for recursive chaining of events
+                Ok(Sv2ClientOutcome::TriggerNewEvent(event)) => self.handle(*event).await,
+                Ok(Sv2ClientOutcome::Reply(messages)) => {
+                    let protocol = current_protocol.ok_or(Sv2ClientEventError::NoCurrentServer)?;
+                    let tcp_client = self
+                        .get_tcp_client(protocol)
+                        .await
+                        .ok_or(Sv2ClientEventError::IsNotConnected)?;
+                    for message in messages {
+                        tcp_client.io.send_message(message).await?;
+                    }
+                    Ok(Sv2ClientOutcome::Ok)
+                }
+                Ok(Sv2ClientOutcome::Disconnect { reason }) => {
+                    let protocol = current_protocol.ok_or(Sv2ClientEventError::NoCurrentServer)?;
+                    info!("Disconnecting from {:?} server: {}", protocol, reason);
+                    self.disconnect(protocol).await;
+                    Ok(Sv2ClientOutcome::Ok)
+                }
+                Ok(Sv2ClientOutcome::Schedule(scheduled_event)) => {
+                    let handle = self.schedule(*scheduled_event);
+                    Ok(Sv2ClientOutcome::Scheduled(handle))
+                }
+                outcome => outcome,
             }
         })
     }
+}
+
+impl<M, T> Sv2Service for Sv2ClientService<M, T>
+where
+    M: Sv2MiningClientHandler + Clone + Send + Sync + 'static,
+    T: Sv2TemplateDistributionClientHandler + Clone + Send + Sync + 'static,
+{
+    type Event = Sv2ClientEvent<'static>;
+    type Outcome = Sv2ClientOutcome<'static>;
+    type ServiceError = Sv2ClientServiceError;
+    type EventError = Sv2ClientEventError;
+
+    // every event is handled inside its own span, and goes through the layers before reaching `handle_unlayered`
+    fn handle(
+        &mut self,
+        event: Sv2ClientEvent<'static>,
+    ) -> impl Future<Output = Result<Sv2ClientOutcome<'static>, Sv2ClientEventError>> + Send {
+        let layers = self.layers.clone();
+        let span = span::client_event_span(&event);
+        async move { layers.run(event, self).await }.instrument(span)
+    }
 
     async fn start(&mut self) -> Result<(), Sv2ClientServiceError> {
         for (protocol, flags) in self.config.supported_protocols() {
@@ -1234,9 +1566,9 @@ where
         }
 
         let mut this = self.clone();
-        if let Some(_sibling_server_service_io) = this.sibling_server_service_io.clone() {
+        if let Some(_sibling_io) = this.sibling_io.clone() {
             tokio::spawn(async move {
-                if let Err(e) = this.listen_for_events_via_sibling_server_service().await {
+                if let Err(e) = this.listen_for_events_via_sibling_io().await {
                     error!("Error listening for events: {:?}", e);
                 }
             });
@@ -1254,6 +1586,11 @@ where
             });
         }
 
+        let mut this = self.clone();
+        tokio::spawn(async move {
+            this.listen_for_events_via_timers().await;
+        });
+
         if !Self::has_null_handler(Protocol::MiningProtocol) {
             match self
                 .handle(Sv2ClientEvent::MiningTrigger(MiningClientTrigger::Start))
@@ -1318,6 +1655,8 @@ mod tests {
     use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
     use crate::server::service::subprotocols::mining::trigger::MiningServerTrigger;
     use crate::server::service::Sv2ServerService;
+    use crate::sibling::{Sv2SiblingBus, Sv2SiblingBusError};
+    use crate::testing::template_provider::Sv2MockTemplateProvider;
     use crate::Sv2Service;
     use integration_tests_sv2::interceptor::MessageDirection;
     use integration_tests_sv2::start_sniffer;
@@ -1327,7 +1666,9 @@ mod tests {
     use std::net::IpAddr;
     use std::net::Ipv4Addr;
     use std::net::SocketAddr;
+    use std::net::TcpListener;
     use std::str::FromStr;
+    use std::time::Duration;
     use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::B064K;
     use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
     use stratum_common::roles_logic_sv2::mining_sv2::OpenExtendedMiningChannel;
@@ -1505,7 +1846,7 @@ mod tests {
         }
     }
 
-    // A template distribution handler that is sending events using the Sibling IO feature
+    // A template distribution handler that is sending events to the "mining_server" sibling
     #[derive(Debug, Clone, Default)]
     struct SiblingIoTemplateDistributionClientHandler;
 
@@ -1519,11 +1860,12 @@ mod tests {
             template: NewTemplate<'_>,
         ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
             let outcome = Sv2ClientOutcome::TriggerNewEvent(Box::new(
-                Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                    Sv2ServerEvent::MiningTrigger(MiningServerTrigger::NewTemplate(
-                        template.into_static(),
+                Sv2ClientEvent::SendEventToSiblingServerService {
+                    sibling: "mining_server".to_string(),
+                    event: Box::new(Sv2ServerEvent::MiningTrigger(
+                        MiningServerTrigger::NewTemplate(template.into_static()),
                     )),
-                )),
+                },
             ));
             Ok(outcome)
         }
@@ -1533,11 +1875,12 @@ mod tests {
             prev_hash: SetNewPrevHash<'_>,
         ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
             let outcome = Sv2ClientOutcome::TriggerNewEvent(Box::new(
-                Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                    Sv2ServerEvent::MiningTrigger(MiningServerTrigger::SetNewPrevHash(
-                        prev_hash.into_static(),
+                Sv2ClientEvent::SendEventToSiblingServerService {
+                    sibling: "mining_server".to_string(),
+                    event: Box::new(Sv2ServerEvent::MiningTrigger(
+                        MiningServerTrigger::SetNewPrevHash(prev_hash.into_static()),
                     )),
-                )),
+                },
             ));
             Ok(outcome)
         }
@@ -1644,10 +1987,42 @@ mod tests {
         }
     }
 
+    // starts a Sv2MockTemplateProvider on a free port, so tests don't need bitcoind
+    async fn start_mock_template_provider(
+        cancellation_token: CancellationToken,
+    ) -> (Sv2MockTemplateProvider, SocketAddr) {
+        let tp_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: tp_addr,
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let template_provider =
+            Sv2MockTemplateProvider::new(tcp_config, cancellation_token).unwrap();
+        let mut service = template_provider.clone();
+        tokio::spawn(async move { service.start().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        (template_provider, tp_addr)
+    }
+
     #[tokio::test]
     async fn sv2_client_service_initiate_connection_success() {
-        // start a TemplateProvider
-        let (_tp, tp_addr) = integration_tests_sv2::start_template_provider(None);
+        let cancellation_token = CancellationToken::new();
+
+        // start a mock TemplateProvider
+        let (_tp, tp_addr) = start_mock_template_provider(cancellation_token.clone()).await;
 
         let template_distribution_config = Sv2ClientServiceTemplateDistributionConfig {
             coinbase_output_constraints: (1, 1),
@@ -1672,13 +2047,11 @@ mod tests {
 
         let template_distribution_handler = DummyTemplateDistributionClientHandler;
 
-        let cancellation_token = CancellationToken::new();
-
         let mut sv2_client_service = Sv2ClientService::new(
             sv2_client_service_config,
             NullSv2MiningClientHandler,
             template_distribution_handler,
-            cancellation_token,
+            cancellation_token.clone(),
         )
         .unwrap();
 
@@ -1707,6 +2080,8 @@ mod tests {
                 .is_connected(Protocol::TemplateDistributionProtocol)
                 .await
         );
+
+        cancellation_token.cancel();
     }
 
     #[tokio::test]
@@ -2014,7 +2389,7 @@ mod tests {
             template_distribution_config: Some(template_distribution_config),
         };
 
-        let (tx, rx) = async_channel::unbounded::<Sv2ClientEvent<'static>>();
+        let (tx, rx) = async_channel::bounded::<Sv2ClientEvent<'static>>(64);
 
         let cancellation_token = CancellationToken::new();
 
@@ -2066,6 +2441,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 3600,
+            disconnect_on_protocol_violation: false,
             tcp_config: Sv2ServerTcpConfig {
                 listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3333),
                 pub_key: Secp256k1PublicKey::from_str(
@@ -2120,37 +2496,31 @@ mod tests {
         // Allow some time for the sniffer to initialize.
         tokio::time::sleep(std::time::Duration::from_millis(200)).await;
 
-        // Clone the template_distribution_config for later use to avoid moving it.
-        let template_distribution_config = client_config
-            .template_distribution_config
-            .as_ref()
-            .unwrap()
-            .clone();
-
         // Initialize the handlers for TemplateDistribution and MiningServer.
         let tdc_handler = SiblingIoTemplateDistributionClientHandler;
         let mining_server_handler = DummyMiningServerHandler;
 
         let cancellation_token = CancellationToken::new();
 
-        // Create the Sv2ServerService and its sibling IO for communication with the client.
-        let (
-            server_service,
-            sibling_server_io, // SiblingIO is created here.
-        ) = Sv2ServerService::new_with_sibling_io(
+        // Register both services on a sibling bus.
+        let sibling_bus = Sv2SiblingBus::new(1024).unwrap();
+        let server_sibling_io = sibling_bus.register_server("mining_server").unwrap();
+        let client_sibling_io = sibling_bus.register_client("tp_client").unwrap();
+
+        // Create the Sv2ServerService and the Sv2ClientService from their sibling IOs.
+        let server_service = Sv2ServerService::new_from_sibling_io(
             server_config,
             mining_server_handler,
+            server_sibling_io,
             cancellation_token.clone(),
         )
         .unwrap();
 
-        // Create the Sv2ClientService and connect it to the server using the sibling IO.
-
         let mut client_service = Sv2ClientService::new_from_sibling_io(
             client_config.clone(),
             NullSv2MiningClientHandler,
             tdc_handler,
-            sibling_server_io,
+            client_sibling_io,
             cancellation_token.clone(),
         )
         .unwrap();
@@ -2171,17 +2541,7 @@ mod tests {
         // Wait for client to be ready
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
 
-        // Trigger the Template Provider to set coinbase output constraints.
-        client_service
-            .handle(Sv2ClientEvent::TemplateDistributionTrigger(
-                TemplateDistributionClientTrigger::SetCoinbaseOutputConstraints(
-                    template_distribution_config.coinbase_output_constraints.0,
-                    template_distribution_config.coinbase_output_constraints.1,
-                ),
-            ))
-            .await
-            .unwrap();
-
+        // The client service sends the configured coinbase output constraints right after connecting.
         // Wait for the sniffer to detect the coinbase output constraints message.
         tp_sniffer
             .wait_for_message_type(
@@ -2215,9 +2575,12 @@ mod tests {
 
         // Send the NewTemplate message to the sibling server and verify the outcome.
         let new_template_outcome = client_service
-            .handle(Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                Sv2ServerEvent::MiningTrigger(MiningServerTrigger::NewTemplate(new_template)),
-            )))
+            .handle(Sv2ClientEvent::SendEventToSiblingServerService {
+                sibling: "mining_server".to_string(),
+                event: Box::new(Sv2ServerEvent::MiningTrigger(
+                    MiningServerTrigger::NewTemplate(new_template),
+                )),
+            })
             .await;
 
         // Assert that the outcome was sent to the sibling server.
@@ -2238,15 +2601,32 @@ mod tests {
 
         // Send the SetNewPrevHash message to the sibling server and verify the outcome.
         let new_prev_hash_outcome = client_service
-            .handle(Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                Sv2ServerEvent::MiningTrigger(MiningServerTrigger::SetNewPrevHash(new_prev_hash)),
-            )))
+            .handle(Sv2ClientEvent::SendEventToSiblingServerService {
+                sibling: "mining_server".to_string(),
+                event: Box::new(Sv2ServerEvent::MiningTrigger(
+                    MiningServerTrigger::SetNewPrevHash(new_prev_hash),
+                )),
+            })
             .await
             .unwrap();
 
         // Assert that the outcome from the MiningServerHandler is received.
         assert!(matches!(new_prev_hash_outcome, Sv2ClientOutcome::Ok));
 
+        // Events addressed to an unknown sibling are rejected.
+        let unknown_sibling_outcome = client_service
+            .handle(Sv2ClientEvent::SendEventToSiblingServerService {
+                sibling: "pool_server".to_string(),
+                event: Box::new(Sv2ServerEvent::DisconnectClient(0)),
+            })
+            .await;
+        assert!(matches!(
+            unknown_sibling_outcome,
+            Err(Sv2ClientEventError::FailedToSendEventToSibling(
+                Sv2SiblingBusError::SiblingNotFound(_)
+            ))
+        ));
+
         // Shutdown the server and client services gracefully.
         cancellation_token.cancel();
     }
@@ -2257,6 +2637,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 3600,
+            disconnect_on_protocol_violation: false,
             tcp_config: Sv2ServerTcpConfig {
                 listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3333),
                 pub_key: Secp256k1PublicKey::from_str(
@@ -2370,15 +2751,18 @@ mod tests {
 
         // Send the NewTemplate message to the sibling server and verify the outcome.
         let new_template_outcome = client_service
-            .handle(Sv2ClientEvent::SendEventToSiblingServerService(Box::new(
-                Sv2ServerEvent::MiningTrigger(MiningServerTrigger::NewTemplate(new_template)),
-            )))
+            .handle(Sv2ClientEvent::SendEventToSiblingServerService {
+                sibling: "mining_server".to_string(),
+                event: Box::new(Sv2ServerEvent::MiningTrigger(
+                    MiningServerTrigger::NewTemplate(new_template),
+                )),
+            })
             .await;
 
-        // Assert that the error is of type Sv2ClientEventError::NoSiblingServerServiceIo.
+        // Assert that the error is of type Sv2ClientEventError::NoSiblingIo.
         assert!(matches!(
             new_template_outcome,
-            Err(Sv2ClientEventError::NoSiblingServerServiceIo)
+            Err(Sv2ClientEventError::NoSiblingIo)
         ));
 
         // Shutdown the server and client services gracefully.
@@ -2393,8 +2777,10 @@ mod tests {
         use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
         use stratum_common::roles_logic_sv2::template_distribution_sv2::SubmitSolution;
 
-        // Start a TemplateProvider
-        let (_tp, tp_addr) = integration_tests_sv2::start_template_provider(None);
+        let cancellation_token = CancellationToken::new();
+
+        // Start a mock TemplateProvider
+        let (tp, tp_addr) = start_mock_template_provider(cancellation_token.clone()).await;
 
         let template_distribution_config = Sv2ClientServiceTemplateDistributionConfig {
             coinbase_output_constraints: (1, 1),
@@ -2419,13 +2805,11 @@ mod tests {
 
         let template_distribution_handler = DummyTemplateDistributionClientHandler;
 
-        let cancellation_token = CancellationToken::new();
-
         let mut sv2_client_service = Sv2ClientService::new(
             sv2_client_service_config,
             NullSv2MiningClientHandler,
             template_distribution_handler,
-            cancellation_token,
+            cancellation_token.clone(),
         )
         .unwrap();
 
@@ -2442,7 +2826,7 @@ mod tests {
             template_id: 0,
             version: 0,
             header_timestamp: 0,
-            header_nonce: 0,
+            header_nonce: 42,
             coinbase_tx: B064K::Owned(vec![]),
         };
 
@@ -2452,5 +2836,89 @@ mod tests {
 
         let outcome = sv2_client_service.handle(submit_solution_event).await;
         assert!(matches!(outcome, Ok(Sv2ClientOutcome::Ok)));
+
+        // the TemplateProvider received the solution
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let solutions = tp.submitted_solutions();
+        assert_eq!(solutions.len(), 1);
+        assert_eq!(solutions[0].header_nonce, 42);
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn sv2_client_service_sends_coinbase_output_constraints() {
+        let cancellation_token = CancellationToken::new();
+
+        // start a mock TemplateProvider
+        let (tp, tp_addr) = start_mock_template_provider(cancellation_token.clone()).await;
+
+        let template_distribution_config = Sv2ClientServiceTemplateDistributionConfig {
+            coinbase_output_constraints: (1, 1),
+            server_addr: tp_addr,
+            auth_pk: None,
+            setup_connection_flags: 0,
+        };
+
+        let sv2_client_service_config = Sv2ClientServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            endpoint_host: None,
+            endpoint_port: None,
+            vendor: None,
+            hardware_version: None,
+            firmware: None,
+            device_id: None,
+            mining_config: None,
+            job_declaration_config: None,
+            template_distribution_config: Some(template_distribution_config.clone()),
+        };
+
+        let mut sv2_client_service = Sv2ClientService::new(
+            sv2_client_service_config,
+            NullSv2MiningClientHandler,
+            DummyTemplateDistributionClientHandler,
+            cancellation_token.clone(),
+        )
+        .unwrap();
+
+        let initiate_connection_event = Sv2ClientEvent::SetupConnectionTrigger(
+            Protocol::TemplateDistributionProtocol,
+            template_distribution_config.setup_connection_flags,
+        );
+
+        // the configured constraints are sent right after SetupConnectionSuccess
+        let outcome = sv2_client_service
+            .handle(initiate_connection_event.clone())
+            .await;
+        assert!(matches!(outcome, Ok(Sv2ClientOutcome::Ok)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(tp.coinbase_output_constraints(), vec![(1, 1)]);
+
+        // updated constraints are sent right away while connected
+        let outcome = sv2_client_service
+            .set_coinbase_output_constraints(100, 2)
+            .await;
+        assert!(matches!(outcome, Ok(Sv2ClientOutcome::Ok)));
+        assert_eq!(
+            sv2_client_service.coinbase_output_constraints().await,
+            Some((100, 2))
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(tp.coinbase_output_constraints(), vec![(1, 1), (100, 2)]);
+
+        // and they are the ones sent after a reconnection
+        sv2_client_service
+            .disconnect(Protocol::TemplateDistributionProtocol)
+            .await;
+        let outcome = sv2_client_service.handle(initiate_connection_event).await;
+        assert!(matches!(outcome, Ok(Sv2ClientOutcome::Ok)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            tp.coinbase_output_constraints(),
+            vec![(1, 1), (100, 2), (100, 2)]
+        );
+
+        cancellation_token.cancel();
     }
 }
@@ -1,8 +1,28 @@
 use crate::client::service::event::Sv2ClientEvent;
+use crate::timer::{Sv2ScheduledEvent, Sv2TimerHandle};
+use stratum_common::roles_logic_sv2::parsers::AnyMessage;
 
 /// The outcome type for the service [`crate::client::service::Sv2ClientService`].
+///
+/// `Reply` and `Disconnect` target the "current server", i.e.: the server that sent the message being handled.
+/// Returning them while handling an event that did not come from a server (e.g.: a trigger) results in
+/// [`crate::client::service::event::Sv2ClientEventError::NoCurrentServer`].
+///
+/// There is no `Broadcast` equivalent, since the client is connected to at most one server per subprotocol.
 #[derive(Debug)]
 pub enum Sv2ClientOutcome<'a> {
     TriggerNewEvent(Box<Sv2ClientEvent<'a>>),
+    /// Send an ordered sequence of Sv2 messages to the current server.
+    Reply(Vec<AnyMessage<'a>>),
+    /// Close the connection to the current server.
+    Disconnect {
+        reason: String,
+    },
+    /// The event was handled, and deliberately produced no response.
+    NoReply,
+    /// Fire an event later, or periodically, until cancelled via its [`Sv2TimerHandle`] or the service shuts down.
+    Schedule(Box<Sv2ScheduledEvent<Sv2ClientEvent<'a>>>),
+    /// The timer was started.
+    Scheduled(Sv2TimerHandle),
     Ok,
 }
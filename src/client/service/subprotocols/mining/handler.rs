@@ -1,6 +1,7 @@
 use crate::client::service::event::Sv2ClientEventError;
 use crate::client::service::outcome::Sv2ClientOutcome;
 
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
 use stratum_common::roles_logic_sv2::mining_sv2::{
     CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannelSuccess,
     OpenMiningChannelError, OpenStandardMiningChannelSuccess, SetCustomMiningJobError,
@@ -98,126 +99,150 @@ pub trait Sv2MiningClientHandler {
 ///
 /// It should be used when creating a [`crate::client::service::Sv2ClientService`] that
 /// does not support the Mining protocol.
+///
+/// It never panics: every handler method returns [`Sv2ClientEventError::UnsupportedProtocol`].
 #[derive(Debug, Clone)]
 pub struct NullSv2MiningClientHandler;
 
 impl Sv2MiningClientHandler for NullSv2MiningClientHandler {
     async fn start(&mut self) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement start");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_open_standard_mining_channel_success(
         &mut self,
         _open_standard_mining_channel_success: OpenStandardMiningChannelSuccess<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_open_standard_mining_channel_success");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_open_extended_mining_channel_success(
         &mut self,
         _open_extended_mining_channel_success: OpenExtendedMiningChannelSuccess<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_open_extended_mining_channel_success");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_open_mining_channel_error(
         &mut self,
         _open_standard_mining_channel_error: OpenMiningChannelError<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_open_standard_mining_channel_error");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_update_channel_error(
         &mut self,
         _update_channel_error: UpdateChannelError<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_update_channel_error");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_close_channel(
         &mut self,
         _close_channel: CloseChannel<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_close_channel");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_set_extranonce_prefix(
         &mut self,
         _set_extranonce_prefix: SetExtranoncePrefix<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2MiningClientHandler does not implement handle_set_extranonce_prefix"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_submit_shares_success(
         &mut self,
         _submit_shares_success: SubmitSharesSuccess,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2MiningClientHandler does not implement handle_submit_shares_success"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_submit_shares_error(
         &mut self,
         _submit_shares_error: SubmitSharesError<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_submit_shares_error");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_new_mining_job(
         &mut self,
         _new_mining_job: NewMiningJob<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_new_mining_job");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_new_extended_mining_job(
         &mut self,
         _new_extended_mining_job: NewExtendedMiningJob<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2MiningClientHandler does not implement handle_new_extended_mining_job"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_set_new_prev_hash(
         &mut self,
         _set_new_prev_hash: SetNewPrevHash<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_set_new_prev_hash");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_set_custom_mining_job_success(
         &mut self,
         _set_custom_mining_job_success: SetCustomMiningJobSuccess,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2MiningClientHandler does not implement handle_set_custom_mining_job_success"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_set_custom_mining_job_error(
         &mut self,
         _set_custom_mining_job_error: SetCustomMiningJobError<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2MiningClientHandler does not implement handle_set_custom_mining_job_error"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_set_target(
         &mut self,
         _set_target: SetTarget<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_set_target");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     async fn handle_set_group_channel(
         &mut self,
         _set_group_channel: SetGroupChannel<'_>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2MiningClientHandler does not implement handle_set_group_channel");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 }
@@ -1,6 +1,7 @@
 use crate::client::service::event::{Sv2ClientEvent, Sv2ClientEventError};
 use crate::client::service::outcome::Sv2ClientOutcome;
 
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
 use stratum_common::roles_logic_sv2::parsers::TemplateDistribution;
 use stratum_common::roles_logic_sv2::template_distribution_sv2::{
     CoinbaseOutputConstraints, NewTemplate, RequestTransactionData, RequestTransactionDataError,
@@ -8,6 +9,9 @@ use stratum_common::roles_logic_sv2::template_distribution_sv2::{
 };
 
 /// Trait that must be implemented in case [`crate::client::service::Sv2ClientService`] supports the Template Distribution protocol
+///
+/// Templates arrive one message at a time: [`super::store::Sv2TemplateStore`] keeps them together with their
+/// `SetNewPrevHash` and transaction data.
 pub trait Sv2TemplateDistributionClientHandler {
     fn start(
         &mut self,
@@ -89,53 +93,61 @@ pub trait Sv2TemplateDistributionClientHandler {
 ///
 /// It should be used when creating a [`crate::client::service::Sv2ClientService`] that
 /// does not support the Template Distribution protocol.
+///
+/// It never panics: every handler method returns [`Sv2ClientEventError::UnsupportedProtocol`].
 #[derive(Debug, Clone)]
 pub struct NullSv2TemplateDistributionClientHandler;
 
 impl Sv2TemplateDistributionClientHandler for NullSv2TemplateDistributionClientHandler {
     async fn start(&mut self) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2TemplateDistributionClientHandler does not implement start");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 
     async fn handle_new_template(
         &self,
         _template: NewTemplate<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2TemplateDistributionClientHandler does not implement handle_new_template"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 
     async fn handle_set_new_prev_hash(
         &self,
         _prev_hash: SetNewPrevHash<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2TemplateDistributionClientHandler does not implement handle_set_new_prev_hash"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 
     async fn handle_request_transaction_data_success(
         &self,
         _transaction_data: RequestTransactionDataSuccess<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2TemplateDistributionClientHandler does not implement handle_request_transaction_data_success");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 
     async fn handle_request_transaction_data_error(
         &self,
         _error: RequestTransactionDataError<'static>,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2TemplateDistributionClientHandler does not implement handle_request_transaction_data_error");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 
     async fn transaction_data_needed(
         &self,
         _template_id: u64,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!(
-            "NullSv2TemplateDistributionClientHandler does not implement request_transaction_data"
-        );
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 
     async fn set_coinbase_output_constraints(
@@ -143,6 +155,8 @@ impl Sv2TemplateDistributionClientHandler for NullSv2TemplateDistributionClientH
         _coinbase_output_max_additional_size: u32,
         _coinbase_output_max_additional_sigops: u16,
     ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
-        unimplemented!("NullSv2TemplateDistributionClientHandler does not implement send_coinbase_output_constraints");
+        Err(Sv2ClientEventError::UnsupportedProtocol {
+            protocol: Protocol::TemplateDistributionProtocol,
+        })
     }
 }
@@ -0,0 +1,363 @@
+//! A store of recent templates for [`crate::client::service::subprotocols::template_distribution::handler::Sv2TemplateDistributionClientHandler`]s.
+//!
+//! The Template Distribution protocol spreads what a template is made of across several messages:
+//! a `NewTemplate`, the `SetNewPrevHash` that activates it (for future templates), and the transactions
+//! that must be requested separately via `RequestTransactionData`.
+//!
+//! [`Sv2TemplateStore`] puts them back together, keyed by `template_id`:
+//! - handlers feed it every message they receive, from the matching handler method
+//! - it optionally asks for the transaction data of every new template, see [`Sv2TemplateStoreConfig::request_transaction_data`]
+//! - on `SetNewPrevHash`, every template that is not built on the new chain tip is evicted
+//!
+//! ```ignore
+//! async fn handle_new_template(
+//!     &self,
+//!     template: NewTemplate<'static>,
+//! ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+//!     // requests the transaction data, if configured to
+//!     Ok(self.store.on_new_template(template))
+//! }
+//!
+//! async fn handle_request_transaction_data_success(
+//!     &self,
+//!     transaction_data: RequestTransactionDataSuccess<'static>,
+//! ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+//!     if let Some(stored) = self.store.on_transaction_data_success(transaction_data) {
+//!         // stored.template, stored.prev_hash and the transactions, all together
+//!     }
+//!     Ok(Sv2ClientOutcome::Ok)
+//! }
+//! ```
+
+use crate::client::service::event::Sv2ClientEvent;
+use crate::client::service::outcome::Sv2ClientOutcome;
+use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use stratum_common::roles_logic_sv2::template_distribution_sv2::{
+    NewTemplate, RequestTransactionDataError, RequestTransactionDataSuccess, SetNewPrevHash,
+};
+use tracing::debug;
+
+/// Configuration of a [`Sv2TemplateStore`]
+#[derive(Debug, Clone)]
+pub struct Sv2TemplateStoreConfig {
+    /// How many templates are kept at most, the oldest ones being evicted first
+    pub max_templates: usize,
+    /// Whether `RequestTransactionData` is sent for every new template
+    pub request_transaction_data: bool,
+}
+
+impl Default for Sv2TemplateStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_templates: 32,
+            request_transaction_data: true,
+        }
+    }
+}
+
+/// The transaction data of a [`Sv2StoredTemplate`]
+#[derive(Debug, Clone)]
+pub enum Sv2TemplateTransactionData {
+    /// `RequestTransactionData` was not sent for this template
+    NotRequested,
+    /// `RequestTransactionData` was sent, and the Template Provider has not answered yet
+    Pending,
+    /// The Template Provider answered with `RequestTransactionDataSuccess`
+    Received(RequestTransactionDataSuccess<'static>),
+    /// The Template Provider answered with `RequestTransactionDataError`, with this error code
+    Failed(String),
+}
+
+/// A template, along with everything else the Template Provider sent about it
+#[derive(Debug, Clone)]
+pub struct Sv2StoredTemplate {
+    pub template: NewTemplate<'static>,
+    /// The chain tip this template is built on.
+    ///
+    /// `None` for a future template, until its `SetNewPrevHash` arrives.
+    pub prev_hash: Option<SetNewPrevHash<'static>>,
+    pub transaction_data: Sv2TemplateTransactionData,
+}
+
+/// Keeps recent templates keyed by `template_id`, and correlates them with their `SetNewPrevHash` and transaction data.
+///
+/// Clones see the same templates.
+#[derive(Debug, Clone)]
+pub struct Sv2TemplateStore {
+    config: Sv2TemplateStoreConfig,
+    state: Arc<Mutex<TemplateStoreState>>,
+}
+
+#[derive(Debug, Default)]
+struct TemplateStoreState {
+    // oldest first
+    templates: VecDeque<Sv2StoredTemplate>,
+    // the SetNewPrevHash of the current chain tip
+    prev_hash: Option<SetNewPrevHash<'static>>,
+}
+
+impl TemplateStoreState {
+    fn get_mut(&mut self, template_id: u64) -> Option<&mut Sv2StoredTemplate> {
+        self.templates
+            .iter_mut()
+            .find(|stored| stored.template.template_id == template_id)
+    }
+}
+
+impl Sv2TemplateStore {
+    /// Creates a new, empty [`Sv2TemplateStore`]
+    pub fn new(config: Sv2TemplateStoreConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(TemplateStoreState::default())),
+        }
+    }
+
+    /// Stores a `NewTemplate`, to be called from `handle_new_template`.
+    ///
+    /// A non-future template is built on the current chain tip.
+    ///
+    /// Returns the outcome for `handle_new_template`: a trigger for `RequestTransactionData` if
+    /// [`Sv2TemplateStoreConfig::request_transaction_data`] is set, [`Sv2ClientOutcome::Ok`] otherwise.
+    pub fn on_new_template(&self, template: NewTemplate<'static>) -> Sv2ClientOutcome<'static> {
+        let template_id = template.template_id;
+        let mut state = self.state.lock().expect("template store lock poisoned");
+
+        let prev_hash = if template.future_template {
+            None
+        } else {
+            state.prev_hash.clone()
+        };
+        let transaction_data = if self.config.request_transaction_data {
+            Sv2TemplateTransactionData::Pending
+        } else {
+            Sv2TemplateTransactionData::NotRequested
+        };
+
+        state
+            .templates
+            .retain(|stored| stored.template.template_id != template_id);
+        state.templates.push_back(Sv2StoredTemplate {
+            template,
+            prev_hash,
+            transaction_data,
+        });
+        while state.templates.len() > self.config.max_templates {
+            if let Some(evicted) = state.templates.pop_front() {
+                debug!(
+                    "Template store is full, evicting template_id {}",
+                    evicted.template.template_id
+                );
+            }
+        }
+
+        if self.config.request_transaction_data {
+            Sv2ClientOutcome::TriggerNewEvent(Box::new(
+                Sv2ClientEvent::TemplateDistributionTrigger(
+                    TemplateDistributionClientTrigger::TransactionDataNeeded(template_id),
+                ),
+            ))
+        } else {
+            Sv2ClientOutcome::Ok
+        }
+    }
+
+    /// Moves the store to a new chain tip, to be called from `handle_set_new_prev_hash`.
+    ///
+    /// Every template other than the one activated by `prev_hash` is evicted, since it is built on the old chain tip.
+    ///
+    /// Returns the activated template, or `None` if it is not in the store.
+    pub fn on_set_new_prev_hash(
+        &self,
+        prev_hash: SetNewPrevHash<'static>,
+    ) -> Option<Sv2StoredTemplate> {
+        let template_id = prev_hash.template_id;
+        let mut state = self.state.lock().expect("template store lock poisoned");
+
+        state
+            .templates
+            .retain(|stored| stored.template.template_id == template_id);
+        state.prev_hash = Some(prev_hash.clone());
+
+        let activated = state.get_mut(template_id)?;
+        activated.prev_hash = Some(prev_hash);
+        Some(activated.clone())
+    }
+
+    /// Records the transaction data of a template, to be called from `handle_request_transaction_data_success`.
+    ///
+    /// Returns the complete template, or `None` if it was evicted in the meantime (e.g.: it went stale).
+    pub fn on_transaction_data_success(
+        &self,
+        transaction_data: RequestTransactionDataSuccess<'static>,
+    ) -> Option<Sv2StoredTemplate> {
+        let mut state = self.state.lock().expect("template store lock poisoned");
+        let stored = state.get_mut(transaction_data.template_id)?;
+        stored.transaction_data = Sv2TemplateTransactionData::Received(transaction_data);
+        Some(stored.clone())
+    }
+
+    /// Records a failed `RequestTransactionData`, to be called from `handle_request_transaction_data_error`.
+    ///
+    /// Returns the template it was requested for, or `None` if it was evicted in the meantime.
+    pub fn on_transaction_data_error(
+        &self,
+        error: RequestTransactionDataError<'static>,
+    ) -> Option<Sv2StoredTemplate> {
+        let error_code = String::from_utf8_lossy(error.error_code.inner_as_ref()).to_string();
+        let mut state = self.state.lock().expect("template store lock poisoned");
+        let stored = state.get_mut(error.template_id)?;
+        stored.transaction_data = Sv2TemplateTransactionData::Failed(error_code);
+        Some(stored.clone())
+    }
+
+    /// Returns the template with this `template_id`, if it is still in the store.
+    pub fn get(&self, template_id: u64) -> Option<Sv2StoredTemplate> {
+        self.state
+            .lock()
+            .expect("template store lock poisoned")
+            .get_mut(template_id)
+            .map(|stored| stored.clone())
+    }
+
+    /// Returns the `SetNewPrevHash` of the current chain tip, if any was received.
+    pub fn prev_hash(&self) -> Option<SetNewPrevHash<'static>> {
+        self.state
+            .lock()
+            .expect("template store lock poisoned")
+            .prev_hash
+            .clone()
+    }
+
+    /// Returns the ids of the stored templates, oldest first.
+    pub fn template_ids(&self) -> Vec<u64> {
+        self.state
+            .lock()
+            .expect("template store lock poisoned")
+            .templates
+            .iter()
+            .map(|stored| stored.template.template_id)
+            .collect()
+    }
+
+    /// Returns how many templates are stored.
+    pub fn len(&self) -> usize {
+        self.state
+            .lock()
+            .expect("template store lock poisoned")
+            .templates
+            .len()
+    }
+
+    /// Returns whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2TemplateStore, Sv2TemplateStoreConfig, Sv2TemplateTransactionData};
+    use crate::client::service::event::Sv2ClientEvent;
+    use crate::client::service::outcome::Sv2ClientOutcome;
+    use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
+    use crate::testing::fixtures::{new_template, set_new_prev_hash};
+    use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::{Seq064K, B064K};
+    use stratum_common::roles_logic_sv2::template_distribution_sv2::{
+        RequestTransactionDataError, RequestTransactionDataSuccess,
+    };
+
+    #[test]
+    fn future_template_is_activated_and_stale_templates_evicted() {
+        let store = Sv2TemplateStore::new(Sv2TemplateStoreConfig {
+            max_templates: 8,
+            request_transaction_data: false,
+        });
+
+        assert!(matches!(
+            store.on_new_template(new_template(1, true)),
+            Sv2ClientOutcome::Ok
+        ));
+        assert!(store.on_set_new_prev_hash(set_new_prev_hash(1)).is_some());
+
+        // a non-future template is built on the current chain tip
+        store.on_new_template(new_template(2, false));
+        assert!(store.get(2).unwrap().prev_hash.is_some());
+
+        // a future template waits for its SetNewPrevHash
+        store.on_new_template(new_template(3, true));
+        assert!(store.get(3).unwrap().prev_hash.is_none());
+        assert_eq!(store.template_ids(), vec![1, 2, 3]);
+
+        // the new chain tip makes every other template stale
+        let activated = store.on_set_new_prev_hash(set_new_prev_hash(3)).unwrap();
+        assert_eq!(activated.template.template_id, 3);
+        assert_eq!(activated.prev_hash.unwrap().template_id, 3);
+        assert_eq!(store.template_ids(), vec![3]);
+        assert_eq!(store.prev_hash().unwrap().template_id, 3);
+
+        // a SetNewPrevHash for an unknown template still moves the chain tip
+        assert!(store.on_set_new_prev_hash(set_new_prev_hash(4)).is_none());
+        assert!(store.is_empty());
+        assert_eq!(store.prev_hash().unwrap().template_id, 4);
+    }
+
+    #[test]
+    fn transaction_data_is_requested_and_correlated() {
+        let store = Sv2TemplateStore::new(Sv2TemplateStoreConfig {
+            max_templates: 2,
+            request_transaction_data: true,
+        });
+
+        match store.on_new_template(new_template(1, false)) {
+            Sv2ClientOutcome::TriggerNewEvent(event) => assert!(matches!(
+                *event,
+                Sv2ClientEvent::TemplateDistributionTrigger(
+                    TemplateDistributionClientTrigger::TransactionDataNeeded(1)
+                )
+            )),
+            _ => panic!("expected a RequestTransactionData trigger"),
+        }
+        store.on_new_template(new_template(2, false));
+        assert!(matches!(
+            store.get(2).unwrap().transaction_data,
+            Sv2TemplateTransactionData::Pending
+        ));
+
+        let stored = store
+            .on_transaction_data_success(RequestTransactionDataSuccess {
+                template_id: 1,
+                excess_data: B064K::Owned(vec![]),
+                transaction_list: Seq064K::new(vec![]).unwrap(),
+            })
+            .unwrap();
+        assert!(matches!(
+            stored.transaction_data,
+            Sv2TemplateTransactionData::Received(_)
+        ));
+
+        let stored = store
+            .on_transaction_data_error(RequestTransactionDataError {
+                template_id: 2,
+                error_code: "stale-template-id".to_string().try_into().unwrap(),
+            })
+            .unwrap();
+        assert!(matches!(
+            stored.transaction_data,
+            Sv2TemplateTransactionData::Failed(ref error_code) if error_code == "stale-template-id"
+        ));
+
+        // the store is full, so the oldest template is evicted, and its transaction data is ignored
+        store.on_new_template(new_template(3, false));
+        assert_eq!(store.template_ids(), vec![2, 3]);
+        assert!(store
+            .on_transaction_data_success(RequestTransactionDataSuccess {
+                template_id: 1,
+                excess_data: B064K::Owned(vec![]),
+                transaction_list: Seq064K::new(vec![]).unwrap(),
+            })
+            .is_none());
+    }
+}
@@ -36,7 +36,7 @@ impl Sv2EncryptedTcpClient {
         )
         .await
         {
-            let sv2_message_io = Sv2MessageIo { rx, tx };
+            let sv2_message_io = Sv2MessageIo::new(rx, tx, Some(server_addr));
             tracing::info!("connected to: {}", server_addr);
             Some(Self { io: sv2_message_io })
         } else {
@@ -22,7 +22,7 @@ impl Sv2UnencryptedTcpClient {
         let tcp_stream = TcpStream::connect(server_addr).await.ok()?;
 
         let (rx, tx) = PlainConnection::new::<'static, AnyMessage<'static>>(tcp_stream).await;
-        let sv2_message_io = Sv2MessageIo { rx, tx };
+        let sv2_message_io = Sv2MessageIo::new(rx, tx, Some(server_addr));
         tracing::info!("connected to: {}", server_addr);
         Some(Self { io: sv2_message_io })
     }
@@ -0,0 +1,263 @@
+//! A minimal HTTP/1.1 server side, shared by the metrics endpoint and the admin API.
+//!
+//! Every connection carries a single request, and is closed after the response.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+// the largest HTTP request (head and body) we are willing to read
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+// how long a peer has to send its whole request
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long a connection may take overall, response included
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many connections are answered at the same time, further ones waiting to be accepted.
+pub(crate) const MAX_CONCURRENT_CONNECTIONS: usize = 64;
+
+/// Accepts connections on `listener` until `cancellation_token` is cancelled, answering each one on its own task with `respond`.
+///
+/// At most `max_connections` are answered at a time, and each of them is dropped after [`CONNECTION_TIMEOUT`].
+/// `name` is used for logging.
+pub(crate) async fn accept_connections<F, Fut>(
+    listener: TcpListener,
+    name: &'static str,
+    max_connections: usize,
+    cancellation_token: CancellationToken,
+    respond: F,
+) where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = std::io::Result<()>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(max_connections));
+    loop {
+        // wait for a free slot before accepting, so that excess connections stay in the listen backlog
+        let permit = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            permit = permits.clone().acquire_owned() => permit.expect("the semaphore is never closed"),
+        };
+        tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, _)) => {
+                        let response = respond(stream);
+                        tokio::spawn(async move {
+                            match tokio::time::timeout(CONNECTION_TIMEOUT, response).await {
+                                Ok(Ok(())) => {}
+                                Ok(Err(e)) => debug!("{} failed to respond: {:?}", name, e),
+                                Err(_) => debug!("{} dropped a connection that timed out", name),
+                            }
+                            drop(permit);
+                        });
+                    }
+                    Err(e) => error!("{} failed to accept connection: {:?}", name, e),
+                }
+            }
+        }
+    }
+    debug!("{} task cancelled", name);
+}
+
+/// A parsed HTTP request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    // header names are lowercased
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the value of the header `name` (case-insensitive), if present.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response, ready to be written.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpResponse {
+    pub(crate) status: &'static str,
+    pub(crate) content_type: String,
+    pub(crate) body: String,
+}
+
+impl HttpResponse {
+    pub(crate) fn new(status: &'static str, content_type: &str, body: String) -> Self {
+        Self {
+            status,
+            content_type: content_type.to_string(),
+            body,
+        }
+    }
+
+    pub(crate) fn empty(status: &'static str) -> Self {
+        Self::new(status, "text/plain", String::new())
+    }
+}
+
+/// Reads a single request from `stream`.
+///
+/// Returns `None` if the peer closed the connection early, took longer than [`REQUEST_READ_TIMEOUT`] to send it,
+/// or the request is malformed or too large.
+pub(crate) async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<HttpRequest>> {
+    read_request_within(stream, REQUEST_READ_TIMEOUT).await
+}
+
+async fn read_request_within(
+    stream: &mut TcpStream,
+    timeout: Duration,
+) -> std::io::Result<Option<HttpRequest>> {
+    match tokio::time::timeout(timeout, read_request_unbounded(stream)).await {
+        Ok(result) => result,
+        Err(_) => Ok(None),
+    }
+}
+
+async fn read_request_unbounded(stream: &mut TcpStream) -> std::io::Result<Option<HttpRequest>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    let head_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 || buffer.len() + read > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: buffer[head_end + 4..].to_vec(),
+    };
+
+    let content_length = match request.header("content-length") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(content_length) => content_length,
+            Err(_) => return Ok(None),
+        },
+        None => 0,
+    };
+    if head_end + 4 + content_length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+    while request.body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.body.extend_from_slice(&chunk[..read]);
+    }
+    request.body.truncate(content_length);
+
+    Ok(Some(request))
+}
+
+/// Writes `response` to `stream`, then closes it.
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    response: HttpResponse,
+) -> std::io::Result<()> {
+    let HttpResponse {
+        status,
+        content_type,
+        body,
+    } = response;
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_connections, read_request_within};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn silent_peers_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // connects, and never sends anything
+        let _peer = TcpStream::connect(address).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let request = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_request_within(&mut stream, Duration::from_millis(100)),
+        )
+        .await
+        .expect("the read did not time out");
+        assert!(request.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_are_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let cancellation_token = CancellationToken::new();
+        let active = Arc::new(AtomicUsize::new(0));
+
+        let answering = active.clone();
+        tokio::spawn(accept_connections(
+            listener,
+            "Test server",
+            1,
+            cancellation_token.clone(),
+            move |_stream| {
+                let active = answering.clone();
+                async move {
+                    // holds on to the connection
+                    active.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    Ok(())
+                }
+            },
+        ));
+
+        let _first = TcpStream::connect(address).await.unwrap();
+        let _second = TcpStream::connect(address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(active.load(Ordering::SeqCst), 1);
+
+        cancellation_token.cancel();
+    }
+}
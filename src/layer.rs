@@ -0,0 +1,310 @@
+//! Middleware for [`crate::Sv2Service`] implementers.
+//!
+//! A [`Sv2Layer`] wraps the `handle` method of a service: it receives every event before the service does,
+//! and can inspect, rewrite or reject it, or look at the outcome once the rest of the stack is done with it.
+//!
+//! Layers are added to a service with `with_layer` (e.g.: [`crate::server::service::Sv2ServerService::with_layer`]).
+//! The first layer added is the outermost one:
+//!
+//! ```ignore
+//! let service = Sv2ServerService::new(config, handler, cancellation_token)?
+//!     .with_layer(Sv2LoggingLayer)      // sees the event first, and the outcome last
+//!     .with_layer(Sv2ServerRateLimitLayer::new(100)?)
+//!     .with_layer(Sv2FilterLayer::new(|event| authorize(event)));
+//! ```
+//!
+//! Layers see every event handled by the service: messages from the connections, events from siblings and timers,
+//! and also the events chained by outcomes (e.g.: the `SendMessagesToClient` event behind a `Reply`).
+
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+/// A boxed future, as returned by [`Sv2Layer::handle`].
+pub type Sv2BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Some cross-cutting behavior around the `handle` method of a service with event type `E`,
+/// outcome type `O` and event error type `Err`.
+pub trait Sv2Layer<E, O, Err>: Send + Sync + 'static {
+    /// Handles `event`, usually by passing it (or a rewritten version of it) down the stack with [`Sv2Next::run`].
+    ///
+    /// Returning without calling `next` short-circuits the stack, and the service never sees the event.
+    fn handle<'a>(
+        &'a self,
+        event: E,
+        next: Sv2Next<'a, E, O, Err>,
+    ) -> Sv2BoxFuture<'a, Result<O, Err>>;
+
+    /// A name for the layer, used for debugging.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+// The innermost step of the stack, i.e.: the service itself, without any layer.
+pub(crate) trait Sv2LayerInner<E, O, Err>: Send {
+    fn handle_unlayered(&mut self, event: E) -> Sv2BoxFuture<'_, Result<O, Err>>;
+}
+
+/// The rest of the stack, below the current layer.
+pub struct Sv2Next<'a, E, O, Err> {
+    layers: &'a [Arc<dyn Sv2Layer<E, O, Err>>],
+    inner: &'a mut dyn Sv2LayerInner<E, O, Err>,
+}
+
+impl<'a, E, O, Err> Sv2Next<'a, E, O, Err>
+where
+    E: Send + 'a,
+{
+    /// Passes `event` to the next layer, or to the service if this is the last layer.
+    pub fn run(self, event: E) -> Sv2BoxFuture<'a, Result<O, Err>> {
+        let Sv2Next { layers, inner } = self;
+        match layers.split_first() {
+            Some((layer, layers)) => layer.handle(event, Sv2Next { layers, inner }),
+            None => inner.handle_unlayered(event),
+        }
+    }
+}
+
+/// An ordered stack of [`Sv2Layer`]s, cheaply cloneable into the tasks of a service.
+pub(crate) struct Sv2Layers<E, O, Err> {
+    layers: Arc<Vec<Arc<dyn Sv2Layer<E, O, Err>>>>,
+}
+
+impl<E, O, Err> Sv2Layers<E, O, Err>
+where
+    E: Send,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            layers: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Adds `layer` below every layer already in the stack.
+    pub(crate) fn push(&mut self, layer: impl Sv2Layer<E, O, Err>) {
+        let mut layers = self.layers.as_ref().clone();
+        layers.push(Arc::new(layer));
+        self.layers = Arc::new(layers);
+    }
+
+    /// Runs `event` through the stack, and finally through `inner`.
+    pub(crate) fn run<'a>(
+        &'a self,
+        event: E,
+        inner: &'a mut dyn Sv2LayerInner<E, O, Err>,
+    ) -> Sv2BoxFuture<'a, Result<O, Err>> {
+        Sv2Next {
+            layers: &self.layers,
+            inner,
+        }
+        .run(event)
+    }
+}
+
+impl<E, O, Err> Clone for Sv2Layers<E, O, Err> {
+    fn clone(&self) -> Self {
+        Self {
+            layers: self.layers.clone(),
+        }
+    }
+}
+
+impl<E, O, Err> Debug for Sv2Layers<E, O, Err> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.layers.iter().map(|layer| layer.name()))
+            .finish()
+    }
+}
+
+/// Logs every event, together with its outcome and how long the rest of the stack took to handle it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sv2LoggingLayer;
+
+impl<E, O, Err> Sv2Layer<E, O, Err> for Sv2LoggingLayer
+where
+    E: Debug + Send + 'static,
+    O: Debug + 'static,
+    Err: Debug + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        event: E,
+        next: Sv2Next<'a, E, O, Err>,
+    ) -> Sv2BoxFuture<'a, Result<O, Err>> {
+        Box::pin(async move {
+            debug!("Handling event: {:?}", event);
+            let start = Instant::now();
+            let result = next.run(event).await;
+            debug!("Handled event in {:?}: {:?}", start.elapsed(), result);
+            result
+        })
+    }
+}
+
+/// Rejects events for which a predicate returns an error, e.g.: for authorization.
+///
+/// Accepted events are passed down the stack untouched.
+pub struct Sv2FilterLayer<F> {
+    filter: F,
+}
+
+impl<F> Sv2FilterLayer<F> {
+    pub fn new(filter: F) -> Self {
+        Self { filter }
+    }
+}
+
+impl<F> Debug for Sv2FilterLayer<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sv2FilterLayer").finish_non_exhaustive()
+    }
+}
+
+impl<E, O, Err, F> Sv2Layer<E, O, Err> for Sv2FilterLayer<F>
+where
+    E: Send + 'static,
+    O: 'static,
+    Err: Send + 'static,
+    F: Fn(&E) -> Result<(), Err> + Send + Sync + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        event: E,
+        next: Sv2Next<'a, E, O, Err>,
+    ) -> Sv2BoxFuture<'a, Result<O, Err>> {
+        match (self.filter)(&event) {
+            Ok(()) => next.run(event),
+            Err(e) => Box::pin(async move { Err(e) }),
+        }
+    }
+}
+
+/// Rewrites every event before passing it down the stack.
+pub struct Sv2MapEventLayer<F> {
+    map: F,
+}
+
+impl<F> Sv2MapEventLayer<F> {
+    pub fn new(map: F) -> Self {
+        Self { map }
+    }
+}
+
+impl<F> Debug for Sv2MapEventLayer<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sv2MapEventLayer").finish_non_exhaustive()
+    }
+}
+
+impl<E, O, Err, F> Sv2Layer<E, O, Err> for Sv2MapEventLayer<F>
+where
+    E: Send + 'static,
+    O: 'static,
+    Err: 'static,
+    F: Fn(E) -> E + Send + Sync + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        event: E,
+        next: Sv2Next<'a, E, O, Err>,
+    ) -> Sv2BoxFuture<'a, Result<O, Err>> {
+        next.run((self.map)(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Sv2BoxFuture, Sv2FilterLayer, Sv2Layer, Sv2LayerInner, Sv2Layers, Sv2MapEventLayer, Sv2Next,
+    };
+    use std::sync::{Arc, Mutex};
+
+    // a service that echoes the events it receives
+    struct Echo;
+
+    impl Sv2LayerInner<u32, u32, String> for Echo {
+        fn handle_unlayered(&mut self, event: u32) -> Sv2BoxFuture<'_, Result<u32, String>> {
+            Box::pin(async move { Ok(event) })
+        }
+    }
+
+    // records the order in which layers see the event and the outcome
+    struct Trace {
+        name: &'static str,
+        trace: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Sv2Layer<u32, u32, String> for Trace {
+        fn handle<'a>(
+            &'a self,
+            event: u32,
+            next: Sv2Next<'a, u32, u32, String>,
+        ) -> Sv2BoxFuture<'a, Result<u32, String>> {
+            Box::pin(async move {
+                self.trace
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} event", self.name));
+                let result = next.run(event).await;
+                self.trace
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} outcome", self.name));
+                result
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn layers_run_in_order() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let mut layers: Sv2Layers<u32, u32, String> = Sv2Layers::new();
+        layers.push(Trace {
+            name: "outer",
+            trace: trace.clone(),
+        });
+        layers.push(Sv2MapEventLayer::new(|event: u32| event * 2));
+        layers.push(Trace {
+            name: "inner",
+            trace: trace.clone(),
+        });
+
+        let result = layers.run(21, &mut Echo).await;
+        assert_eq!(result, Ok(42));
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "outer event",
+                "inner event",
+                "inner outcome",
+                "outer outcome"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_layer_short_circuits() {
+        let mut layers: Sv2Layers<u32, u32, String> = Sv2Layers::new();
+        layers.push(Sv2FilterLayer::new(|event: &u32| {
+            if *event % 2 == 0 {
+                Ok(())
+            } else {
+                Err(format!("odd event {}", event))
+            }
+        }));
+
+        assert_eq!(layers.run(2, &mut Echo).await, Ok(2));
+        assert_eq!(
+            layers.run(3, &mut Echo).await,
+            Err("odd event 3".to_string())
+        );
+        // an empty stack goes straight to the service
+        let empty: Sv2Layers<u32, u32, String> = Sv2Layers::new();
+        assert_eq!(empty.run(3, &mut Echo).await, Ok(3));
+    }
+}
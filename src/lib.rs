@@ -16,11 +16,17 @@ use stratum_common::roles_logic_sv2::{
             DeclareMiningJobError, DeclareMiningJobSuccess, ProvideMissingTransactions,
             ProvideMissingTransactionsSuccess, PushSolution,
         },
+        Mining,
         TemplateDistribution::{self, CoinbaseOutputConstraints},
     },
 };
 
+use crate::capture::{Sv2Capture, Sv2CaptureDirection};
 use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::warn;
 
 pub use key_utils;
 pub use stratum_common::roles_logic_sv2;
@@ -42,6 +48,58 @@ pub mod client;
 /// - Configuration options for server behavior
 pub mod server;
 
+/// Communication between services that live within the same application.
+///
+/// This module provides a [`sibling::Sv2SiblingBus`] where any number of server and client services
+/// register by name, and address events to each other.
+pub mod sibling;
+
+/// Middleware around the `handle` method of services.
+///
+/// This module provides the [`layer::Sv2Layer`] trait, plus a few built-in layers,
+/// so that cross-cutting behavior (logging, authorization, rate limits, message rewriting) is kept out of the handlers.
+pub mod layer;
+
+/// Adapters that implement [`tower::Service`] for the server and client services.
+///
+/// Only available with the `tower` feature.
+#[cfg(feature = "tower")]
+pub mod tower_compat;
+
+/// Prometheus metrics for the services, plus an HTTP endpoint to expose them.
+///
+/// Metrics are only recorded with the `metrics` feature.
+pub mod metrics;
+
+// A minimal HTTP server side, for the metrics endpoint and the admin API.
+#[cfg(any(feature = "metrics", feature = "admin"))]
+mod http;
+
+/// One-shot and periodic events, scheduled by handlers and fired by the service.
+///
+/// This module provides [`timer::Sv2ScheduledEvent`], which handlers return on their outcomes,
+/// and [`timer::Sv2TimerHandle`], which cancels a timer.
+pub mod timer;
+
+/// Recording the messages exchanged by the services, and replaying them for deterministic reproduction.
+///
+/// This module provides [`capture::Sv2Capture`], which records messages to disk,
+/// and [`capture::read_capture`], which reads them back.
+pub mod capture;
+
+// `tracing` spans around the events handled by the services.
+mod span;
+
+/// Mock Sv2 roles, so tests run without network access or external binaries.
+///
+/// This module provides [`testing::template_provider::Sv2MockTemplateProvider`], a Template Provider
+/// serving scripted, regtest-shaped templates, plus [`testing::pool::Sv2MockPool`] and [`testing::miner::Sv2MockMiner`],
+/// to test mining handlers end to end.
+///
+/// Only available with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 /// Core service abstraction for Stratum V2 protocol implementations.
 ///
 /// [`Sv2Service`] represents a long-running, stateful service that handles Stratum V2 protocol
@@ -78,28 +136,109 @@ pub type Sv2MessageFrame = StandardEitherFrame<AnyMessage<'static>>;
 pub type StandardSv2MessageFrame = StandardSv2Frame<AnyMessage<'static>>;
 
 /// Sv2 Message IO as [`async_channel`] of [`Sv2MessageFrame`]
+///
+/// Clones share the same channels, traffic counters and capture.
 #[derive(Debug, Clone)]
 pub struct Sv2MessageIo {
     // receiver channel of Sv2 Message Frames
     rx: Receiver<Sv2MessageFrame>,
     // sender channel of Sv2 Message Frames
     tx: Sender<Sv2MessageFrame>,
+    // the address of the other end of the TCP connection, if any
+    peer_addr: Option<SocketAddr>,
+    counters: Arc<Sv2MessageIoCounters>,
+    // where messages are recorded, and the connection id they are recorded under
+    capture: Option<(Sv2Capture, u32)>,
+}
+
+/// A snapshot of the traffic that went through a [`Sv2MessageIo`].
+///
+/// Bytes are counted on Sv2 frames (header and payload), before encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sv2MessageIoStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Default)]
+struct Sv2MessageIoCounters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
 }
 
 impl Sv2MessageIo {
+    pub(crate) fn new(
+        rx: Receiver<Sv2MessageFrame>,
+        tx: Sender<Sv2MessageFrame>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            rx,
+            tx,
+            peer_addr,
+            counters: Arc::new(Sv2MessageIoCounters::default()),
+            capture: None,
+        }
+    }
+
+    /// Records every message sent and received from now on into `capture`, under `connection_id`.
+    pub fn with_capture(mut self, capture: Sv2Capture, connection_id: u32) -> Self {
+        self.capture = Some((capture, connection_id));
+        self
+    }
+
+    /// The address of the other end of the TCP connection, or `None` for in-memory IO.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Returns how many messages and bytes were sent and received so far.
+    pub fn stats(&self) -> Sv2MessageIoStats {
+        Sv2MessageIoStats {
+            messages_sent: self.counters.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.counters.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+
     pub async fn send_message(
         &self,
         message: AnyMessage<'static>,
     ) -> Result<(), Sv2MessageIoError> {
-        let frame: Sv2MessageFrame = message
+        let sv2_frame: StandardSv2MessageFrame = message
             .clone()
             .try_into()
-            .map_err(|_| Sv2MessageIoError::FrameError)
-            .map(|sv2_frame: StandardSv2MessageFrame| sv2_frame.into())?;
+            .map_err(|_| Sv2MessageIoError::FrameError)?;
+        let message_type = sv2_frame.get_header().map(|header| header.msg_type());
+        let frame_length = sv2_frame.encoded_length() as u64;
         self.tx
-            .send(frame)
+            .send(sv2_frame.into())
             .await
             .map_err(|_| Sv2MessageIoError::SendError)?;
+
+        self.counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_sent
+            .fetch_add(frame_length, Ordering::Relaxed);
+
+        if let Some(message_type) = message_type {
+            metrics::record_message_sent(message_type);
+        }
+        match &message {
+            AnyMessage::Mining(Mining::SubmitSharesSuccess(success)) => {
+                metrics::record_shares(success.new_submits_accepted_count as u64, 0)
+            }
+            AnyMessage::Mining(Mining::SubmitSharesError(_)) => metrics::record_shares(0, 1),
+            _ => {}
+        }
+        if let Some((capture, connection_id)) = &self.capture {
+            capture.record(*connection_id, Sv2CaptureDirection::Outbound, &message);
+        }
         Ok(())
     }
 
@@ -112,14 +251,28 @@ impl Sv2MessageIo {
 
         match frame {
             Frame::Sv2(mut frame) => {
+                self.counters
+                    .messages_received
+                    .fetch_add(1, Ordering::Relaxed);
+                self.counters
+                    .bytes_received
+                    .fetch_add(frame.encoded_length() as u64, Ordering::Relaxed);
                 if let Some(header) = frame.get_header() {
                     let message_type = header.msg_type();
+                    metrics::record_message_received(message_type);
                     let mut payload = frame.payload().to_vec();
                     let message: Result<AnyMessage<'_>, _> =
                         (message_type, payload.as_mut_slice()).try_into();
                     match message {
                         Ok(message) => {
                             let message = Self::into_static(message);
+                            if let Some((capture, connection_id)) = &self.capture {
+                                capture.record(
+                                    *connection_id,
+                                    Sv2CaptureDirection::Inbound,
+                                    &message,
+                                );
+                            }
                             Ok(message)
                         }
                         _ => Err(Sv2MessageIoError::FrameError),
@@ -214,9 +367,62 @@ impl Sv2MessageIo {
     }
 }
 
+/// Fraction of a bounded channel's capacity above which a warning is logged.
+pub(crate) const CHANNEL_USAGE_WARNING_THRESHOLD: f64 = 0.8;
+
+/// Logs a warning when a bounded channel goes above [`CHANNEL_USAGE_WARNING_THRESHOLD`] of its capacity.
+///
+/// It only warns on the crossing, and again once the channel drained below the threshold and filled up again,
+/// so that a channel staying full doesn't flood the logs. Unbounded channels (`capacity == None`) are never reported.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelUsageWarning {
+    above_threshold: AtomicBool,
+}
+
+impl ChannelUsageWarning {
+    /// Observes that the channel holds `len` items, returning whether a warning was logged.
+    pub(crate) fn observe(&self, channel_name: &str, len: usize, capacity: Option<usize>) -> bool {
+        let Some(capacity) = capacity else {
+            return false;
+        };
+        let above_threshold = len as f64 >= capacity as f64 * CHANNEL_USAGE_WARNING_THRESHOLD;
+        // swapped, so that concurrent observers of the same crossing only warn once
+        let was_above_threshold = self
+            .above_threshold
+            .swap(above_threshold, Ordering::Relaxed);
+        if above_threshold && !was_above_threshold {
+            warn!(
+                "{} is near capacity: {}/{} queued",
+                channel_name, len, capacity
+            );
+            return true;
+        }
+        false
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Sv2MessageIoError {
     FrameError,
     SendError,
     RecvError,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ChannelUsageWarning;
+
+    #[test]
+    fn channel_usage_warning_only_on_crossing() {
+        let warning = ChannelUsageWarning::default();
+        assert!(!warning.observe("channel", 7, Some(10)));
+        assert!(warning.observe("channel", 8, Some(10)));
+        // staying above the threshold doesn't warn again
+        assert!(!warning.observe("channel", 9, Some(10)));
+        assert!(!warning.observe("channel", 10, Some(10)));
+        // until the channel drains and fills up again
+        assert!(!warning.observe("channel", 2, Some(10)));
+        assert!(warning.observe("channel", 8, Some(10)));
+        assert!(!warning.observe("channel", 1_000, None));
+    }
+}
@@ -0,0 +1,407 @@
+//! Prometheus metrics for the server and client services.
+//!
+//! With the `metrics` cargo feature, the services record:
+//! - `sv2_connected_clients`: clients currently connected to server services
+//! - `sv2_tcp_connections_total{result}`: TCP connections accepted by server services, by handshake result
+//! - `sv2_setup_connection_errors_total{code}`: `SetupConnection.Error`s sent by server services, by error code
+//! - `sv2_messages_received_total{message_type}` and `sv2_messages_sent_total{message_type}`: Sv2 messages, by type
+//! - `sv2_shares_total{result}`: shares accepted and rejected by mining servers
+//! - `sv2_events_total{service, event}`: events handled by the services, by kind
+//! - `sv2_event_errors_total{service, error}`: events that failed to be handled, by error
+//! - `sv2_sibling_queue_depth{sibling}`: events waiting on the inbox of each sibling
+//!
+//! [`serve_metrics`] exposes them on an HTTP `/metrics` endpoint.
+//!
+//! Without the feature, recording is a no-op.
+
+use std::fmt::Debug;
+
+#[cfg(feature = "metrics")]
+pub use self::prometheus_metrics::{serve_metrics, Sv2Metrics};
+
+#[cfg(feature = "metrics")]
+mod prometheus_metrics {
+    use crate::http::{
+        accept_connections, read_request, write_response, HttpResponse, MAX_CONCURRENT_CONNECTIONS,
+    };
+    use prometheus::{Encoder, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+    use std::net::SocketAddr;
+    use std::sync::OnceLock;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::sync::CancellationToken;
+    use tracing::debug;
+
+    static METRICS: OnceLock<Sv2Metrics> = OnceLock::new();
+
+    /// The metrics of every service in the application, registered on their own [`Registry`].
+    #[derive(Debug, Clone)]
+    pub struct Sv2Metrics {
+        registry: Registry,
+        pub(crate) connected_clients: IntGauge,
+        pub(crate) tcp_connections: IntCounterVec,
+        pub(crate) setup_connection_errors: IntCounterVec,
+        pub(crate) messages_received: IntCounterVec,
+        pub(crate) messages_sent: IntCounterVec,
+        pub(crate) shares: IntCounterVec,
+        pub(crate) events: IntCounterVec,
+        pub(crate) event_errors: IntCounterVec,
+        pub(crate) sibling_queue_depth: IntGaugeVec,
+    }
+
+    impl Sv2Metrics {
+        /// The metrics shared by every service in the application.
+        pub fn global() -> &'static Sv2Metrics {
+            METRICS.get_or_init(Sv2Metrics::new)
+        }
+
+        fn new() -> Self {
+            let registry = Registry::new();
+
+            let connected_clients = IntGauge::new(
+                "sv2_connected_clients",
+                "Clients currently connected to server services",
+            )
+            .expect("valid metric");
+            let tcp_connections = IntCounterVec::new(
+                Opts::new(
+                    "sv2_tcp_connections_total",
+                    "TCP connections accepted by server services, by handshake result",
+                ),
+                &["result"],
+            )
+            .expect("valid metric");
+            let setup_connection_errors = IntCounterVec::new(
+                Opts::new(
+                    "sv2_setup_connection_errors_total",
+                    "SetupConnection.Error messages sent by server services, by error code",
+                ),
+                &["code"],
+            )
+            .expect("valid metric");
+            let messages_received = IntCounterVec::new(
+                Opts::new(
+                    "sv2_messages_received_total",
+                    "Sv2 messages received, by type",
+                ),
+                &["message_type"],
+            )
+            .expect("valid metric");
+            let messages_sent = IntCounterVec::new(
+                Opts::new("sv2_messages_sent_total", "Sv2 messages sent, by type"),
+                &["message_type"],
+            )
+            .expect("valid metric");
+            let shares = IntCounterVec::new(
+                Opts::new(
+                    "sv2_shares_total",
+                    "Shares accepted and rejected by mining servers",
+                ),
+                &["result"],
+            )
+            .expect("valid metric");
+            let events = IntCounterVec::new(
+                Opts::new(
+                    "sv2_events_total",
+                    "Events handled by the services, by kind",
+                ),
+                &["service", "event"],
+            )
+            .expect("valid metric");
+            let event_errors = IntCounterVec::new(
+                Opts::new(
+                    "sv2_event_errors_total",
+                    "Events that failed to be handled by the services, by error",
+                ),
+                &["service", "error"],
+            )
+            .expect("valid metric");
+            let sibling_queue_depth = IntGaugeVec::new(
+                Opts::new(
+                    "sv2_sibling_queue_depth",
+                    "Events waiting on the inbox of each sibling service",
+                ),
+                &["sibling"],
+            )
+            .expect("valid metric");
+
+            registry
+                .register(Box::new(connected_clients.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(tcp_connections.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(setup_connection_errors.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(messages_received.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(messages_sent.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(shares.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(events.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(event_errors.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(sibling_queue_depth.clone()))
+                .expect("metric registered once");
+
+            Self {
+                registry,
+                connected_clients,
+                tcp_connections,
+                setup_connection_errors,
+                messages_received,
+                messages_sent,
+                shares,
+                events,
+                event_errors,
+                sibling_queue_depth,
+            }
+        }
+
+        /// The registry holding every metric, e.g.: to register application-specific metrics next to them.
+        pub fn registry(&self) -> &Registry {
+            &self.registry
+        }
+
+        /// Renders every metric in the Prometheus text format.
+        pub fn render(&self) -> String {
+            let mut buffer = Vec::new();
+            TextEncoder::new()
+                .encode(&self.registry.gather(), &mut buffer)
+                .expect("text encoding never fails");
+            String::from_utf8(buffer).expect("text encoding is valid utf8")
+        }
+    }
+
+    /// Serves [`Sv2Metrics::global`] on `http://<listen_address>/metrics`, until `cancellation_token` is cancelled.
+    ///
+    /// Fails if `listen_address` can't be bound.
+    pub async fn serve_metrics(
+        listen_address: SocketAddr,
+        cancellation_token: CancellationToken,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(listen_address).await?;
+        debug!("Metrics endpoint bound to {}", listen_address);
+
+        tokio::spawn(accept_connections(
+            listener,
+            "Metrics endpoint",
+            MAX_CONCURRENT_CONNECTIONS,
+            cancellation_token,
+            respond,
+        ));
+
+        Ok(())
+    }
+
+    // Answers a single HTTP request, then closes the connection.
+    async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+        let Some(request) = read_request(&mut stream).await? else {
+            return Ok(());
+        };
+
+        let response = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => HttpResponse::new(
+                "200 OK",
+                TextEncoder::new().format_type(),
+                Sv2Metrics::global().render(),
+            ),
+            ("GET", _) => HttpResponse::empty("404 Not Found"),
+            _ => HttpResponse::empty("405 Method Not Allowed"),
+        };
+
+        write_response(&mut stream, response).await
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics() -> &'static Sv2Metrics {
+    Sv2Metrics::global()
+}
+
+// The name of an enum variant, out of its Debug representation.
+#[cfg(feature = "metrics")]
+fn variant_name(value: &impl Debug) -> String {
+    let debug = format!("{:?}", value);
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub(crate) fn record_client_connected() {
+    #[cfg(feature = "metrics")]
+    metrics().connected_clients.inc();
+}
+
+pub(crate) fn record_clients_disconnected(count: usize) {
+    #[cfg(feature = "metrics")]
+    metrics().connected_clients.sub(count as i64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = count;
+}
+
+pub(crate) fn record_tcp_connection(handshake_ok: bool) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .tcp_connections
+        .with_label_values(&[if handshake_ok {
+            "ok"
+        } else {
+            "handshake_failed"
+        }])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = handshake_ok;
+}
+
+pub(crate) fn record_setup_connection_error(code: &str) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .setup_connection_errors
+        .with_label_values(&[code])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = code;
+}
+
+pub(crate) fn record_message_received(message_type: u8) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .messages_received
+        .with_label_values(&[&format!("0x{:02x}", message_type)])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = message_type;
+}
+
+pub(crate) fn record_message_sent(message_type: u8) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .messages_sent
+        .with_label_values(&[&format!("0x{:02x}", message_type)])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = message_type;
+}
+
+pub(crate) fn record_shares(accepted: u64, rejected: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics()
+            .shares
+            .with_label_values(&["accepted"])
+            .inc_by(accepted);
+        metrics()
+            .shares
+            .with_label_values(&["rejected"])
+            .inc_by(rejected);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (accepted, rejected);
+}
+
+pub(crate) fn record_event(service: &str, event: &str) {
+    #[cfg(feature = "metrics")]
+    metrics().events.with_label_values(&[service, event]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (service, event);
+}
+
+pub(crate) fn record_event_error(service: &str, error: &impl Debug) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .event_errors
+        .with_label_values(&[service, &variant_name(error)])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (service, error);
+}
+
+pub(crate) fn record_sibling_queue_depth(sibling: &str, depth: usize) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .sibling_queue_depth
+        .with_label_values(&[sibling])
+        .set(depth as i64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (sibling, depth);
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::{serve_metrics, variant_name, Sv2Metrics};
+    use std::net::TcpListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_util::sync::CancellationToken;
+
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    enum SomeError {
+        Unit,
+        Tuple(String),
+        Struct { code: u32 },
+    }
+
+    #[test]
+    fn variant_names() {
+        assert_eq!(variant_name(&SomeError::Unit), "Unit");
+        assert_eq!(variant_name(&SomeError::Tuple("x".to_string())), "Tuple");
+        assert_eq!(variant_name(&SomeError::Struct { code: 1 }), "Struct");
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_serves_recorded_metrics() {
+        super::record_setup_connection_error("unsupported-protocol");
+        super::record_message_sent(0x01);
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = ([127, 0, 0, 1], port).into();
+        let cancellation_token = CancellationToken::new();
+        serve_metrics(address, cancellation_token.clone())
+            .await
+            .unwrap();
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(
+            response.contains("sv2_setup_connection_errors_total{code=\"unsupported-protocol\"}")
+        );
+        assert!(response.contains("sv2_messages_sent_total{message_type=\"0x01\"}"));
+        assert!(Sv2Metrics::global()
+            .render()
+            .contains("sv2_connected_clients"));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /other HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        cancellation_token.cancel();
+    }
+}
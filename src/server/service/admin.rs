@@ -0,0 +1,516 @@
+//! An HTTP/JSON admin API for a running [`Sv2ServerService`].
+//!
+//! Only available with the `admin` feature. The API is meant to be bound to a local address
+//! (e.g.: `127.0.0.1`), so that operators can inspect and control the service while it is running:
+//!
+//! | Method | Path                         | Role      | Description                                         |
+//! |--------|------------------------------|-----------|-----------------------------------------------------|
+//! | GET    | `/status`                    | read-only | client count, and whether new clients are accepted  |
+//! | GET    | `/clients`                   | read-only | every connected client                              |
+//! | GET    | `/clients/{id}`              | read-only | a single client                                     |
+//! | GET    | `/audit`                     | read-only | the audit log of mutating actions                   |
+//! | POST   | `/clients/{id}/disconnect`   | operator  | kicks the client                                    |
+//! | POST   | `/clients/{id}/reconnect`    | operator  | sends `Reconnect`, body `{"new_host": "..", "new_port": 1234}` |
+//! | POST   | `/clients/pause`             | operator  | stops accepting new clients                         |
+//! | POST   | `/clients/resume`            | operator  | accepts new clients again                           |
+//!
+//! Every request must carry an `Authorization: Bearer <token>` header, with one of the tokens of
+//! [`Sv2AdminConfig`]. Every mutating request made with a valid token is recorded on the audit log
+//! (whether it was allowed or not), and also logged under the `sv2_admin_audit` tracing target.
+//!
+//! Channels are owned by the subprotocol handlers, so they are not listed here.
+
+use crate::http::{
+    accept_connections, read_request, write_response, HttpRequest, HttpResponse,
+    MAX_CONCURRENT_CONNECTIONS,
+};
+use crate::server::service::client::Sv2ServerServiceClient;
+use crate::server::service::connection::Sv2ConnectionClient;
+use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
+use crate::server::service::Sv2ServerService;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::Str0255;
+use stratum_common::roles_logic_sv2::common_messages_sv2::Reconnect;
+use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+/// How many entries the audit log keeps, oldest entries are dropped first.
+const AUDIT_LOG_CAPACITY: usize = 1024;
+
+/// What a token is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Sv2AdminRole {
+    /// Can only inspect the service.
+    ReadOnly,
+    /// Can also kick clients, send `Reconnect` and pause new connections.
+    Operator,
+}
+
+/// A token accepted by the admin API.
+///
+/// Its `Debug` output redacts `token`, and so does that of [`Sv2AdminConfig`] and [`Sv2AdminApi`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sv2AdminToken {
+    /// Identifies whoever holds the token on the audit log, so the token itself is never logged.
+    pub name: String,
+    pub token: String,
+    pub role: Sv2AdminRole,
+}
+
+impl std::fmt::Debug for Sv2AdminToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sv2AdminToken")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+/// Configuration for the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sv2AdminConfig {
+    pub listen_address: SocketAddr,
+    pub tokens: Vec<Sv2AdminToken>,
+}
+
+/// A mutating request made to the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sv2AdminAuditEntry {
+    /// When the request was made (as seconds since UNIX_EPOCH)
+    pub timestamp: u64,
+    /// The name of the token used
+    pub token_name: String,
+    /// The method and path of the request, e.g.: `POST /clients/1/disconnect`
+    pub action: String,
+    /// The HTTP status of the response
+    pub status: String,
+}
+
+/// The admin API of a [`Sv2ServerService`].
+///
+/// Clones share the same audit log.
+#[derive(Debug, Clone)]
+pub struct Sv2AdminApi<M>
+where
+    M: Sv2MiningServerHandler + Clone + Send + Sync + 'static,
+{
+    service: Sv2ServerService<M>,
+    config: Sv2AdminConfig,
+    audit_log: Arc<Mutex<VecDeque<Sv2AdminAuditEntry>>>,
+}
+
+impl<M> Sv2AdminApi<M>
+where
+    M: Sv2MiningServerHandler + Clone + Send + Sync + 'static,
+{
+    pub fn new(service: Sv2ServerService<M>, config: Sv2AdminConfig) -> Self {
+        Self {
+            service,
+            config,
+            audit_log: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Returns the audit log, oldest entry first.
+    pub fn audit_log(&self) -> Vec<Sv2AdminAuditEntry> {
+        self.audit_log
+            .lock()
+            .expect("audit log lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Serves the API on `http://<listen_address>`, until the service is shut down.
+    ///
+    /// Fails if `listen_address` can't be bound.
+    pub async fn serve(self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.config.listen_address).await?;
+        debug!("Admin API bound to {}", self.config.listen_address);
+
+        let cancellation_token = self.service.cancellation_token.clone();
+        tokio::spawn(accept_connections(
+            listener,
+            "Admin API",
+            MAX_CONCURRENT_CONNECTIONS,
+            cancellation_token,
+            move |stream| self.clone().respond_to(stream),
+        ));
+
+        Ok(())
+    }
+
+    // Answers a single HTTP request, then closes the connection.
+    async fn respond_to(self, mut stream: TcpStream) -> std::io::Result<()> {
+        let Some(request) = read_request(&mut stream).await? else {
+            return Ok(());
+        };
+        let response = self.respond(request).await;
+        write_response(&mut stream, response).await
+    }
+
+    async fn respond(&self, request: HttpRequest) -> HttpResponse {
+        let token = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| {
+                self.config.tokens.iter().find(|admin_token| {
+                    constant_time_eq(admin_token.token.as_bytes(), token.as_bytes())
+                })
+            });
+        let Some(token) = token else {
+            return error_response("401 Unauthorized", "missing or unknown token");
+        };
+
+        if request.method == "GET" {
+            return self.query(&request.path).await;
+        }
+        if request.method != "POST" {
+            return error_response("405 Method Not Allowed", "only GET and POST are allowed");
+        }
+
+        let response = if token.role == Sv2AdminRole::Operator {
+            self.mutate(&request).await
+        } else {
+            error_response("403 Forbidden", "operator role required")
+        };
+        self.audit(token, &request, &response);
+        response
+    }
+
+    async fn query(&self, path: &str) -> HttpResponse {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["status"] => json_response(
+                "200 OK",
+                json!({
+                    "clients": self.service.get_client_count(),
+                    "accepting_new_clients": self.service.is_accepting_new_clients(),
+                }),
+            ),
+            ["clients"] => {
+                let mut clients_json: Vec<Value> = self
+                    .service
+                    .clients
+                    .iter()
+                    .map(|entry| client_json(*entry.key(), entry.value()))
+                    .collect();
+                clients_json.sort_by_key(|client| client["id"].as_u64());
+                json_response("200 OK", Value::Array(clients_json))
+            }
+            ["clients", client_id] => match self.find_client(client_id) {
+                Some((client_id, client)) => {
+                    json_response("200 OK", client_json(client_id, &client))
+                }
+                None => error_response("404 Not Found", "client not found"),
+            },
+            ["audit"] => json_response("200 OK", json!(self.audit_log())),
+            _ => error_response("404 Not Found", "unknown path"),
+        }
+    }
+
+    async fn mutate(&self, request: &HttpRequest) -> HttpResponse {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["clients", "pause"] => {
+                self.service.pause_new_clients();
+                json_response("200 OK", json!({ "accepting_new_clients": false }))
+            }
+            ["clients", "resume"] => {
+                self.service.resume_new_clients();
+                json_response("200 OK", json!({ "accepting_new_clients": true }))
+            }
+            ["clients", client_id, "disconnect"] => {
+                let Some((client_id, _)) = self.find_client(client_id) else {
+                    return error_response("404 Not Found", "client not found");
+                };
+                self.service.clone().remove_client(client_id).await;
+                json_response("200 OK", json!({ "disconnected": client_id }))
+            }
+            ["clients", client_id, "reconnect"] => {
+                let Some((client_id, client)) = self.find_client(client_id) else {
+                    return error_response("404 Not Found", "client not found");
+                };
+                let Ok(body) = serde_json::from_slice::<ReconnectBody>(&request.body) else {
+                    return error_response("400 Bad Request", "expected new_host and new_port");
+                };
+                let Ok(new_host) = Str0255::try_from(body.new_host) else {
+                    return error_response("400 Bad Request", "new_host is too long");
+                };
+                let reconnect = AnyMessage::Common(CommonMessages::Reconnect(Reconnect {
+                    new_host,
+                    new_port: body.new_port,
+                }));
+                match client.io.send_message(reconnect).await {
+                    Ok(()) => json_response("200 OK", json!({ "reconnected": client_id })),
+                    Err(e) => {
+                        error!("Failed to send Reconnect to client {}: {:?}", client_id, e);
+                        error_response("500 Internal Server Error", "failed to send Reconnect")
+                    }
+                }
+            }
+            _ => error_response("404 Not Found", "unknown path"),
+        }
+    }
+
+    fn find_client(&self, client_id: &str) -> Option<(u32, Arc<Sv2ServerServiceClient>)> {
+        let client_id = client_id.parse::<u32>().ok()?;
+        self.service
+            .get_client(client_id)
+            .map(|client| (client_id, client))
+    }
+
+    fn audit(&self, token: &Sv2AdminToken, request: &HttpRequest, response: &HttpResponse) {
+        let entry = Sv2AdminAuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system clock should never go backwards")
+                .as_secs(),
+            token_name: token.name.clone(),
+            action: format!("{} {}", request.method, request.path),
+            status: response.status.to_string(),
+        };
+        info!(
+            target: "sv2_admin_audit",
+            "{} by {}: {}", entry.action, entry.token_name, entry.status
+        );
+
+        let mut audit_log = self.audit_log.lock().expect("audit log lock poisoned");
+        if audit_log.len() == AUDIT_LOG_CAPACITY {
+            audit_log.pop_front();
+        }
+        audit_log.push_back(entry);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReconnectBody {
+    new_host: String,
+    new_port: u16,
+}
+
+// never waits on the connection lock, a client being set up is listed without its connection
+fn client_json(client_id: u32, client: &Sv2ServerServiceClient) -> Value {
+    let connection = client
+        .connection
+        .try_read()
+        .ok()
+        .and_then(|connection| connection.as_ref().map(connection_json))
+        .unwrap_or(Value::Null);
+    let stats = client.stats();
+    json!({
+        "id": client_id,
+        "peer_addr": client.peer_addr().map(|peer_addr| peer_addr.to_string()),
+        "connected_at": client.connected_at,
+        "last_message_time": client.last_message_time.load(Ordering::Relaxed),
+        "messages_sent": stats.messages_sent,
+        "messages_received": stats.messages_received,
+        "bytes_sent": stats.bytes_sent,
+        "bytes_received": stats.bytes_received,
+        "connection": connection,
+    })
+}
+
+// compares every byte, so that the time taken does not tell how much of a token was guessed right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn connection_json(connection: &Sv2ConnectionClient) -> Value {
+    let string = |s: &Str0255<'static>| String::from_utf8_lossy(s.inner_as_ref()).to_string();
+    json!({
+        "protocol": format!("{:?}", connection.protocol),
+        "min_version": connection.min_version,
+        "max_version": connection.max_version,
+        "flags": connection.flags,
+        "endpoint_host": string(&connection.endpoint_host),
+        "endpoint_port": connection.endpoint_port,
+        "vendor": string(&connection.vendor),
+        "hardware_version": string(&connection.hardware_version),
+        "firmware": string(&connection.firmware),
+        "device_id": string(&connection.device_id),
+    })
+}
+
+fn json_response(status: &'static str, body: Value) -> HttpResponse {
+    HttpResponse::new(status, "application/json", body.to_string())
+}
+
+fn error_response(status: &'static str, error: &str) -> HttpResponse {
+    json_response(status, json!({ "error": error }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, Sv2AdminApi, Sv2AdminConfig, Sv2AdminRole, Sv2AdminToken};
+    use crate::http::HttpRequest;
+    use crate::server::service::subprotocols::mining::handler::NullSv2MiningServerHandler;
+    use crate::server::service::Sv2ServerService;
+    use crate::testing::fixtures;
+    use serde_json::Value;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
+    use tokio_util::sync::CancellationToken;
+
+    fn admin_api() -> (
+        Sv2AdminApi<NullSv2MiningServerHandler>,
+        Sv2ServerService<NullSv2MiningServerHandler>,
+    ) {
+        let service = fixtures::null_server_service(CancellationToken::new());
+        let admin_config = Sv2AdminConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+            tokens: vec![
+                Sv2AdminToken {
+                    name: "dashboard".to_string(),
+                    token: "read-token".to_string(),
+                    role: Sv2AdminRole::ReadOnly,
+                },
+                Sv2AdminToken {
+                    name: "alice".to_string(),
+                    token: "operator-token".to_string(),
+                    role: Sv2AdminRole::Operator,
+                },
+            ],
+        };
+
+        (Sv2AdminApi::new(service.clone(), admin_config), service)
+    }
+
+    fn request(method: &str, path: &str, token: Option<&str>, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: token
+                .map(|token| vec![("authorization".to_string(), format!("Bearer {}", token))])
+                .unwrap_or_default(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_api_enforces_roles_and_audits_mutations() {
+        let (api, _service) = admin_api();
+
+        let response = api.respond(request("GET", "/status", None, "")).await;
+        assert_eq!(response.status, "401 Unauthorized");
+        let response = api
+            .respond(request("GET", "/status", Some("wrong-token"), ""))
+            .await;
+        assert_eq!(response.status, "401 Unauthorized");
+
+        let response = api
+            .respond(request("GET", "/status", Some("read-token"), ""))
+            .await;
+        assert_eq!(response.status, "200 OK");
+
+        let response = api
+            .respond(request("POST", "/clients/pause", Some("read-token"), ""))
+            .await;
+        assert_eq!(response.status, "403 Forbidden");
+
+        let response = api
+            .respond(request(
+                "POST",
+                "/clients/pause",
+                Some("operator-token"),
+                "",
+            ))
+            .await;
+        assert_eq!(response.status, "200 OK");
+
+        let audit_log = api.audit_log();
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].token_name, "dashboard");
+        assert_eq!(audit_log[0].status, "403 Forbidden");
+        assert_eq!(audit_log[1].token_name, "alice");
+        assert_eq!(audit_log[1].action, "POST /clients/pause");
+        assert_eq!(audit_log[1].status, "200 OK");
+    }
+
+    #[tokio::test]
+    async fn admin_api_lists_kicks_and_reconnects_clients() {
+        let (api, mut service) = admin_api();
+        let (client_1, _client_rx_1) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        let (client_2, client_rx_2) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        if let Some(connection) = client_1.connection.write().await.as_mut() {
+            connection.vendor = "bitmain".to_string().try_into().unwrap();
+        }
+        service.add_client(1, client_1);
+        service.add_client(2, client_2);
+
+        let response = api
+            .respond(request("GET", "/clients", Some("read-token"), ""))
+            .await;
+        assert_eq!(response.status, "200 OK");
+        let clients: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(clients.as_array().unwrap().len(), 2);
+        assert_eq!(clients[0]["id"], 1);
+        assert_eq!(clients[0]["connection"]["vendor"], "bitmain");
+        assert_eq!(clients[0]["connection"]["protocol"], "MiningProtocol");
+
+        let response = api
+            .respond(request(
+                "POST",
+                "/clients/2/reconnect",
+                Some("operator-token"),
+                r#"{"new_host": "pool.example.com", "new_port": 3333}"#,
+            ))
+            .await;
+        assert_eq!(response.status, "200 OK");
+        assert!(client_rx_2.try_recv().is_ok());
+
+        let response = api
+            .respond(request(
+                "POST",
+                "/clients/1/disconnect",
+                Some("operator-token"),
+                "",
+            ))
+            .await;
+        assert_eq!(response.status, "200 OK");
+        assert!(service.get_client(1).is_none());
+
+        let response = api
+            .respond(request("GET", "/clients/1", Some("read-token"), ""))
+            .await;
+        assert_eq!(response.status, "404 Not Found");
+        let response = api
+            .respond(request("GET", "/clients/2", Some("read-token"), ""))
+            .await;
+        assert_eq!(response.status, "200 OK");
+    }
+
+    #[test]
+    fn tokens_are_compared_in_full() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn tokens_are_redacted_from_debug_output() {
+        let config = Sv2AdminConfig {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            tokens: vec![Sv2AdminToken {
+                name: "ops".to_string(),
+                token: "super-secret".to_string(),
+                role: Sv2AdminRole::Operator,
+            }],
+        };
+        let debug = format!("{config:?}");
+        assert!(debug.contains("ops"));
+        assert!(!debug.contains("super-secret"));
+    }
+}
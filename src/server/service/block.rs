@@ -0,0 +1,211 @@
+//! Assembling found blocks, as a safety net next to `SubmitSolution`.
+//!
+//! `SubmitSolution` only reaches the network if the connection to the Template Provider is up when the block is found.
+//! [`assemble_block`] rebuilds the full block from a [`Sv2FoundBlock`] (header, coinbase and the transactions of
+//! `RequestTransactionData.Success`), so that [`crate::server::service::solution::Sv2SolutionPipeline`] can also:
+//! - write it to disk as hex, see [`crate::server::service::solution::Sv2SolutionPipeline::with_block_dir`]
+//! - hand it to a [`Sv2BlockSubmitter`] (e.g.: a `submitblock` RPC client), see
+//!   [`crate::server::service::solution::Sv2SolutionPipeline::with_block_submitter`]
+//!
+//! The transactions are only known if the pipeline shares its [`crate::client::service::subprotocols::template_distribution::store::Sv2TemplateStore`]
+//! with the Template Distribution client handler, see [`crate::server::service::solution::Sv2SolutionPipeline::with_template_store`].
+
+use crate::client::service::subprotocols::template_distribution::store::Sv2TemplateTransactionData;
+use crate::layer::Sv2BoxFuture;
+use crate::server::service::solution::Sv2FoundBlock;
+use crate::server::service::subprotocols::mining::coinbase::merkle_root_from_path;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, BlockHash, CompactTarget, Transaction, TxMerkleNode};
+
+/// Why a [`Sv2FoundBlock`] could not be assembled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2BlockAssemblyError {
+    /// The template the block was mined on is unknown.
+    MissingTemplate,
+    /// The `SetNewPrevHash` of the template is unknown.
+    MissingPrevHash,
+    /// The template has transactions other than the coinbase, but its transaction data was not received.
+    MissingTransactionData,
+    InvalidCoinbase(String),
+    InvalidTransaction(String),
+}
+
+/// Rebuilds the full block from a [`Sv2FoundBlock`].
+///
+/// The merkle root is recomputed from the coinbase and the merkle path of the template,
+/// so the result is only as good as the solution: it is not checked against the network target.
+pub fn assemble_block(found_block: &Sv2FoundBlock) -> Result<Block, Sv2BlockAssemblyError> {
+    let stored = found_block
+        .template
+        .as_ref()
+        .ok_or(Sv2BlockAssemblyError::MissingTemplate)?;
+    let prev_hash = stored
+        .prev_hash
+        .as_ref()
+        .ok_or(Sv2BlockAssemblyError::MissingPrevHash)?;
+
+    let coinbase: Transaction = deserialize(&found_block.solution.coinbase_tx)
+        .map_err(|e| Sv2BlockAssemblyError::InvalidCoinbase(e.to_string()))?;
+
+    let merkle_path = stored.template.merkle_path.to_vec();
+    let transactions = match &stored.transaction_data {
+        Sv2TemplateTransactionData::Received(transaction_data) => transaction_data
+            .transaction_list
+            .to_vec()
+            .iter()
+            .map(|tx| {
+                deserialize::<Transaction>(tx)
+                    .map_err(|e| Sv2BlockAssemblyError::InvalidTransaction(e.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        // a template with an empty merkle path is made of the coinbase alone
+        _ if merkle_path.is_empty() => Vec::new(),
+        _ => return Err(Sv2BlockAssemblyError::MissingTransactionData),
+    };
+
+    let merkle_root = merkle_root_from_path(coinbase.compute_txid().to_byte_array(), &merkle_path);
+
+    let prev_blockhash: [u8; 32] = prev_hash
+        .prev_hash
+        .inner_as_ref()
+        .try_into()
+        .map_err(|_| Sv2BlockAssemblyError::MissingPrevHash)?;
+
+    let header = Header {
+        version: Version::from_consensus(found_block.solution.version as i32),
+        prev_blockhash: BlockHash::from_byte_array(prev_blockhash),
+        merkle_root: TxMerkleNode::from_byte_array(merkle_root),
+        time: found_block.solution.header_timestamp,
+        bits: CompactTarget::from_consensus(prev_hash.n_bits),
+        nonce: found_block.solution.header_nonce,
+    };
+
+    let mut txdata = Vec::with_capacity(transactions.len() + 1);
+    txdata.push(coinbase);
+    txdata.extend(transactions);
+
+    Ok(Block { header, txdata })
+}
+
+/// Somewhere found blocks can be sent to, besides the Template Provider: e.g.: the `submitblock` RPC of a node.
+///
+/// Returns a boxed future, so that a solution pipeline can hold any submitter behind an `Arc`.
+pub trait Sv2BlockSubmitter: Send + Sync + 'static {
+    /// Submits `block`, returning a description of the failure, if any.
+    fn submit_block(&self, block: Block) -> Sv2BoxFuture<'_, Result<(), String>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble_block, Sv2BlockAssemblyError};
+    use crate::client::service::subprotocols::template_distribution::store::{
+        Sv2StoredTemplate, Sv2TemplateTransactionData,
+    };
+    use crate::server::service::solution::{Sv2BlockSolution, Sv2FoundBlock};
+    use crate::testing::fixtures::{new_template, set_new_prev_hash};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::Hash;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+    use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::{
+        Seq0255, Seq064K, B016M, B064K, U256,
+    };
+    use stratum_common::roles_logic_sv2::template_distribution_sv2::{
+        NewTemplate, RequestTransactionDataSuccess, SetNewPrevHash,
+    };
+
+    fn transaction(script_sig: Vec<u8>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(script_sig),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(5_000_000_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    fn found_block(
+        coinbase: &Transaction,
+        merkle_path: Vec<U256<'static>>,
+        transaction_data: Sv2TemplateTransactionData,
+    ) -> Sv2FoundBlock {
+        Sv2FoundBlock {
+            solution: Sv2BlockSolution {
+                client_id: 1,
+                channel_id: 1,
+                template_id: 1,
+                version: 0x20000000,
+                header_timestamp: 1_296_688_603,
+                header_nonce: 42,
+                coinbase_tx: serialize(coinbase),
+            },
+            template: Some(Sv2StoredTemplate {
+                template: NewTemplate {
+                    merkle_path: Seq0255::new(merkle_path).unwrap(),
+                    ..new_template(1, false)
+                },
+                prev_hash: Some(SetNewPrevHash {
+                    prev_hash: U256::Owned(vec![7; 32]),
+                    ..set_new_prev_hash(1)
+                }),
+                transaction_data,
+            }),
+        }
+    }
+
+    #[test]
+    fn block_with_coinbase_only() {
+        let coinbase = transaction(vec![0x51]);
+        let found_block = found_block(&coinbase, vec![], Sv2TemplateTransactionData::NotRequested);
+
+        let block = assemble_block(&found_block).unwrap();
+        assert_eq!(block.txdata, vec![coinbase.clone()]);
+        assert!(block.check_merkle_root());
+        assert_eq!(block.header.nonce, 42);
+        assert_eq!(block.header.time, 1_296_688_603);
+        assert_eq!(block.header.bits.to_consensus(), 0x207fffff);
+        assert_eq!(block.header.prev_blockhash.to_byte_array(), [7; 32]);
+    }
+
+    #[test]
+    fn block_with_transactions() {
+        let coinbase = transaction(vec![0x51]);
+        let tx = transaction(vec![0x52]);
+        let merkle_path = vec![U256::Owned(tx.compute_txid().to_byte_array().to_vec())];
+
+        // without the transaction data, the block can't be assembled
+        let without_transactions = found_block(
+            &coinbase,
+            merkle_path.clone(),
+            Sv2TemplateTransactionData::Pending,
+        );
+        assert_eq!(
+            assemble_block(&without_transactions),
+            Err(Sv2BlockAssemblyError::MissingTransactionData)
+        );
+
+        let transaction_list: Vec<B016M<'static>> = vec![serialize(&tx).try_into().unwrap()];
+        let with_transactions = found_block(
+            &coinbase,
+            merkle_path,
+            Sv2TemplateTransactionData::Received(RequestTransactionDataSuccess {
+                template_id: 1,
+                excess_data: B064K::Owned(vec![]),
+                transaction_list: Seq064K::new(transaction_list).unwrap(),
+            }),
+        );
+        let block = assemble_block(&with_transactions).unwrap();
+        assert_eq!(block.txdata, vec![coinbase, tx]);
+        assert!(block.check_merkle_root());
+    }
+}
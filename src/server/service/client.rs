@@ -1,7 +1,11 @@
 use crate::server::service::connection::Sv2ConnectionClient;
-use crate::Sv2MessageIo;
+use crate::{Sv2MessageIo, Sv2MessageIoError, Sv2MessageIoStats};
+use std::fmt;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
 use stratum_common::roles_logic_sv2::parsers::AnyMessage;
 use tokio::sync::RwLock;
 
@@ -16,6 +20,8 @@ pub struct Sv2ServerServiceClient {
     pub connection: RwLock<Option<Sv2ConnectionClient>>,
     /// The time of the last message received from this client (as seconds since UNIX_EPOCH)
     pub last_message_time: AtomicU64,
+    /// The time this client connected (as seconds since UNIX_EPOCH)
+    pub connected_at: u64,
 }
 
 impl Sv2ServerServiceClient {
@@ -30,9 +36,20 @@ impl Sv2ServerServiceClient {
             io,
             connection: RwLock::new(None),
             last_message_time: AtomicU64::new(current_time),
+            connected_at: current_time,
         }
     }
 
+    /// The remote address of this client, or `None` if it is not connected over TCP.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    /// Returns how many messages and bytes were exchanged with this client so far.
+    pub fn stats(&self) -> Sv2MessageIoStats {
+        self.io.stats()
+    }
+
     /// Updates the last_message_time to the current time
     pub fn update_last_message_time(&self) {
         let current_time = SystemTime::now()
@@ -60,3 +77,55 @@ pub struct Sv2MessagesToClient<'a> {
     pub client_id: u32,
     pub messages: Vec<AnyMessage<'a>>,
 }
+
+/// An ordered sequence of Sv2 messages, to be delivered to every client matching `filter`.
+#[derive(Debug, Clone)]
+pub struct Sv2MessagesToClients<'a> {
+    pub filter: Sv2ClientFilter,
+    pub messages: Vec<AnyMessage<'a>>,
+}
+
+/// Selects clients by their connection details.
+///
+/// Only clients that completed SetupConnection are ever selected.
+#[derive(Clone)]
+pub enum Sv2ClientFilter {
+    /// Every client.
+    All,
+    /// Clients that set up a connection under this protocol.
+    Protocol(Protocol),
+    /// Clients whose connection satisfies this predicate.
+    Predicate(Arc<dyn Fn(&Sv2ConnectionClient) -> bool + Send + Sync>),
+}
+
+impl Sv2ClientFilter {
+    /// Returns whether a client with this connection is selected.
+    pub fn matches(&self, connection: &Sv2ConnectionClient) -> bool {
+        match self {
+            Sv2ClientFilter::All => true,
+            Sv2ClientFilter::Protocol(protocol) => connection.protocol == *protocol,
+            Sv2ClientFilter::Predicate(predicate) => predicate(connection),
+        }
+    }
+}
+
+impl fmt::Debug for Sv2ClientFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sv2ClientFilter::All => write!(f, "All"),
+            Sv2ClientFilter::Protocol(protocol) => write!(f, "Protocol({protocol:?})"),
+            Sv2ClientFilter::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+
+/// The result of broadcasting messages to many clients.
+#[derive(Debug, Clone, Default)]
+pub struct Sv2BroadcastReport {
+    /// Clients that received every message.
+    pub delivered: Vec<u32>,
+    /// Clients that disconnected while the broadcast was ongoing.
+    pub skipped: Vec<u32>,
+    /// Clients that are still connected, but failed to receive some message.
+    pub failed: Vec<(u32, Sv2MessageIoError)>,
+}
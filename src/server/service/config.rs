@@ -12,6 +12,11 @@ pub struct Sv2ServerServiceConfig {
     pub max_supported_version: u16,
     /// Time limit that a connection is allowed to remain inactive before being dropped from memory (in seconds).
     pub inactivity_limit: u64,
+    /// Whether a client should be disconnected when it violates the protocol it negotiated,
+    /// e.g.: sending messages before SetupConnection, or messages of a subprotocol it did not set up.
+    ///
+    /// If `false`, the offending messages are simply rejected.
+    pub disconnect_on_protocol_violation: bool,
     /// The configuration for the TCP server.
     pub tcp_config: Sv2ServerTcpConfig,
     pub mining_config: Option<Sv2ServerServiceMiningConfig>,
@@ -2,8 +2,10 @@ use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
 use stratum_common::roles_logic_sv2::parsers::AnyMessage;
 
 use crate::client::service::event::Sv2ClientEvent;
-use crate::server::service::client::Sv2MessagesToClient;
+use crate::server::service::client::{Sv2MessagesToClient, Sv2MessagesToClients};
+use crate::server::service::solution::Sv2BlockSolution;
 use crate::server::service::subprotocols::mining::trigger::MiningServerTrigger;
+use crate::sibling::Sv2SiblingBusError;
 
 /// The event type for [`crate::server::service::Sv2ServerService`].
 #[derive(Debug, Clone)]
@@ -16,14 +18,60 @@ pub enum Sv2ServerEvent<'a> {
     // todo:
     // JobDeclarationTrigger(JobDeclarationTrigger<'a>),
     // TemplateDistributionTrigger(TemplateDistributionTrigger<'a>),
+    /// Send an event to the sibling client service registered under `sibling` on the [`crate::sibling::Sv2SiblingBus`].
+    ///
     /// The event is boxed to break the recursive type definition between Sv2ClientEvent and Sv2ServerEvent.
-    SendEventToSiblingClientService(Box<Sv2ClientEvent<'a>>),
+    SendEventToSiblingClientService {
+        sibling: String,
+        event: Box<Sv2ClientEvent<'a>>,
+    },
+    /// Send an event to the sibling server service registered under `sibling` on the [`crate::sibling::Sv2SiblingBus`].
+    SendEventToSiblingServerService {
+        sibling: String,
+        event: Box<Sv2ServerEvent<'a>>,
+    },
     /// Send ordered sequence of Sv2 messages to a specific client.
     SendMessagesToClient(Box<Sv2MessagesToClient<'a>>),
     /// Send ordered sequences of Sv2 messages to different clients.
     SendMessagesToClients(Box<Vec<Sv2MessagesToClient<'a>>>),
+    /// Send an ordered sequence of Sv2 messages to every client matching some filter.
+    ///
+    /// Clients are reached concurrently. Clients that disconnect in the meantime are skipped,
+    /// and failures are reported per client in [`crate::server::service::outcome::Sv2ServerOutcome::Broadcasted`],
+    /// instead of aborting the broadcast.
+    Broadcast(Box<Sv2MessagesToClients<'a>>),
+    /// Shut down the connection to a specific client and remove it from memory.
+    ///
+    /// Messages that were already queued for the client are still flushed before the connection is closed.
+    DisconnectClient(u32),
     /// Execute an ordered sequence of events.
     MultipleEvents(Box<Vec<Sv2ServerEvent<'a>>>),
+    /// Dispatch a share that meets the network target to the Template Provider.
+    ///
+    /// Requires a [`crate::server::service::solution::Sv2SolutionPipeline`].
+    SubmitSolution(Box<Sv2BlockSolution>),
+}
+
+impl Sv2ServerEvent<'_> {
+    /// The name of the event variant, e.g.: for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sv2ServerEvent::IncomingMessage(_) => "IncomingMessage",
+            Sv2ServerEvent::MiningTrigger(_) => "MiningTrigger",
+            Sv2ServerEvent::SendEventToSiblingClientService { .. } => {
+                "SendEventToSiblingClientService"
+            }
+            Sv2ServerEvent::SendEventToSiblingServerService { .. } => {
+                "SendEventToSiblingServerService"
+            }
+            Sv2ServerEvent::SendMessagesToClient(_) => "SendMessagesToClient",
+            Sv2ServerEvent::SendMessagesToClients(_) => "SendMessagesToClients",
+            Sv2ServerEvent::Broadcast(_) => "Broadcast",
+            Sv2ServerEvent::DisconnectClient(_) => "DisconnectClient",
+            Sv2ServerEvent::MultipleEvents(_) => "MultipleEvents",
+            Sv2ServerEvent::SubmitSolution(_) => "SubmitSolution",
+        }
+    }
 }
 
 /// A Sv2 message addressed to the server, to be used as the event type of [`crate::server::service::Sv2ServerService`].
@@ -41,13 +89,33 @@ pub struct Sv2MessageToServer<'a> {
 pub enum Sv2ServerEventError {
     IdNotFound,
     IdMustBeSome,
+    SetupConnectionNotCompleted,
+    /// The client sent a second SetupConnection, after its connection was already set up.
+    SetupConnectionAlreadyCompleted,
     BadRouting,
     UnsupportedMessage,
     FailedToSendOutcome,
-    UnsupportedProtocol { protocol: Protocol },
-    FailedToSendEventToSiblingClientService,
+    UnsupportedProtocol {
+        protocol: Protocol,
+    },
+    /// The client sent a message of a subprotocol that differs from the one negotiated on SetupConnection.
+    ProtocolNotSetUp {
+        protocol: Protocol,
+    },
+    FailedToSendEventToSibling(Sv2SiblingBusError),
     FailedToSendMessageToClient,
-    NoSiblingClientService,
+    /// The service was not created with a [`crate::sibling::Sv2ServerSiblingIo`].
+    NoSiblingIo,
+    /// The client exceeded the message rate allowed by [`crate::server::service::layer::Sv2ServerRateLimitLayer`].
+    RateLimited {
+        client_id: u32,
+    },
+    /// The service [`tokio_util::sync::CancellationToken`] was cancelled.
+    ServiceShutDown,
+    /// The service was not set up with a [`crate::server::service::solution::Sv2SolutionPipeline`].
+    NoSolutionPipeline,
+    /// A [`Sv2BlockSolution`] that cannot be turned into a `SubmitSolution`.
+    InvalidSolution(String),
     MiningHandlerError(String),
     TemplateDistributionHandlerError(String),
     JobDeclarationHandlerError(String),
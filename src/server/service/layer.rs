@@ -0,0 +1,123 @@
+//! Built-in [`crate::layer::Sv2Layer`]s that only make sense for a [`crate::server::service::Sv2ServerService`].
+
+use crate::layer::{Sv2BoxFuture, Sv2Layer, Sv2Next};
+use crate::server::service::event::{Sv2ServerEvent, Sv2ServerEventError};
+use crate::server::service::outcome::Sv2ServerOutcome;
+use dashmap::DashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// past this many tracked clients, the windows of clients that went quiet are dropped
+const RATE_LIMIT_PRUNE_THRESHOLD: usize = 4096;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Rejects messages from clients that send more than `max_messages_per_second`,
+/// with [`Sv2ServerEventError::RateLimited`].
+///
+/// Only [`Sv2ServerEvent::IncomingMessage`]s are counted, over fixed one-second windows.
+#[derive(Debug)]
+pub struct Sv2ServerRateLimitLayer {
+    max_messages_per_second: u32,
+    // client_id -> (start of the current window, messages in the current window)
+    windows: DashMap<u32, (Instant, u32)>,
+}
+
+impl Sv2ServerRateLimitLayer {
+    /// Fails with [`Sv2ServerLayerError::InvalidRateLimit`] if `max_messages_per_second` is zero.
+    pub fn new(max_messages_per_second: u32) -> Result<Self, Sv2ServerLayerError> {
+        if max_messages_per_second == 0 {
+            return Err(Sv2ServerLayerError::InvalidRateLimit);
+        }
+        Ok(Self {
+            max_messages_per_second,
+            windows: DashMap::new(),
+        })
+    }
+
+    // Counts a message from `client_id`, returning whether it is within the limit.
+    fn allow(&self, client_id: u32) -> bool {
+        let now = Instant::now();
+
+        if self.windows.len() > RATE_LIMIT_PRUNE_THRESHOLD {
+            self.windows
+                .retain(|_, (start, _)| now.duration_since(*start) < RATE_LIMIT_WINDOW);
+        }
+
+        let mut window = self.windows.entry(client_id).or_insert((now, 0));
+        let (start, count) = window.value_mut();
+        if now.duration_since(*start) >= RATE_LIMIT_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.max_messages_per_second
+    }
+}
+
+impl Sv2Layer<Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>
+    for Sv2ServerRateLimitLayer
+{
+    fn handle<'a>(
+        &'a self,
+        event: Sv2ServerEvent<'static>,
+        next: Sv2Next<'a, Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>,
+    ) -> Sv2BoxFuture<'a, Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> {
+        if let Sv2ServerEvent::IncomingMessage(sv2_message) = &event {
+            if let Some(client_id) = sv2_message.client_id {
+                if !self.allow(client_id) {
+                    warn!("client_id {} exceeded its message rate limit", client_id);
+                    return Box::pin(
+                        async move { Err(Sv2ServerEventError::RateLimited { client_id }) },
+                    );
+                }
+            }
+        }
+        next.run(event)
+    }
+}
+
+/// Errors that can occur when building the layers of this module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2ServerLayerError {
+    /// A [`Sv2ServerRateLimitLayer`] was created with a rate of 0 messages per second.
+    InvalidRateLimit,
+}
+
+impl fmt::Display for Sv2ServerLayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sv2ServerLayerError::InvalidRateLimit => {
+                write!(f, "Rate limit must be greater than 0 messages per second")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Sv2ServerLayerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2ServerLayerError, Sv2ServerRateLimitLayer};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rate_limit_is_per_client_and_per_window() {
+        assert_eq!(
+            Sv2ServerRateLimitLayer::new(0).err(),
+            Some(Sv2ServerLayerError::InvalidRateLimit)
+        );
+        let layer = Sv2ServerRateLimitLayer::new(2).unwrap();
+
+        assert!(layer.allow(1));
+        assert!(layer.allow(1));
+        assert!(!layer.allow(1));
+        // other clients have their own budget
+        assert!(layer.allow(2));
+
+        // once the window is over, the budget is restored
+        layer.windows.get_mut(&1).unwrap().0 = Instant::now() - Duration::from_secs(2);
+        assert!(layer.allow(1));
+    }
+}
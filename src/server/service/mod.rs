@@ -1,34 +1,50 @@
-use crate::client::service::sibling::Sv2SiblingServerServiceIo;
-use crate::server::service::client::{Sv2MessagesToClient, Sv2ServerServiceClient};
+use crate::capture::{Sv2Capture, Sv2CaptureDirection, Sv2CaptureRecord};
+use crate::layer::{Sv2BoxFuture, Sv2Layer, Sv2LayerInner, Sv2Layers};
+use crate::metrics;
+use crate::server::service::client::{
+    Sv2BroadcastReport, Sv2ClientFilter, Sv2MessagesToClient, Sv2MessagesToClients,
+    Sv2ServerServiceClient,
+};
 use crate::server::service::config::Sv2ServerServiceConfig;
 use crate::server::service::connection::Sv2ConnectionClient;
 use crate::server::service::error::Sv2ServerServiceError;
 use crate::server::service::event::{Sv2MessageToServer, Sv2ServerEvent, Sv2ServerEventError};
 use crate::server::service::outcome::Sv2ServerOutcome;
-use crate::server::service::sibling::Sv2SiblingClientServiceIo;
+use crate::server::service::solution::Sv2SolutionPipeline;
 use crate::server::service::subprotocols::mining::handler::NullSv2MiningServerHandler;
 use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
 use crate::server::service::subprotocols::mining::trigger::MiningServerTrigger;
 use crate::server::tcp::encrypted::start_encrypted_tcp_server;
 use crate::server::ClientIdGenerator;
+use crate::sibling::Sv2ServerSiblingIo;
+use crate::span;
+use crate::timer::{Sv2ScheduledEvent, Sv2TimerHandle, Sv2Timers};
+use crate::Sv2MessageIo;
 use crate::Sv2Service;
 use dashmap::DashMap;
 use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use stratum_common::roles_logic_sv2::common_messages_sv2::{
     Protocol, SetupConnection, SetupConnectionError, SetupConnectionSuccess,
 };
 use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages, Mining};
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
+use tracing::{debug, error, info, Instrument};
 
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod block;
 pub mod client;
 pub mod config;
 pub mod connection;
 pub mod error;
 pub mod event;
+pub mod layer;
 pub mod outcome;
-pub mod sibling;
+pub mod solution;
+pub mod state;
 pub mod subprotocols;
 
 /// A [`Sv2Service`] implementer that provides:
@@ -39,6 +55,23 @@ pub mod subprotocols;
 ///
 /// Inactive clients have their connections killed and are removed from memory after some predefined time (configurable via [`config::Sv2ServerServiceConfig`]).
 ///
+/// ## Concurrency model
+///
+/// Every client gets a dedicated task, which holds a clone of the service:
+/// - messages from the same client are handled in the order they were received, one at a time
+///   (the inbound channel of the client's [`crate::Sv2MessageIo`] acts as its ordered queue)
+/// - messages from different clients are handled in parallel, across the tokio worker threads
+/// - events from sibling services (e.g.: new templates) are handled on yet another task,
+///   concurrently with client messages
+/// - events fired by timers (see [`crate::timer`]) are handled on yet another task, too
+///
+/// Only the messages of a single client are ordered against each other: sibling and timer events are not queued
+/// behind the messages of the clients they concern, and neither are the broadcasts they trigger.
+///
+/// Since handlers are cloned into every task, any state they keep must be shared across clones.
+/// [`state::Sv2ServerHandlerState`] offers locks for per-client and global state, which handlers must take
+/// themselves: the service does not serialize anything else on their behalf.
+///
 /// The `M` generic parameter is the handler for the Mining subprotocol.
 /// If the service does not support mining subprotocol, `M` should be set to [`NullSv2MiningServerHandler`].
 ///
@@ -59,7 +92,13 @@ where
     mining_handler: M,
     // todo: job_declaration_handler: J,
     // todo: template_distribution_handler: T,
-    sibling_client_service_io: Option<Sv2SiblingClientServiceIo>,
+    sibling_io: Option<Sv2ServerSiblingIo>,
+    timers: Sv2Timers<Sv2ServerEvent<'static>>,
+    layers: Sv2Layers<Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>,
+    // whether new TCP connections are accepted, or closed right away
+    accepting_new_clients: Arc<AtomicBool>,
+    capture: Option<Sv2Capture>,
+    solution_pipeline: Option<Sv2SolutionPipeline>,
     cancellation_token: CancellationToken,
 }
 
@@ -69,7 +108,7 @@ where
 {
     /// Creates a new [`Sv2ServerService`]
     ///
-    /// No sibling client service is required.
+    /// No sibling service is required.
     pub fn new(
         config: Sv2ServerServiceConfig,
         mining_handler: M,
@@ -81,23 +120,18 @@ where
         Ok(sv2_server_service)
     }
 
-    /// Creates a new [`Sv2ServerService`] plus a new [`Sv2SiblingServerServiceIo`].
+    /// Creates a new [`Sv2ServerService`] that communicates with sibling services.
     ///
-    /// The [`Sv2SiblingClientServiceIo`] can be used as input to [`crate::client::service::Sv2ClientService::new_from_sibling_io`] to create a sibling client service that pairs with this server.
-    pub fn new_with_sibling_io(
+    /// Before calling this, you need to register the service on a [`crate::sibling::Sv2SiblingBus`] using [`crate::sibling::Sv2SiblingBus::register_server`].
+    pub fn new_from_sibling_io(
         config: Sv2ServerServiceConfig,
         mining_handler: M,
+        sibling_io: Sv2ServerSiblingIo,
         cancellation_token: CancellationToken,
-    ) -> Result<(Self, Sv2SiblingServerServiceIo), Sv2ServerServiceError> {
-        let (sibling_client_service_io, sibling_server_service_io) =
-            Sv2SiblingClientServiceIo::new();
-        let sv2_server_service = Self::_new(
-            config,
-            mining_handler,
-            Some(sibling_client_service_io),
-            cancellation_token,
-        )?;
-        Ok((sv2_server_service, sibling_server_service_io))
+    ) -> Result<Self, Sv2ServerServiceError> {
+        let sv2_server_service =
+            Self::_new(config, mining_handler, Some(sibling_io), cancellation_token)?;
+        Ok(sv2_server_service)
     }
 
     // internal constructor
@@ -106,7 +140,7 @@ where
         mining_handler: M,
         // todo: job_declaration_handler: J,
         // todo: template_distribution_handler: T,
-        sibling_client_service_io: Option<Sv2SiblingClientServiceIo>,
+        sibling_io: Option<Sv2ServerSiblingIo>,
         cancellation_token: CancellationToken,
     ) -> Result<Self, Sv2ServerServiceError> {
         Self::validate_protocol_handlers(&config)?;
@@ -116,23 +150,108 @@ where
             clients: Arc::new(DashMap::new()),
             client_id_generator: ClientIdGenerator::new(),
             mining_handler,
-            sibling_client_service_io,
+            sibling_io,
+            timers: Sv2Timers::new(cancellation_token.clone()),
+            layers: Sv2Layers::new(),
+            accepting_new_clients: Arc::new(AtomicBool::new(true)),
+            capture: None,
+            solution_pipeline: None,
             cancellation_token,
         };
 
         Ok(sv2_server_service)
     }
 
+    /// Wraps [`Sv2Service::handle`] with `layer`, below every layer added before.
+    ///
+    /// Layers must be added before the service is started. See [`crate::layer`] for how layers are composed.
+    pub fn with_layer(
+        mut self,
+        layer: impl Sv2Layer<Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>,
+    ) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Records every message exchanged with clients into `capture`, under the id of each client.
+    ///
+    /// Must be set before the service is started. See [`crate::capture`] for the capture format.
+    pub fn with_capture(mut self, capture: Sv2Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Sends the [`Sv2ServerEvent::SubmitSolution`]s reported by the mining handler to the Template Provider,
+    /// through a sibling Template Distribution client. See [`crate::server::service::solution`].
+    ///
+    /// Requires the service to be created with [`Self::new_from_sibling_io`].
+    pub fn with_solution_pipeline(mut self, solution_pipeline: Sv2SolutionPipeline) -> Self {
+        self.solution_pipeline = Some(solution_pipeline);
+        self
+    }
+
+    /// Feeds the inbound messages of a capture into the service, one at a time, in the order they were recorded.
+    ///
+    /// Meant for a service that was not started: every connection of the capture is replayed as an in-memory
+    /// client under the same id, whose outbound messages are queued on its [`Sv2MessageIo`]
+    /// (see [`Self::get_client`]), so they can be compared against the capture.
+    pub async fn replay(
+        &mut self,
+        records: Vec<Sv2CaptureRecord>,
+    ) -> Vec<Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> {
+        let mut results = Vec::new();
+        for record in records {
+            if record.direction != Sv2CaptureDirection::Inbound {
+                continue;
+            }
+            if !self.clients.contains_key(&record.connection_id) {
+                let (tx, rx) = async_channel::unbounded();
+                let client = Sv2ServerServiceClient::new(Sv2MessageIo::new(rx, tx, None));
+                Self::insert_client(&self.clients, record.connection_id, client);
+            }
+            let event = Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(record.connection_id),
+                message: record.message,
+            });
+            results.push(self.handle(event).await);
+        }
+        results
+    }
+
+    // Counts the client as connected until `remove_client` (or `remove_all_clients`) takes it out.
+    fn insert_client(
+        clients: &DashMap<u32, Arc<Sv2ServerServiceClient>>,
+        client_id: u32,
+        client: Sv2ServerServiceClient,
+    ) {
+        clients.insert(client_id, Arc::new(client));
+        metrics::record_client_connected();
+    }
+
     async fn remove_client(&mut self, client_id: u32) {
-        if !Self::has_null_handler(Protocol::MiningProtocol) {
+        let Some((_, client)) = self.clients.remove(&client_id) else {
+            // client was already removed
+            return;
+        };
+        metrics::record_clients_disconnected(1);
+
+        client.io.shutdown();
+
+        // only clients that completed SetupConnection were ever added to the subprotocol handlers
+        let protocol = client
+            .connection
+            .read()
+            .await
+            .as_ref()
+            .map(|connection| connection.protocol);
+
+        if protocol == Some(Protocol::MiningProtocol)
+            && !Self::has_null_handler(Protocol::MiningProtocol)
+        {
             self.mining_handler.remove_client(client_id).await;
         }
 
         // todo: remove client from other subprotocols
-
-        if let Some((_, client)) = self.clients.remove(&client_id) {
-            client.io.shutdown();
-        }
     }
 
     async fn remove_all_clients(&mut self) {
@@ -145,13 +264,24 @@ where
         for (client_id, client) in client_entries {
             client.io.shutdown();
 
-            if !Self::has_null_handler(Protocol::MiningProtocol) {
+            // only clients that completed SetupConnection were ever added to the subprotocol handlers
+            let protocol = client
+                .connection
+                .read()
+                .await
+                .as_ref()
+                .map(|connection| connection.protocol);
+
+            if protocol == Some(Protocol::MiningProtocol)
+                && !Self::has_null_handler(Protocol::MiningProtocol)
+            {
                 self.mining_handler.remove_client(client_id).await;
             }
 
             // todo: remove client from other subprotocols
         }
 
+        metrics::record_clients_disconnected(self.clients.len());
         self.clients.clear();
     }
 
@@ -208,6 +338,40 @@ where
         self.clients.len()
     }
 
+    /// Fires an event later, or periodically, until cancelled via the returned [`Sv2TimerHandle`] or the service shuts down.
+    ///
+    /// Handlers should return [`Sv2ServerOutcome::Schedule`] instead.
+    pub fn schedule(
+        &self,
+        scheduled_event: Sv2ScheduledEvent<Sv2ServerEvent<'static>>,
+    ) -> Sv2TimerHandle {
+        self.timers.schedule(scheduled_event)
+    }
+
+    /// Stops accepting new clients: new TCP connections are closed right after the handshake.
+    ///
+    /// Clients that are already connected are not affected.
+    pub fn pause_new_clients(&self) {
+        self.accepting_new_clients.store(false, Ordering::Relaxed);
+        info!("Sv2ServerService paused new client connections");
+    }
+
+    /// Accepts new clients again, after [`Self::pause_new_clients`].
+    pub fn resume_new_clients(&self) {
+        self.accepting_new_clients.store(true, Ordering::Relaxed);
+        info!("Sv2ServerService resumed new client connections");
+    }
+
+    /// Returns whether new clients are accepted.
+    pub fn is_accepting_new_clients(&self) -> bool {
+        self.accepting_new_clients.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the service [`CancellationToken`] was cancelled.
+    pub fn is_shut_down(&self) -> bool {
+        self.cancellation_token.is_cancelled()
+    }
+
     /// Updates the last message time for a given client
     pub fn update_client_message_time(&self, client_id: u32) -> bool {
         if let Some(client_entry) = self.clients.get(&client_id) {
@@ -245,13 +409,10 @@ where
                     .expect("failed to encode string"),
             };
 
-            let outcome = Sv2ServerOutcome::TriggerNewEvent(Box::new(
-                Sv2ServerEvent::SendMessagesToClient(Box::new(Sv2MessagesToClient {
-                    client_id,
-                    messages: vec![setup_connection_error.into()],
-                })),
+            return Ok(Self::setup_connection_error_outcome(
+                client_id,
+                setup_connection_error,
             ));
-            return Ok(outcome);
         }
 
         // 2) Check version support
@@ -267,13 +428,10 @@ where
                     .expect("failed to encode string"),
             };
 
-            let outcome = Sv2ServerOutcome::TriggerNewEvent(Box::new(
-                Sv2ServerEvent::SendMessagesToClient(Box::new(Sv2MessagesToClient {
-                    client_id,
-                    messages: vec![setup_connection_error.into()],
-                })),
+            return Ok(Self::setup_connection_error_outcome(
+                client_id,
+                setup_connection_error,
             ));
-            return Ok(outcome);
         }
 
         // Choose an actual version to use.
@@ -314,18 +472,10 @@ where
                     .expect("failed to encode string"),
             };
 
-            let outcome = Sv2ServerOutcome::TriggerNewEvent(Box::new(
-                Sv2ServerEvent::SendMessagesToClient(Box::new(Sv2MessagesToClient {
-                    client_id,
-                    messages: vec![setup_connection_error.into()],
-                })),
+            return Ok(Self::setup_connection_error_outcome(
+                client_id,
+                setup_connection_error,
             ));
-
-            if !Self::has_null_handler(Protocol::MiningProtocol) {
-                self.mining_handler.add_client(client_id, req.flags).await;
-            }
-
-            return Ok(outcome);
         }
 
         // 4) Create connection details and update client
@@ -342,10 +492,16 @@ where
             device_id: req.device_id,
         };
 
-        if let Some(client_entry) = self.clients.get(&client_id) {
-            *client_entry.connection.write().await = Some(connection);
-        } else {
+        let Some(client) = self.get_client(client_id) else {
             return Err(Sv2ServerEventError::IdNotFound);
+        };
+        {
+            let mut client_connection = client.connection.write().await;
+            // the connection of a client is only set up once
+            if client_connection.is_some() {
+                return Err(Sv2ServerEventError::SetupConnectionAlreadyCompleted);
+            }
+            *client_connection = Some(connection);
         }
 
         let setup_connection_success_flags = match req.protocol {
@@ -379,28 +535,162 @@ where
         Ok(outcome)
     }
 
+    // Builds the outcome for a failed SetupConnection:
+    // the SetupConnectionError is sent to the client, and then the client is disconnected.
+    //
+    // Messages already queued on the client's IO are still flushed before the connection is closed.
+    fn setup_connection_error_outcome(
+        client_id: u32,
+        setup_connection_error: SetupConnectionError<'static>,
+    ) -> Sv2ServerOutcome<'static> {
+        metrics::record_setup_connection_error(&String::from_utf8_lossy(
+            setup_connection_error.error_code.inner_as_ref(),
+        ));
+
+        Sv2ServerOutcome::TriggerNewEvent(Box::new(Sv2ServerEvent::MultipleEvents(Box::new(vec![
+            Sv2ServerEvent::SendMessagesToClient(Box::new(Sv2MessagesToClient {
+                client_id,
+                messages: vec![setup_connection_error.into()],
+            })),
+            Sv2ServerEvent::DisconnectClient(client_id),
+        ]))))
+    }
+
+    // Checks an incoming message against the connection state of the client that sent it:
+    // - the client must be known
+    // - SetupConnection must be the first message from the client, and is only accepted once
+    // - after SetupConnection, only messages of the negotiated subprotocol (or Common messages) are allowed
+    async fn validate_incoming_message(
+        &self,
+        client_id: u32,
+        message: &AnyMessage<'static>,
+    ) -> Result<(), Sv2ServerEventError> {
+        let Some(client) = self.get_client(client_id) else {
+            error!(
+                "Sv2ServerService received a message from unknown client_id {}: {}",
+                client_id, message
+            );
+            return Err(Sv2ServerEventError::IdNotFound);
+        };
+        let connection_protocol = client
+            .connection
+            .read()
+            .await
+            .as_ref()
+            .map(|connection| connection.protocol);
+
+        let message_protocol = match message {
+            AnyMessage::Common(CommonMessages::SetupConnection(_)) => {
+                if let Some(connection_protocol) = connection_protocol {
+                    error!(
+                        "Sv2ServerService received a second SetupConnection from client_id {}, which set up a {:?} connection",
+                        client_id, connection_protocol
+                    );
+                    return Err(Sv2ServerEventError::SetupConnectionAlreadyCompleted);
+                }
+                return Ok(());
+            }
+            AnyMessage::Common(_) => None,
+            AnyMessage::Mining(_) => Some(Protocol::MiningProtocol),
+            AnyMessage::JobDeclaration(_) => Some(Protocol::JobDeclarationProtocol),
+            AnyMessage::TemplateDistribution(_) => Some(Protocol::TemplateDistributionProtocol),
+        };
+
+        let Some(connection_protocol) = connection_protocol else {
+            error!(
+                "Sv2ServerService received a message from client_id {} before SetupConnection: {}",
+                client_id, message
+            );
+            return Err(Sv2ServerEventError::SetupConnectionNotCompleted);
+        };
+
+        match message_protocol {
+            Some(protocol) if protocol != connection_protocol => {
+                error!(
+                    "Sv2ServerService received a {:?} message from client_id {}, which set up a {:?} connection: {}",
+                    protocol, client_id, connection_protocol, message
+                );
+                Err(Sv2ServerEventError::ProtocolNotSetUp { protocol })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Sends an ordered sequence of messages to every client matching the filter, concurrently.
+    // A client that fails to receive the messages is reported, without affecting the others.
+    async fn broadcast(&self, broadcast: Sv2MessagesToClients<'static>) -> Sv2BroadcastReport {
+        // collect the clients first, so that no DashMap shard is locked across await points
+        let clients: Vec<(u32, Arc<Sv2ServerServiceClient>)> = self
+            .clients
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+
+        let mut tasks = JoinSet::new();
+        for (client_id, client) in clients {
+            let is_selected = match client.connection.read().await.as_ref() {
+                Some(connection) => broadcast.filter.matches(connection),
+                None => false,
+            };
+            if !is_selected {
+                continue;
+            }
+
+            let messages = broadcast.messages.clone();
+            tasks.spawn(async move {
+                for message in messages {
+                    client
+                        .io
+                        .send_message(message)
+                        .await
+                        .map_err(|e| (client_id, e))?;
+                }
+                Ok(client_id)
+            });
+        }
+
+        let mut report = Sv2BroadcastReport::default();
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(client_id)) => report.delivered.push(client_id),
+                // the client disconnected while the broadcast was ongoing
+                Ok(Err((client_id, _))) if !self.clients.contains_key(&client_id) => {
+                    debug!("Skipping broadcast to disconnected client_id {}", client_id);
+                    report.skipped.push(client_id);
+                }
+                Ok(Err((client_id, e))) => {
+                    error!(
+                        "Failed to broadcast message to client_id {}: {:?}",
+                        client_id, e
+                    );
+                    report.failed.push((client_id, e));
+                }
+                Err(e) => error!("Broadcast task failed: {:?}", e),
+            }
+        }
+        report
+    }
+
     /// Add a client to the service (for testing purposes)
     #[cfg(test)]
     pub fn add_client(&mut self, client_id: u32, client: Sv2ServerServiceClient) {
-        self.clients.insert(client_id, Arc::new(client));
+        Self::insert_client(&self.clients, client_id, client);
     }
 }
 
-impl<M> Sv2Service for Sv2ServerService<M>
+impl<M> Sv2LayerInner<Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>
+    for Sv2ServerService<M>
 where
     M: Sv2MiningServerHandler + Clone + Send + Sync + 'static,
 {
-    type Event = Sv2ServerEvent<'static>;
-    type Outcome = Sv2ServerOutcome<'static>;
-    type ServiceError = Sv2ServerServiceError;
-    type EventError = Sv2ServerEventError;
-
     // we cannot use `async fn` syntax here because of the recursive calls to `self.handle`
-    fn handle(
+    fn handle_unlayered(
         &mut self,
-        event: Self::Event,
-    ) -> impl Future<Output = Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> + Send {
+        event: Sv2ServerEvent<'static>,
+    ) -> Sv2BoxFuture<'_, Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> {
         Box::pin(async move {
+            metrics::record_event("server", event.name());
+
             // Extract client_id if available and update message time
             if let Sv2ServerEvent::IncomingMessage(sv2_message) = &event {
                 if let Some(client_id) = sv2_message.client_id {
@@ -408,6 +698,26 @@ where
                 }
             }
 
+            // make sure the client is only sending messages that are allowed by its connection state,
+            // so that subprotocol handlers can trust the caller
+            if let Sv2ServerEvent::IncomingMessage(sv2_message) = &event {
+                if let Some(client_id) = sv2_message.client_id {
+                    if let Err(e) = self
+                        .validate_incoming_message(client_id, &sv2_message.message)
+                        .await
+                    {
+                        if self.config.disconnect_on_protocol_violation {
+                            debug!(
+                                "Disconnecting client_id {} due to protocol violation",
+                                client_id
+                            );
+                            self.remove_client(client_id).await;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+
             let event_clone = event.clone();
             let outcome = match event_clone {
                 Sv2ServerEvent::IncomingMessage(sv2_message) => {
@@ -449,7 +759,9 @@ where
                                     debug!("Sv2ServerService received a OpenStandardMiningChannel message: {}", open_standard_mining_channel);
                                     self.mining_handler
                                         .handle_open_standard_mining_channel(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             open_standard_mining_channel,
                                         )
                                         .await
@@ -458,7 +770,9 @@ where
                                     debug!("Sv2ServerService received a OpenExtendedMiningChannel message: {}", open_extended_mining_channel);
                                     self.mining_handler
                                         .handle_open_extended_mining_channel(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             open_extended_mining_channel,
                                         )
                                         .await
@@ -470,7 +784,9 @@ where
                                     );
                                     self.mining_handler
                                         .handle_update_channel(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             update_channel,
                                         )
                                         .await
@@ -479,7 +795,9 @@ where
                                     debug!("Sv2ServerService received a SubmitSharesStandard message: {}", submit_shares_standard);
                                     self.mining_handler
                                         .handle_submit_shares_standard(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             submit_shares_standard,
                                         )
                                         .await
@@ -488,7 +806,9 @@ where
                                     debug!("Sv2ServerService received a SubmitSharesExtended message: {}", submit_shares_extended);
                                     self.mining_handler
                                         .handle_submit_shares_extended(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             submit_shares_extended,
                                         )
                                         .await
@@ -497,7 +817,9 @@ where
                                     debug!("Sv2ServerService received a SetCustomMiningJob message: {}", set_custom_mining_job);
                                     self.mining_handler
                                         .handle_set_custom_mining_job(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             set_custom_mining_job,
                                         )
                                         .await
@@ -509,7 +831,9 @@ where
                                     );
                                     self.mining_handler
                                         .handle_close_channel(
-                                            sv2_message.client_id.expect("client_id must be Some"),
+                                            sv2_message
+                                                .client_id
+                                                .ok_or(Sv2ServerEventError::IdMustBeSome)?,
                                             close_channel,
                                         )
                                         .await
@@ -611,12 +935,20 @@ where
                         }
                         MiningServerTrigger::NewTemplate(new_template) => {
                             debug!("Sv2ServerService received a MiningServerTrigger::NewTemplate");
+                            // keep the template around, so that block solutions can be matched to it
+                            if let Some(solution_pipeline) = &self.solution_pipeline {
+                                solution_pipeline.record_new_template(new_template.clone());
+                            }
                             self.mining_handler.on_new_template(new_template).await
                         }
                         MiningServerTrigger::SetNewPrevHash(set_new_prev_hash) => {
                             debug!(
                                 "Sv2ServerService received a MiningServerTrigger::SetNewPrevHash"
                             );
+                            if let Some(solution_pipeline) = &self.solution_pipeline {
+                                solution_pipeline
+                                    .record_set_new_prev_hash(set_new_prev_hash.clone());
+                            }
                             self.mining_handler
                                 .on_set_new_prev_hash(set_new_prev_hash)
                                 .await
@@ -629,18 +961,33 @@ where
                 // Sv2ServerEvent::TemplateDistributionTrigger(trigger) => {
                 //     todo!()
                 // }
-                Sv2ServerEvent::SendEventToSiblingClientService(event) => {
-                    debug!("Sv2ServerService received a Sv2ServerEvent::SendEventToSiblingClientService");
-                    match self.sibling_client_service_io {
+                Sv2ServerEvent::SendEventToSiblingClientService { sibling, event } => {
+                    debug!("Sv2ServerService received a Sv2ServerEvent::SendEventToSiblingClientService for {}", sibling);
+                    match self.sibling_io {
+                        Some(ref io) => {
+                            io.send_to_client(&sibling, *event.clone())
+                                .await
+                                .map_err(Sv2ServerEventError::FailedToSendEventToSibling)?;
+                            Ok(Sv2ServerOutcome::Ok)
+                        }
+                        None => {
+                            error!("No sibling io on Sv2ServerService");
+                            Err(Sv2ServerEventError::NoSiblingIo)
+                        }
+                    }
+                }
+                Sv2ServerEvent::SendEventToSiblingServerService { sibling, event } => {
+                    debug!("Sv2ServerService received a Sv2ServerEvent::SendEventToSiblingServerService for {}", sibling);
+                    match self.sibling_io {
                         Some(ref io) => {
-                            io.send(*event.clone()).map_err(|_| {
-                                Sv2ServerEventError::FailedToSendEventToSiblingClientService
-                            })?;
+                            io.send_to_server(&sibling, *event.clone())
+                                .await
+                                .map_err(Sv2ServerEventError::FailedToSendEventToSibling)?;
                             Ok(Sv2ServerOutcome::Ok)
                         }
                         None => {
-                            error!("No sibling client service on Sv2ServerService");
-                            Err(Sv2ServerEventError::NoSiblingClientService)
+                            error!("No sibling io on Sv2ServerService");
+                            Err(Sv2ServerEventError::NoSiblingIo)
                         }
                     }
                 }
@@ -710,6 +1057,28 @@ where
                     }
                     Ok(Sv2ServerOutcome::Ok)
                 }
+                Sv2ServerEvent::Broadcast(broadcast) => {
+                    debug!(
+                        "Sv2ServerService received a Sv2ServerEvent::Broadcast to {:?}",
+                        broadcast.filter
+                    );
+                    let report = self.broadcast(*broadcast).await;
+                    Ok(Sv2ServerOutcome::Broadcasted(report))
+                }
+                Sv2ServerEvent::DisconnectClient(client_id) => {
+                    debug!(
+                        "Sv2ServerService received a Sv2ServerEvent::DisconnectClient for client_id {}",
+                        client_id
+                    );
+
+                    if self.get_client(client_id).is_none() {
+                        error!("Client not found in Sv2ServerService");
+                        return Err(Sv2ServerEventError::IdNotFound);
+                    }
+
+                    self.remove_client(client_id).await;
+                    Ok(Sv2ServerOutcome::Ok)
+                }
                 Sv2ServerEvent::MultipleEvents(events) => {
                     debug!(
                         "Sv2ServerService received a Sv2ServerEvent::MultipleEvents: {:?}",
@@ -724,16 +1093,105 @@ where
                     }
                     Ok(Sv2ServerOutcome::Ok)
                 }
+                Sv2ServerEvent::SubmitSolution(solution) => {
+                    let solution_pipeline = self
+                        .solution_pipeline
+                        .as_ref()
+                        .ok_or(Sv2ServerEventError::NoSolutionPipeline)?;
+                    info!(
+                        "Block found by client_id {} on channel_id {}, for template_id {}",
+                        solution.client_id, solution.channel_id, solution.template_id
+                    );
+
+                    let found_block = solution_pipeline.found_block(*solution);
+                    // SubmitSolution goes out first, the safety net must not delay it
+                    let submitted = match (
+                        Sv2SolutionPipeline::submit_solution_event(&found_block),
+                        &self.sibling_io,
+                    ) {
+                        (Ok(event), Some(io)) => io
+                            .send_to_client(
+                                solution_pipeline.template_distribution_sibling(),
+                                event,
+                            )
+                            .await
+                            .map(|_| Sv2ServerOutcome::Ok)
+                            .map_err(Sv2ServerEventError::FailedToSendEventToSibling),
+                        (Err(e), _) => Err(e),
+                        (Ok(_), None) => {
+                            error!("No sibling io on Sv2ServerService");
+                            Err(Sv2ServerEventError::NoSiblingIo)
+                        }
+                    };
+                    solution_pipeline.secure_block(&found_block);
+                    submitted
+                }
+            };
+
+            // the client whose message was handled, if any
+            let current_client_id = match &event {
+                Sv2ServerEvent::IncomingMessage(sv2_message) => sv2_message.client_id,
+                _ => None,
             };
 
-            // allow for recursive chaining of events
-            if let Ok(Sv2ServerOutcome::TriggerNewEvent(event)) = outcome {
-                self.handle(*event).await
-            } else {
-                outcome
+            match outcome {
+                // allow for recursive chaining of events
+                Ok(Sv2ServerOutcome::TriggerNewEvent(event)) => self.handle(*event).await,
+                Ok(Sv2ServerOutcome::Reply(messages)) => {
+                    let client_id = current_client_id.ok_or(Sv2ServerEventError::IdMustBeSome)?;
+                    self.handle(Sv2ServerEvent::SendMessagesToClient(Box::new(
+                        Sv2MessagesToClient {
+                            client_id,
+                            messages,
+                        },
+                    )))
+                    .await
+                }
+                Ok(Sv2ServerOutcome::Broadcast { protocol, messages }) => {
+                    self.handle(Sv2ServerEvent::Broadcast(Box::new(Sv2MessagesToClients {
+                        filter: Sv2ClientFilter::Protocol(protocol),
+                        messages,
+                    })))
+                    .await
+                }
+                Ok(Sv2ServerOutcome::Disconnect { reason }) => {
+                    let client_id = current_client_id.ok_or(Sv2ServerEventError::IdMustBeSome)?;
+                    info!("Disconnecting client_id {}: {}", client_id, reason);
+                    self.handle(Sv2ServerEvent::DisconnectClient(client_id))
+                        .await
+                }
+                Ok(Sv2ServerOutcome::Schedule(scheduled_event)) => {
+                    let handle = self.schedule(*scheduled_event);
+                    Ok(Sv2ServerOutcome::Scheduled(handle))
+                }
+                outcome => outcome,
             }
         })
     }
+}
+
+impl<M> Sv2Service for Sv2ServerService<M>
+where
+    M: Sv2MiningServerHandler + Clone + Send + Sync + 'static,
+{
+    type Event = Sv2ServerEvent<'static>;
+    type Outcome = Sv2ServerOutcome<'static>;
+    type ServiceError = Sv2ServerServiceError;
+    type EventError = Sv2ServerEventError;
+
+    // every event is handled inside its own span, and goes through the layers before reaching `handle_unlayered`
+    fn handle(
+        &mut self,
+        event: Sv2ServerEvent<'static>,
+    ) -> impl Future<Output = Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> + Send {
+        let layers = self.layers.clone();
+        let span = span::server_event_span(&event, |client_id| {
+            let client = self.clients.get(&client_id)?;
+            let connection = client.connection.try_read().ok()?;
+            connection.as_ref().map(|connection| connection.protocol)
+        });
+        async move { layers.run(event, self).await }.instrument(span)
+    }
 
     async fn start(&mut self) -> Result<(), Sv2ServerServiceError> {
         debug!("Sv2ServerService starting");
@@ -795,6 +1253,8 @@ where
         // Spawn a task to handle new client connections
         let clients = self.clients.clone();
         let mut client_id_generator = self.client_id_generator.clone();
+        let accepting_new_clients = self.accepting_new_clients.clone();
+        let capture = self.capture.clone();
 
         tokio::spawn(async move {
             let cancellation_token = cancellation_token;
@@ -805,9 +1265,19 @@ where
                         break;
                     }
                     Some(io) = new_client_rx.recv() => {
-                        let client = Sv2ServerServiceClient::new(io.clone());
+                        if !accepting_new_clients.load(Ordering::Relaxed) {
+                            debug!("new client connections are paused, closing connection");
+                            io.shutdown();
+                            continue;
+                        }
+
                         let client_id = client_id_generator.next();
-                        clients.insert(client_id, Arc::new(client));
+                        let io = match &capture {
+                            Some(capture) => io.with_capture(capture.clone(), client_id),
+                            None => io,
+                        };
+                        let client = Sv2ServerServiceClient::new(io.clone());
+                        Self::insert_client(&clients, client_id, client);
                         debug!("added new client with id: {}", client_id);
 
                         // Spawn a task to handle incoming messages from this client
@@ -838,6 +1308,7 @@ where
                                                         client_id,
                                                         e
                                                     );
+                                                    metrics::record_event_error("server", &e);
                                                 }
                                             }
                                             Err(_) => {
@@ -859,44 +1330,61 @@ where
         let cancellation_token = self.cancellation_token.clone();
         let mut this = self.clone();
 
-        // spawn a task to route events from the sibling client service
-        if let Some(sibling_io) = this.sibling_client_service_io.clone() {
+        // spawn a task to route events from sibling services
+        if let Some(sibling_io) = this.sibling_io.clone() {
             tokio::spawn(async move {
                 let cancellation_token = cancellation_token;
 
                 loop {
                     tokio::select! {
                         _ = cancellation_token.cancelled() => {
-                            debug!("Sibling client service event monitor task cancelled");
+                            debug!("Sibling event monitor task cancelled");
                             break;
                         }
                         result = sibling_io.recv() => {
                             match result {
                                 Ok(event) => {
-                                    debug!("Received event from sibling client service: {:?}", event);
+                                    debug!("Received event from sibling service: {:?}", event);
 
                                     // handle the event
                                     if let Err(e) = this.handle(*event.clone()).await {
                                         error!(
-                                            "Error handling event from sibling client service: {:?}",
+                                            "Error handling event from sibling service: {:?}",
                                             e
                                         );
+                                        metrics::record_event_error("server", &e);
                                     }
                                 }
                                 Err(e) => {
-                                    error!("Failed to receive event from sibling client service: {:?}", e);
+                                    error!("Failed to receive event from sibling service: {:?}", e);
+                                    break;
                                 }
                             }
                         }
                     }
                 }
-                debug!("Sibling client service event monitor task ended");
+                debug!("Sibling event monitor task ended");
                 sibling_io.shutdown();
             });
         }
 
         let mut this = self.clone();
 
+        // spawn a task to handle events fired by timers
+        let timers = this.timers.clone();
+        tokio::spawn(async move {
+            while let Some(event) = timers.recv().await {
+                debug!("Timer fired event: {:?}", event);
+                if let Err(e) = this.handle(event).await {
+                    error!("Error handling event fired by timer: {:?}", e);
+                    metrics::record_event_error("server", &e);
+                }
+            }
+            debug!("Timer event monitor task ended");
+        });
+
+        let mut this = self.clone();
+
         // start the mining handler if it is not a null handler
         if !Self::has_null_handler(Protocol::MiningProtocol) {
             match this
@@ -927,22 +1415,49 @@ where
 
 #[cfg(test)]
 mod tests {
+    use crate::capture::{read_capture, Sv2Capture};
+    use crate::client::service::event::Sv2ClientEvent;
+    use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
     use crate::client::tcp::encrypted::Sv2EncryptedTcpClient;
+    use crate::layer::{Sv2FilterLayer, Sv2LoggingLayer};
+    use crate::server::service::client::{
+        Sv2ClientFilter, Sv2MessagesToClient, Sv2MessagesToClients, Sv2ServerServiceClient,
+    };
     use crate::server::service::config::Sv2ServerServiceJobDeclarationConfig;
     use crate::server::service::config::Sv2ServerServiceMiningConfig;
     use crate::server::service::config::Sv2ServerTcpConfig;
+    use crate::server::service::event::{Sv2MessageToServer, Sv2ServerEvent, Sv2ServerEventError};
+    use crate::server::service::layer::Sv2ServerRateLimitLayer;
+    use crate::server::service::outcome::Sv2ServerOutcome;
+    use crate::server::service::solution::{Sv2BlockSolution, Sv2FoundBlock, Sv2SolutionPipeline};
+    use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
+    use crate::server::service::subprotocols::mining::trigger::MiningServerTrigger;
     use crate::server::service::Sv2ServerService;
     use crate::server::service::{
         error::Sv2ServerServiceError, subprotocols::mining::handler::NullSv2MiningServerHandler,
         Sv2ServerServiceConfig,
     };
+    use crate::sibling::Sv2SiblingBus;
+    use crate::testing::fixtures;
+    use crate::timer::Sv2ScheduledEvent;
     use crate::Sv2MessageFrame;
+    use crate::Sv2MessageIo;
     use crate::Sv2Service;
     use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
     use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
     use stratum_common::roles_logic_sv2;
-    use stratum_common::roles_logic_sv2::common_messages_sv2::{Protocol, SetupConnection};
-    use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages};
+    use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::{Seq0255, B0255, B064K};
+    use stratum_common::roles_logic_sv2::common_messages_sv2::{
+        ChannelEndpointChanged, Protocol, SetupConnection,
+    };
+    use stratum_common::roles_logic_sv2::mining_sv2::{
+        CloseChannel, OpenExtendedMiningChannel, OpenStandardMiningChannel, SetCustomMiningJob,
+        SubmitSharesExtended, SubmitSharesStandard, UpdateChannel,
+    };
+    use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages, Mining};
+    use stratum_common::roles_logic_sv2::template_distribution_sv2::{NewTemplate, SetNewPrevHash};
     use tokio_util::sync::CancellationToken;
 
     fn get_available_port() -> u16 {
@@ -950,6 +1465,197 @@ mod tests {
         listener.local_addr().unwrap().port()
     }
 
+    // A Dummy Mining Server Handler that is not null, but not actually handling anything
+    #[derive(Debug, Clone, Default)]
+    struct DummyMiningServerHandler;
+    impl Sv2MiningServerHandler for DummyMiningServerHandler {
+        async fn start(&mut self) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn on_new_template(
+            &self,
+            _m: NewTemplate<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn on_set_new_prev_hash(
+            &self,
+            _m: SetNewPrevHash<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        fn setup_connection_success_flags(&self) -> u32 {
+            0
+        }
+
+        async fn add_client(&mut self, _client_id: u32, _flags: u32) {}
+
+        async fn remove_client(&mut self, _client_id: u32) {}
+
+        async fn handle_open_standard_mining_channel(
+            &self,
+            _client_id: u32,
+            _m: OpenStandardMiningChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_open_extended_mining_channel(
+            &self,
+            _client_id: u32,
+            _m: OpenExtendedMiningChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_update_channel(
+            &self,
+            _client_id: u32,
+            _m: UpdateChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_close_channel(
+            &self,
+            _client_id: u32,
+            _m: CloseChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_submit_shares_standard(
+            &self,
+            _client_id: u32,
+            _m: SubmitSharesStandard,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_submit_shares_extended(
+            &self,
+            _client_id: u32,
+            _m: SubmitSharesExtended<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_set_custom_mining_job(
+            &self,
+            _client_id: u32,
+            _m: SetCustomMiningJob<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+    }
+
+    // A Mining Server Handler that answers with the richer outcomes:
+    // - OpenStandardMiningChannel -> Reply
+    // - UpdateChannel -> Broadcast
+    // - CloseChannel -> Disconnect
+    // - NewTemplate (not bound to any client) -> Reply
+    #[derive(Debug, Clone, Default)]
+    struct OutcomeMiningServerHandler;
+
+    fn close_channel_message() -> AnyMessage<'static> {
+        AnyMessage::Mining(Mining::CloseChannel(CloseChannel {
+            channel_id: 1,
+            reason_code: "".to_string().try_into().unwrap(),
+        }))
+    }
+
+    impl Sv2MiningServerHandler for OutcomeMiningServerHandler {
+        async fn start(&mut self) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn on_new_template(
+            &self,
+            _m: NewTemplate<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Reply(vec![close_channel_message()]))
+        }
+
+        async fn on_set_new_prev_hash(
+            &self,
+            _m: SetNewPrevHash<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::NoReply)
+        }
+
+        fn setup_connection_success_flags(&self) -> u32 {
+            0
+        }
+
+        async fn add_client(&mut self, _client_id: u32, _flags: u32) {}
+
+        async fn remove_client(&mut self, _client_id: u32) {}
+
+        async fn handle_open_standard_mining_channel(
+            &self,
+            _client_id: u32,
+            _m: OpenStandardMiningChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Reply(vec![close_channel_message()]))
+        }
+
+        async fn handle_open_extended_mining_channel(
+            &self,
+            _client_id: u32,
+            _m: OpenExtendedMiningChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_update_channel(
+            &self,
+            _client_id: u32,
+            _m: UpdateChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Broadcast {
+                protocol: Protocol::MiningProtocol,
+                messages: vec![close_channel_message()],
+            })
+        }
+
+        async fn handle_close_channel(
+            &self,
+            _client_id: u32,
+            _m: CloseChannel<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Disconnect {
+                reason: "channel closed".to_string(),
+            })
+        }
+
+        async fn handle_submit_shares_standard(
+            &self,
+            _client_id: u32,
+            _m: SubmitSharesStandard,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_submit_shares_extended(
+            &self,
+            _client_id: u32,
+            _m: SubmitSharesExtended<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+
+        async fn handle_set_custom_mining_job(
+            &self,
+            _client_id: u32,
+            _m: SetCustomMiningJob<'static>,
+        ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+            Ok(Sv2ServerOutcome::Ok)
+        }
+    }
+
     #[tokio::test]
     async fn sv2_server_ok() {
         let server_port = get_available_port();
@@ -978,6 +1684,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
             tcp_config,
             mining_config: None,
             job_declaration_config: Some(job_declaration_config),
@@ -1102,6 +1809,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
             tcp_config,
             job_declaration_config: Some(job_declaration_config),
             mining_config: None,
@@ -1164,10 +1872,187 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn sv2_server_service_bad_protocol() {
-        let server_port = {
-            use std::net::TcpListener;
-            TcpListener::bind("127.0.0.1:0")
+    async fn sv2_server_service_replays_capture() {
+        let path =
+            std::env::temp_dir().join(format!("sv2-server-replay-test-{}", std::process::id()));
+
+        // record a SetupConnection from client 5, as the service would have received it
+        let capture = Sv2Capture::create(&path).unwrap();
+        let (tx, rx) = async_channel::unbounded();
+        let io = Sv2MessageIo::new(rx, tx, None).with_capture(capture.clone(), 5);
+        let setup_connection = SetupConnection {
+            protocol: Protocol::JobDeclarationProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        };
+        io.send_message(setup_connection.into()).await.unwrap();
+        io.recv_message().await.unwrap();
+        capture.flush().await.unwrap();
+
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let job_declaration_config = Sv2ServerServiceJobDeclarationConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            job_declaration_config: Some(job_declaration_config),
+            mining_config: None,
+            template_distribution_config: None,
+        };
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            NullSv2MiningServerHandler,
+            CancellationToken::new(),
+        )
+        .unwrap();
+
+        let records = read_capture(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        let results = sv2_server_service.replay(records).await;
+
+        // only the inbound SetupConnection is replayed
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok());
+
+        let client = sv2_server_service.get_client(5).unwrap();
+        assert!(client.connection.read().await.is_some());
+        let response = client.io.recv_message().await.unwrap();
+        assert!(matches!(
+            response,
+            AnyMessage::Common(CommonMessages::SetupConnectionSuccess(_))
+        ));
+
+        // replayed clients are counted as connected, so disconnecting them doesn't drive the gauge negative
+        // (other tests share the gauge, but never take out more clients than they put in)
+        #[cfg(feature = "metrics")]
+        let connected_clients = || crate::metrics::Sv2Metrics::global().connected_clients.get();
+        #[cfg(feature = "metrics")]
+        assert!(connected_clients() >= 1);
+        sv2_server_service
+            .handle(Sv2ServerEvent::DisconnectClient(5))
+            .await
+            .unwrap();
+        assert!(sv2_server_service.get_client(5).is_none());
+        #[cfg(feature = "metrics")]
+        assert!(connected_clients() >= 0);
+
+        let _ = std::fs::remove_file(path.with_extension("bin"));
+        let _ = std::fs::remove_file(path.with_extension("jsonl"));
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_records_client_peer_addr_and_traffic() {
+        let server_port = get_available_port();
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: server_addr,
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let job_declaration_config = Sv2ServerServiceJobDeclarationConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            job_declaration_config: Some(job_declaration_config),
+            mining_config: None,
+            template_distribution_config: None,
+        };
+
+        let sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            NullSv2MiningServerHandler,
+            CancellationToken::new(),
+        )
+        .unwrap();
+
+        let mut sv2_server_service_clone = sv2_server_service.clone();
+        tokio::spawn(async move {
+            sv2_server_service_clone.start().await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = Sv2EncryptedTcpClient::new(server_addr, None).await.unwrap();
+        let setup_connection = SetupConnection {
+            protocol: Protocol::JobDeclarationProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        };
+        client
+            .io
+            .send_message(setup_connection.into())
+            .await
+            .unwrap();
+        let _response = client.io.rx.recv().await.unwrap();
+        // counters are updated right after the response is queued
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let client_id = *sv2_server_service.clients.iter().next().unwrap().key();
+        let server_client = sv2_server_service.get_client(client_id).unwrap();
+
+        let peer_addr = server_client.peer_addr().unwrap();
+        assert_eq!(peer_addr.ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_ne!(peer_addr.port(), server_port);
+        assert!(server_client.connected_at > 0);
+
+        let stats = server_client.stats();
+        assert_eq!(stats.messages_received, 1);
+        assert_eq!(stats.messages_sent, 1);
+        assert!(stats.bytes_received > 0);
+        assert!(stats.bytes_sent > 0);
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_bad_protocol() {
+        let server_port = {
+            use std::net::TcpListener;
+            TcpListener::bind("127.0.0.1:0")
                 .unwrap()
                 .local_addr()
                 .unwrap()
@@ -1198,6 +2083,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
             tcp_config,
             job_declaration_config: Some(job_declaration_config),
             mining_config: None,
@@ -1307,6 +2193,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
             tcp_config,
             job_declaration_config: Some(job_declaration_config),
             mining_config: None,
@@ -1461,6 +2348,7 @@ mod tests {
             min_supported_version: 2,
             max_supported_version: 2,
             inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
             tcp_config,
             job_declaration_config: Some(job_declaration_config),
             mining_config: None,
@@ -1535,60 +2423,8 @@ mod tests {
         assert_eq!(sv2_server_service.get_client_count(), 0);
     }
 
-    #[test]
-    fn sv2_server_service_null_handler_error() {
-        let tcp_config = Sv2ServerTcpConfig {
-            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
-            pub_key: Secp256k1PublicKey::try_from(
-                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
-            )
-            .expect("failed"),
-            priv_key: Secp256k1SecretKey::try_from(
-                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
-            )
-            .expect("failed"),
-            cert_validity: 3600,
-        };
-
-        let mining_config = Sv2ServerServiceMiningConfig {
-            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
-        };
-
-        let sv2_server_config = Sv2ServerServiceConfig {
-            min_supported_version: 2,
-            max_supported_version: 2,
-            inactivity_limit: 1,
-            tcp_config,
-            mining_config: Some(mining_config),
-            job_declaration_config: None,
-            template_distribution_config: None,
-        };
-
-        // Create a null mining handler
-        let mining_handler = NullSv2MiningServerHandler {};
-
-        let cancellation_token = CancellationToken::new();
-
-        // This should return an error because we're using a null handler for a supported protocol
-        let result =
-            super::Sv2ServerService::new(sv2_server_config, mining_handler, cancellation_token);
-
-        assert!(result.is_err());
-
-        if let Err(err) = result {
-            match err {
-                Sv2ServerServiceError::NullHandlerForSupportedProtocol { protocol } => {
-                    assert_eq!(protocol, Protocol::MiningProtocol);
-                }
-                _ => panic!("Expected NullHandlerForSupportedProtocol error"),
-            }
-        } else {
-            panic!("Expected error for null handler for supported protocol");
-        }
-    }
-
     #[tokio::test]
-    async fn sv2_server_shutdown_with_no_clients() {
+    async fn sv2_server_service_setup_connection_error_disconnects_client() {
         let server_port = get_available_port();
         let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
         let pub_key = Secp256k1PublicKey::try_from(
@@ -1614,23 +2450,20 @@ mod tests {
         let sv2_server_config = Sv2ServerServiceConfig {
             min_supported_version: 2,
             max_supported_version: 2,
+            inactivity_limit: 10, // Set higher to make sure removal is not due to inactivity
+            disconnect_on_protocol_violation: false,
+            tcp_config,
             job_declaration_config: Some(job_declaration_config),
             mining_config: None,
             template_distribution_config: None,
-            inactivity_limit: 1,
-            tcp_config,
         };
 
         let mining_handler = NullSv2MiningServerHandler;
 
         let cancellation_token = CancellationToken::new();
 
-        let sv2_server_service = Sv2ServerService::new(
-            sv2_server_config,
-            mining_handler,
-            cancellation_token.clone(),
-        )
-        .unwrap();
+        let sv2_server_service =
+            Sv2ServerService::new(sv2_server_config, mining_handler, cancellation_token).unwrap();
 
         // Spawn the server start in a background task
         let mut sv2_server_service_clone = sv2_server_service.clone();
@@ -1641,33 +2474,54 @@ mod tests {
         // Wait for server to be ready
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
 
-        // Verify initial state
-        assert_eq!(sv2_server_service.get_client_count(), 0);
+        let client = Sv2EncryptedTcpClient::new(server_addr, None).await.unwrap();
 
-        // Shutdown the server
-        cancellation_token.cancel();
+        let setup_connection_bad_protocol = SetupConnection {
+            protocol: Protocol::TemplateDistributionProtocol, // unsupported protocol
+            min_version: 2,
+            max_version: 2,
+            flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        };
 
-        // Verify final state
+        client
+            .io
+            .send_message(setup_connection_bad_protocol.into())
+            .await
+            .unwrap();
+
+        // the SetupConnectionError is flushed before the connection is closed
+        match client.io.recv_message().await.unwrap() {
+            AnyMessage::Common(CommonMessages::SetupConnectionError(error)) => {
+                assert_eq!(error.error_code.as_ref(), b"unsupported-protocol");
+            }
+            _ => panic!("expected SetupConnectionError message"),
+        }
+
+        // the server closes the connection right after the SetupConnectionError
+        assert!(client.io.recv_message().await.is_err());
+
+        // the client is removed without waiting for the inactivity limit
         assert_eq!(sv2_server_service.get_client_count(), 0);
     }
 
     #[tokio::test]
-    async fn sv2_server_shutdown_with_active_clients() {
-        let server_port = get_available_port();
-        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
-        let pub_key = Secp256k1PublicKey::try_from(
-            "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
-        )
-        .expect("failed");
-        let priv_key = Secp256k1SecretKey::try_from(
-            "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
-        )
-        .expect("failed");
-
+    async fn sv2_server_service_rejects_message_before_setup_connection() {
         let tcp_config = Sv2ServerTcpConfig {
-            listen_address: server_addr,
-            pub_key,
-            priv_key,
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
             cert_validity: 3600,
         };
 
@@ -1678,40 +2532,458 @@ mod tests {
         let sv2_server_config = Sv2ServerServiceConfig {
             min_supported_version: 2,
             max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
             job_declaration_config: Some(job_declaration_config),
             mining_config: None,
             template_distribution_config: None,
-            inactivity_limit: 10, // Set higher to prevent automatic cleanup
-            tcp_config,
         };
 
         let mining_handler = NullSv2MiningServerHandler;
 
         let cancellation_token = CancellationToken::new();
 
-        let sv2_server_service = Sv2ServerService::new(
-            sv2_server_config,
-            mining_handler,
-            cancellation_token.clone(),
-        )
-        .unwrap();
+        let mut sv2_server_service =
+            Sv2ServerService::new(sv2_server_config, mining_handler, cancellation_token).unwrap();
 
-        // Spawn the server start in a background task
-        let mut sv2_server_service_clone = sv2_server_service.clone();
-        tokio::spawn(async move {
-            sv2_server_service_clone.start().await.unwrap();
-        });
+        // a client that connected but never sent SetupConnection
+        let (tx, rx) = async_channel::unbounded();
+        sv2_server_service.add_client(
+            1,
+            Sv2ServerServiceClient::new(Sv2MessageIo::new(rx, tx, None)),
+        );
 
-        // Wait for server to be ready
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let channel_endpoint_changed = ChannelEndpointChanged { channel_id: 1 };
 
-        // Create and connect multiple clients
-        let client1 = Sv2EncryptedTcpClient::new(server_addr, None).await.unwrap();
-        let client2 = Sv2EncryptedTcpClient::new(server_addr, None).await.unwrap();
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: AnyMessage::Common(CommonMessages::ChannelEndpointChanged(
+                    channel_endpoint_changed,
+                )),
+            }))
+            .await;
 
-        let setup_connection = SetupConnection {
-            protocol: Protocol::JobDeclarationProtocol,
-            min_version: 2,
+        assert!(matches!(
+            result,
+            Err(Sv2ServerEventError::SetupConnectionNotCompleted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_rejects_second_setup_connection_and_unknown_clients() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let job_declaration_config = Sv2ServerServiceJobDeclarationConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            job_declaration_config: Some(job_declaration_config),
+            mining_config: None,
+            template_distribution_config: None,
+        };
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            NullSv2MiningServerHandler,
+            CancellationToken::new(),
+        )
+        .unwrap();
+
+        let (tx, rx) = async_channel::unbounded();
+        sv2_server_service.add_client(
+            1,
+            Sv2ServerServiceClient::new(Sv2MessageIo::new(rx, tx, None)),
+        );
+
+        let setup_connection = SetupConnection {
+            protocol: Protocol::JobDeclarationProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        };
+
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: setup_connection.clone().into(),
+            }))
+            .await;
+        assert!(result.is_ok());
+
+        // the connection is only set up once
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: setup_connection.clone().into(),
+            }))
+            .await;
+        assert!(matches!(
+            result,
+            Err(Sv2ServerEventError::SetupConnectionAlreadyCompleted)
+        ));
+
+        // a client that is not connected at all
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(2),
+                message: setup_connection.into(),
+            }))
+            .await;
+        assert!(matches!(result, Err(Sv2ServerEventError::IdNotFound)));
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_rejects_message_for_protocol_not_set_up() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let job_declaration_config = Sv2ServerServiceJobDeclarationConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: true,
+            tcp_config,
+            job_declaration_config: Some(job_declaration_config),
+            mining_config: None,
+            template_distribution_config: None,
+        };
+
+        let mining_handler = NullSv2MiningServerHandler;
+
+        let cancellation_token = CancellationToken::new();
+
+        let mut sv2_server_service =
+            Sv2ServerService::new(sv2_server_config, mining_handler, cancellation_token).unwrap();
+
+        // a client that completed SetupConnection under the Job Declaration protocol
+        let (client, _client_rx) =
+            fixtures::connected_client(Protocol::JobDeclarationProtocol).await;
+        sv2_server_service.add_client(1, client);
+
+        let close_channel = CloseChannel {
+            channel_id: 1,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: AnyMessage::Mining(Mining::CloseChannel(close_channel)),
+            }))
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(Sv2ServerEventError::ProtocolNotSetUp {
+                protocol: Protocol::MiningProtocol
+            })
+        ));
+
+        // disconnect_on_protocol_violation is set, so the client is removed
+        assert_eq!(sv2_server_service.get_client_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_mining_message_without_client_id() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let mining_config = Sv2ServerServiceMiningConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(mining_config),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let cancellation_token = CancellationToken::new();
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            DummyMiningServerHandler,
+            cancellation_token,
+        )
+        .unwrap();
+
+        let close_channel = CloseChannel {
+            channel_id: 1,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+
+        // e.g.: an event sent by a sibling service, which is not bound to any client
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: None,
+                message: AnyMessage::Mining(Mining::CloseChannel(close_channel)),
+            }))
+            .await;
+
+        assert!(matches!(result, Err(Sv2ServerEventError::IdMustBeSome)));
+    }
+
+    #[tokio::test]
+    async fn null_mining_server_handler_does_not_panic() {
+        let mut mining_handler = NullSv2MiningServerHandler;
+
+        mining_handler.add_client(1, 0).await;
+        mining_handler.remove_client(1).await;
+
+        assert!(matches!(
+            mining_handler.start().await,
+            Err(Sv2ServerEventError::UnsupportedProtocol {
+                protocol: Protocol::MiningProtocol
+            })
+        ));
+
+        let close_channel = CloseChannel {
+            channel_id: 1,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+
+        assert!(matches!(
+            mining_handler.handle_close_channel(1, close_channel).await,
+            Err(Sv2ServerEventError::UnsupportedProtocol {
+                protocol: Protocol::MiningProtocol
+            })
+        ));
+    }
+
+    #[test]
+    fn sv2_server_service_null_handler_error() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let mining_config = Sv2ServerServiceMiningConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(mining_config),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        // Create a null mining handler
+        let mining_handler = NullSv2MiningServerHandler {};
+
+        let cancellation_token = CancellationToken::new();
+
+        // This should return an error because we're using a null handler for a supported protocol
+        let result =
+            super::Sv2ServerService::new(sv2_server_config, mining_handler, cancellation_token);
+
+        assert!(result.is_err());
+
+        if let Err(err) = result {
+            match err {
+                Sv2ServerServiceError::NullHandlerForSupportedProtocol { protocol } => {
+                    assert_eq!(protocol, Protocol::MiningProtocol);
+                }
+                _ => panic!("Expected NullHandlerForSupportedProtocol error"),
+            }
+        } else {
+            panic!("Expected error for null handler for supported protocol");
+        }
+    }
+
+    #[tokio::test]
+    async fn sv2_server_shutdown_with_no_clients() {
+        let server_port = get_available_port();
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+        let pub_key = Secp256k1PublicKey::try_from(
+            "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+        )
+        .expect("failed");
+        let priv_key = Secp256k1SecretKey::try_from(
+            "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+        )
+        .expect("failed");
+
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: server_addr,
+            pub_key,
+            priv_key,
+            cert_validity: 3600,
+        };
+
+        let job_declaration_config = Sv2ServerServiceJobDeclarationConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            job_declaration_config: Some(job_declaration_config),
+            mining_config: None,
+            template_distribution_config: None,
+            inactivity_limit: 1,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+        };
+
+        let mining_handler = NullSv2MiningServerHandler;
+
+        let cancellation_token = CancellationToken::new();
+
+        let sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            mining_handler,
+            cancellation_token.clone(),
+        )
+        .unwrap();
+
+        // Spawn the server start in a background task
+        let mut sv2_server_service_clone = sv2_server_service.clone();
+        tokio::spawn(async move {
+            sv2_server_service_clone.start().await.unwrap();
+        });
+
+        // Wait for server to be ready
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Verify initial state
+        assert_eq!(sv2_server_service.get_client_count(), 0);
+
+        // Shutdown the server
+        cancellation_token.cancel();
+
+        // Verify final state
+        assert_eq!(sv2_server_service.get_client_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn sv2_server_shutdown_with_active_clients() {
+        let server_port = get_available_port();
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+        let pub_key = Secp256k1PublicKey::try_from(
+            "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+        )
+        .expect("failed");
+        let priv_key = Secp256k1SecretKey::try_from(
+            "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+        )
+        .expect("failed");
+
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: server_addr,
+            pub_key,
+            priv_key,
+            cert_validity: 3600,
+        };
+
+        let job_declaration_config = Sv2ServerServiceJobDeclarationConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            job_declaration_config: Some(job_declaration_config),
+            mining_config: None,
+            template_distribution_config: None,
+            inactivity_limit: 10, // Set higher to prevent automatic cleanup
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+        };
+
+        let mining_handler = NullSv2MiningServerHandler;
+
+        let cancellation_token = CancellationToken::new();
+
+        let sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            mining_handler,
+            cancellation_token.clone(),
+        )
+        .unwrap();
+
+        // Spawn the server start in a background task
+        let mut sv2_server_service_clone = sv2_server_service.clone();
+        tokio::spawn(async move {
+            sv2_server_service_clone.start().await.unwrap();
+        });
+
+        // Wait for server to be ready
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Create and connect multiple clients
+        let client1 = Sv2EncryptedTcpClient::new(server_addr, None).await.unwrap();
+        let client2 = Sv2EncryptedTcpClient::new(server_addr, None).await.unwrap();
+
+        let setup_connection = SetupConnection {
+            protocol: Protocol::JobDeclarationProtocol,
+            min_version: 2,
             max_version: 2,
             flags: 0,
             endpoint_host: "".to_string().try_into().unwrap(),
@@ -1744,4 +3016,449 @@ mod tests {
         assert!(client1.io.recv_message().await.is_err());
         assert!(client2.io.recv_message().await.is_err());
     }
+
+    #[tokio::test]
+    async fn sv2_server_service_handles_reply_broadcast_and_disconnect_outcomes() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let mining_config = Sv2ServerServiceMiningConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(mining_config),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let cancellation_token = CancellationToken::new();
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            OutcomeMiningServerHandler,
+            cancellation_token,
+        )
+        .unwrap();
+
+        let (client_1, client_1_rx) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        let (client_2, client_2_rx) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        let (client_3, client_3_rx) =
+            fixtures::connected_client(Protocol::JobDeclarationProtocol).await;
+        sv2_server_service.add_client(1, client_1);
+        sv2_server_service.add_client(2, client_2);
+        sv2_server_service.add_client(3, client_3);
+
+        // Reply goes to the current client only
+        let open_standard_mining_channel = OpenStandardMiningChannel {
+            request_id: 1.into(),
+            user_identity: "user".to_string().try_into().unwrap(),
+            nominal_hash_rate: 1.0,
+            max_target: [0xff; 32].into(),
+        };
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: AnyMessage::Mining(Mining::OpenStandardMiningChannel(
+                    open_standard_mining_channel,
+                )),
+            }))
+            .await;
+        assert!(matches!(result, Ok(Sv2ServerOutcome::Ok)));
+        assert_eq!(client_1_rx.len(), 1);
+        assert_eq!(client_2_rx.len(), 0);
+
+        // Broadcast goes to every client of the protocol
+        let update_channel = UpdateChannel {
+            channel_id: 1,
+            nominal_hash_rate: 1.0,
+            maximum_target: [0xff; 32].into(),
+        };
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(2),
+                message: AnyMessage::Mining(Mining::UpdateChannel(update_channel)),
+            }))
+            .await;
+        assert!(matches!(result, Ok(Sv2ServerOutcome::Broadcasted(_))));
+        assert_eq!(client_1_rx.len(), 2);
+        assert_eq!(client_2_rx.len(), 1);
+        assert_eq!(client_3_rx.len(), 0);
+
+        // Disconnect removes the current client
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(2),
+                message: close_channel_message(),
+            }))
+            .await;
+        assert!(matches!(result, Ok(Sv2ServerOutcome::Ok)));
+        assert!(sv2_server_service.get_client(2).is_none());
+        assert_eq!(sv2_server_service.get_client_count(), 2);
+
+        // there is no current client to Reply to when handling a trigger
+        let new_template = NewTemplate {
+            template_id: 0,
+            future_template: false,
+            version: 0,
+            coinbase_tx_version: 0,
+            coinbase_prefix: B0255::Owned(vec![0]),
+            coinbase_tx_input_sequence: 0,
+            coinbase_tx_value_remaining: 0,
+            coinbase_tx_outputs_count: 0,
+            coinbase_tx_outputs: B064K::Owned(vec![0]),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(Vec::new()).unwrap(),
+        };
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::MiningTrigger(
+                MiningServerTrigger::NewTemplate(new_template),
+            ))
+            .await;
+        assert!(matches!(result, Err(Sv2ServerEventError::IdMustBeSome)));
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_broadcast_reports_per_client() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let mining_config = Sv2ServerServiceMiningConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(mining_config),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let cancellation_token = CancellationToken::new();
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            DummyMiningServerHandler,
+            cancellation_token,
+        )
+        .unwrap();
+
+        // a healthy mining client
+        let (client_1, client_1_rx) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        // a mining client whose connection is broken, but was not removed yet
+        let (client_2, _client_2_rx) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        client_2.io.shutdown();
+        // a client of another protocol
+        let (client_3, client_3_rx) =
+            fixtures::connected_client(Protocol::JobDeclarationProtocol).await;
+        // a client that did not complete SetupConnection
+        let (tx, client_4_rx) = async_channel::unbounded();
+        let client_4 =
+            Sv2ServerServiceClient::new(Sv2MessageIo::new(client_4_rx.clone(), tx, None));
+        sv2_server_service.add_client(1, client_1);
+        sv2_server_service.add_client(2, client_2);
+        sv2_server_service.add_client(3, client_3);
+        sv2_server_service.add_client(4, client_4);
+
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::Broadcast(Box::new(Sv2MessagesToClients {
+                filter: Sv2ClientFilter::Predicate(Arc::new(|connection| {
+                    connection.protocol == Protocol::MiningProtocol
+                })),
+                messages: vec![close_channel_message(), close_channel_message()],
+            })))
+            .await;
+
+        let Ok(Sv2ServerOutcome::Broadcasted(report)) = result else {
+            panic!("expected a broadcast report, got {result:?}");
+        };
+        assert_eq!(report.delivered, vec![1]);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, 2);
+
+        assert_eq!(client_1_rx.len(), 2);
+        assert_eq!(client_3_rx.len(), 0);
+        assert_eq!(client_4_rx.len(), 0);
+
+        // the broken client did not abort the broadcast, nor was it removed
+        assert_eq!(sv2_server_service.get_client_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_fires_scheduled_events() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                get_available_port(),
+            ),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let mining_config = Sv2ServerServiceMiningConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(mining_config),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let cancellation_token = CancellationToken::new();
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            DummyMiningServerHandler,
+            cancellation_token.clone(),
+        )
+        .unwrap();
+
+        let (client, client_rx) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        sv2_server_service.add_client(1, client);
+
+        let mut sv2_server_service_clone = sv2_server_service.clone();
+        tokio::spawn(async move {
+            sv2_server_service_clone.start().await.unwrap();
+        });
+
+        let handle = sv2_server_service.schedule(
+            Sv2ScheduledEvent::every(
+                Duration::from_millis(10),
+                Sv2ServerEvent::SendMessagesToClient(Box::new(Sv2MessagesToClient {
+                    client_id: 1,
+                    messages: vec![close_channel_message()],
+                })),
+            )
+            .unwrap(),
+        );
+
+        for _ in 0..3 {
+            let frame = tokio::time::timeout(Duration::from_secs(1), client_rx.recv()).await;
+            assert!(frame.unwrap().is_ok());
+        }
+
+        handle.cancel();
+        // drain a message that may have been sent right before cancellation
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        while client_rx.try_recv().is_ok() {}
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(client_rx.is_empty());
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn sv2_server_service_runs_events_through_layers() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let mining_config = Sv2ServerServiceMiningConfig {
+            supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(mining_config),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let cancellation_token = CancellationToken::new();
+
+        let mut sv2_server_service = Sv2ServerService::new(
+            sv2_server_config,
+            DummyMiningServerHandler,
+            cancellation_token,
+        )
+        .unwrap()
+        .with_layer(Sv2LoggingLayer)
+        // clients are never disconnected on purpose
+        .with_layer(Sv2FilterLayer::new(
+            |event: &Sv2ServerEvent<'static>| match event {
+                Sv2ServerEvent::DisconnectClient(_) => Err(Sv2ServerEventError::BadRouting),
+                _ => Ok(()),
+            },
+        ))
+        .with_layer(Sv2ServerRateLimitLayer::new(1).unwrap());
+
+        let (client, _client_rx) = fixtures::connected_client(Protocol::MiningProtocol).await;
+        sv2_server_service.add_client(1, client);
+
+        let close_channel = || {
+            Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: close_channel_message(),
+            })
+        };
+
+        let result = sv2_server_service.handle(close_channel()).await;
+        assert!(matches!(result, Ok(Sv2ServerOutcome::Ok)));
+
+        let result = sv2_server_service.handle(close_channel()).await;
+        assert!(matches!(
+            result,
+            Err(Sv2ServerEventError::RateLimited { client_id: 1 })
+        ));
+
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::DisconnectClient(1))
+            .await;
+        assert!(matches!(result, Err(Sv2ServerEventError::BadRouting)));
+        assert_eq!(sv2_server_service.get_client_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn sv2_server_submit_solution_reaches_template_distribution_sibling() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let sv2_server_config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            inactivity_limit: 10,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(Sv2ServerServiceMiningConfig {
+                supported_flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+            }),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        // the Template Distribution client is only an inbox on the bus
+        let sibling_bus = Sv2SiblingBus::new(16).unwrap();
+        let server_sibling_io = sibling_bus.register_server("mining_server").unwrap();
+        let client_sibling_io = sibling_bus.register_client("tp_client").unwrap();
+
+        let blocks_found = Arc::new(Mutex::new(Vec::new()));
+        let found = blocks_found.clone();
+        let mut sv2_server_service = Sv2ServerService::new_from_sibling_io(
+            sv2_server_config,
+            DummyMiningServerHandler,
+            server_sibling_io,
+            CancellationToken::new(),
+        )
+        .unwrap()
+        .with_solution_pipeline(Sv2SolutionPipeline::new("tp_client").on_block_found(
+            move |found_block: &Sv2FoundBlock| {
+                found.lock().unwrap().push(found_block.clone());
+            },
+        ));
+
+        sv2_server_service
+            .handle(Sv2ServerEvent::MiningTrigger(
+                MiningServerTrigger::NewTemplate(fixtures::new_template(7, false)),
+            ))
+            .await
+            .unwrap();
+
+        let solution = Sv2BlockSolution {
+            client_id: 1,
+            channel_id: 2,
+            template_id: 7,
+            version: 0x20000000,
+            header_timestamp: 1_296_688_603,
+            header_nonce: 42,
+            coinbase_tx: vec![1, 2, 3],
+        };
+        let result = sv2_server_service
+            .handle(Sv2ServerEvent::SubmitSolution(Box::new(solution)))
+            .await;
+        assert!(matches!(result, Ok(Sv2ServerOutcome::Ok)));
+
+        // the app was notified, with the template the block was mined on
+        {
+            let blocks_found = blocks_found.lock().unwrap();
+            assert_eq!(blocks_found.len(), 1);
+            assert_eq!(
+                blocks_found[0]
+                    .template
+                    .as_ref()
+                    .unwrap()
+                    .template
+                    .template_id,
+                7
+            );
+        }
+
+        // and the Template Distribution client got a SubmitSolution trigger
+        let event = client_sibling_io.recv().await.unwrap();
+        match *event {
+            Sv2ClientEvent::TemplateDistributionTrigger(
+                TemplateDistributionClientTrigger::SubmitSolution(submit_solution),
+            ) => {
+                assert_eq!(submit_solution.template_id, 7);
+                assert_eq!(submit_solution.header_nonce, 42);
+                assert_eq!(submit_solution.coinbase_tx.inner_as_ref(), &[1, 2, 3]);
+            }
+            _ => panic!("expected a SubmitSolution trigger"),
+        }
+    }
 }
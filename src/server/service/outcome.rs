@@ -1,8 +1,35 @@
+use crate::server::service::client::Sv2BroadcastReport;
 use crate::server::service::event::Sv2ServerEvent;
+use crate::timer::{Sv2ScheduledEvent, Sv2TimerHandle};
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
+use stratum_common::roles_logic_sv2::parsers::AnyMessage;
 
 /// The outcome type for [`crate::server::service::Sv2ServerService`].
+///
+/// `Reply` and `Disconnect` target the "current client", i.e.: the client that sent the message being handled.
+/// Returning them while handling an event that did not come from a client (e.g.: a trigger) results in
+/// [`crate::server::service::event::Sv2ServerEventError::IdMustBeSome`].
 #[derive(Debug, Clone)]
 pub enum Sv2ServerOutcome<'a> {
     TriggerNewEvent(Box<Sv2ServerEvent<'a>>),
+    /// Send an ordered sequence of Sv2 messages to the current client.
+    Reply(Vec<AnyMessage<'a>>),
+    /// Send an ordered sequence of Sv2 messages to every client that set up a connection under `protocol`.
+    Broadcast {
+        protocol: Protocol,
+        messages: Vec<AnyMessage<'a>>,
+    },
+    /// Shut down the connection to the current client and remove it from memory.
+    Disconnect {
+        reason: String,
+    },
+    /// The event was handled, and deliberately produced no response.
+    NoReply,
+    /// The result of a [`Sv2ServerEvent::Broadcast`].
+    Broadcasted(Sv2BroadcastReport),
+    /// Fire an event later, or periodically, until cancelled via its [`Sv2TimerHandle`] or the service shuts down.
+    Schedule(Box<Sv2ScheduledEvent<Sv2ServerEvent<'a>>>),
+    /// The timer was started.
+    Scheduled(Sv2TimerHandle),
     Ok,
 }
@@ -0,0 +1,371 @@
+//! Dispatching block solutions found by mining clients to the Template Provider.
+//!
+//! When a share meets the network target, the mining handler reports it as a [`Sv2BlockSolution`], via
+//! [`crate::server::service::event::Sv2ServerEvent::SubmitSolution`] (typically next to its `SubmitShares.Success`):
+//!
+//! ```ignore
+//! Ok(Sv2ServerOutcome::TriggerNewEvent(Box::new(Sv2ServerEvent::MultipleEvents(Box::new(vec![
+//!     Sv2ServerEvent::SendMessagesToClient(Box::new(submit_shares_success)),
+//!     Sv2ServerEvent::SubmitSolution(Box::new(solution)),
+//! ])))))
+//! ```
+//!
+//! A [`crate::server::service::Sv2ServerService`] set up with a [`Sv2SolutionPipeline`] then:
+//! - looks up the template the solution was mined on, among the templates it relayed to the mining handler
+//!   via [`crate::server::service::subprotocols::mining::trigger::MiningServerTrigger`]
+//!   (or in the store of the Template Distribution client, see [`Sv2SolutionPipeline::with_template_store`])
+//! - notifies the app, see [`Sv2SolutionPipeline::on_block_found`]
+//! - optionally assembles the full block, writes it to disk and hands it to a [`Sv2BlockSubmitter`],
+//!   see [`crate::server::service::block`]
+//! - sends `SubmitSolution` to the Template Distribution client registered on the [`crate::sibling::Sv2SiblingBus`]
+//!
+//! `PushSolution` to a Job Declaration Server is not dispatched yet, since [`crate::client::service::Sv2ClientService`]
+//! does not support the Job Declaration protocol.
+
+use crate::client::service::event::Sv2ClientEvent;
+use crate::client::service::subprotocols::template_distribution::store::{
+    Sv2StoredTemplate, Sv2TemplateStore, Sv2TemplateStoreConfig,
+};
+use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
+use crate::server::service::block::{assemble_block, Sv2BlockSubmitter};
+use crate::server::service::event::Sv2ServerEventError;
+use bitcoin::consensus::encode::serialize_hex;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use stratum_common::roles_logic_sv2::template_distribution_sv2::{
+    NewTemplate, SetNewPrevHash, SubmitSolution,
+};
+use tracing::{error, info, warn};
+
+/// How many relayed templates are kept to look solutions up.
+const MAX_TEMPLATES: usize = 32;
+
+/// A share that meets the network target, as reported by a mining handler.
+#[derive(Debug, Clone)]
+pub struct Sv2BlockSolution {
+    /// The client that submitted the share
+    pub client_id: u32,
+    pub channel_id: u32,
+    /// The template the job of the share was built from
+    pub template_id: u64,
+    pub version: u32,
+    pub header_timestamp: u32,
+    pub header_nonce: u32,
+    /// The full serialized coinbase transaction of the block
+    pub coinbase_tx: Vec<u8>,
+}
+
+/// A block found by a mining client, as handed to [`Sv2SolutionPipeline::on_block_found`].
+#[derive(Debug, Clone)]
+pub struct Sv2FoundBlock {
+    pub solution: Sv2BlockSolution,
+    /// The template the block was mined on.
+    ///
+    /// `None` if it was not relayed to the mining handler through this service, or it was evicted.
+    pub template: Option<Sv2StoredTemplate>,
+}
+
+impl Sv2FoundBlock {
+    /// The `SubmitSolution` for the Template Provider.
+    pub fn submit_solution(&self) -> Result<SubmitSolution<'static>, Sv2ServerEventError> {
+        let coinbase_tx = self.solution.coinbase_tx.clone().try_into().map_err(|_| {
+            Sv2ServerEventError::InvalidSolution(format!(
+                "coinbase_tx of {} bytes does not fit in a SubmitSolution",
+                self.solution.coinbase_tx.len()
+            ))
+        })?;
+        Ok(SubmitSolution {
+            template_id: self.solution.template_id,
+            version: self.solution.version,
+            header_timestamp: self.solution.header_timestamp,
+            header_nonce: self.solution.header_nonce,
+            coinbase_tx,
+        })
+    }
+}
+
+/// Routes the [`Sv2BlockSolution`]s of a [`crate::server::service::Sv2ServerService`] to a sibling Template Distribution client.
+///
+/// Set with [`crate::server::service::Sv2ServerService::with_solution_pipeline`].
+#[derive(Clone)]
+pub struct Sv2SolutionPipeline {
+    template_distribution_sibling: String,
+    templates: Sv2TemplateStore,
+    // false if the templates are fed by the Template Distribution client handler, see `with_template_store`
+    records_templates: bool,
+    on_block_found: Option<Arc<dyn Fn(&Sv2FoundBlock) + Send + Sync>>,
+    block_dir: Option<PathBuf>,
+    block_submitter: Option<Arc<dyn Sv2BlockSubmitter>>,
+}
+
+impl fmt::Debug for Sv2SolutionPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sv2SolutionPipeline")
+            .field(
+                "template_distribution_sibling",
+                &self.template_distribution_sibling,
+            )
+            .field("templates", &self.templates)
+            .field("records_templates", &self.records_templates)
+            .field("on_block_found", &self.on_block_found.is_some())
+            .field("block_dir", &self.block_dir)
+            .field("block_submitter", &self.block_submitter.is_some())
+            .finish()
+    }
+}
+
+impl Sv2SolutionPipeline {
+    /// Creates a new [`Sv2SolutionPipeline`], sending solutions to the client service registered as
+    /// `template_distribution_sibling` on the [`crate::sibling::Sv2SiblingBus`].
+    pub fn new(template_distribution_sibling: impl Into<String>) -> Self {
+        Self {
+            template_distribution_sibling: template_distribution_sibling.into(),
+            templates: Sv2TemplateStore::new(Sv2TemplateStoreConfig {
+                max_templates: MAX_TEMPLATES,
+                request_transaction_data: false,
+            }),
+            records_templates: true,
+            on_block_found: None,
+            block_dir: None,
+            block_submitter: None,
+        }
+    }
+
+    /// Looks templates up in `store`, instead of recording the ones relayed to the mining handler.
+    ///
+    /// `store` should be the one the Template Distribution client handler feeds, since it also holds the
+    /// transaction data needed to assemble full blocks.
+    pub fn with_template_store(mut self, store: Sv2TemplateStore) -> Self {
+        self.templates = store;
+        self.records_templates = false;
+        self
+    }
+
+    /// Writes every found block that can be assembled to `dir`, as `<block hash>.hex`.
+    ///
+    /// The directory must exist.
+    pub fn with_block_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.block_dir = Some(dir.into());
+        self
+    }
+
+    /// Hands every found block that can be assembled to `block_submitter`, on its own task.
+    pub fn with_block_submitter(mut self, block_submitter: impl Sv2BlockSubmitter) -> Self {
+        self.block_submitter = Some(Arc::new(block_submitter));
+        self
+    }
+
+    /// Calls `hook` for every block found, before its `SubmitSolution` is sent.
+    ///
+    /// The hook runs inside the event handling of the service, so it should return quickly.
+    pub fn on_block_found(mut self, hook: impl Fn(&Sv2FoundBlock) + Send + Sync + 'static) -> Self {
+        self.on_block_found = Some(Arc::new(hook));
+        self
+    }
+
+    /// The name of the Template Distribution client on the [`crate::sibling::Sv2SiblingBus`].
+    pub fn template_distribution_sibling(&self) -> &str {
+        &self.template_distribution_sibling
+    }
+
+    pub(crate) fn record_new_template(&self, template: NewTemplate<'static>) {
+        if self.records_templates {
+            self.templates.on_new_template(template);
+        }
+    }
+
+    pub(crate) fn record_set_new_prev_hash(&self, prev_hash: SetNewPrevHash<'static>) {
+        if self.records_templates {
+            self.templates.on_set_new_prev_hash(prev_hash);
+        }
+    }
+
+    // Looks up the template of `solution` and notifies the app.
+    pub(crate) fn found_block(&self, solution: Sv2BlockSolution) -> Sv2FoundBlock {
+        let template = self.templates.get(solution.template_id);
+        if template.is_none() {
+            warn!(
+                "Block found on unknown template_id {}, submitting it anyway",
+                solution.template_id
+            );
+        }
+
+        let found_block = Sv2FoundBlock { solution, template };
+        if let Some(hook) = &self.on_block_found {
+            hook(&found_block);
+        }
+        found_block
+    }
+
+    // Writes the block to disk and hands it to the block submitter, if configured to, on their own tasks.
+    //
+    // This is a safety net behind SubmitSolution, so it is called once SubmitSolution is sent, and failures are only logged.
+    pub(crate) fn secure_block(&self, found_block: &Sv2FoundBlock) {
+        if self.block_dir.is_none() && self.block_submitter.is_none() {
+            return;
+        }
+
+        let block = match assemble_block(found_block) {
+            Ok(block) => block,
+            Err(e) => {
+                error!(
+                    "Failed to assemble the block of template_id {}: {:?}",
+                    found_block.solution.template_id, e
+                );
+                return;
+            }
+        };
+        let block_hash = block.block_hash();
+
+        if let Some(block_dir) = &self.block_dir {
+            let path = block_dir.join(format!("{block_hash}.hex"));
+            let hex = serialize_hex(&block);
+            tokio::spawn(async move {
+                match tokio::fs::write(&path, hex).await {
+                    Ok(()) => info!("Block {} written to {}", block_hash, path.display()),
+                    Err(e) => error!(
+                        "Failed to write block {} to {}: {}",
+                        block_hash,
+                        path.display(),
+                        e
+                    ),
+                }
+            });
+        }
+
+        if let Some(block_submitter) = self.block_submitter.clone() {
+            tokio::spawn(async move {
+                match block_submitter.submit_block(block).await {
+                    Ok(()) => info!("Block {} submitted", block_hash),
+                    Err(e) => error!("Failed to submit block {}: {}", block_hash, e),
+                }
+            });
+        }
+    }
+
+    // The event for the Template Distribution client.
+    pub(crate) fn submit_solution_event(
+        found_block: &Sv2FoundBlock,
+    ) -> Result<Sv2ClientEvent<'static>, Sv2ServerEventError> {
+        Ok(Sv2ClientEvent::TemplateDistributionTrigger(
+            TemplateDistributionClientTrigger::SubmitSolution(found_block.submit_solution()?),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2BlockSolution, Sv2SolutionPipeline};
+    use crate::client::service::subprotocols::template_distribution::store::{
+        Sv2TemplateStore, Sv2TemplateStoreConfig,
+    };
+    use crate::testing::block_submitter::Sv2MockBlockSubmitter;
+    use crate::testing::fixtures::{new_template, set_new_prev_hash};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::consensus::encode::serialize_hex;
+    use bitcoin::consensus::serialize;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn solution(template_id: u64, coinbase_tx: Vec<u8>) -> Sv2BlockSolution {
+        Sv2BlockSolution {
+            client_id: 1,
+            channel_id: 2,
+            template_id,
+            version: 0x20000000,
+            header_timestamp: 1_296_688_603,
+            header_nonce: 42,
+            coinbase_tx,
+        }
+    }
+
+    #[test]
+    fn found_block_looks_up_template_and_notifies_hook() {
+        let blocks_found = Arc::new(AtomicU32::new(0));
+        let counter = blocks_found.clone();
+        let pipeline = Sv2SolutionPipeline::new("tp_client").on_block_found(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        pipeline.record_new_template(new_template(7, true));
+        pipeline.record_set_new_prev_hash(set_new_prev_hash(7));
+
+        let found_block = pipeline.found_block(solution(7, vec![1, 2, 3]));
+        let template = found_block.template.as_ref().unwrap();
+        assert_eq!(template.template.template_id, 7);
+        assert!(template.prev_hash.is_some());
+
+        let submit_solution = found_block.submit_solution().unwrap();
+        assert_eq!(submit_solution.template_id, 7);
+        assert_eq!(submit_solution.header_nonce, 42);
+        assert_eq!(submit_solution.coinbase_tx.inner_as_ref(), &[1, 2, 3]);
+
+        // unknown templates are still reported
+        let found_block = pipeline.found_block(solution(8, vec![]));
+        assert!(found_block.template.is_none());
+        assert_eq!(blocks_found.load(Ordering::SeqCst), 2);
+
+        // a coinbase that does not fit in a B064K is rejected
+        let found_block = pipeline.found_block(solution(7, vec![0; 70_000]));
+        assert!(found_block.submit_solution().is_err());
+    }
+
+    #[tokio::test]
+    async fn found_block_is_written_to_disk_and_submitted() {
+        let block_dir = std::env::temp_dir().join(format!("sv2-blocks-{}", std::process::id()));
+        std::fs::create_dir_all(&block_dir).unwrap();
+
+        // the templates come from the store of the Template Distribution client handler
+        let store = Sv2TemplateStore::new(Sv2TemplateStoreConfig {
+            max_templates: 8,
+            request_transaction_data: false,
+        });
+        store.on_new_template(new_template(7, true));
+        store.on_set_new_prev_hash(set_new_prev_hash(7));
+
+        let block_submitter = Sv2MockBlockSubmitter::new();
+        let pipeline = Sv2SolutionPipeline::new("tp_client")
+            .with_template_store(store.clone())
+            .with_block_dir(&block_dir)
+            .with_block_submitter(block_submitter.clone());
+
+        // relayed templates are left to the Template Distribution client handler
+        pipeline.record_new_template(new_template(8, true));
+        assert_eq!(store.template_ids(), vec![7]);
+
+        let coinbase = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![0x51]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(5_000_000_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let found_block = pipeline.found_block(solution(7, serialize(&coinbase)));
+        pipeline.secure_block(&found_block);
+
+        // the disk write and the submitter run on their own tasks
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let blocks = block_submitter.blocks();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].txdata, vec![coinbase]);
+
+        let path = block_dir.join(format!("{}.hex", blocks[0].block_hash()));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            serialize_hex(&blocks[0])
+        );
+
+        std::fs::remove_dir_all(&block_dir).unwrap();
+    }
+}
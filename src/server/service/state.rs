@@ -0,0 +1,171 @@
+//! State containers for subprotocol handlers of a [`crate::server::service::Sv2ServerService`].
+//!
+//! Handlers are cloned into every per-client task of the service, so each clone only mutates its own copy.
+//! [`Sv2ServerHandlerState`] is a cheaply cloneable container that gives handlers:
+//! - exclusive access to the state of one client at a time
+//! - shared access to the global state (with exclusive access for the rare global updates)
+//!
+//! Different clients can be locked at the same time, so messages from different clients are processed in parallel.
+//!
+//! The container is opt-in, and nothing is locked unless the handler asks for it: events that don't come from
+//! the client itself (e.g.: a new template, or a timer) run concurrently with its messages, so they must take
+//! the client lock too before touching its state.
+//!
+//! To avoid deadlocks, handlers should acquire locks in a consistent order:
+//! global state first, then client state, and never hold two client locks at the same time.
+
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Global state `G` plus per-client state `C`, indexed by `client_id`.
+///
+/// Clones share the same underlying state.
+#[derive(Debug)]
+pub struct Sv2ServerHandlerState<G, C> {
+    global: Arc<RwLock<G>>,
+    clients: Arc<DashMap<u32, Arc<Mutex<C>>>>,
+}
+
+impl<G, C> Clone for Sv2ServerHandlerState<G, C> {
+    fn clone(&self) -> Self {
+        Self {
+            global: self.global.clone(),
+            clients: self.clients.clone(),
+        }
+    }
+}
+
+impl<G: Default, C> Default for Sv2ServerHandlerState<G, C> {
+    fn default() -> Self {
+        Self::new(G::default())
+    }
+}
+
+impl<G, C> Sv2ServerHandlerState<G, C> {
+    /// Creates a new [`Sv2ServerHandlerState`] with the given global state and no clients.
+    pub fn new(global: G) -> Self {
+        Self {
+            global: Arc::new(RwLock::new(global)),
+            clients: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Shared access to the global state.
+    ///
+    /// Many readers can hold this at the same time.
+    pub async fn global(&self) -> RwLockReadGuard<'_, G> {
+        self.global.read().await
+    }
+
+    /// Exclusive access to the global state.
+    ///
+    /// This blocks every reader, so it should be reserved for infrequent updates (e.g.: a new template).
+    pub async fn global_mut(&self) -> RwLockWriteGuard<'_, G> {
+        self.global.write().await
+    }
+
+    /// Adds the state of a new client, replacing any previous state under the same `client_id`.
+    pub fn add_client(&self, client_id: u32, state: C) {
+        self.clients.insert(client_id, Arc::new(Mutex::new(state)));
+    }
+
+    /// Removes the state of a client.
+    ///
+    /// Returns `false` if there was no state for this `client_id`.
+    pub fn remove_client(&self, client_id: u32) -> bool {
+        self.clients.remove(&client_id).is_some()
+    }
+
+    /// Exclusive access to the state of a client.
+    ///
+    /// Returns `None` if there is no state for this `client_id`.
+    ///
+    /// The returned guard does not borrow `self`, so it can be held across `.await` points.
+    pub async fn client(&self, client_id: u32) -> Option<OwnedMutexGuard<C>> {
+        // clone the Arc so that the DashMap shard is not locked while waiting for the client lock
+        let client = self.clients.get(&client_id).map(|entry| entry.clone())?;
+        Some(client.lock_owned().await)
+    }
+
+    /// Returns whether there is state for this `client_id`.
+    pub fn has_client(&self, client_id: u32) -> bool {
+        self.clients.contains_key(&client_id)
+    }
+
+    /// Returns the ids of all clients with state.
+    ///
+    /// Useful for global events (e.g.: a new template) that must update every client, one at a time.
+    pub fn client_ids(&self) -> Vec<u32> {
+        self.clients.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Returns how many clients have state.
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sv2ServerHandlerState;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn client_state_access_is_exclusive() {
+        let state: Sv2ServerHandlerState<(), u64> = Sv2ServerHandlerState::default();
+        state.add_client(1, 0);
+
+        let mut tasks = Vec::new();
+        for _ in 0..100 {
+            let state = state.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut client = state.client(1).await.unwrap();
+                let value = *client;
+                // yield while holding the lock, so that a data race would lose updates
+                tokio::task::yield_now().await;
+                *client = value + 1;
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*state.client(1).await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn different_clients_can_be_locked_concurrently() {
+        let state: Sv2ServerHandlerState<(), u64> = Sv2ServerHandlerState::default();
+        state.add_client(1, 0);
+        state.add_client(2, 0);
+
+        let _client_1 = state.client(1).await.unwrap();
+
+        // locking client 2 must not wait for client 1
+        let client_2 = tokio::time::timeout(Duration::from_millis(100), state.client(2)).await;
+        assert!(client_2.is_ok());
+    }
+
+    #[tokio::test]
+    async fn global_state_and_client_removal() {
+        let state: Sv2ServerHandlerState<u32, u64> = Sv2ServerHandlerState::new(7);
+        state.add_client(1, 0);
+
+        {
+            let reader_1 = state.global().await;
+            let reader_2 = state.global().await;
+            assert_eq!(*reader_1 + *reader_2, 14);
+        }
+
+        *state.global_mut().await = 8;
+        assert_eq!(*state.global().await, 8);
+
+        assert_eq!(state.client_ids(), vec![1]);
+        assert!(state.remove_client(1));
+        assert!(!state.remove_client(1));
+        assert!(state.client(1).await.is_none());
+        assert_eq!(state.client_count(), 0);
+    }
+}
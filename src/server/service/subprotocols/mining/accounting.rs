@@ -0,0 +1,990 @@
+//! Crediting valid shares to the users of a pool.
+//!
+//! [`Sv2ShareAccounting`] is fed every valid share via [`Sv2ShareAccounting::record_share`], from the
+//! `handle_submit_shares_*` methods of a [`crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler`].
+//! Shares are weighted by the difficulty of the channel they were mined on (see [`difficulty_from_target`]), and credited
+//! according to the [`Sv2PayoutScheme`]:
+//! - [`Sv2PayoutScheme::Pps`]: every share adds to the balance of its user right away
+//! - [`Sv2PayoutScheme::Pplns`]: the last shares are kept in a window, which the reward of a found block is split over
+//!
+//! Shares are persisted by a [`Sv2ShareAccountingStore`] before being credited, and replayed from it on [`Sv2ShareAccounting::load`],
+//! so that balances and the window survive restarts. [`Sv2MemoryShareAccountingStore`] and [`Sv2FileShareAccountingStore`]
+//! are provided, and the trait can be implemented for other backends (e.g.: SQLite).
+//!
+//! PPS balances are paid out with [`Sv2ShareAccounting::debit_pps_balance`], which is persisted the same way.
+//! The store grows with every share and debit: call [`Sv2ShareAccounting::compact`] every now and then (e.g.: after paying
+//! users out) to replace it with a snapshot of the current totals and window.
+//!
+//! ```ignore
+//! async fn handle_submit_shares_standard(
+//!     &self,
+//!     client_id: u32,
+//!     m: SubmitSharesStandard,
+//! ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+//!     // once the share is validated against the channel target
+//!     self.accounting
+//!         .record_share(channel.user_identity.clone(), m.channel_id, difficulty_from_target(&channel.target))
+//!         .await
+//!         .map_err(|e| Sv2ServerEventError::MiningHandlerError(format!("{e:?}")))?;
+//!     ...
+//! }
+//! ```
+
+use crate::layer::Sv2BoxFuture;
+use bitcoin::hex::{DisplayHex, FromHex};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+/// The difficulty of shares mined against `target`, a little-endian U256 as in Sv2 messages.
+///
+/// A zero target has an infinite difficulty, which [`Sv2ShareAccounting::record_share`] rejects.
+pub fn difficulty_from_target(target: &[u8; 32]) -> f64 {
+    let target = target
+        .iter()
+        .rev()
+        .fold(0.0, |value, byte| value * 256.0 + *byte as f64);
+    // the difficulty 1 target, as used by pools: 0xffff * 2^208
+    65535.0 * 2f64.powi(208) / target
+}
+
+/// Why a share could not be accounted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sv2ShareAccountingError {
+    /// The difficulty of a share must be finite and positive.
+    InvalidDifficulty(f64),
+    /// The parameter of a [`Sv2PayoutScheme`] must be finite and positive.
+    InvalidPayoutScheme(Sv2PayoutScheme),
+    /// A debit must be of at least 1 satoshi.
+    InvalidAmount(u64),
+    /// The user has less than the debit in its PPS balance, in satoshis.
+    InsufficientBalance { available: u64 },
+    /// The [`Sv2ShareAccountingStore`] failed, with this description.
+    Store(String),
+    /// The operation only makes sense with another [`Sv2PayoutScheme`].
+    WrongPayoutScheme,
+}
+
+/// How shares are credited to users.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sv2PayoutScheme {
+    /// Pay Per Share: every share credits `sats_per_difficulty * difficulty` to its user.
+    ///
+    /// e.g.: `block_reward / network_difficulty`, minus the pool fee.
+    Pps { sats_per_difficulty: f64 },
+    /// Pay Per Last N Shares: block rewards are split over the most recent shares, whose difficulty adds up to `window_difficulty`.
+    Pplns { window_difficulty: f64 },
+}
+
+impl Sv2PayoutScheme {
+    fn is_valid(&self) -> bool {
+        let (Sv2PayoutScheme::Pps {
+            sats_per_difficulty: parameter,
+        }
+        | Sv2PayoutScheme::Pplns {
+            window_difficulty: parameter,
+        }) = self;
+        parameter.is_finite() && *parameter > 0.0
+    }
+}
+
+/// A valid share, credited to `user_identity`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sv2Share {
+    /// The `user_identity` of the channel, usually `<user>.<worker>`
+    pub user_identity: String,
+    pub channel_id: u32,
+    pub difficulty: f64,
+    /// When the share was recorded, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// What a user has been credited with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sv2UserShareTotals {
+    pub shares: u64,
+    /// The sum of the difficulty of the shares
+    pub difficulty: f64,
+    /// What the shares are worth with [`Sv2PayoutScheme::Pps`], in whole satoshis, minus what was debited
+    /// (always 0 with [`Sv2PayoutScheme::Pplns`])
+    pub pps_balance: u64,
+}
+
+/// An entry of the log kept by a [`Sv2ShareAccountingStore`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sv2ShareAccountingEntry {
+    /// A share, credited to its user.
+    Share(Sv2Share),
+    /// `amount` satoshis paid out of the PPS balance of `user_identity`.
+    PpsDebit {
+        user_identity: String,
+        amount: u64,
+        timestamp: u64,
+    },
+    /// The totals of `user_identity` at the time of a [`Sv2ShareAccounting::compact`], replacing whatever came before.
+    Totals {
+        user_identity: String,
+        shares: u64,
+        difficulty: f64,
+        /// The satoshis debited from the PPS balance so far
+        pps_debited: u64,
+    },
+}
+
+/// Where shares are persisted, as an append-only log that can be compacted.
+///
+/// Dyn-compatible: [`Sv2ShareAccounting`] only ever sees an `Arc<dyn Sv2ShareAccountingStore>`.
+pub trait Sv2ShareAccountingStore: Send + Sync + 'static {
+    /// Persists `entry`, after every entry appended before it.
+    fn append(&self, entry: Sv2ShareAccountingEntry) -> Sv2BoxFuture<'_, Result<(), String>>;
+
+    /// Returns every entry appended so far, oldest first.
+    fn load(&self) -> Sv2BoxFuture<'_, Result<Vec<Sv2ShareAccountingEntry>, String>>;
+
+    /// Replaces every entry with `entries`, which add up to the same totals and window.
+    ///
+    /// Either every entry is replaced, or none is.
+    fn compact(
+        &self,
+        entries: Vec<Sv2ShareAccountingEntry>,
+    ) -> Sv2BoxFuture<'_, Result<(), String>>;
+}
+
+/// A [`Sv2ShareAccountingStore`] in memory, whose shares are lost on restart.
+///
+/// Clones share the same log.
+#[derive(Debug, Clone, Default)]
+pub struct Sv2MemoryShareAccountingStore {
+    entries: Arc<Mutex<Vec<Sv2ShareAccountingEntry>>>,
+}
+
+impl Sv2MemoryShareAccountingStore {
+    /// Creates a new, empty [`Sv2MemoryShareAccountingStore`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Vec<Sv2ShareAccountingEntry>> {
+        self.entries
+            .lock()
+            .expect("share accounting store lock poisoned")
+    }
+}
+
+impl Sv2ShareAccountingStore for Sv2MemoryShareAccountingStore {
+    fn append(&self, entry: Sv2ShareAccountingEntry) -> Sv2BoxFuture<'_, Result<(), String>> {
+        self.entries().push(entry);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load(&self) -> Sv2BoxFuture<'_, Result<Vec<Sv2ShareAccountingEntry>, String>> {
+        let entries = self.entries().clone();
+        Box::pin(async move { Ok(entries) })
+    }
+
+    fn compact(
+        &self,
+        entries: Vec<Sv2ShareAccountingEntry>,
+    ) -> Sv2BoxFuture<'_, Result<(), String>> {
+        *self.entries() = entries;
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A [`Sv2ShareAccountingStore`] appending entries to a local file, one per line.
+///
+/// Lines are, with user identities hex encoded:
+/// - shares: `<timestamp> <channel_id> <difficulty> <user_identity>`
+/// - debits: `debit <timestamp> <amount> <user_identity>`
+/// - totals: `totals <shares> <difficulty> <pps_debited> <user_identity>`
+///
+/// Every entry is synced to disk before `append` returns. A crash in the middle of an append leaves a torn last line,
+/// which the next `load` drops (and truncates away), while a malformed line anywhere else fails it.
+///
+/// Compaction writes `<path>.tmp`, then renames it over `path`.
+#[derive(Debug)]
+pub struct Sv2FileShareAccountingStore {
+    path: PathBuf,
+    // appends and compactions are serialized, so that lines never interleave
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Sv2FileShareAccountingStore {
+    /// Creates a new [`Sv2FileShareAccountingStore`] on `path`, which is created on the first share if missing.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+fn format_entry(entry: &Sv2ShareAccountingEntry) -> String {
+    match entry {
+        Sv2ShareAccountingEntry::Share(share) => format!(
+            "{} {} {} {}\n",
+            share.timestamp,
+            share.channel_id,
+            share.difficulty,
+            share.user_identity.as_bytes().to_lower_hex_string()
+        ),
+        Sv2ShareAccountingEntry::PpsDebit {
+            user_identity,
+            amount,
+            timestamp,
+        } => format!(
+            "debit {} {} {}\n",
+            timestamp,
+            amount,
+            user_identity.as_bytes().to_lower_hex_string()
+        ),
+        Sv2ShareAccountingEntry::Totals {
+            user_identity,
+            shares,
+            difficulty,
+            pps_debited,
+        } => format!(
+            "totals {} {} {} {}\n",
+            shares,
+            difficulty,
+            pps_debited,
+            user_identity.as_bytes().to_lower_hex_string()
+        ),
+    }
+}
+
+fn parse_entry(line: &str) -> Result<Sv2ShareAccountingEntry, String> {
+    fn field<T: std::str::FromStr>(value: &str, name: &str, line: &str) -> Result<T, String> {
+        value
+            .parse()
+            .map_err(|_| format!("malformed {name}: {line}"))
+    }
+    let user = |user_identity: &str| {
+        Vec::<u8>::from_hex(user_identity)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| format!("malformed user_identity: {line}"))
+    };
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["debit", timestamp, amount, user_identity] => Ok(Sv2ShareAccountingEntry::PpsDebit {
+            user_identity: user(user_identity)?,
+            amount: field(amount, "amount", line)?,
+            timestamp: field(timestamp, "timestamp", line)?,
+        }),
+        ["totals", shares, difficulty, pps_debited, user_identity] => {
+            Ok(Sv2ShareAccountingEntry::Totals {
+                user_identity: user(user_identity)?,
+                shares: field(shares, "shares", line)?,
+                difficulty: field(difficulty, "difficulty", line)?,
+                pps_debited: field(pps_debited, "pps_debited", line)?,
+            })
+        }
+        [timestamp, channel_id, difficulty, user_identity] => {
+            Ok(Sv2ShareAccountingEntry::Share(Sv2Share {
+                user_identity: user(user_identity)?,
+                channel_id: field(channel_id, "channel_id", line)?,
+                difficulty: field(difficulty, "difficulty", line)?,
+                timestamp: field(timestamp, "timestamp", line)?,
+            }))
+        }
+        _ => Err(format!("malformed entry: {line}")),
+    }
+}
+
+impl Sv2ShareAccountingStore for Sv2FileShareAccountingStore {
+    fn append(&self, entry: Sv2ShareAccountingEntry) -> Sv2BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let line = format_entry(&entry);
+            let _guard = self.lock.lock().await;
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|e| format!("failed to open {}: {e}", self.path.display()))?;
+            file.write_all(line.as_bytes())
+                .await
+                .map_err(|e| format!("failed to write to {}: {e}", self.path.display()))?;
+            file.flush()
+                .await
+                .map_err(|e| format!("failed to write to {}: {e}", self.path.display()))?;
+            file.sync_data()
+                .await
+                .map_err(|e| format!("failed to sync {}: {e}", self.path.display()))
+        })
+    }
+
+    fn load(&self) -> Sv2BoxFuture<'_, Result<Vec<Sv2ShareAccountingEntry>, String>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            let content = match tokio::fs::read_to_string(&self.path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(format!("failed to read {}: {e}", self.path.display())),
+            };
+            // every complete line ends with a newline, anything after the last one is a torn append
+            let complete = content.rfind('\n').map_or(0, |end| end + 1);
+            let (lines, torn) = content.split_at(complete);
+            if !torn.is_empty() {
+                warn!(
+                    "Dropping the torn last line of {}: {}",
+                    self.path.display(),
+                    torn
+                );
+                // so that the next append starts on a line of its own
+                let file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&self.path)
+                    .await
+                    .map_err(|e| format!("failed to open {}: {e}", self.path.display()))?;
+                file.set_len(complete as u64)
+                    .await
+                    .map_err(|e| format!("failed to truncate {}: {e}", self.path.display()))?;
+                file.sync_data()
+                    .await
+                    .map_err(|e| format!("failed to sync {}: {e}", self.path.display()))?;
+            }
+            lines
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(parse_entry)
+                .collect()
+        })
+    }
+
+    fn compact(
+        &self,
+        entries: Vec<Sv2ShareAccountingEntry>,
+    ) -> Sv2BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let content: String = entries.iter().map(format_entry).collect();
+            let mut tmp_path = self.path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            let tmp_path = PathBuf::from(tmp_path);
+
+            let _guard = self.lock.lock().await;
+            let mut file = tokio::fs::File::create(&tmp_path)
+                .await
+                .map_err(|e| format!("failed to create {}: {e}", tmp_path.display()))?;
+            file.write_all(content.as_bytes())
+                .await
+                .map_err(|e| format!("failed to write to {}: {e}", tmp_path.display()))?;
+            file.sync_all()
+                .await
+                .map_err(|e| format!("failed to write to {}: {e}", tmp_path.display()))?;
+            tokio::fs::rename(&tmp_path, &self.path)
+                .await
+                .map_err(|e| format!("failed to replace {}: {e}", self.path.display()))
+        })
+    }
+}
+
+/// Credits valid shares to their users, see the module docs.
+///
+/// Clones credit the same users.
+#[derive(Clone)]
+pub struct Sv2ShareAccounting {
+    scheme: Sv2PayoutScheme,
+    store: Arc<dyn Sv2ShareAccountingStore>,
+    // held from persisting an entry until it is applied, so that entries are applied in the order they are persisted
+    log: Arc<tokio::sync::Mutex<()>>,
+    state: Arc<Mutex<ShareAccountingState>>,
+}
+
+impl std::fmt::Debug for Sv2ShareAccounting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sv2ShareAccounting")
+            .field("scheme", &self.scheme)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ShareAccountingState {
+    users: HashMap<String, UserAccount>,
+    // oldest first, only with PPLNS
+    window: VecDeque<Sv2Share>,
+    window_difficulty: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct UserAccount {
+    shares: u64,
+    difficulty: f64,
+    pps_debited: u64,
+}
+
+impl UserAccount {
+    // The PPS balance is worked out from the whole difficulty at once, and kept in whole satoshis,
+    // so that rounding never builds up over many shares and debits.
+    fn totals(&self, scheme: &Sv2PayoutScheme) -> Sv2UserShareTotals {
+        let pps_balance = match scheme {
+            Sv2PayoutScheme::Pps {
+                sats_per_difficulty,
+            } => ((self.difficulty * sats_per_difficulty).floor() as u64)
+                .saturating_sub(self.pps_debited),
+            Sv2PayoutScheme::Pplns { .. } => 0,
+        };
+        Sv2UserShareTotals {
+            shares: self.shares,
+            difficulty: self.difficulty,
+            pps_balance,
+        }
+    }
+}
+
+impl ShareAccountingState {
+    fn apply(&mut self, scheme: &Sv2PayoutScheme, entry: Sv2ShareAccountingEntry) {
+        match entry {
+            Sv2ShareAccountingEntry::Share(share) => self.credit(scheme, share),
+            Sv2ShareAccountingEntry::PpsDebit {
+                user_identity,
+                amount,
+                ..
+            } => {
+                self.users.entry(user_identity).or_default().pps_debited += amount;
+            }
+            Sv2ShareAccountingEntry::Totals {
+                user_identity,
+                shares,
+                difficulty,
+                pps_debited,
+            } => {
+                self.users.insert(
+                    user_identity,
+                    UserAccount {
+                        shares,
+                        difficulty,
+                        pps_debited,
+                    },
+                );
+            }
+        }
+    }
+
+    fn credit(&mut self, scheme: &Sv2PayoutScheme, share: Sv2Share) {
+        let account = self.users.entry(share.user_identity.clone()).or_default();
+        account.shares += 1;
+        account.difficulty += share.difficulty;
+
+        match scheme {
+            Sv2PayoutScheme::Pps { .. } => {}
+            Sv2PayoutScheme::Pplns { window_difficulty } => {
+                self.window_difficulty += share.difficulty;
+                self.window.push_back(share);
+                // drop the oldest shares, as long as the rest still fills the window
+                while let Some(oldest) = self.window.front() {
+                    if self.window_difficulty - oldest.difficulty < *window_difficulty {
+                        break;
+                    }
+                    self.window_difficulty -= oldest.difficulty;
+                    self.window.pop_front();
+                }
+            }
+        }
+    }
+
+    // The shortest log that adds up to this state: the window shares, then the totals overwriting what they credited.
+    fn snapshot(&self) -> Vec<Sv2ShareAccountingEntry> {
+        let mut users: Vec<(&String, &UserAccount)> = self.users.iter().collect();
+        users.sort_by_key(|(user_identity, _)| *user_identity);
+
+        self.window
+            .iter()
+            .cloned()
+            .map(Sv2ShareAccountingEntry::Share)
+            .chain(users.into_iter().map(|(user_identity, account)| {
+                Sv2ShareAccountingEntry::Totals {
+                    user_identity: user_identity.clone(),
+                    shares: account.shares,
+                    difficulty: account.difficulty,
+                    pps_debited: account.pps_debited,
+                }
+            }))
+            .collect()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+impl Sv2ShareAccounting {
+    /// Creates a new [`Sv2ShareAccounting`], applying every entry already in `store`.
+    ///
+    /// Fails with [`Sv2ShareAccountingError::InvalidPayoutScheme`] if the parameter of `scheme` is not finite and positive.
+    pub async fn load(
+        scheme: Sv2PayoutScheme,
+        store: impl Sv2ShareAccountingStore,
+    ) -> Result<Self, Sv2ShareAccountingError> {
+        if !scheme.is_valid() {
+            return Err(Sv2ShareAccountingError::InvalidPayoutScheme(scheme));
+        }
+
+        let entries = store.load().await.map_err(Sv2ShareAccountingError::Store)?;
+
+        let mut state = ShareAccountingState::default();
+        for entry in entries {
+            state.apply(&scheme, entry);
+        }
+
+        Ok(Self {
+            scheme,
+            store: Arc::new(store),
+            log: Arc::new(tokio::sync::Mutex::new(())),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Persists a valid share of `difficulty`, then credits it to `user_identity`.
+    ///
+    /// The share is not credited if the store fails to persist it.
+    pub async fn record_share(
+        &self,
+        user_identity: impl Into<String>,
+        channel_id: u32,
+        difficulty: f64,
+    ) -> Result<(), Sv2ShareAccountingError> {
+        if !difficulty.is_finite() || difficulty <= 0.0 {
+            return Err(Sv2ShareAccountingError::InvalidDifficulty(difficulty));
+        }
+
+        let share = Sv2Share {
+            user_identity: user_identity.into(),
+            channel_id,
+            difficulty,
+            timestamp: now(),
+        };
+        let _log = self.log.lock().await;
+        self.persist_and_apply(Sv2ShareAccountingEntry::Share(share))
+            .await
+    }
+
+    /// Persists a payout of `amount` satoshis to `user_identity`, then takes it out of its PPS balance.
+    ///
+    /// Only with [`Sv2PayoutScheme::Pps`], and for at most the current balance of the user.
+    pub async fn debit_pps_balance(
+        &self,
+        user_identity: impl Into<String>,
+        amount: u64,
+    ) -> Result<(), Sv2ShareAccountingError> {
+        if !matches!(self.scheme, Sv2PayoutScheme::Pps { .. }) {
+            return Err(Sv2ShareAccountingError::WrongPayoutScheme);
+        }
+        if amount == 0 {
+            return Err(Sv2ShareAccountingError::InvalidAmount(amount));
+        }
+
+        let user_identity = user_identity.into();
+        let _log = self.log.lock().await;
+        // checked under the log lock, so that concurrent debits can't overdraw the balance
+        let available = self
+            .user_totals(&user_identity)
+            .map(|totals| totals.pps_balance)
+            .unwrap_or_default();
+        if available < amount {
+            return Err(Sv2ShareAccountingError::InsufficientBalance { available });
+        }
+
+        self.persist_and_apply(Sv2ShareAccountingEntry::PpsDebit {
+            user_identity,
+            amount,
+            timestamp: now(),
+        })
+        .await
+    }
+
+    /// Replaces the entries of the store with a snapshot of the current totals and window, so that it stops growing.
+    pub async fn compact(&self) -> Result<(), Sv2ShareAccountingError> {
+        let _log = self.log.lock().await;
+        let snapshot = self.state().snapshot();
+        self.store
+            .compact(snapshot)
+            .await
+            .map_err(Sv2ShareAccountingError::Store)
+    }
+
+    // To be called with the log lock held, from persisting `entry` until it is applied.
+    async fn persist_and_apply(
+        &self,
+        entry: Sv2ShareAccountingEntry,
+    ) -> Result<(), Sv2ShareAccountingError> {
+        self.store
+            .append(entry.clone())
+            .await
+            .map_err(Sv2ShareAccountingError::Store)?;
+        self.state().apply(&self.scheme, entry);
+        Ok(())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ShareAccountingState> {
+        self.state.lock().expect("share accounting lock poisoned")
+    }
+
+    /// The payout scheme shares are credited with.
+    pub fn scheme(&self) -> &Sv2PayoutScheme {
+        &self.scheme
+    }
+
+    /// What `user_identity` has been credited with, if it has any share.
+    pub fn user_totals(&self, user_identity: &str) -> Option<Sv2UserShareTotals> {
+        self.state()
+            .users
+            .get(user_identity)
+            .map(|account| account.totals(&self.scheme))
+    }
+
+    /// What every user has been credited with, keyed by `user_identity`.
+    pub fn totals(&self) -> HashMap<String, Sv2UserShareTotals> {
+        self.state()
+            .users
+            .iter()
+            .map(|(user_identity, account)| (user_identity.clone(), account.totals(&self.scheme)))
+            .collect()
+    }
+
+    /// Splits `reward` (in satoshis) over the shares of the PPLNS window, by difficulty, e.g.: when a block is found.
+    ///
+    /// The satoshis left over by rounding go to the user with the most difficulty in the window.
+    pub fn pplns_payouts(
+        &self,
+        reward: u64,
+    ) -> Result<Vec<(String, u64)>, Sv2ShareAccountingError> {
+        if !matches!(self.scheme, Sv2PayoutScheme::Pplns { .. }) {
+            return Err(Sv2ShareAccountingError::WrongPayoutScheme);
+        }
+
+        let state = self.state();
+        let mut difficulty_by_user: BTreeMap<&str, f64> = BTreeMap::new();
+        for share in &state.window {
+            *difficulty_by_user.entry(&share.user_identity).or_default() += share.difficulty;
+        }
+        if difficulty_by_user.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut payouts: Vec<(String, u64)> = difficulty_by_user
+            .iter()
+            .map(|(user_identity, difficulty)| {
+                let payout = (reward as f64 * difficulty / state.window_difficulty).floor() as u64;
+                (user_identity.to_string(), payout.min(reward))
+            })
+            .collect();
+        let paid: u64 = payouts.iter().map(|(_, payout)| payout).sum();
+        let leftover = reward.saturating_sub(paid);
+        let (top, _) = difficulty_by_user.values().enumerate().fold(
+            (0, f64::MIN),
+            |(top, max), (i, difficulty)| {
+                if *difficulty > max {
+                    (i, *difficulty)
+                } else {
+                    (top, max)
+                }
+            },
+        );
+        payouts[top].1 += leftover;
+        Ok(payouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        difficulty_from_target, Sv2FileShareAccountingStore, Sv2MemoryShareAccountingStore,
+        Sv2PayoutScheme, Sv2ShareAccounting, Sv2ShareAccountingEntry, Sv2ShareAccountingError,
+        Sv2ShareAccountingStore,
+    };
+    use crate::layer::Sv2BoxFuture;
+    use std::time::Duration;
+
+    // a memory store that takes a while to persist the shares of alice
+    #[derive(Clone, Default)]
+    struct SlowStore(Sv2MemoryShareAccountingStore);
+
+    impl Sv2ShareAccountingStore for SlowStore {
+        fn append(&self, entry: Sv2ShareAccountingEntry) -> Sv2BoxFuture<'_, Result<(), String>> {
+            Box::pin(async move {
+                if matches!(&entry, Sv2ShareAccountingEntry::Share(share) if share.user_identity == "alice")
+                {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                self.0.append(entry).await
+            })
+        }
+
+        fn load(&self) -> Sv2BoxFuture<'_, Result<Vec<Sv2ShareAccountingEntry>, String>> {
+            self.0.load()
+        }
+
+        fn compact(
+            &self,
+            entries: Vec<Sv2ShareAccountingEntry>,
+        ) -> Sv2BoxFuture<'_, Result<(), String>> {
+            self.0.compact(entries)
+        }
+    }
+
+    #[test]
+    fn difficulty_of_targets() {
+        // 0x00000000ffff0000..., little-endian
+        let mut difficulty_1 = [0u8; 32];
+        difficulty_1[26] = 0xff;
+        difficulty_1[27] = 0xff;
+        assert_eq!(difficulty_from_target(&difficulty_1), 1.0);
+
+        let mut difficulty_256 = [0u8; 32];
+        difficulty_256[25] = 0xff;
+        difficulty_256[26] = 0xff;
+        assert_eq!(difficulty_from_target(&difficulty_256), 256.0);
+
+        assert!(difficulty_from_target(&[0; 32]).is_infinite());
+    }
+
+    #[tokio::test]
+    async fn pps_balances_survive_reloads() {
+        let store = Sv2MemoryShareAccountingStore::new();
+        let scheme = Sv2PayoutScheme::Pps {
+            sats_per_difficulty: 2.0,
+        };
+        let accounting = Sv2ShareAccounting::load(scheme.clone(), store.clone())
+            .await
+            .unwrap();
+
+        accounting
+            .record_share("alice.rig1", 1, 10.0)
+            .await
+            .unwrap();
+        accounting.record_share("alice.rig1", 1, 5.0).await.unwrap();
+        accounting.record_share("bob.rig1", 2, 1.0).await.unwrap();
+        assert_eq!(
+            accounting.record_share("bob.rig1", 2, 0.0).await,
+            Err(Sv2ShareAccountingError::InvalidDifficulty(0.0))
+        );
+        assert_eq!(
+            accounting.pplns_payouts(100),
+            Err(Sv2ShareAccountingError::WrongPayoutScheme)
+        );
+
+        let alice = accounting.user_totals("alice.rig1").unwrap();
+        assert_eq!(alice.shares, 2);
+        assert_eq!(alice.difficulty, 15.0);
+        assert_eq!(alice.pps_balance, 30);
+        assert!(accounting.user_totals("carol.rig1").is_none());
+
+        // the shares are replayed from the store
+        let reloaded = Sv2ShareAccounting::load(scheme, store).await.unwrap();
+        assert_eq!(reloaded.totals(), accounting.totals());
+    }
+
+    #[tokio::test]
+    async fn pplns_window_from_file() {
+        let path = std::env::temp_dir().join(format!("sv2-shares-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scheme = Sv2PayoutScheme::Pplns {
+            window_difficulty: 10.0,
+        };
+        let accounting =
+            Sv2ShareAccounting::load(scheme.clone(), Sv2FileShareAccountingStore::new(&path))
+                .await
+                .unwrap();
+        assert_eq!(accounting.pplns_payouts(100).unwrap(), vec![]);
+
+        // the first share of alice falls out of the window once bob's shares fill it
+        accounting.record_share("alice", 1, 4.0).await.unwrap();
+        accounting.record_share("bob", 2, 6.0).await.unwrap();
+        accounting.record_share("bob", 2, 2.0).await.unwrap();
+        accounting.record_share("alice", 1, 2.0).await.unwrap();
+        assert_eq!(
+            accounting.pplns_payouts(100).unwrap(),
+            vec![("alice".to_string(), 20), ("bob".to_string(), 80)]
+        );
+
+        // totals still count every share
+        assert_eq!(accounting.user_totals("alice").unwrap().difficulty, 6.0);
+
+        let reloaded = Sv2ShareAccounting::load(scheme, Sv2FileShareAccountingStore::new(&path))
+            .await
+            .unwrap();
+        assert_eq!(
+            reloaded.pplns_payouts(101).unwrap(),
+            vec![("alice".to_string(), 20), ("bob".to_string(), 81)]
+        );
+        assert_eq!(reloaded.totals(), accounting.totals());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn torn_last_lines_are_dropped() {
+        let path = std::env::temp_dir().join(format!("sv2-torn-shares-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scheme = Sv2PayoutScheme::Pps {
+            sats_per_difficulty: 10.0,
+        };
+        let accounting =
+            Sv2ShareAccounting::load(scheme.clone(), Sv2FileShareAccountingStore::new(&path))
+                .await
+                .unwrap();
+        accounting.record_share("alice", 1, 3.0).await.unwrap();
+
+        // a crash in the middle of an append
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("share 2.0 ");
+        std::fs::write(&path, &content).unwrap();
+
+        let reloaded =
+            Sv2ShareAccounting::load(scheme.clone(), Sv2FileShareAccountingStore::new(&path))
+                .await
+                .unwrap();
+        assert_eq!(reloaded.totals(), accounting.totals());
+
+        // the torn line was truncated away, so appends start on a line of their own
+        reloaded.record_share("alice", 1, 2.0).await.unwrap();
+        let reloaded =
+            Sv2ShareAccounting::load(scheme.clone(), Sv2FileShareAccountingStore::new(&path))
+                .await
+                .unwrap();
+        assert_eq!(reloaded.user_totals("alice").unwrap().pps_balance, 50);
+
+        // a malformed line in the middle is corruption
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("share 2.0\n{content}")).unwrap();
+        assert!(matches!(
+            Sv2ShareAccounting::load(scheme, Sv2FileShareAccountingStore::new(&path)).await,
+            Err(Sv2ShareAccountingError::Store(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn payout_schemes_are_validated() {
+        for scheme in [
+            Sv2PayoutScheme::Pps {
+                sats_per_difficulty: 0.0,
+            },
+            Sv2PayoutScheme::Pps {
+                sats_per_difficulty: f64::INFINITY,
+            },
+            Sv2PayoutScheme::Pplns {
+                window_difficulty: -1.0,
+            },
+            Sv2PayoutScheme::Pplns {
+                window_difficulty: f64::NAN,
+            },
+        ] {
+            assert!(matches!(
+                Sv2ShareAccounting::load(scheme, Sv2MemoryShareAccountingStore::new()).await,
+                Err(Sv2ShareAccountingError::InvalidPayoutScheme(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_are_credited_in_the_order_they_are_persisted() {
+        let store = SlowStore::default();
+        // the window only ever holds the latest share
+        let scheme = Sv2PayoutScheme::Pplns {
+            window_difficulty: 1.0,
+        };
+        let accounting = Sv2ShareAccounting::load(scheme.clone(), store.clone())
+            .await
+            .unwrap();
+
+        let alice = tokio::spawn({
+            let accounting = accounting.clone();
+            async move { accounting.record_share("alice", 1, 1.0).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        accounting.record_share("bob", 2, 1.0).await.unwrap();
+        alice.await.unwrap().unwrap();
+
+        let reloaded = Sv2ShareAccounting::load(scheme, store).await.unwrap();
+        assert_eq!(
+            accounting.pplns_payouts(100).unwrap(),
+            reloaded.pplns_payouts(100).unwrap()
+        );
+        assert_eq!(
+            accounting.pplns_payouts(100).unwrap(),
+            vec![("bob".to_string(), 100)]
+        );
+    }
+
+    #[tokio::test]
+    async fn pps_debits_and_compaction_survive_reloads() {
+        let path = std::env::temp_dir().join(format!("sv2-pps-shares-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scheme = Sv2PayoutScheme::Pps {
+            sats_per_difficulty: 10.0,
+        };
+        let accounting =
+            Sv2ShareAccounting::load(scheme.clone(), Sv2FileShareAccountingStore::new(&path))
+                .await
+                .unwrap();
+
+        accounting.record_share("alice", 1, 3.0).await.unwrap();
+        accounting.record_share("alice", 1, 2.0).await.unwrap();
+        accounting.debit_pps_balance("alice", 20).await.unwrap();
+        assert_eq!(
+            accounting.debit_pps_balance("alice", 31).await,
+            Err(Sv2ShareAccountingError::InsufficientBalance { available: 30 })
+        );
+        assert_eq!(
+            accounting.debit_pps_balance("alice", 0).await,
+            Err(Sv2ShareAccountingError::InvalidAmount(0))
+        );
+        assert_eq!(accounting.user_totals("alice").unwrap().pps_balance, 30);
+
+        accounting.compact().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        accounting.record_share("alice", 1, 1.0).await.unwrap();
+
+        let reloaded = Sv2ShareAccounting::load(scheme, Sv2FileShareAccountingStore::new(&path))
+            .await
+            .unwrap();
+        let alice = reloaded.user_totals("alice").unwrap();
+        assert_eq!(alice.shares, 3);
+        assert_eq!(alice.difficulty, 6.0);
+        assert_eq!(alice.pps_balance, 40);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_the_pplns_window() {
+        let store = Sv2MemoryShareAccountingStore::new();
+        let scheme = Sv2PayoutScheme::Pplns {
+            window_difficulty: 10.0,
+        };
+        let accounting = Sv2ShareAccounting::load(scheme.clone(), store.clone())
+            .await
+            .unwrap();
+        for _ in 0..20 {
+            accounting.record_share("alice", 1, 1.0).await.unwrap();
+        }
+        accounting.record_share("bob", 2, 2.0).await.unwrap();
+        assert_eq!(
+            accounting.debit_pps_balance("alice", 1).await,
+            Err(Sv2ShareAccountingError::WrongPayoutScheme)
+        );
+
+        accounting.compact().await.unwrap();
+        // the 9 shares still in the window, and the totals of both users
+        assert_eq!(store.load().await.unwrap().len(), 11);
+
+        let reloaded = Sv2ShareAccounting::load(scheme, store).await.unwrap();
+        assert_eq!(reloaded.totals(), accounting.totals());
+        assert_eq!(
+            reloaded.pplns_payouts(100).unwrap(),
+            accounting.pplns_payouts(100).unwrap()
+        );
+    }
+}
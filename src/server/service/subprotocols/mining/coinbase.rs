@@ -0,0 +1,469 @@
+//! Building coinbase transactions out of `NewTemplate`s, for mining handlers that turn templates into jobs.
+//!
+//! A template only carries part of the coinbase: the start of the scriptSig (`coinbase_prefix`, e.g.: the BIP34 height),
+//! the value left for the pool (`coinbase_tx_value_remaining`) and the outputs the node requires (e.g.: the witness commitment).
+//! [`Sv2CoinbaseBuilder`] adds the pool payout outputs and room for the extranonce, and returns a [`Sv2Coinbase`] with:
+//! - [`Sv2Coinbase::coinbase_tx_prefix`] and [`Sv2Coinbase::coinbase_tx_suffix`], for `NewExtendedMiningJob`
+//! - [`Sv2Coinbase::merkle_root`], for `NewMiningJob` on standard channels
+//! - [`Sv2Coinbase::serialize`], the full transaction for `SubmitSolution`
+//!
+//! ```ignore
+//! let builder = Sv2CoinbaseBuilder::new(
+//!     vec![
+//!         Sv2CoinbasePayout::from_address("bc1q...", Network::Bitcoin, 99)?,
+//!         Sv2CoinbasePayout::from_script(fee_script, 1),
+//!     ],
+//!     coinbase_output_constraints,
+//!     extranonce_size,
+//! )?;
+//! let coinbase = builder.build(&template)?;
+//! ```
+
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::{deserialize_partial, serialize};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, VarInt,
+    Witness,
+};
+use std::str::FromStr;
+use stratum_common::roles_logic_sv2::template_distribution_sv2::NewTemplate;
+
+/// Consensus limit on the size of a coinbase scriptSig.
+const MAX_COINBASE_SCRIPT_SIG_SIZE: usize = 100;
+
+/// Sigops count 4 times in the block sigops cost, as legacy sigops.
+const WITNESS_SCALE_FACTOR: usize = 4;
+
+/// The start of the witness commitment output script: OP_RETURN, push 36 bytes, then the BIP141 header.
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Why a coinbase could not be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2CoinbaseError {
+    InvalidAddress(String),
+    /// No payout, or payouts that all weigh 0.
+    NoPayouts,
+    /// The payout outputs need more bytes than the Template Provider leaves room for.
+    ExceedsMaxAdditionalSize {
+        required: u32,
+        max: u32,
+    },
+    /// The payout outputs need more sigops than the Template Provider leaves room for.
+    ExceedsMaxAdditionalSigops {
+        required: u16,
+        max: u16,
+    },
+    /// The `coinbase_tx_outputs` of the template could not be decoded.
+    InvalidTemplateOutputs(String),
+    /// The scriptSig (template `coinbase_prefix` plus extranonce) is larger than 100 bytes.
+    ScriptSigTooLarge(usize),
+    WrongExtranonceSize {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// An output paying the pool (or anyone sharing the reward), weighted against the other payouts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sv2CoinbasePayout {
+    pub script_pubkey: ScriptBuf,
+    /// The share of `coinbase_tx_value_remaining` this output gets is `weight / sum of all weights`.
+    pub weight: u32,
+}
+
+impl Sv2CoinbasePayout {
+    /// A payout to `address`, which must belong to `network`.
+    pub fn from_address(
+        address: &str,
+        network: Network,
+        weight: u32,
+    ) -> Result<Self, Sv2CoinbaseError> {
+        let address = Address::from_str(address)
+            .and_then(|address| address.require_network(network))
+            .map_err(|e| Sv2CoinbaseError::InvalidAddress(format!("{address}: {e}")))?;
+        Ok(Self {
+            script_pubkey: address.script_pubkey(),
+            weight,
+        })
+    }
+
+    /// A payout to a raw output script.
+    pub fn from_script(script_pubkey: impl Into<ScriptBuf>, weight: u32) -> Self {
+        Self {
+            script_pubkey: script_pubkey.into(),
+            weight,
+        }
+    }
+}
+
+/// Builds the coinbase of every template with the same payouts and extranonce size.
+#[derive(Debug, Clone)]
+pub struct Sv2CoinbaseBuilder {
+    payouts: Vec<Sv2CoinbasePayout>,
+    extranonce_size: usize,
+}
+
+impl Sv2CoinbaseBuilder {
+    /// Creates a new [`Sv2CoinbaseBuilder`].
+    ///
+    /// `coinbase_output_constraints` are the (max_additional_size, max_additional_sigops) sent to the Template Provider,
+    /// which the payout outputs must fit in. `extranonce_size` is the size of the full extranonce
+    /// (i.e.: the extranonce prefix of the channel plus the part rolled by the miner).
+    pub fn new(
+        payouts: Vec<Sv2CoinbasePayout>,
+        coinbase_output_constraints: (u32, u16),
+        extranonce_size: usize,
+    ) -> Result<Self, Sv2CoinbaseError> {
+        if payouts.iter().all(|payout| payout.weight == 0) {
+            return Err(Sv2CoinbaseError::NoPayouts);
+        }
+
+        let builder = Self {
+            payouts,
+            extranonce_size,
+        };
+
+        let (max_additional_size, max_additional_sigops) = coinbase_output_constraints;
+        let (required_size, required_sigops) = builder.required_coinbase_output_constraints();
+        if required_size > max_additional_size {
+            return Err(Sv2CoinbaseError::ExceedsMaxAdditionalSize {
+                required: required_size,
+                max: max_additional_size,
+            });
+        }
+        if required_sigops > max_additional_sigops {
+            return Err(Sv2CoinbaseError::ExceedsMaxAdditionalSigops {
+                required: required_sigops,
+                max: max_additional_sigops,
+            });
+        }
+
+        Ok(builder)
+    }
+
+    /// The (max_additional_size, max_additional_sigops) the payout outputs need, i.e.: the smallest
+    /// CoinbaseOutputConstraints to send to the Template Provider.
+    ///
+    /// The size covers the serialized outputs plus the growth of the output count, and the sigops are in block sigops cost.
+    pub fn required_coinbase_output_constraints(&self) -> (u32, u16) {
+        let outputs_size: usize = self
+            .payouts
+            .iter()
+            .map(|payout| {
+                serialize(&TxOut {
+                    value: Amount::ZERO,
+                    script_pubkey: payout.script_pubkey.clone(),
+                })
+                .len()
+            })
+            .sum();
+        // the output count is a varint, which may grow by up to 2 bytes with our outputs
+        let size = outputs_size + VarInt(self.payouts.len() as u64).size() - 1;
+        let sigops: usize = self
+            .payouts
+            .iter()
+            .map(|payout| payout.script_pubkey.count_sigops_legacy() * WITNESS_SCALE_FACTOR)
+            .sum();
+        (
+            size.try_into().unwrap_or(u32::MAX),
+            sigops.try_into().unwrap_or(u16::MAX),
+        )
+    }
+
+    /// The size of the extranonce the coinbases leave room for.
+    pub fn extranonce_size(&self) -> usize {
+        self.extranonce_size
+    }
+
+    /// Builds the coinbase of `template`.
+    pub fn build(&self, template: &NewTemplate<'_>) -> Result<Sv2Coinbase, Sv2CoinbaseError> {
+        let coinbase_prefix = template.coinbase_prefix.inner_as_ref().to_vec();
+        let script_sig_size = coinbase_prefix.len() + self.extranonce_size;
+        if script_sig_size > MAX_COINBASE_SCRIPT_SIG_SIZE {
+            return Err(Sv2CoinbaseError::ScriptSigTooLarge(script_sig_size));
+        }
+
+        let mut output = self.payout_outputs(template.coinbase_tx_value_remaining);
+        let template_outputs = template_outputs(template)?;
+        // a block with a witness commitment needs the witness reserved value on the coinbase input
+        let segwit = template_outputs.iter().any(|output| {
+            output
+                .script_pubkey
+                .as_bytes()
+                .starts_with(&WITNESS_COMMITMENT_HEADER)
+        });
+        output.extend(template_outputs);
+
+        let mut script_sig = coinbase_prefix.clone();
+        script_sig.resize(script_sig_size, 0);
+        let transaction = Transaction {
+            version: Version(template.coinbase_tx_version as i32),
+            lock_time: LockTime::from_consensus(template.coinbase_tx_locktime),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(script_sig),
+                sequence: Sequence(template.coinbase_tx_input_sequence),
+                witness: Witness::new(),
+            }],
+            output,
+        };
+
+        // without witness, the extranonce sits right after the coinbase prefix in the scriptSig:
+        // version, input count, prevout, scriptSig length, then the scriptSig itself
+        let stripped = serialize(&transaction);
+        let prefix_size =
+            4 + 1 + 36 + VarInt(script_sig_size as u64).size() + coinbase_prefix.len();
+        let suffix_start = prefix_size + self.extranonce_size;
+
+        Ok(Sv2Coinbase {
+            coinbase_tx_prefix: stripped[..prefix_size].to_vec(),
+            coinbase_tx_suffix: stripped[suffix_start..].to_vec(),
+            extranonce_size: self.extranonce_size,
+            merkle_path: template.merkle_path.to_vec(),
+            segwit,
+            transaction,
+        })
+    }
+
+    // Splits `value` between the payouts by weight, the rounding leftovers going to the first payout.
+    fn payout_outputs(&self, value: u64) -> Vec<TxOut> {
+        let total_weight: u128 = self
+            .payouts
+            .iter()
+            .map(|payout| payout.weight as u128)
+            .sum();
+        let mut values: Vec<u64> = self
+            .payouts
+            .iter()
+            .map(|payout| (value as u128 * payout.weight as u128 / total_weight) as u64)
+            .collect();
+        let leftover = value - values.iter().sum::<u64>();
+        values[0] += leftover;
+
+        self.payouts
+            .iter()
+            .zip(values)
+            .map(|(payout, value)| TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: payout.script_pubkey.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The coinbase of a template, with room for an extranonce.
+#[derive(Debug, Clone)]
+pub struct Sv2Coinbase {
+    /// The coinbase up to the extranonce, without witness, as in `NewExtendedMiningJob`
+    pub coinbase_tx_prefix: Vec<u8>,
+    /// The coinbase after the extranonce, without witness, as in `NewExtendedMiningJob`
+    pub coinbase_tx_suffix: Vec<u8>,
+    pub extranonce_size: usize,
+    merkle_path: Vec<Vec<u8>>,
+    segwit: bool,
+    // with a zeroed extranonce
+    transaction: Transaction,
+}
+
+impl Sv2Coinbase {
+    /// The coinbase transaction with `extranonce`.
+    pub fn transaction(&self, extranonce: &[u8]) -> Result<Transaction, Sv2CoinbaseError> {
+        if extranonce.len() != self.extranonce_size {
+            return Err(Sv2CoinbaseError::WrongExtranonceSize {
+                expected: self.extranonce_size,
+                actual: extranonce.len(),
+            });
+        }
+
+        let mut transaction = self.transaction.clone();
+        let mut script_sig = transaction.input[0].script_sig.to_bytes();
+        let coinbase_prefix_size = script_sig.len() - self.extranonce_size;
+        script_sig[coinbase_prefix_size..].copy_from_slice(extranonce);
+        transaction.input[0].script_sig = ScriptBuf::from_bytes(script_sig);
+        if self.segwit {
+            transaction.input[0].witness = Witness::from_slice(&[[0u8; 32]]);
+        }
+        Ok(transaction)
+    }
+
+    /// The full serialized coinbase with `extranonce` (with witness, if any), as in `SubmitSolution`.
+    pub fn serialize(&self, extranonce: &[u8]) -> Result<Vec<u8>, Sv2CoinbaseError> {
+        Ok(serialize(&self.transaction(extranonce)?))
+    }
+
+    /// The merkle root of the block with this coinbase and `extranonce`, as in `NewMiningJob`.
+    pub fn merkle_root(&self, extranonce: &[u8]) -> Result<[u8; 32], Sv2CoinbaseError> {
+        let txid = self.transaction(extranonce)?.compute_txid().to_byte_array();
+        Ok(merkle_root_from_path(txid, &self.merkle_path))
+    }
+}
+
+/// Folds the merkle path of a template (the siblings of the coinbase, from the leaves up) into the merkle root.
+pub fn merkle_root_from_path(coinbase_txid: [u8; 32], merkle_path: &[Vec<u8>]) -> [u8; 32] {
+    merkle_path.iter().fold(coinbase_txid, |node, sibling| {
+        let mut concatenated = node.to_vec();
+        concatenated.extend_from_slice(sibling);
+        sha256d::Hash::hash(&concatenated).to_byte_array()
+    })
+}
+
+// Decodes the `coinbase_tx_outputs` of a template.
+fn template_outputs(template: &NewTemplate<'_>) -> Result<Vec<TxOut>, Sv2CoinbaseError> {
+    let mut remaining = template.coinbase_tx_outputs.inner_as_ref();
+    let mut outputs = Vec::with_capacity(template.coinbase_tx_outputs_count as usize);
+    for _ in 0..template.coinbase_tx_outputs_count {
+        let (output, consumed) = deserialize_partial::<TxOut>(remaining)
+            .map_err(|e| Sv2CoinbaseError::InvalidTemplateOutputs(e.to_string()))?;
+        outputs.push(output);
+        remaining = &remaining[consumed..];
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        merkle_root_from_path, Sv2CoinbaseBuilder, Sv2CoinbaseError, Sv2CoinbasePayout,
+        WITNESS_COMMITMENT_HEADER,
+    };
+    use crate::testing::fixtures;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, Network, ScriptBuf, Transaction, TxOut};
+    use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::B064K;
+    use stratum_common::roles_logic_sv2::template_distribution_sv2::NewTemplate;
+
+    // a template at height 1, whose only output is a (zeroed) witness commitment
+    fn new_template() -> NewTemplate<'static> {
+        let mut witness_commitment = WITNESS_COMMITMENT_HEADER.to_vec();
+        witness_commitment.extend_from_slice(&[0; 32]);
+        let outputs = serialize(&TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(witness_commitment),
+        });
+        NewTemplate {
+            coinbase_tx_outputs_count: 1,
+            coinbase_tx_outputs: B064K::Owned(outputs),
+            ..fixtures::new_template(1, false)
+        }
+    }
+
+    #[test]
+    fn payouts_from_addresses_and_scripts() {
+        // BIP173 test vector
+        let payout = Sv2CoinbasePayout::from_address(
+            "bc1qw508d6qejxtdg4c5r3zarvary0c5xw7kv8f3t4",
+            Network::Bitcoin,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            payout.script_pubkey.as_bytes(),
+            hex::decode("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap()
+        );
+
+        assert!(matches!(
+            Sv2CoinbasePayout::from_address(
+                "bc1qw508d6qejxtdg4c5r3zarvary0c5xw7kv8f3t4",
+                Network::Regtest,
+                1
+            ),
+            Err(Sv2CoinbaseError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            Sv2CoinbasePayout::from_address("not an address", Network::Bitcoin, 1),
+            Err(Sv2CoinbaseError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn coinbase_respects_constraints_and_splits_value() {
+        let payouts = vec![
+            Sv2CoinbasePayout::from_script(ScriptBuf::from_bytes(vec![0x51]), 2),
+            Sv2CoinbasePayout::from_script(ScriptBuf::from_bytes(vec![0x52]), 1),
+        ];
+
+        // each output is 8 bytes of value, 1 of script length and 1 of script
+        assert_eq!(
+            Sv2CoinbaseBuilder::new(payouts.clone(), (19, 0), 8).unwrap_err(),
+            Sv2CoinbaseError::ExceedsMaxAdditionalSize {
+                required: 20,
+                max: 19
+            }
+        );
+        let builder = Sv2CoinbaseBuilder::new(payouts, (20, 0), 8).unwrap();
+        assert_eq!(builder.required_coinbase_output_constraints(), (20, 0));
+
+        let coinbase = builder.build(&new_template()).unwrap();
+        let transaction = coinbase.transaction(&[7; 8]).unwrap();
+        let values: Vec<u64> = transaction
+            .output
+            .iter()
+            .map(|output| output.value.to_sat())
+            .collect();
+        // the leftover satoshi of the 2:1 split goes to the first payout
+        assert_eq!(values, vec![3_333_333_334, 1_666_666_666, 0]);
+        assert_eq!(
+            transaction.input[0].script_sig.as_bytes(),
+            &[0x51, 7, 7, 7, 7, 7, 7, 7, 7]
+        );
+
+        assert_eq!(
+            coinbase.transaction(&[7; 4]).unwrap_err(),
+            Sv2CoinbaseError::WrongExtranonceSize {
+                expected: 8,
+                actual: 4
+            }
+        );
+    }
+
+    #[test]
+    fn coinbase_parts_reassemble_into_the_transaction() {
+        let builder = Sv2CoinbaseBuilder::new(
+            vec![Sv2CoinbasePayout::from_script(
+                ScriptBuf::from_bytes(vec![0x51]),
+                1,
+            )],
+            (100, 0),
+            16,
+        )
+        .unwrap();
+        let coinbase = builder.build(&new_template()).unwrap();
+        let extranonce = [9; 16];
+
+        // prefix + extranonce + suffix is the coinbase without witness
+        let mut stripped = coinbase.coinbase_tx_prefix.clone();
+        stripped.extend_from_slice(&extranonce);
+        stripped.extend_from_slice(&coinbase.coinbase_tx_suffix);
+        let from_parts: Transaction = deserialize(&stripped).unwrap();
+
+        // the full coinbase carries the witness reserved value, since the template has a witness commitment
+        let full: Transaction = deserialize(&coinbase.serialize(&extranonce).unwrap()).unwrap();
+        assert_eq!(full.input[0].witness.len(), 1);
+        assert_eq!(from_parts.compute_txid(), full.compute_txid());
+
+        // with an empty merkle path, the merkle root is the txid
+        assert_eq!(
+            coinbase.merkle_root(&extranonce).unwrap(),
+            full.compute_txid().to_byte_array()
+        );
+        assert_eq!(merkle_root_from_path([1; 32], &[]), [1; 32]);
+
+        // the scriptSig is limited to 100 bytes
+        let builder = Sv2CoinbaseBuilder::new(
+            vec![Sv2CoinbasePayout::from_script(
+                ScriptBuf::from_bytes(vec![0x51]),
+                1,
+            )],
+            (100, 0),
+            100,
+        )
+        .unwrap();
+        assert_eq!(
+            builder.build(&new_template()).unwrap_err(),
+            Sv2CoinbaseError::ScriptSigTooLarge(101)
+        );
+    }
+}
@@ -0,0 +1,517 @@
+//! Allocating extranonce prefixes to the channels of a mining server.
+//!
+//! Every channel must be given an extranonce prefix that no other channel has, or their miners would search the same space.
+//! [`Sv2ExtranonceAllocator`] splits the extranonce into:
+//! - an upstream prefix, assigned by the upstream server when running as a proxy (empty otherwise)
+//! - a local prefix, unique per channel, of [`Sv2ExtranonceAllocatorConfig::local_prefix_size`] bytes
+//! - the rest, rolled by the miner
+//!
+//! Local prefixes are reclaimed when their channel is released (on `CloseChannel`, or on `remove_client` for all channels of a client),
+//! and only handed out again once every fresh prefix is taken, oldest release first. Shares and solutions still in flight for a
+//! closed channel thus can't be mistaken for ones of the channel that took over its prefix.
+//!
+//! ```ignore
+//! async fn handle_open_extended_mining_channel(
+//!     &self,
+//!     client_id: u32,
+//!     m: OpenExtendedMiningChannel<'static>,
+//! ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+//!     let channel_id = self.next_channel_id();
+//!     let prefix = self
+//!         .extranonce_allocator
+//!         .allocate(client_id, channel_id, m.min_extranonce_size as usize)?;
+//!     // reply with prefix.prefix as extranonce_prefix, and prefix.rollable_size as extranonce_size
+//! }
+//!
+//! async fn handle_close_channel(
+//!     &self,
+//!     client_id: u32,
+//!     m: CloseChannel<'static>,
+//! ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+//!     self.extranonce_allocator.release(client_id, m.channel_id);
+//!     Ok(Sv2ServerOutcome::Ok)
+//! }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::B032;
+use stratum_common::roles_logic_sv2::mining_sv2::SetExtranoncePrefix;
+
+/// The largest extranonce Sv2 allows.
+pub const MAX_EXTRANONCE_SIZE: usize = 32;
+
+/// Local prefixes are counters, so they can't be larger than a `u64`.
+const MAX_LOCAL_PREFIX_SIZE: usize = 8;
+
+/// Why an extranonce prefix could not be allocated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2ExtranonceError {
+    /// The configuration leaves no valid extranonce layout.
+    InvalidConfig(String),
+    /// The channel asked for more rollable bytes than are left after the prefix.
+    MinExtranonceSizeTooLarge { requested: usize, available: usize },
+    /// Every local prefix is taken.
+    Exhausted,
+    /// No prefix is allocated to this channel.
+    UnknownChannel { client_id: u32, channel_id: u32 },
+}
+
+/// Configuration of a [`Sv2ExtranonceAllocator`]
+#[derive(Debug, Clone)]
+pub struct Sv2ExtranonceAllocatorConfig {
+    /// The size of the full extranonce, prefixes included.
+    pub extranonce_size: usize,
+    /// The extranonce prefix assigned by the upstream server, when running as a proxy.
+    pub upstream_prefix: Vec<u8>,
+    /// How many bytes tell the channels of this server apart, at most 8.
+    pub local_prefix_size: usize,
+}
+
+impl Default for Sv2ExtranonceAllocatorConfig {
+    fn default() -> Self {
+        Self {
+            extranonce_size: MAX_EXTRANONCE_SIZE,
+            upstream_prefix: Vec::new(),
+            local_prefix_size: 4,
+        }
+    }
+}
+
+/// The extranonce prefix of a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sv2ExtranoncePrefix {
+    /// The upstream prefix followed by the local prefix of the channel.
+    pub prefix: Vec<u8>,
+    /// How many bytes are left for the miner to roll.
+    pub rollable_size: usize,
+}
+
+impl Sv2ExtranoncePrefix {
+    /// The prefix as the full extranonce of a standard channel, padded with zeros.
+    pub fn standard(&self) -> Vec<u8> {
+        let mut extranonce = self.prefix.clone();
+        extranonce.resize(self.prefix.len() + self.rollable_size, 0);
+        extranonce
+    }
+}
+
+/// Hands out unique extranonce prefixes to channels, keyed by `(client_id, channel_id)`.
+///
+/// Clones hand out prefixes from the same pool, so no two channels ever get the same prefix.
+#[derive(Debug, Clone)]
+pub struct Sv2ExtranonceAllocator {
+    state: Arc<Mutex<ExtranonceAllocatorState>>,
+}
+
+#[derive(Debug)]
+struct ExtranonceAllocatorState {
+    extranonce_size: usize,
+    upstream_prefix: Vec<u8>,
+    local_prefix_size: usize,
+    // local prefixes never handed out are >= next, which may go past u64::MAX
+    next: u128,
+    // local prefixes released by their channel, oldest first, handed out again once the fresh ones run out
+    released: VecDeque<u64>,
+    channels: HashMap<(u32, u32), AllocatedChannel>,
+}
+
+#[derive(Debug)]
+struct AllocatedChannel {
+    local_prefix: u64,
+    min_extranonce_size: usize,
+}
+
+impl ExtranonceAllocatorState {
+    fn available(&self) -> usize {
+        self.extranonce_size - self.upstream_prefix.len() - self.local_prefix_size
+    }
+
+    fn max_local_prefix(&self) -> u64 {
+        match self.local_prefix_size {
+            MAX_LOCAL_PREFIX_SIZE => u64::MAX,
+            size => (1u64 << (size * 8)) - 1,
+        }
+    }
+
+    fn prefix(&self, local_prefix: u64) -> Sv2ExtranoncePrefix {
+        let mut prefix = self.upstream_prefix.clone();
+        prefix.extend_from_slice(
+            &local_prefix.to_be_bytes()[MAX_LOCAL_PREFIX_SIZE - self.local_prefix_size..],
+        );
+        Sv2ExtranoncePrefix {
+            prefix,
+            rollable_size: self.available(),
+        }
+    }
+
+    fn next_local_prefix(&mut self) -> Result<u64, Sv2ExtranonceError> {
+        if self.next <= self.max_local_prefix() as u128 {
+            let local_prefix = self.next as u64;
+            self.next += 1;
+            return Ok(local_prefix);
+        }
+        self.released
+            .pop_front()
+            .ok_or(Sv2ExtranonceError::Exhausted)
+    }
+}
+
+fn validate(
+    extranonce_size: usize,
+    upstream_prefix: &[u8],
+    local_prefix_size: usize,
+) -> Result<(), Sv2ExtranonceError> {
+    if extranonce_size > MAX_EXTRANONCE_SIZE {
+        return Err(Sv2ExtranonceError::InvalidConfig(format!(
+            "extranonce_size {extranonce_size} is larger than {MAX_EXTRANONCE_SIZE}"
+        )));
+    }
+    if local_prefix_size == 0 || local_prefix_size > MAX_LOCAL_PREFIX_SIZE {
+        return Err(Sv2ExtranonceError::InvalidConfig(format!(
+            "local_prefix_size {local_prefix_size} is not between 1 and {MAX_LOCAL_PREFIX_SIZE}"
+        )));
+    }
+    if upstream_prefix.len() + local_prefix_size > extranonce_size {
+        return Err(Sv2ExtranonceError::InvalidConfig(format!(
+            "an upstream prefix of {} bytes and a local prefix of {local_prefix_size} bytes don't fit in {extranonce_size} bytes",
+            upstream_prefix.len()
+        )));
+    }
+    Ok(())
+}
+
+impl Default for Sv2ExtranonceAllocator {
+    fn default() -> Self {
+        Self::new(Sv2ExtranonceAllocatorConfig::default())
+            .expect("the default configuration is valid")
+    }
+}
+
+impl Sv2ExtranonceAllocator {
+    /// Creates a new [`Sv2ExtranonceAllocator`], with no channel allocated.
+    pub fn new(config: Sv2ExtranonceAllocatorConfig) -> Result<Self, Sv2ExtranonceError> {
+        validate(
+            config.extranonce_size,
+            &config.upstream_prefix,
+            config.local_prefix_size,
+        )?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(ExtranonceAllocatorState {
+                extranonce_size: config.extranonce_size,
+                upstream_prefix: config.upstream_prefix,
+                local_prefix_size: config.local_prefix_size,
+                next: 0,
+                released: VecDeque::new(),
+                channels: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Allocates a unique prefix to a channel, leaving at least `min_extranonce_size` bytes for the miner to roll.
+    ///
+    /// A channel that already has a prefix keeps it, if it leaves enough room.
+    pub fn allocate(
+        &self,
+        client_id: u32,
+        channel_id: u32,
+        min_extranonce_size: usize,
+    ) -> Result<Sv2ExtranoncePrefix, Sv2ExtranonceError> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("extranonce allocator lock poisoned");
+
+        let available = state.available();
+        if min_extranonce_size > available {
+            return Err(Sv2ExtranonceError::MinExtranonceSizeTooLarge {
+                requested: min_extranonce_size,
+                available,
+            });
+        }
+
+        let existing = state
+            .channels
+            .get_mut(&(client_id, channel_id))
+            .map(|channel| {
+                channel.min_extranonce_size = min_extranonce_size;
+                channel.local_prefix
+            });
+        let local_prefix = match existing {
+            Some(local_prefix) => local_prefix,
+            None => {
+                let local_prefix = state.next_local_prefix()?;
+                state.channels.insert(
+                    (client_id, channel_id),
+                    AllocatedChannel {
+                        local_prefix,
+                        min_extranonce_size,
+                    },
+                );
+                local_prefix
+            }
+        };
+        Ok(state.prefix(local_prefix))
+    }
+
+    /// Allocates a unique prefix to a standard channel, whose extranonce is not rolled.
+    ///
+    /// See [`Sv2ExtranoncePrefix::standard`] for the full extranonce to send in `OpenStandardMiningChannelSuccess`.
+    pub fn allocate_standard(
+        &self,
+        client_id: u32,
+        channel_id: u32,
+    ) -> Result<Sv2ExtranoncePrefix, Sv2ExtranonceError> {
+        self.allocate(client_id, channel_id, 0)
+    }
+
+    /// Returns the prefix allocated to a channel, if any.
+    pub fn get(&self, client_id: u32, channel_id: u32) -> Option<Sv2ExtranoncePrefix> {
+        let state = self
+            .state
+            .lock()
+            .expect("extranonce allocator lock poisoned");
+        state
+            .channels
+            .get(&(client_id, channel_id))
+            .map(|channel| state.prefix(channel.local_prefix))
+    }
+
+    /// Reclaims the prefix of a channel, to be called from `handle_close_channel`.
+    ///
+    /// Returns whether the channel had a prefix.
+    pub fn release(&self, client_id: u32, channel_id: u32) -> bool {
+        let mut state = self
+            .state
+            .lock()
+            .expect("extranonce allocator lock poisoned");
+        match state.channels.remove(&(client_id, channel_id)) {
+            Some(channel) => {
+                state.released.push_back(channel.local_prefix);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reclaims the prefixes of every channel of a client, to be called from `remove_client`.
+    ///
+    /// Returns how many prefixes were reclaimed.
+    pub fn release_client(&self, client_id: u32) -> usize {
+        let mut state = self
+            .state
+            .lock()
+            .expect("extranonce allocator lock poisoned");
+        let channel_ids: Vec<(u32, u32)> = state
+            .channels
+            .keys()
+            .filter(|(id, _)| *id == client_id)
+            .copied()
+            .collect();
+        for key in &channel_ids {
+            if let Some(channel) = state.channels.remove(key) {
+                state.released.push_back(channel.local_prefix);
+            }
+        }
+        channel_ids.len()
+    }
+
+    /// Gives a channel a new prefix, e.g.: when its current prefix must not be mined on anymore.
+    ///
+    /// The old prefix is only reclaimed after the new one is allocated, so they always differ.
+    /// The result is meant to be sent to the client via [`Self::set_extranonce_prefix`].
+    pub fn reallocate(
+        &self,
+        client_id: u32,
+        channel_id: u32,
+    ) -> Result<Sv2ExtranoncePrefix, Sv2ExtranonceError> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("extranonce allocator lock poisoned");
+        let old_local_prefix = state
+            .channels
+            .get(&(client_id, channel_id))
+            .map(|channel| channel.local_prefix)
+            .ok_or(Sv2ExtranonceError::UnknownChannel {
+                client_id,
+                channel_id,
+            })?;
+
+        let local_prefix = state.next_local_prefix()?;
+        if let Some(channel) = state.channels.get_mut(&(client_id, channel_id)) {
+            channel.local_prefix = local_prefix;
+        }
+        state.released.push_back(old_local_prefix);
+        Ok(state.prefix(local_prefix))
+    }
+
+    /// Replaces the upstream prefix, e.g.: when a proxy receives `SetExtranoncePrefix` from its upstream server.
+    ///
+    /// Every channel keeps its local prefix, and the new prefixes of all channels are returned, keyed by
+    /// `(client_id, channel_id)`, to be sent to the clients via [`Self::set_extranonce_prefix`].
+    /// Nothing changes if the new upstream prefix leaves too little room for the `min_extranonce_size` of some channel.
+    pub fn set_upstream_prefix(
+        &self,
+        upstream_prefix: Vec<u8>,
+    ) -> Result<Vec<((u32, u32), Sv2ExtranoncePrefix)>, Sv2ExtranonceError> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("extranonce allocator lock poisoned");
+        validate(
+            state.extranonce_size,
+            &upstream_prefix,
+            state.local_prefix_size,
+        )?;
+
+        let available = state.extranonce_size - upstream_prefix.len() - state.local_prefix_size;
+        if let Some(requested) = state
+            .channels
+            .values()
+            .map(|channel| channel.min_extranonce_size)
+            .max()
+            .filter(|requested| *requested > available)
+        {
+            return Err(Sv2ExtranonceError::MinExtranonceSizeTooLarge {
+                requested,
+                available,
+            });
+        }
+
+        state.upstream_prefix = upstream_prefix;
+        Ok(state
+            .channels
+            .iter()
+            .map(|(key, channel)| (*key, state.prefix(channel.local_prefix)))
+            .collect())
+    }
+
+    /// Builds the `SetExtranoncePrefix` message announcing `prefix` to a channel.
+    pub fn set_extranonce_prefix(
+        channel_id: u32,
+        prefix: &Sv2ExtranoncePrefix,
+    ) -> Result<SetExtranoncePrefix<'static>, Sv2ExtranonceError> {
+        let extranonce_prefix: B032<'static> =
+            prefix.prefix.clone().try_into().map_err(|_| {
+                Sv2ExtranonceError::InvalidConfig("prefix larger than 32 bytes".into())
+            })?;
+        Ok(SetExtranoncePrefix {
+            channel_id,
+            extranonce_prefix,
+        })
+    }
+
+    /// How many channels have a prefix.
+    pub fn len(&self) -> usize {
+        self.state
+            .lock()
+            .expect("extranonce allocator lock poisoned")
+            .channels
+            .len()
+    }
+
+    /// Whether no channel has a prefix.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2ExtranonceAllocator, Sv2ExtranonceAllocatorConfig, Sv2ExtranonceError};
+
+    #[test]
+    fn prefixes_are_unique_and_reclaimed() {
+        let allocator = Sv2ExtranonceAllocator::new(Sv2ExtranonceAllocatorConfig {
+            extranonce_size: 8,
+            upstream_prefix: vec![0xaa],
+            local_prefix_size: 1,
+        })
+        .unwrap();
+
+        // two channels of the same client get different prefixes
+        let first = allocator.allocate(1, 1, 4).unwrap();
+        let second = allocator.allocate(1, 2, 4).unwrap();
+        assert_eq!(first.prefix, vec![0xaa, 0]);
+        assert_eq!(second.prefix, vec![0xaa, 1]);
+        assert_eq!(first.rollable_size, 6);
+        assert_eq!(allocator.allocate(1, 1, 4).unwrap(), first);
+
+        assert_eq!(
+            allocator.allocate(2, 1, 7),
+            Err(Sv2ExtranonceError::MinExtranonceSizeTooLarge {
+                requested: 7,
+                available: 6
+            })
+        );
+
+        // a standard channel gets the whole extranonce
+        let standard = allocator.allocate_standard(2, 1).unwrap();
+        assert_eq!(standard.standard(), vec![0xaa, 2, 0, 0, 0, 0, 0, 0]);
+
+        // released prefixes are only handed out again once the fresh ones run out
+        assert!(allocator.release(1, 1));
+        assert!(!allocator.release(1, 1));
+        assert_eq!(allocator.allocate(3, 1, 0).unwrap().prefix, vec![0xaa, 3]);
+        assert_eq!(allocator.release_client(1), 1);
+        assert_eq!(allocator.release_client(2), 1);
+        assert_eq!(allocator.len(), 1);
+
+        // a local prefix of 1 byte leaves room for 256 channels, the released ones coming last in the order they were released
+        let local_prefixes: Vec<u8> = (0..255)
+            .map(|channel_id| allocator.allocate(4, channel_id, 0).unwrap().prefix[1])
+            .collect();
+        assert_eq!(local_prefixes[..252], (4..=255).collect::<Vec<u8>>()[..]);
+        assert_eq!(local_prefixes[252..], [0, 1, 2]);
+        assert_eq!(
+            allocator.allocate(5, 1, 0),
+            Err(Sv2ExtranonceError::Exhausted)
+        );
+    }
+
+    #[test]
+    fn prefixes_are_reallocated() {
+        let allocator = Sv2ExtranonceAllocator::new(Sv2ExtranonceAllocatorConfig {
+            extranonce_size: 8,
+            upstream_prefix: vec![],
+            local_prefix_size: 2,
+        })
+        .unwrap();
+        let first = allocator.allocate(1, 1, 4).unwrap();
+        allocator.allocate(1, 2, 2).unwrap();
+
+        let reallocated = allocator.reallocate(1, 1).unwrap();
+        assert_ne!(reallocated, first);
+        assert_eq!(allocator.get(1, 1), Some(reallocated.clone()));
+        assert_eq!(
+            allocator.reallocate(1, 3),
+            Err(Sv2ExtranonceError::UnknownChannel {
+                client_id: 1,
+                channel_id: 3
+            })
+        );
+
+        let message = Sv2ExtranonceAllocator::set_extranonce_prefix(1, &reallocated).unwrap();
+        assert_eq!(message.channel_id, 1);
+        assert_eq!(
+            message.extranonce_prefix.inner_as_ref(),
+            &reallocated.prefix[..]
+        );
+
+        // an upstream prefix of 3 bytes leaves 3 rollable bytes, which is too few for channel 1
+        assert_eq!(
+            allocator.set_upstream_prefix(vec![1, 2, 3]),
+            Err(Sv2ExtranonceError::MinExtranonceSizeTooLarge {
+                requested: 4,
+                available: 3
+            })
+        );
+        let prefixes = allocator.set_upstream_prefix(vec![1, 2]).unwrap();
+        assert_eq!(prefixes.len(), 2);
+        assert!(prefixes
+            .iter()
+            .all(|(_, prefix)| prefix.prefix.starts_with(&[1, 2]) && prefix.rollable_size == 4));
+        assert_eq!(allocator.get(1, 1).unwrap().prefix[..2], [1, 2]);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::server::service::event::Sv2ServerEventError;
 use crate::server::service::outcome::Sv2ServerOutcome;
 
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
 use stratum_common::roles_logic_sv2::mining_sv2::{
     CloseChannel, OpenExtendedMiningChannel, OpenStandardMiningChannel, SetCustomMiningJob,
     SubmitSharesExtended, SubmitSharesStandard, UpdateChannel,
@@ -13,6 +14,14 @@ use stratum_common::roles_logic_sv2::template_distribution_sv2::{NewTemplate, Se
 /// [`crate::server::service::Sv2ServerServiceClient`] in the [`crate::server::service::Sv2ServerService`].
 ///
 /// Removing a client on [`crate::server::service::Sv2ServerService`] also triggers removing the client on this handler.
+///
+/// The handler is cloned into every per-client task, so methods for different clients may run in parallel.
+/// Only the messages sent by a client are ordered: they are handled one at a time, in the order they were received.
+/// Everything else (`on_new_template`, `on_set_new_prev_hash`, and events from siblings, timers or broadcasts)
+/// runs on other tasks, concurrently with the messages of every client.
+///
+/// The service does not lock any state on behalf of the handler. [`crate::server::service::state::Sv2ServerHandlerState`]
+/// can be used to keep per-client and global state, as long as every method that touches some state takes its lock.
 pub trait Sv2MiningServerHandler {
     fn start(
         &mut self,
@@ -71,14 +80,14 @@ pub trait Sv2MiningServerHandler {
     ) -> impl std::future::Future<Output = Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> + Send;
 
     /// This is for apps that also operate as a Sv2ClientService with support to Template Distribution subprotocol
-    /// If Template Distribution subprotocol is not supported, this method should be marked with `unimplemented!()`.
+    /// If Template Distribution subprotocol is not supported, this method should return [`Sv2ServerEventError::UnsupportedProtocol`].
     fn on_new_template(
         &self,
         m: NewTemplate<'static>,
     ) -> impl std::future::Future<Output = Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> + Send;
 
     /// This is for apps that also operate as a Sv2ClientService with support to Template Distribution subprotocol
-    /// If Template Distribution subprotocol is not supported, this method should be marked with `unimplemented!()`.
+    /// If Template Distribution subprotocol is not supported, this method should return [`Sv2ServerEventError::UnsupportedProtocol`].
     fn on_set_new_prev_hash(
         &self,
         m: SetNewPrevHash<'static>,
@@ -93,28 +102,29 @@ pub trait Sv2MiningServerHandler {
 ///
 /// It should be used when creating a [`crate::server::service::Sv2ServerService`] that
 /// does not support the mining subprotocol.
+///
+/// It never panics: every handler method returns [`Sv2ServerEventError::UnsupportedProtocol`],
+/// while client bookkeeping methods are no-ops.
 #[derive(Debug, Clone)]
 pub struct NullSv2MiningServerHandler;
 
 impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
     async fn start(&mut self) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!("NullSv2MiningServerHandler does not implement start");
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// The subprotocol flags to be used on SetupConnectionSuccess
     fn setup_connection_success_flags(&self) -> u32 {
-        unimplemented!("NullSv2MiningServerHandler does not implement return_flags")
+        0
     }
 
     /// Add a client to the subprotocol handler
-    async fn add_client(&mut self, _client_id: u32, _flags: u32) {
-        unimplemented!("NullSv2MiningServerHandler does not implement add_client")
-    }
+    async fn add_client(&mut self, _client_id: u32, _flags: u32) {}
 
     /// Remove a client from the subprotocol handler
-    async fn remove_client(&mut self, _client_id: u32) {
-        unimplemented!("NullSv2MiningServerHandler does not implement remove_client")
-    }
+    async fn remove_client(&mut self, _client_id: u32) {}
 
     /// Handle an OpenStandardMiningChannel message
     async fn handle_open_standard_mining_channel(
@@ -122,9 +132,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: OpenStandardMiningChannel<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!(
-            "NullSv2MiningServerHandler does not implement handle_open_standard_mining_channel"
-        )
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle an OpenExtendedMiningChannel message
@@ -133,9 +143,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: OpenExtendedMiningChannel<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!(
-            "NullSv2MiningServerHandler does not implement handle_open_extended_mining_channel"
-        )
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle an UpdateChannel message
@@ -144,7 +154,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: UpdateChannel<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!("NullSv2MiningServerHandler does not implement handle_update_channel")
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle a CloseChannel message
@@ -153,7 +165,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: CloseChannel<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!("NullSv2MiningServerHandler does not implement handle_close_channel")
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle a SubmitSharesStandard message
@@ -162,9 +176,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: SubmitSharesStandard,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!(
-            "NullSv2MiningServerHandler does not implement handle_submit_shares_standard"
-        )
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle a SubmitSharesExtended message
@@ -173,9 +187,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: SubmitSharesExtended<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!(
-            "NullSv2MiningServerHandler does not implement handle_submit_shares_extended"
-        )
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle a SetCustomMiningJob message
@@ -184,7 +198,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         _client_id: u32,
         _m: SetCustomMiningJob<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!("NullSv2MiningServerHandler does not implement handle_set_custom_mining_job")
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle a NewTemplate message
@@ -192,7 +208,9 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         &self,
         _m: NewTemplate<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!("NullSv2MiningServerHandler does not implement on_new_template")
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 
     /// Handle a SetNewPrevHash message
@@ -200,6 +218,8 @@ impl Sv2MiningServerHandler for NullSv2MiningServerHandler {
         &self,
         _m: SetNewPrevHash<'static>,
     ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
-        unimplemented!("NullSv2MiningServerHandler does not implement on_set_new_prev_hash")
+        Err(Sv2ServerEventError::UnsupportedProtocol {
+            protocol: Protocol::MiningProtocol,
+        })
     }
 }
@@ -1,2 +1,5 @@
+pub mod accounting;
+pub mod coinbase;
+pub mod extranonce;
 pub mod handler;
 pub mod trigger;
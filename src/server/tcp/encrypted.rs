@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::Sv2MessageIo;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
 use std::net::SocketAddr;
@@ -48,10 +49,8 @@ pub async fn start_encrypted_tcp_server(
                         )
                         .await
                     {
-                        let sv2_message_io = Sv2MessageIo {
-                            rx,
-                            tx,
-                        };
+                        metrics::record_tcp_connection(true);
+                        let sv2_message_io = Sv2MessageIo::new(rx, tx, Some(addr));
 
                         // Send the new client's IO to the service layer
                         if new_client_tx.send(sv2_message_io).await.is_ok() {
@@ -60,6 +59,7 @@ pub async fn start_encrypted_tcp_server(
                             tracing::error!("Failed to send new client to service layer for {}", addr);
                         }
                     } else {
+                        metrics::record_tcp_connection(false);
                         tracing::warn!("Failed to perform handshake with client {}", addr);
                     }
                 }
@@ -5,6 +5,7 @@ use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 
+use crate::metrics;
 use crate::Sv2MessageIo;
 
 /// A function that creates a TCP server that listens for clients without Sv2 noise encryption.
@@ -33,7 +34,8 @@ pub async fn start_unencrypted_tcp_server(
                     let (rx, tx) =
                     PlainConnection::new::<'static, AnyMessage<'static>>(stream).await;
 
-                    let sv2_message_io = Sv2MessageIo { rx, tx };
+                    metrics::record_tcp_connection(true);
+                    let sv2_message_io = Sv2MessageIo::new(rx, tx, Some(addr));
 
                     if new_client_tx.send(sv2_message_io).await.is_ok() {
                         tracing::debug!("Connected to: {}", addr);
@@ -0,0 +1,603 @@
+//! A [`Sv2SiblingBus`] connects any number of [`crate::server::service::Sv2ServerService`]s and
+//! [`crate::client::service::Sv2ClientService`]s that live within the same application.
+//!
+//! Every service registers on the bus under a unique name and gets a [`Sv2SiblingIo`],
+//! which it uses to receive events addressed to it, and to send events to any other sibling by name.
+//!
+//! For example, a proxy could register:
+//! - `"mining_server"`: a server for downstream miners
+//! - `"pool_client"`: a client for the upstream pool
+//! - `"jds_client"`: a client for the Job Declaration Server
+//! - `"tp_client"`: a client for the Template Provider
+//!
+//! Every inbox is bounded to the capacity of the bus, so that a slow sibling can't grow memory without limit.
+//! Sending to a full inbox waits for a free slot, for up to the send timeout of the bus
+//! (see [`Sv2SiblingBus::with_send_timeout`]), and only then fails with [`Sv2SiblingBusError::SiblingFull`].
+//! The timeout is what breaks a cycle: every service handles its sibling events one at a time, so two siblings
+//! waiting on each other's full inbox would otherwise never drain either of them again.
+//! Senders that would rather fail right away use `try_send_to_server` / `try_send_to_client`.
+
+use crate::client::service::event::Sv2ClientEvent;
+use crate::metrics;
+use crate::server::service::event::Sv2ServerEvent;
+use crate::ChannelUsageWarning;
+
+use async_channel::{Receiver, RecvError, Sender, TrySendError};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long sending to a full inbox waits for a free slot, unless set with [`Sv2SiblingBus::with_send_timeout`].
+pub const DEFAULT_SIBLING_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The [`Sv2SiblingIo`] of a [`crate::server::service::Sv2ServerService`].
+pub type Sv2ServerSiblingIo = Sv2SiblingIo<Sv2ServerEvent<'static>>;
+
+/// The [`Sv2SiblingIo`] of a [`crate::client::service::Sv2ClientService`].
+pub type Sv2ClientSiblingIo = Sv2SiblingIo<Sv2ClientEvent<'static>>;
+
+type ServerSender = Sender<Box<Sv2ServerEvent<'static>>>;
+type ClientSender = Sender<Box<Sv2ClientEvent<'static>>>;
+
+// the sending side of the inbox of a registered sibling
+#[derive(Debug, Clone)]
+enum SiblingSender {
+    Server(ServerSender),
+    Client(ClientSender),
+}
+
+#[derive(Debug, Clone)]
+struct SiblingInbox {
+    sender: SiblingSender,
+    usage_warning: Arc<ChannelUsageWarning>,
+}
+
+/// A registry of sibling services, indexed by name.
+///
+/// Clones share the same registry.
+#[derive(Debug, Clone)]
+pub struct Sv2SiblingBus {
+    inboxes: Arc<DashMap<String, SiblingInbox>>,
+    capacity: usize,
+    send_timeout: Duration,
+}
+
+impl Sv2SiblingBus {
+    /// Creates a new [`Sv2SiblingBus`], where every inbox holds up to `capacity` events.
+    ///
+    /// Fails with [`Sv2SiblingBusError::InvalidCapacity`] if `capacity` is 0.
+    pub fn new(capacity: usize) -> Result<Self, Sv2SiblingBusError> {
+        if capacity == 0 {
+            return Err(Sv2SiblingBusError::InvalidCapacity);
+        }
+        Ok(Self {
+            inboxes: Arc::new(DashMap::new()),
+            capacity,
+            send_timeout: DEFAULT_SIBLING_SEND_TIMEOUT,
+        })
+    }
+
+    /// Sets how long sending to a full inbox waits for a free slot, before failing with [`Sv2SiblingBusError::SiblingFull`].
+    ///
+    /// To be called before registering any sibling, since every [`Sv2SiblingIo`] keeps a copy of the bus.
+    pub fn with_send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    /// Registers a server service under `name`.
+    ///
+    /// The returned [`Sv2ServerSiblingIo`] is used as input to [`crate::server::service::Sv2ServerService::new_from_sibling_io`].
+    pub fn register_server(
+        &self,
+        name: impl Into<String>,
+    ) -> Result<Sv2ServerSiblingIo, Sv2SiblingBusError> {
+        self.register(name.into(), SiblingSender::Server)
+    }
+
+    /// Registers a client service under `name`.
+    ///
+    /// The returned [`Sv2ClientSiblingIo`] is used as input to [`crate::client::service::Sv2ClientService::new_from_sibling_io`].
+    pub fn register_client(
+        &self,
+        name: impl Into<String>,
+    ) -> Result<Sv2ClientSiblingIo, Sv2SiblingBusError> {
+        self.register(name.into(), SiblingSender::Client)
+    }
+
+    fn register<E>(
+        &self,
+        name: String,
+        sender: impl FnOnce(Sender<Box<E>>) -> SiblingSender,
+    ) -> Result<Sv2SiblingIo<E>, Sv2SiblingBusError> {
+        match self.inboxes.entry(name.clone()) {
+            Entry::Occupied(_) => Err(Sv2SiblingBusError::NameAlreadyRegistered(name)),
+            Entry::Vacant(entry) => {
+                let (tx, rx) = async_channel::bounded::<Box<E>>(self.capacity);
+                entry.insert(SiblingInbox {
+                    sender: sender(tx),
+                    usage_warning: Arc::new(ChannelUsageWarning::default()),
+                });
+                Ok(Sv2SiblingIo {
+                    name,
+                    rx,
+                    bus: self.clone(),
+                })
+            }
+        }
+    }
+
+    /// Returns the names of all registered siblings.
+    pub fn siblings(&self) -> Vec<String> {
+        self.inboxes
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Returns whether some sibling is registered under `name`.
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.inboxes.contains_key(name)
+    }
+
+    /// Sends an event to the server service registered under `sibling`.
+    ///
+    /// Waits for a free slot if its inbox is full, and fails with [`Sv2SiblingBusError::SiblingFull`]
+    /// if none frees up within the send timeout.
+    pub async fn send_to_server(
+        &self,
+        sibling: &str,
+        event: Sv2ServerEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        let (tx, usage_warning) = self.server_inbox(sibling)?;
+        self.send(sibling, &tx, &usage_warning, Box::new(event))
+            .await
+    }
+
+    /// Sends an event to the client service registered under `sibling`.
+    ///
+    /// Waits for a free slot if its inbox is full, and fails with [`Sv2SiblingBusError::SiblingFull`]
+    /// if none frees up within the send timeout.
+    pub async fn send_to_client(
+        &self,
+        sibling: &str,
+        event: Sv2ClientEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        let (tx, usage_warning) = self.client_inbox(sibling)?;
+        self.send(sibling, &tx, &usage_warning, Box::new(event))
+            .await
+    }
+
+    /// Like [`Self::send_to_server`], but fails with [`Sv2SiblingBusError::SiblingFull`] right away if the inbox is full.
+    pub fn try_send_to_server(
+        &self,
+        sibling: &str,
+        event: Sv2ServerEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        let (tx, usage_warning) = self.server_inbox(sibling)?;
+        Self::try_send(sibling, &tx, &usage_warning, Box::new(event))
+    }
+
+    /// Like [`Self::send_to_client`], but fails with [`Sv2SiblingBusError::SiblingFull`] right away if the inbox is full.
+    pub fn try_send_to_client(
+        &self,
+        sibling: &str,
+        event: Sv2ClientEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        let (tx, usage_warning) = self.client_inbox(sibling)?;
+        Self::try_send(sibling, &tx, &usage_warning, Box::new(event))
+    }
+
+    fn server_inbox(
+        &self,
+        sibling: &str,
+    ) -> Result<(ServerSender, Arc<ChannelUsageWarning>), Sv2SiblingBusError> {
+        let inbox = self.inbox(sibling)?;
+        match inbox.sender {
+            SiblingSender::Server(tx) => Ok((tx, inbox.usage_warning)),
+            SiblingSender::Client(_) => Err(Sv2SiblingBusError::NotAServer(sibling.to_string())),
+        }
+    }
+
+    fn client_inbox(
+        &self,
+        sibling: &str,
+    ) -> Result<(ClientSender, Arc<ChannelUsageWarning>), Sv2SiblingBusError> {
+        let inbox = self.inbox(sibling)?;
+        match inbox.sender {
+            SiblingSender::Client(tx) => Ok((tx, inbox.usage_warning)),
+            SiblingSender::Server(_) => Err(Sv2SiblingBusError::NotAClient(sibling.to_string())),
+        }
+    }
+
+    async fn send<E>(
+        &self,
+        sibling: &str,
+        tx: &Sender<E>,
+        usage_warning: &ChannelUsageWarning,
+        event: E,
+    ) -> Result<(), Sv2SiblingBusError> {
+        match tokio::time::timeout(self.send_timeout, tx.send(event)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(Sv2SiblingBusError::SiblingClosed(sibling.to_string())),
+            Err(_) => return Err(Sv2SiblingBusError::SiblingFull(sibling.to_string())),
+        }
+        Self::observe(sibling, tx, usage_warning);
+        Ok(())
+    }
+
+    fn try_send<E>(
+        sibling: &str,
+        tx: &Sender<E>,
+        usage_warning: &ChannelUsageWarning,
+        event: E,
+    ) -> Result<(), Sv2SiblingBusError> {
+        tx.try_send(event).map_err(|e| match e {
+            TrySendError::Full(_) => Sv2SiblingBusError::SiblingFull(sibling.to_string()),
+            TrySendError::Closed(_) => Sv2SiblingBusError::SiblingClosed(sibling.to_string()),
+        })?;
+        Self::observe(sibling, tx, usage_warning);
+        Ok(())
+    }
+
+    fn observe<E>(sibling: &str, tx: &Sender<E>, usage_warning: &ChannelUsageWarning) {
+        usage_warning.observe(
+            &format!("Inbox of sibling {sibling}"),
+            tx.len(),
+            tx.capacity(),
+        );
+        metrics::record_sibling_queue_depth(sibling, tx.len());
+    }
+
+    // clones the sender, so that the DashMap shard is not locked while sending
+    fn inbox(&self, sibling: &str) -> Result<SiblingInbox, Sv2SiblingBusError> {
+        self.inboxes
+            .get(sibling)
+            .map(|entry| entry.clone())
+            .ok_or_else(|| Sv2SiblingBusError::SiblingNotFound(sibling.to_string()))
+    }
+
+    fn deregister(&self, name: &str) {
+        self.inboxes.remove(name);
+    }
+}
+
+/// The endpoint of a service registered on a [`Sv2SiblingBus`].
+///
+/// `E` is the event type of the service (i.e.: what it receives from its siblings).
+#[derive(Debug, Clone)]
+pub struct Sv2SiblingIo<E> {
+    name: String,
+    rx: Receiver<Box<E>>,
+    bus: Sv2SiblingBus,
+}
+
+impl<E> Sv2SiblingIo<E> {
+    /// The name this service was registered with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The bus this service is registered on.
+    pub fn bus(&self) -> &Sv2SiblingBus {
+        &self.bus
+    }
+
+    /// Receive an event from some sibling.
+    pub async fn recv(&self) -> Result<Box<E>, RecvError> {
+        let event = self.rx.recv().await?;
+        metrics::record_sibling_queue_depth(&self.name, self.rx.len());
+        Ok(event)
+    }
+
+    /// Send an event to the server service registered under `sibling`.
+    pub async fn send_to_server(
+        &self,
+        sibling: &str,
+        event: Sv2ServerEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        self.bus.send_to_server(sibling, event).await
+    }
+
+    /// Send an event to the client service registered under `sibling`.
+    pub async fn send_to_client(
+        &self,
+        sibling: &str,
+        event: Sv2ClientEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        self.bus.send_to_client(sibling, event).await
+    }
+
+    /// Send an event to the server service registered under `sibling`, failing right away if its inbox is full.
+    pub fn try_send_to_server(
+        &self,
+        sibling: &str,
+        event: Sv2ServerEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        self.bus.try_send_to_server(sibling, event)
+    }
+
+    /// Send an event to the client service registered under `sibling`, failing right away if its inbox is full.
+    pub fn try_send_to_client(
+        &self,
+        sibling: &str,
+        event: Sv2ClientEvent<'static>,
+    ) -> Result<(), Sv2SiblingBusError> {
+        self.bus.try_send_to_client(sibling, event)
+    }
+
+    /// Number of events waiting to be received by this service.
+    pub fn pending(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Removes this service from the bus and closes its inbox.
+    pub fn shutdown(&self) {
+        self.bus.deregister(&self.name);
+        self.rx.close();
+    }
+}
+
+/// Errors that can occur when working with a [`Sv2SiblingBus`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2SiblingBusError {
+    /// The bus was created with a capacity of 0.
+    InvalidCapacity,
+    /// Some sibling is already registered under this name.
+    NameAlreadyRegistered(String),
+    /// No sibling is registered under this name.
+    SiblingNotFound(String),
+    /// A server event was addressed to a sibling that is not a server service.
+    NotAServer(String),
+    /// A client event was addressed to a sibling that is not a client service.
+    NotAClient(String),
+    /// The inbox of the sibling was closed.
+    SiblingClosed(String),
+    /// The inbox of the sibling stayed full for the whole send timeout (or was full, with `try_send_*`):
+    /// it is not keeping up with the events sent to it.
+    SiblingFull(String),
+}
+
+impl fmt::Display for Sv2SiblingBusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sv2SiblingBusError::InvalidCapacity => {
+                write!(f, "Sibling bus capacity must be greater than 0")
+            }
+            Sv2SiblingBusError::NameAlreadyRegistered(name) => {
+                write!(f, "Sibling {name} is already registered")
+            }
+            Sv2SiblingBusError::SiblingNotFound(name) => write!(f, "Sibling {name} not found"),
+            Sv2SiblingBusError::NotAServer(name) => {
+                write!(f, "Sibling {name} is not a server service")
+            }
+            Sv2SiblingBusError::NotAClient(name) => {
+                write!(f, "Sibling {name} is not a client service")
+            }
+            Sv2SiblingBusError::SiblingClosed(name) => write!(f, "Sibling {name} is closed"),
+            Sv2SiblingBusError::SiblingFull(name) => write!(f, "Sibling {name} inbox is full"),
+        }
+    }
+}
+
+impl std::error::Error for Sv2SiblingBusError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2SiblingBus, Sv2SiblingBusError};
+    use crate::client::service::event::Sv2ClientEvent;
+    use crate::client::service::subprotocols::template_distribution::trigger::TemplateDistributionClientTrigger;
+    use crate::server::service::event::Sv2ServerEvent;
+    use std::time::Duration;
+
+    fn client_event() -> Sv2ClientEvent<'static> {
+        Sv2ClientEvent::TemplateDistributionTrigger(
+            TemplateDistributionClientTrigger::TransactionDataNeeded(0),
+        )
+    }
+
+    fn server_event() -> Sv2ServerEvent<'static> {
+        Sv2ServerEvent::DisconnectClient(0)
+    }
+
+    #[tokio::test]
+    async fn events_are_routed_by_name() {
+        let bus = Sv2SiblingBus::new(8).unwrap();
+        let mining_server = bus.register_server("mining_server").unwrap();
+        let tp_client = bus.register_client("tp_client").unwrap();
+        let pool_client = bus.register_client("pool_client").unwrap();
+
+        assert_eq!(
+            bus.register_client("tp_client").unwrap_err(),
+            Sv2SiblingBusError::NameAlreadyRegistered("tp_client".to_string())
+        );
+
+        tp_client
+            .send_to_server("mining_server", server_event())
+            .await
+            .unwrap();
+        mining_server
+            .send_to_client("pool_client", client_event())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            *mining_server.recv().await.unwrap(),
+            Sv2ServerEvent::DisconnectClient(0)
+        ));
+        assert_eq!(pool_client.pending(), 1);
+        assert_eq!(tp_client.pending(), 0);
+
+        assert_eq!(
+            mining_server
+                .send_to_server("tp_client", server_event())
+                .await
+                .unwrap_err(),
+            Sv2SiblingBusError::NotAServer("tp_client".to_string())
+        );
+        assert_eq!(
+            mining_server
+                .send_to_client("jds_client", client_event())
+                .await
+                .unwrap_err(),
+            Sv2SiblingBusError::SiblingNotFound("jds_client".to_string())
+        );
+
+        // a sibling that shuts down leaves the bus
+        pool_client.shutdown();
+        assert!(!bus.is_registered("pool_client"));
+        assert_eq!(bus.siblings().len(), 2);
+    }
+
+    #[test]
+    fn capacity_must_be_positive() {
+        assert_eq!(
+            Sv2SiblingBus::new(0).unwrap_err(),
+            Sv2SiblingBusError::InvalidCapacity
+        );
+    }
+
+    #[tokio::test]
+    async fn senders_wait_for_a_free_slot() {
+        let bus = Sv2SiblingBus::new(1).unwrap();
+        let mining_server = bus.register_server("mining_server").unwrap();
+        let tp_client = bus.register_client("tp_client").unwrap();
+
+        mining_server
+            .send_to_client("tp_client", client_event())
+            .await
+            .unwrap();
+
+        // the inbox is full, so the next send waits
+        let sender = mining_server.clone();
+        let send =
+            tokio::spawn(async move { sender.send_to_client("tp_client", client_event()).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!send.is_finished());
+
+        // receiving frees a slot for the sender
+        tp_client.recv().await.unwrap();
+        send.await.unwrap().unwrap();
+        assert_eq!(tp_client.pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn full_inboxes_reject_events_after_the_send_timeout() {
+        let bus = Sv2SiblingBus::new(1)
+            .unwrap()
+            .with_send_timeout(Duration::from_millis(50));
+        let mining_server = bus.register_server("mining_server").unwrap();
+        let _tp_client = bus.register_client("tp_client").unwrap();
+
+        mining_server
+            .send_to_client("tp_client", client_event())
+            .await
+            .unwrap();
+        assert_eq!(
+            mining_server
+                .send_to_client("tp_client", client_event())
+                .await
+                .unwrap_err(),
+            Sv2SiblingBusError::SiblingFull("tp_client".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn try_send_rejects_full_inboxes_right_away() {
+        let bus = Sv2SiblingBus::new(2).unwrap();
+        let mining_server = bus.register_server("mining_server").unwrap();
+        let tp_client = bus.register_client("tp_client").unwrap();
+
+        mining_server
+            .try_send_to_client("tp_client", client_event())
+            .unwrap();
+        mining_server
+            .try_send_to_client("tp_client", client_event())
+            .unwrap();
+        assert_eq!(
+            mining_server
+                .try_send_to_client("tp_client", client_event())
+                .unwrap_err(),
+            Sv2SiblingBusError::SiblingFull("tp_client".to_string())
+        );
+
+        tp_client.recv().await.unwrap();
+        mining_server
+            .try_send_to_client("tp_client", client_event())
+            .unwrap();
+        assert_eq!(tp_client.pending(), 2);
+    }
+
+    #[tokio::test]
+    async fn siblings_sending_to_each_other_never_deadlock() {
+        let bus = Sv2SiblingBus::new(1)
+            .unwrap()
+            .with_send_timeout(Duration::from_millis(20));
+        let mining_server = bus.register_server("mining_server").unwrap();
+        let tp_client = bus.register_client("tp_client").unwrap();
+
+        // both inboxes start full
+        tp_client
+            .send_to_server("mining_server", server_event())
+            .await
+            .unwrap();
+        mining_server
+            .send_to_client("tp_client", client_event())
+            .await
+            .unwrap();
+
+        // like the services, each sibling handles its events one at a time,
+        // and every event it handles sends two events to the other sibling (for a while, since that never settles)
+        let server_loop = tokio::spawn(async move {
+            let mut rejected = 0;
+            for _ in 0..10 {
+                let Ok(Ok(_)) =
+                    tokio::time::timeout(Duration::from_millis(100), mining_server.recv()).await
+                else {
+                    break;
+                };
+                for _ in 0..2 {
+                    if mining_server
+                        .send_to_client("tp_client", client_event())
+                        .await
+                        .is_err()
+                    {
+                        rejected += 1;
+                    }
+                }
+            }
+            rejected
+        });
+        let client_loop = tokio::spawn(async move {
+            let mut rejected = 0;
+            for _ in 0..10 {
+                let Ok(Ok(_)) =
+                    tokio::time::timeout(Duration::from_millis(100), tp_client.recv()).await
+                else {
+                    break;
+                };
+                for _ in 0..2 {
+                    if tp_client
+                        .send_to_server("mining_server", server_event())
+                        .await
+                        .is_err()
+                    {
+                        rejected += 1;
+                    }
+                }
+            }
+            rejected
+        });
+
+        // the send timeout breaks the cycle
+        let (server_rejected, client_rejected) =
+            tokio::time::timeout(Duration::from_secs(5), async {
+                (server_loop.await.unwrap(), client_loop.await.unwrap())
+            })
+            .await
+            .expect("siblings deadlocked");
+        assert!(server_rejected + client_rejected > 0);
+    }
+}
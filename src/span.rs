@@ -0,0 +1,238 @@
+//! `tracing` spans around the events handled by the services.
+//!
+//! Every event is handled inside a span carrying the event name, plus (whenever they apply) the `client_id`,
+//! `protocol`, `channel_id` and `message_type` it refers to. Logs can then be filtered for a single miner,
+//! and spans exported (e.g.: to OpenTelemetry) via the subscriber of the application.
+
+use crate::client::service::event::Sv2ClientEvent;
+use crate::server::service::event::Sv2ServerEvent;
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
+use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages, IsSv2Message, Mining};
+use tracing::field::{debug, display, Empty};
+use tracing::{info_span, Span};
+
+/// The span for a [`Sv2ServerEvent`].
+///
+/// `client_protocol` looks up the protocol a client negotiated on SetupConnection, for messages that don't carry it.
+pub(crate) fn server_event_span(
+    event: &Sv2ServerEvent<'_>,
+    client_protocol: impl FnOnce(u32) -> Option<Protocol>,
+) -> Span {
+    let span = info_span!(
+        "sv2_server_event",
+        event = event.name(),
+        client_id = Empty,
+        protocol = Empty,
+        channel_id = Empty,
+        message_type = Empty,
+    );
+
+    match event {
+        Sv2ServerEvent::IncomingMessage(message_to_server) => {
+            if let Some(client_id) = message_to_server.client_id {
+                span.record("client_id", client_id);
+            }
+            let protocol = message_protocol(&message_to_server.message)
+                .or_else(|| message_to_server.client_id.and_then(client_protocol));
+            record_message(&span, &message_to_server.message, protocol);
+        }
+        Sv2ServerEvent::SendMessagesToClient(messages_to_client) => {
+            span.record("client_id", messages_to_client.client_id);
+            if let [message] = messages_to_client.messages.as_slice() {
+                let protocol = message_protocol(message)
+                    .or_else(|| client_protocol(messages_to_client.client_id));
+                record_message(&span, message, protocol);
+            }
+        }
+        Sv2ServerEvent::DisconnectClient(client_id) => {
+            span.record("client_id", client_id);
+        }
+        Sv2ServerEvent::SubmitSolution(solution) => {
+            span.record("client_id", solution.client_id);
+            span.record("channel_id", solution.channel_id);
+        }
+        _ => {}
+    }
+
+    span
+}
+
+/// The span for a [`Sv2ClientEvent`].
+pub(crate) fn client_event_span(event: &Sv2ClientEvent<'_>) -> Span {
+    let span = info_span!(
+        "sv2_client_event",
+        event = event.name(),
+        protocol = Empty,
+        channel_id = Empty,
+        message_type = Empty,
+    );
+
+    match event {
+        Sv2ClientEvent::SetupConnectionTrigger(protocol, _) => {
+            span.record("protocol", debug(protocol));
+        }
+        Sv2ClientEvent::IncomingMessage(message) => {
+            record_message(&span, message, message_protocol(message));
+        }
+        Sv2ClientEvent::SendMessageToMiningServer(message) => {
+            span.record("protocol", debug(Protocol::MiningProtocol));
+            span.record(
+                "message_type",
+                display(message_type(message.message_type())),
+            );
+            if let Some(channel_id) = mining_channel_id(message) {
+                span.record("channel_id", channel_id);
+            }
+        }
+        Sv2ClientEvent::SendMessageToTemplateDistributionServer(message) => {
+            span.record("protocol", debug(Protocol::TemplateDistributionProtocol));
+            span.record(
+                "message_type",
+                display(message_type(message.message_type())),
+            );
+        }
+        _ => {}
+    }
+
+    span
+}
+
+fn record_message(span: &Span, message: &AnyMessage<'_>, protocol: Option<Protocol>) {
+    span.record(
+        "message_type",
+        display(message_type(message.message_type())),
+    );
+    if let Some(protocol) = protocol {
+        span.record("protocol", debug(protocol));
+    }
+    if let AnyMessage::Mining(mining_message) = message {
+        if let Some(channel_id) = mining_channel_id(mining_message) {
+            span.record("channel_id", channel_id);
+        }
+    }
+}
+
+fn message_type(message_type: u8) -> String {
+    format!("0x{:02x}", message_type)
+}
+
+// The subprotocol a message belongs to, if it can be told from the message alone.
+fn message_protocol(message: &AnyMessage<'_>) -> Option<Protocol> {
+    match message {
+        AnyMessage::Common(CommonMessages::SetupConnection(setup_connection)) => {
+            Some(setup_connection.protocol)
+        }
+        AnyMessage::Common(_) => None,
+        AnyMessage::Mining(_) => Some(Protocol::MiningProtocol),
+        AnyMessage::JobDeclaration(_) => Some(Protocol::JobDeclarationProtocol),
+        AnyMessage::TemplateDistribution(_) => Some(Protocol::TemplateDistributionProtocol),
+    }
+}
+
+// The channel a Mining message refers to, if any.
+fn mining_channel_id(message: &Mining<'_>) -> Option<u32> {
+    match message {
+        Mining::CloseChannel(m) => Some(m.channel_id),
+        Mining::NewExtendedMiningJob(m) => Some(m.channel_id),
+        Mining::NewMiningJob(m) => Some(m.channel_id),
+        Mining::OpenExtendedMiningChannelSuccess(m) => Some(m.channel_id),
+        Mining::OpenStandardMiningChannelSuccess(m) => Some(m.channel_id),
+        Mining::SetCustomMiningJob(m) => Some(m.channel_id),
+        Mining::SetCustomMiningJobError(m) => Some(m.channel_id),
+        Mining::SetCustomMiningJobSuccess(m) => Some(m.channel_id),
+        Mining::SetExtranoncePrefix(m) => Some(m.channel_id),
+        Mining::SetGroupChannel(m) => Some(m.group_channel_id),
+        Mining::SetNewPrevHash(m) => Some(m.channel_id),
+        Mining::SetTarget(m) => Some(m.channel_id),
+        Mining::SubmitSharesError(m) => Some(m.channel_id),
+        Mining::SubmitSharesExtended(m) => Some(m.channel_id),
+        Mining::SubmitSharesStandard(m) => Some(m.channel_id),
+        Mining::SubmitSharesSuccess(m) => Some(m.channel_id),
+        Mining::UpdateChannel(m) => Some(m.channel_id),
+        Mining::UpdateChannelError(m) => Some(m.channel_id),
+        // channels are not open yet
+        Mining::OpenExtendedMiningChannel(_)
+        | Mining::OpenStandardMiningChannel(_)
+        | Mining::OpenMiningChannelError(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message_protocol, mining_channel_id, server_event_span};
+    use crate::server::service::event::{Sv2MessageToServer, Sv2ServerEvent};
+    use stratum_common::roles_logic_sv2::common_messages_sv2::{
+        Protocol, SetupConnection, SetupConnectionSuccess,
+    };
+    use stratum_common::roles_logic_sv2::mining_sv2::{CloseChannel, OpenStandardMiningChannel};
+    use stratum_common::roles_logic_sv2::parsers::{AnyMessage, CommonMessages, Mining};
+
+    #[test]
+    fn messages_are_mapped_to_protocols_and_channels() {
+        let close_channel = Mining::CloseChannel(CloseChannel {
+            channel_id: 7,
+            reason_code: "".to_string().try_into().unwrap(),
+        });
+        assert_eq!(mining_channel_id(&close_channel), Some(7));
+        assert_eq!(
+            message_protocol(&AnyMessage::Mining(close_channel)),
+            Some(Protocol::MiningProtocol)
+        );
+
+        let open_standard_mining_channel =
+            Mining::OpenStandardMiningChannel(OpenStandardMiningChannel {
+                request_id: 1.into(),
+                user_identity: "user".to_string().try_into().unwrap(),
+                nominal_hash_rate: 1.0,
+                max_target: [0xff; 32].into(),
+            });
+        assert_eq!(mining_channel_id(&open_standard_mining_channel), None);
+
+        let setup_connection =
+            AnyMessage::Common(CommonMessages::SetupConnection(SetupConnection {
+                protocol: Protocol::TemplateDistributionProtocol,
+                min_version: 2,
+                max_version: 2,
+                flags: 0,
+                endpoint_host: "".to_string().try_into().unwrap(),
+                endpoint_port: 0,
+                vendor: "".to_string().try_into().unwrap(),
+                hardware_version: "".to_string().try_into().unwrap(),
+                firmware: "".to_string().try_into().unwrap(),
+                device_id: "".to_string().try_into().unwrap(),
+            }));
+        assert_eq!(
+            message_protocol(&setup_connection),
+            Some(Protocol::TemplateDistributionProtocol)
+        );
+
+        let setup_connection_success = AnyMessage::Common(CommonMessages::SetupConnectionSuccess(
+            SetupConnectionSuccess {
+                used_version: 2,
+                flags: 0,
+            },
+        ));
+        assert_eq!(message_protocol(&setup_connection_success), None);
+    }
+
+    #[test]
+    fn server_span_looks_up_protocol_of_client() {
+        let setup_connection_success = AnyMessage::Common(CommonMessages::SetupConnectionSuccess(
+            SetupConnectionSuccess {
+                used_version: 2,
+                flags: 0,
+            },
+        ));
+        let event = Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+            client_id: Some(3),
+            message: setup_connection_success,
+        });
+
+        let mut looked_up = Vec::new();
+        let _span = server_event_span(&event, |client_id| {
+            looked_up.push(client_id);
+            Some(Protocol::MiningProtocol)
+        });
+        assert_eq!(looked_up, vec![3]);
+    }
+}
@@ -0,0 +1,53 @@
+//! A mock [`Sv2BlockSubmitter`], recording the blocks it is handed instead of sending them to a node.
+
+use crate::layer::Sv2BoxFuture;
+use crate::server::service::block::Sv2BlockSubmitter;
+use bitcoin::Block;
+use std::sync::{Arc, Mutex};
+
+/// A [`Sv2BlockSubmitter`] that records every block, and accepts or rejects them all.
+///
+/// Clones share the same recorded blocks, so a clone can be handed to the service while the test keeps the other.
+#[derive(Debug, Clone, Default)]
+pub struct Sv2MockBlockSubmitter {
+    // the error every submission fails with, if any
+    error: Option<String>,
+    blocks: Arc<Mutex<Vec<Block>>>,
+}
+
+impl Sv2MockBlockSubmitter {
+    /// Creates a new [`Sv2MockBlockSubmitter`] that accepts every block.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new [`Sv2MockBlockSubmitter`] that rejects every block with `error`, e.g.: to test fallbacks.
+    pub fn failing(error: impl Into<String>) -> Self {
+        Self {
+            error: Some(error.into()),
+            blocks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns every block submitted so far (accepted or not), in the order they arrived.
+    pub fn blocks(&self) -> Vec<Block> {
+        self.blocks
+            .lock()
+            .expect("mock block submitter lock poisoned")
+            .clone()
+    }
+}
+
+impl Sv2BlockSubmitter for Sv2MockBlockSubmitter {
+    fn submit_block(&self, block: Block) -> Sv2BoxFuture<'_, Result<(), String>> {
+        self.blocks
+            .lock()
+            .expect("mock block submitter lock poisoned")
+            .push(block);
+        let result = match &self.error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        };
+        Box::pin(async move { result })
+    }
+}
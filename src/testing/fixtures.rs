@@ -0,0 +1,105 @@
+//! Configs, services and messages shared by the unit tests of the crate.
+
+use crate::server::service::client::Sv2ServerServiceClient;
+use crate::server::service::config::{Sv2ServerServiceConfig, Sv2ServerTcpConfig};
+use crate::server::service::connection::Sv2ConnectionClient;
+use crate::server::service::subprotocols::mining::handler::NullSv2MiningServerHandler;
+use crate::server::service::Sv2ServerService;
+use crate::{Sv2MessageFrame, Sv2MessageIo};
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use std::net::SocketAddr;
+use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::{Seq0255, B0255, B064K, U256};
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
+use stratum_common::roles_logic_sv2::template_distribution_sv2::{NewTemplate, SetNewPrevHash};
+use tokio_util::sync::CancellationToken;
+
+/// A server config listening on `listen_address` with the test keys, without any subprotocol config.
+pub(crate) fn server_config(listen_address: SocketAddr) -> Sv2ServerServiceConfig {
+    Sv2ServerServiceConfig {
+        min_supported_version: 2,
+        max_supported_version: 2,
+        inactivity_limit: 10,
+        disconnect_on_protocol_violation: false,
+        tcp_config: Sv2ServerTcpConfig {
+            listen_address,
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        },
+        mining_config: None,
+        job_declaration_config: None,
+        template_distribution_config: None,
+    }
+}
+
+/// A server service with no handler, for tests that never start it (so nothing listens on port 8080).
+pub(crate) fn null_server_service(
+    cancellation_token: CancellationToken,
+) -> Sv2ServerService<NullSv2MiningServerHandler> {
+    Sv2ServerService::new(
+        server_config(([127, 0, 0, 1], 8080).into()),
+        NullSv2MiningServerHandler,
+        cancellation_token,
+    )
+    .unwrap()
+}
+
+/// A client that completed SetupConnection under `protocol`, to be added to a service with `add_client`.
+///
+/// Frames sent by the service to this client can be read from the returned receiver.
+pub(crate) async fn connected_client(
+    protocol: Protocol,
+) -> (
+    Sv2ServerServiceClient,
+    async_channel::Receiver<Sv2MessageFrame>,
+) {
+    let (tx, rx) = async_channel::unbounded();
+    let client = Sv2ServerServiceClient::new(Sv2MessageIo::new(rx.clone(), tx, None));
+    *client.connection.write().await = Some(Sv2ConnectionClient {
+        protocol,
+        min_version: 2,
+        max_version: 2,
+        flags: 0,
+        endpoint_host: "".to_string().try_into().unwrap(),
+        endpoint_port: 0,
+        vendor: "".to_string().try_into().unwrap(),
+        hardware_version: "".to_string().try_into().unwrap(),
+        firmware: "".to_string().try_into().unwrap(),
+        device_id: "".to_string().try_into().unwrap(),
+    });
+    (client, rx)
+}
+
+/// A template at height 1, with no coinbase outputs and an empty merkle path.
+pub(crate) fn new_template(template_id: u64, future_template: bool) -> NewTemplate<'static> {
+    NewTemplate {
+        template_id,
+        future_template,
+        version: 0x20000000,
+        coinbase_tx_version: 2,
+        coinbase_prefix: B0255::Owned(vec![0x51]),
+        coinbase_tx_input_sequence: u32::MAX,
+        coinbase_tx_value_remaining: 5_000_000_000,
+        coinbase_tx_outputs_count: 0,
+        coinbase_tx_outputs: B064K::Owned(vec![]),
+        coinbase_tx_locktime: 0,
+        merkle_path: Seq0255::new(vec![]).unwrap(),
+    }
+}
+
+/// Activates `template_id` on top of a zeroed regtest chain tip.
+pub(crate) fn set_new_prev_hash(template_id: u64) -> SetNewPrevHash<'static> {
+    SetNewPrevHash {
+        template_id,
+        prev_hash: U256::Owned(vec![0; 32]),
+        header_timestamp: 1_296_688_602,
+        n_bits: 0x207fffff,
+        target: U256::Owned(vec![0xff; 32]),
+    }
+}
@@ -0,0 +1,540 @@
+//! A mock miner, built on [`Sv2ClientService`].
+//!
+//! Once started, [`Sv2MockMiner`] opens `n_channels` standard channels on the pool, and keeps submitting
+//! one share per channel every `1 / shares_per_second` seconds, for the active job of the channel.
+//! Shares carry incrementing nonces, and no actual hashing is done, so they only make sense to pools
+//! that don't check proof of work (e.g.: [`crate::testing::pool::Sv2MockPool`], or a handler under test).
+//!
+//! Every `SubmitShares.Success` and `SubmitShares.Error` is counted, see [`Sv2MockMiner::shares_accepted`]
+//! and [`Sv2MockMiner::share_errors`].
+
+use crate::client::service::config::{Sv2ClientServiceConfig, Sv2ClientServiceMiningConfig};
+use crate::client::service::error::Sv2ClientServiceError;
+use crate::client::service::event::{Sv2ClientEvent, Sv2ClientEventError};
+use crate::client::service::outcome::Sv2ClientOutcome;
+use crate::client::service::subprotocols::mining::handler::Sv2MiningClientHandler;
+use crate::client::service::subprotocols::mining::trigger::MiningClientTrigger;
+use crate::client::service::subprotocols::template_distribution::handler::NullSv2TemplateDistributionClientHandler;
+use crate::client::service::Sv2ClientService;
+use crate::Sv2Service;
+use key_utils::Secp256k1PublicKey;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stratum_common::roles_logic_sv2::mining_sv2::{
+    CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannelSuccess,
+    OpenMiningChannelError, OpenStandardMiningChannelSuccess, SetCustomMiningJobError,
+    SetCustomMiningJobSuccess, SetExtranoncePrefix, SetGroupChannel, SetNewPrevHash, SetTarget,
+    SubmitSharesError, SubmitSharesStandard, SubmitSharesSuccess, UpdateChannelError,
+};
+use stratum_common::roles_logic_sv2::parsers::Mining;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+// REQUIRES_STANDARD_JOBS
+const SETUP_CONNECTION_FLAGS: u32 = 0b001;
+
+// how many submissions may be waiting for the service before the share loop waits too
+const EVENT_INJECTOR_CAPACITY: usize = 64;
+
+/// Config parameters for a [`Sv2MockMiner`].
+#[derive(Debug, Clone)]
+pub struct Sv2MockMinerConfig {
+    /// The address of the pool.
+    pub server_addr: SocketAddr,
+    /// The authority public key of the pool, if it must be checked.
+    pub auth_pk: Option<Secp256k1PublicKey>,
+    /// The user identity every channel is opened with.
+    pub user_identity: String,
+    /// How many standard channels to open.
+    pub n_channels: u32,
+    /// How many shares each channel submits per second.
+    pub shares_per_second: f32,
+}
+
+/// A miner that opens standard channels and submits shares at a fixed rate. See [`crate::testing::miner`].
+///
+/// Clones share the same channels and share counters.
+#[derive(Debug, Clone)]
+pub struct Sv2MockMiner {
+    service: Sv2ClientService<MockMinerHandler, NullSv2TemplateDistributionClientHandler>,
+    state: Arc<Mutex<MockMinerState>>,
+}
+
+impl Sv2MockMiner {
+    /// Creates a new [`Sv2MockMiner`], which connects to the pool once started.
+    ///
+    /// Returns [`Sv2ClientServiceError::BadConfig`] if `config.shares_per_second` doesn't give a positive,
+    /// finite interval between shares.
+    pub fn new(
+        config: Sv2MockMinerConfig,
+        cancellation_token: CancellationToken,
+    ) -> Result<Self, Sv2ClientServiceError> {
+        let share_interval = share_interval(config.shares_per_second)?;
+
+        let client_config = Sv2ClientServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            endpoint_host: None,
+            endpoint_port: None,
+            vendor: Some("sv2-services mock miner".to_string()),
+            hardware_version: None,
+            firmware: None,
+            device_id: None,
+            mining_config: Some(Sv2ClientServiceMiningConfig {
+                server_addr: config.server_addr,
+                auth_pk: config.auth_pk,
+                setup_connection_flags: SETUP_CONNECTION_FLAGS,
+            }),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let state = Arc::new(Mutex::new(MockMinerState::default()));
+        let (event_tx, event_rx) = async_channel::bounded(EVENT_INJECTOR_CAPACITY);
+        let handler = MockMinerHandler {
+            user_identity: config.user_identity,
+            n_channels: config.n_channels,
+            share_interval,
+            state: state.clone(),
+            event_injector: event_tx,
+            cancellation_token: cancellation_token.clone(),
+        };
+
+        let service = Sv2ClientService::new_with_event_injector(
+            client_config,
+            handler,
+            NullSv2TemplateDistributionClientHandler,
+            event_rx,
+            cancellation_token,
+        )?;
+
+        Ok(Self { service, state })
+    }
+
+    /// Returns the ids of the open channels.
+    pub fn channel_ids(&self) -> Vec<u32> {
+        let mut channel_ids: Vec<u32> = self.state().channels.keys().copied().collect();
+        channel_ids.sort();
+        channel_ids
+    }
+
+    /// Returns how many shares were submitted so far.
+    pub fn shares_submitted(&self) -> u64 {
+        self.state().shares_submitted
+    }
+
+    /// Returns how many shares the pool accepted so far.
+    pub fn shares_accepted(&self) -> u64 {
+        self.state().shares_accepted
+    }
+
+    /// Returns the error codes of every rejected share, in the order they arrived.
+    pub fn share_errors(&self) -> Vec<String> {
+        self.state().share_errors.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockMinerState> {
+        self.state.lock().expect("mock miner lock poisoned")
+    }
+}
+
+impl Sv2Service for Sv2MockMiner {
+    type Event = Sv2ClientEvent<'static>;
+    type Outcome = Sv2ClientOutcome<'static>;
+    type ServiceError = Sv2ClientServiceError;
+    type EventError = Sv2ClientEventError;
+
+    fn handle(
+        &mut self,
+        event: Sv2ClientEvent<'static>,
+    ) -> impl Future<Output = Result<Sv2ClientOutcome<'static>, Sv2ClientEventError>> + Send {
+        self.service.handle(event)
+    }
+
+    async fn start(&mut self) -> Result<(), Sv2ClientServiceError> {
+        Sv2Service::start(&mut self.service).await
+    }
+}
+
+// The time between two shares of a channel, rejecting rates that are not positive or give no usable interval.
+fn share_interval(shares_per_second: f32) -> Result<Duration, Sv2ClientServiceError> {
+    // zero, negative and NaN rates give an infinite, negative or NaN interval, which are all rejected
+    match Duration::try_from_secs_f32(1.0 / shares_per_second) {
+        Ok(share_interval) if !share_interval.is_zero() => Ok(share_interval),
+        _ => Err(Sv2ClientServiceError::BadConfig),
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockMinerState {
+    channels: HashMap<u32, MockMinerChannel>,
+    // whether a share loop is running, so that restarting the handler doesn't start another one
+    share_loop_running: bool,
+    shares_submitted: u64,
+    shares_accepted: u64,
+    share_errors: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct MockMinerChannel {
+    // job_id -> version, of jobs waiting for a SetNewPrevHash
+    future_jobs: HashMap<u32, u32>,
+    // (job_id, version) of the job that shares are submitted for
+    active_job: Option<(u32, u32)>,
+    ntime: u32,
+    nonce: u32,
+    sequence_number: u32,
+}
+
+impl MockMinerState {
+    // One share for every channel with an active job.
+    fn next_shares(&mut self) -> Vec<SubmitSharesStandard> {
+        let mut shares = Vec::new();
+        for (channel_id, channel) in self.channels.iter_mut() {
+            let Some((job_id, version)) = channel.active_job else {
+                continue;
+            };
+            channel.nonce = channel.nonce.wrapping_add(1);
+            channel.sequence_number += 1;
+            shares.push(SubmitSharesStandard {
+                channel_id: *channel_id,
+                sequence_number: channel.sequence_number,
+                job_id,
+                nonce: channel.nonce,
+                ntime: channel.ntime,
+                version,
+            });
+        }
+        self.shares_submitted += shares.len() as u64;
+        shares
+    }
+}
+
+// Opens the channels of the mock miner, follows their jobs, and submits shares.
+#[derive(Debug, Clone)]
+struct MockMinerHandler {
+    user_identity: String,
+    n_channels: u32,
+    share_interval: Duration,
+    state: Arc<Mutex<MockMinerState>>,
+    event_injector: async_channel::Sender<Sv2ClientEvent<'static>>,
+    cancellation_token: CancellationToken,
+}
+
+impl MockMinerHandler {
+    fn state(&self) -> std::sync::MutexGuard<'_, MockMinerState> {
+        self.state.lock().expect("mock miner lock poisoned")
+    }
+
+    // Submits shares until the service shuts down, unless a previous start already did.
+    fn spawn_share_loop(&self) {
+        if std::mem::replace(&mut self.state().share_loop_running, true) {
+            return;
+        }
+        let state = self.state.clone();
+        let event_injector = self.event_injector.clone();
+        let cancellation_token = self.cancellation_token.clone();
+        let mut interval = tokio::time::interval(self.share_interval);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => {
+                        debug!("Sv2MockMiner share loop cancelled");
+                        break;
+                    }
+                    _ = interval.tick() => {
+                        let shares = state.lock().expect("mock miner lock poisoned").next_shares();
+                        for share in shares {
+                            let event = Sv2ClientEvent::SendMessageToMiningServer(Box::new(
+                                Mining::SubmitSharesStandard(share),
+                            ));
+                            if event_injector.send(event).await.is_err() {
+                                debug!("Sv2MockMiner event injector closed");
+                                state.lock().expect("mock miner lock poisoned").share_loop_running = false;
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+            state
+                .lock()
+                .expect("mock miner lock poisoned")
+                .share_loop_running = false;
+        });
+    }
+}
+
+impl Sv2MiningClientHandler for MockMinerHandler {
+    async fn start(&mut self) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        let events = (0..self.n_channels)
+            .map(|request_id| {
+                Sv2ClientEvent::MiningTrigger(MiningClientTrigger::OpenStandardMiningChannel(
+                    request_id,
+                    self.user_identity.clone(),
+                    1.0,
+                    vec![0xff; 32],
+                ))
+            })
+            .collect();
+
+        self.spawn_share_loop();
+
+        Ok(Sv2ClientOutcome::TriggerNewEvent(Box::new(
+            Sv2ClientEvent::MultipleEvents(Box::new(events)),
+        )))
+    }
+
+    async fn handle_open_standard_mining_channel_success(
+        &mut self,
+        open_standard_mining_channel_success: OpenStandardMiningChannelSuccess<'static>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        debug!(
+            "Sv2MockMiner opened standard channel {}",
+            open_standard_mining_channel_success.channel_id
+        );
+        self.state().channels.insert(
+            open_standard_mining_channel_success.channel_id,
+            MockMinerChannel::default(),
+        );
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_open_extended_mining_channel_success(
+        &mut self,
+        _open_extended_mining_channel_success: OpenExtendedMiningChannelSuccess<'static>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        // extended channels are never requested
+        Err(Sv2ClientEventError::UnsupportedMessage)
+    }
+
+    async fn handle_open_mining_channel_error(
+        &mut self,
+        open_mining_channel_error: OpenMiningChannelError<'static>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        warn!(
+            "Sv2MockMiner failed to open channel: {}",
+            open_mining_channel_error
+        );
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_update_channel_error(
+        &mut self,
+        _update_channel_error: UpdateChannelError<'static>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_close_channel(
+        &mut self,
+        close_channel: CloseChannel<'static>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        self.state().channels.remove(&close_channel.channel_id);
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_set_extranonce_prefix(
+        &mut self,
+        _set_extranonce_prefix: SetExtranoncePrefix<'static>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_submit_shares_success(
+        &mut self,
+        submit_shares_success: SubmitSharesSuccess,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        self.state().shares_accepted += submit_shares_success.new_submits_accepted_count as u64;
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_submit_shares_error(
+        &mut self,
+        submit_shares_error: SubmitSharesError<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        let error_code =
+            String::from_utf8_lossy(&submit_shares_error.error_code.to_vec()).to_string();
+        self.state().share_errors.push(error_code);
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_new_mining_job(
+        &mut self,
+        new_mining_job: NewMiningJob<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        let mut state = self.state();
+        let Some(channel) = state.channels.get_mut(&new_mining_job.channel_id) else {
+            warn!(
+                "Sv2MockMiner received a job for unknown channel {}",
+                new_mining_job.channel_id
+            );
+            return Ok(Sv2ClientOutcome::Ok);
+        };
+
+        match new_mining_job.min_ntime.into_inner() {
+            Some(min_ntime) => {
+                channel.active_job = Some((new_mining_job.job_id, new_mining_job.version));
+                channel.ntime = min_ntime;
+            }
+            None => {
+                channel
+                    .future_jobs
+                    .insert(new_mining_job.job_id, new_mining_job.version);
+            }
+        }
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_new_extended_mining_job(
+        &mut self,
+        _new_extended_mining_job: NewExtendedMiningJob<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Err(Sv2ClientEventError::UnsupportedMessage)
+    }
+
+    async fn handle_set_new_prev_hash(
+        &mut self,
+        set_new_prev_hash: SetNewPrevHash<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        let mut state = self.state();
+        let Some(channel) = state.channels.get_mut(&set_new_prev_hash.channel_id) else {
+            return Ok(Sv2ClientOutcome::Ok);
+        };
+
+        match channel.future_jobs.remove(&set_new_prev_hash.job_id) {
+            Some(version) => {
+                channel.active_job = Some((set_new_prev_hash.job_id, version));
+                channel.ntime = set_new_prev_hash.min_ntime;
+            }
+            None => {
+                warn!(
+                    "Sv2MockMiner received a SetNewPrevHash for unknown job {}",
+                    set_new_prev_hash.job_id
+                );
+                channel.active_job = None;
+            }
+        }
+        channel.future_jobs.clear();
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_set_custom_mining_job_success(
+        &mut self,
+        _set_custom_mining_job_success: SetCustomMiningJobSuccess,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Err(Sv2ClientEventError::UnsupportedMessage)
+    }
+
+    async fn handle_set_custom_mining_job_error(
+        &mut self,
+        _set_custom_mining_job_error: SetCustomMiningJobError<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Err(Sv2ClientEventError::UnsupportedMessage)
+    }
+
+    async fn handle_set_target(
+        &mut self,
+        _set_target: SetTarget<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Ok(Sv2ClientOutcome::Ok)
+    }
+
+    async fn handle_set_group_channel(
+        &mut self,
+        _set_group_channel: SetGroupChannel<'_>,
+    ) -> Result<Sv2ClientOutcome<'static>, Sv2ClientEventError> {
+        Ok(Sv2ClientOutcome::Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{share_interval, Sv2MockMiner, Sv2MockMinerConfig};
+    use crate::server::service::config::Sv2ServerTcpConfig;
+    use crate::testing::pool::{Sv2MockPool, Sv2MockPoolStep, Sv2MockSharePolicy};
+    use crate::Sv2Service;
+    use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn share_rates_must_give_a_usable_interval() {
+        assert_eq!(share_interval(2.0).unwrap(), Duration::from_millis(500));
+        assert!(share_interval(0.0).is_err());
+        assert!(share_interval(-1.0).is_err());
+        assert!(share_interval(f32::NAN).is_err());
+        // 1 / f32::MIN_POSITIVE overflows a Duration, and f32::INFINITY gives a zero interval
+        assert!(share_interval(f32::MIN_POSITIVE).is_err());
+        assert!(share_interval(f32::INFINITY).is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_miner_submits_shares_to_mock_pool() {
+        let pool_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let pub_key = Secp256k1PublicKey::try_from(
+            "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+        )
+        .expect("failed");
+        let priv_key = Secp256k1SecretKey::try_from(
+            "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+        )
+        .expect("failed");
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: pool_addr,
+            pub_key,
+            priv_key,
+            cert_validity: 3600,
+        };
+
+        let cancellation_token = CancellationToken::new();
+        let mut pool = Sv2MockPool::new(
+            tcp_config,
+            [0xff; 32],
+            Sv2MockSharePolicy::Validate,
+            cancellation_token.clone(),
+        )
+        .unwrap();
+        let mut pool_service = pool.clone();
+        tokio::spawn(async move { pool_service.start().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let miner = Sv2MockMiner::new(
+            Sv2MockMinerConfig {
+                server_addr: pool_addr,
+                auth_pk: Some(pub_key),
+                user_identity: "user".to_string(),
+                n_channels: 2,
+                shares_per_second: 20.0,
+            },
+            cancellation_token.clone(),
+        )
+        .unwrap();
+        let mut miner_service = miner.clone();
+        tokio::spawn(async move { miner_service.start().await });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(pool.channel_count(), 2);
+        assert_eq!(miner.channel_ids().len(), 2);
+        assert!(miner.shares_accepted() > 0);
+
+        // miners follow new blocks, so their shares stay valid
+        pool.emit(Sv2MockPoolStep::NewBlock).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let shares = pool.shares();
+        let last_share = shares.last().unwrap();
+        assert_eq!(last_share.error_code, None);
+        assert!(miner.shares_submitted() >= miner.shares_accepted());
+
+        cancellation_token.cancel();
+    }
+}
@@ -0,0 +1,17 @@
+//! Pure-Rust stand-ins for the Sv2 roles our services talk to, so tests (ours and downstream) run
+//! without network access or external binaries.
+//!
+//! - [`template_provider::Sv2MockTemplateProvider`]: a Template Provider serving scripted, regtest-shaped templates
+//! - [`pool::Sv2MockPool`]: a mining pool issuing scripted jobs, and validating or canned-responding to shares
+//! - [`miner::Sv2MockMiner`]: a miner opening standard channels and submitting shares at a fixed rate
+//! - [`block_submitter::Sv2MockBlockSubmitter`]: a block submitter recording found blocks instead of sending them to a node
+//!
+//! They are regular services, so a handler under test can be paired with any of them: e.g.: a pool handler
+//! driven by a [`miner::Sv2MockMiner`], or a proxy handler between a [`miner::Sv2MockMiner`] and a [`pool::Sv2MockPool`].
+
+pub mod block_submitter;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod miner;
+pub mod pool;
+pub mod template_provider;
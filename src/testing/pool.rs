@@ -0,0 +1,618 @@
+//! A mock mining pool, built on [`Sv2ServerService`].
+//!
+//! [`Sv2MockPool`] speaks the Mining subprotocol over the usual encrypted TCP transport:
+//! - on `OpenStandardMiningChannel`, it opens the channel with a unique extranonce prefix, and sends it
+//!   a future job followed by the `SetNewPrevHash` activating it
+//! - on `SubmitSharesStandard`, it answers according to its [`Sv2MockSharePolicy`], and records the share,
+//!   see [`Sv2MockPool::shares`]
+//!
+//! New jobs and blocks are sent to every open channel on demand, via [`Sv2MockPool::emit`] or [`Sv2MockPool::play`].
+//!
+//! Only standard channels are supported: `OpenExtendedMiningChannel` is answered with `OpenMiningChannelError`.
+//! Jobs are fake (zeroed merkle roots and made up prev hashes), so shares are never checked for proof of work.
+
+use crate::server::service::client::Sv2MessagesToClient;
+use crate::server::service::config::{
+    Sv2ServerServiceConfig, Sv2ServerServiceMiningConfig, Sv2ServerTcpConfig,
+};
+use crate::server::service::error::Sv2ServerServiceError;
+use crate::server::service::event::{Sv2ServerEvent, Sv2ServerEventError};
+use crate::server::service::outcome::Sv2ServerOutcome;
+use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
+use crate::server::service::Sv2ServerService;
+use crate::Sv2Service;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::{Str0255, Sv2Option};
+use stratum_common::roles_logic_sv2::mining_sv2::{
+    CloseChannel, NewMiningJob, OpenExtendedMiningChannel, OpenMiningChannelError,
+    OpenStandardMiningChannel, OpenStandardMiningChannelSuccess, SetCustomMiningJob,
+    SetNewPrevHash as SetNewPrevHashMining, SubmitSharesError, SubmitSharesExtended,
+    SubmitSharesStandard, SubmitSharesSuccess, UpdateChannel,
+};
+use stratum_common::roles_logic_sv2::parsers::{AnyMessage, Mining};
+use stratum_common::roles_logic_sv2::template_distribution_sv2::{NewTemplate, SetNewPrevHash};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+const VERSION: u32 = 0x2000_0000;
+const N_BITS: u32 = 0x207f_ffff;
+const INITIAL_NTIME: u32 = 1_296_688_602;
+const BLOCK_INTERVAL_SECS: u32 = 600;
+// REQUIRES_STANDARD_JOBS and REQUIRES_VERSION_ROLLING, but not REQUIRES_WORK_SELECTION: custom jobs are not supported
+const SUPPORTED_FLAGS: u32 = 0b101;
+
+/// How a [`Sv2MockPool`] answers shares.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2MockSharePolicy {
+    /// Accept every share.
+    AcceptAll,
+    /// Reject every share with this error code.
+    RejectAll(String),
+    /// Accept shares for a channel of the client and for the active job, and reject
+    /// unknown channels or jobs (`invalid-channel-id`, `invalid-job-id`), stale jobs (`stale-share`)
+    /// and duplicates (`duplicate-share`).
+    Validate,
+}
+
+/// A scripted step of a [`Sv2MockPool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sv2MockPoolStep {
+    /// A new job on the current prev hash, active right away.
+    NewJob,
+    /// A new block: a future job, followed by the `SetNewPrevHash` activating it.
+    NewBlock,
+}
+
+/// A share received by a [`Sv2MockPool`], and how it was answered.
+#[derive(Debug, Clone)]
+pub struct Sv2MockPoolShare {
+    pub client_id: u32,
+    pub share: SubmitSharesStandard,
+    /// `None` if the share was accepted.
+    pub error_code: Option<String>,
+}
+
+/// A mining pool with scripted jobs and canned (or validated) share responses. See [`crate::testing::pool`].
+///
+/// Clones share the same channels and recorded shares.
+#[derive(Debug, Clone)]
+pub struct Sv2MockPool {
+    service: Sv2ServerService<MockPoolHandler>,
+    state: Arc<Mutex<MockPoolState>>,
+}
+
+impl Sv2MockPool {
+    /// Creates a new [`Sv2MockPool`], listening on `tcp_config.listen_address` once started.
+    ///
+    /// Channels are opened with `target` (little-endian).
+    pub fn new(
+        tcp_config: Sv2ServerTcpConfig,
+        target: [u8; 32],
+        share_policy: Sv2MockSharePolicy,
+        cancellation_token: CancellationToken,
+    ) -> Result<Self, Sv2ServerServiceError> {
+        let config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            // the mock never drops idle clients
+            inactivity_limit: u64::MAX,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: Some(Sv2ServerServiceMiningConfig {
+                supported_flags: SUPPORTED_FLAGS,
+            }),
+            job_declaration_config: None,
+            template_distribution_config: None,
+        };
+
+        let state = Arc::new(Mutex::new(MockPoolState::new(target, share_policy)));
+        let handler = MockPoolHandler {
+            state: state.clone(),
+        };
+        let service = Sv2ServerService::new(config, handler, cancellation_token)?;
+
+        Ok(Self { service, state })
+    }
+
+    /// Sends the messages of `step` to every open channel.
+    pub async fn emit(
+        &mut self,
+        step: Sv2MockPoolStep,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        let messages_to_clients = {
+            let mut state = self.state.lock().expect("mock pool lock poisoned");
+            if step == Sv2MockPoolStep::NewBlock {
+                state.ntime += BLOCK_INTERVAL_SECS;
+                state.prev_hash_counter += 1;
+            }
+            let channel_ids: Vec<u32> = state.channels.keys().copied().collect();
+
+            let mut messages_to_clients: HashMap<u32, Vec<AnyMessage<'static>>> = HashMap::new();
+            for channel_id in channel_ids {
+                let client_id = state.channels[&channel_id];
+                let messages = match step {
+                    Sv2MockPoolStep::NewJob => vec![state.new_mining_job(channel_id, false)],
+                    Sv2MockPoolStep::NewBlock => state.new_block_messages(channel_id),
+                };
+                messages_to_clients
+                    .entry(client_id)
+                    .or_default()
+                    .extend(messages);
+            }
+            messages_to_clients
+        };
+        debug!("Sv2MockPool emitting {:?}", step);
+
+        self.service
+            .handle(Sv2ServerEvent::SendMessagesToClients(Box::new(
+                messages_to_clients
+                    .into_iter()
+                    .map(|(client_id, messages)| Sv2MessagesToClient {
+                        client_id,
+                        messages,
+                    })
+                    .collect(),
+            )))
+            .await
+    }
+
+    /// Emits every step of `script` in order, waiting `interval` between them.
+    pub async fn play(
+        &mut self,
+        script: &[Sv2MockPoolStep],
+        interval: Duration,
+    ) -> Result<(), Sv2ServerEventError> {
+        for (i, step) in script.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(interval).await;
+            }
+            self.emit(*step).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns every share received so far, in the order they arrived.
+    pub fn shares(&self) -> Vec<Sv2MockPoolShare> {
+        self.state
+            .lock()
+            .expect("mock pool lock poisoned")
+            .shares
+            .clone()
+    }
+
+    /// Returns how many channels are open.
+    pub fn channel_count(&self) -> usize {
+        self.state
+            .lock()
+            .expect("mock pool lock poisoned")
+            .channels
+            .len()
+    }
+}
+
+impl Sv2Service for Sv2MockPool {
+    type Event = Sv2ServerEvent<'static>;
+    type Outcome = Sv2ServerOutcome<'static>;
+    type ServiceError = Sv2ServerServiceError;
+    type EventError = Sv2ServerEventError;
+
+    fn handle(
+        &mut self,
+        event: Sv2ServerEvent<'static>,
+    ) -> impl Future<Output = Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> + Send {
+        self.service.handle(event)
+    }
+
+    async fn start(&mut self) -> Result<(), Sv2ServerServiceError> {
+        self.service.start().await
+    }
+}
+
+#[derive(Debug)]
+struct MockPoolState {
+    target: [u8; 32],
+    share_policy: Sv2MockSharePolicy,
+    next_channel_id: u32,
+    // channel_id -> client_id
+    channels: HashMap<u32, u32>,
+    next_job_id: u32,
+    // channel_id -> the job that shares must be submitted for
+    active_jobs: HashMap<u32, u32>,
+    ntime: u32,
+    // makes up a new prev hash on every block
+    prev_hash_counter: u32,
+    // (channel_id, job_id, nonce, ntime, version) of accepted shares
+    seen_shares: HashSet<(u32, u32, u32, u32, u32)>,
+    shares: Vec<Sv2MockPoolShare>,
+}
+
+impl MockPoolState {
+    fn new(target: [u8; 32], share_policy: Sv2MockSharePolicy) -> Self {
+        Self {
+            target,
+            share_policy,
+            next_channel_id: 1,
+            channels: HashMap::new(),
+            next_job_id: 1,
+            active_jobs: HashMap::new(),
+            ntime: INITIAL_NTIME,
+            prev_hash_counter: 0,
+            seen_shares: HashSet::new(),
+            shares: Vec::new(),
+        }
+    }
+
+    // A new job for `channel_id`. Jobs that are not future jobs are active right away.
+    fn new_mining_job(&mut self, channel_id: u32, future_job: bool) -> AnyMessage<'static> {
+        let job_id = self.next_job_id;
+        self.next_job_id += 1;
+
+        let min_ntime = if future_job {
+            None
+        } else {
+            self.active_jobs.insert(channel_id, job_id);
+            Some(self.ntime)
+        };
+
+        AnyMessage::Mining(Mining::NewMiningJob(NewMiningJob {
+            channel_id,
+            job_id,
+            min_ntime: Sv2Option::new(min_ntime),
+            version: VERSION,
+            merkle_root: vec![0u8; 32]
+                .try_into()
+                .expect("merkle_root should be 32 bytes"),
+        }))
+    }
+
+    // A future job for `channel_id`, followed by the SetNewPrevHash activating it.
+    fn new_block_messages(&mut self, channel_id: u32) -> Vec<AnyMessage<'static>> {
+        let new_mining_job = self.new_mining_job(channel_id, true);
+        let job_id = self.next_job_id - 1;
+        self.active_jobs.insert(channel_id, job_id);
+
+        let mut prev_hash = [0u8; 32];
+        prev_hash[..4].copy_from_slice(&self.prev_hash_counter.to_le_bytes());
+
+        let set_new_prev_hash = AnyMessage::Mining(Mining::SetNewPrevHash(SetNewPrevHashMining {
+            channel_id,
+            job_id,
+            prev_hash: prev_hash.into(),
+            min_ntime: self.ntime,
+            nbits: N_BITS,
+        }));
+
+        vec![new_mining_job, set_new_prev_hash]
+    }
+
+    // Returns the error code for `share`, or `None` if it is accepted.
+    fn check_share(&mut self, client_id: u32, share: &SubmitSharesStandard) -> Option<String> {
+        match &self.share_policy {
+            Sv2MockSharePolicy::AcceptAll => None,
+            Sv2MockSharePolicy::RejectAll(error_code) => Some(error_code.clone()),
+            Sv2MockSharePolicy::Validate => {
+                if self.channels.get(&share.channel_id) != Some(&client_id) {
+                    return Some("invalid-channel-id".to_string());
+                }
+                let active_job_id = self.active_jobs.get(&share.channel_id).copied();
+                if active_job_id != Some(share.job_id) {
+                    return Some(if share.job_id < self.next_job_id {
+                        "stale-share".to_string()
+                    } else {
+                        "invalid-job-id".to_string()
+                    });
+                }
+                let key = (
+                    share.channel_id,
+                    share.job_id,
+                    share.nonce,
+                    share.ntime,
+                    share.version,
+                );
+                if !self.seen_shares.insert(key) {
+                    return Some("duplicate-share".to_string());
+                }
+                None
+            }
+        }
+    }
+
+    fn remove_channel(&mut self, channel_id: u32) {
+        self.channels.remove(&channel_id);
+        self.active_jobs.remove(&channel_id);
+    }
+}
+
+// Opens standard channels, issues jobs, and answers shares of the mock pool.
+#[derive(Debug, Clone)]
+struct MockPoolHandler {
+    state: Arc<Mutex<MockPoolState>>,
+}
+
+impl MockPoolHandler {
+    fn state(&self) -> std::sync::MutexGuard<'_, MockPoolState> {
+        self.state.lock().expect("mock pool lock poisoned")
+    }
+}
+
+fn str0255(value: String) -> Result<Str0255<'static>, Sv2ServerEventError> {
+    value
+        .try_into()
+        .map_err(|_| Sv2ServerEventError::MiningHandlerError("string too long".to_string()))
+}
+
+impl Sv2MiningServerHandler for MockPoolHandler {
+    async fn start(&mut self) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        Ok(Sv2ServerOutcome::Ok)
+    }
+
+    fn setup_connection_success_flags(&self) -> u32 {
+        0
+    }
+
+    async fn add_client(&mut self, _client_id: u32, _flags: u32) {}
+
+    async fn remove_client(&mut self, client_id: u32) {
+        let mut state = self.state();
+        let channel_ids: Vec<u32> = state
+            .channels
+            .iter()
+            .filter(|(_, owner)| **owner == client_id)
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        for channel_id in channel_ids {
+            state.remove_channel(channel_id);
+        }
+    }
+
+    async fn handle_open_standard_mining_channel(
+        &self,
+        client_id: u32,
+        m: OpenStandardMiningChannel<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        let mut state = self.state();
+        let channel_id = state.next_channel_id;
+        state.next_channel_id += 1;
+        state.channels.insert(channel_id, client_id);
+        debug!(
+            "Sv2MockPool opened standard channel {} for client_id {}",
+            channel_id, client_id
+        );
+
+        // the channel id makes the extranonce prefix unique
+        let mut extranonce_prefix = vec![0u8; 32];
+        extranonce_prefix[..4].copy_from_slice(&channel_id.to_be_bytes());
+
+        let open_standard_mining_channel_success = AnyMessage::Mining(
+            Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
+                request_id: m.request_id,
+                channel_id,
+                target: state.target.into(),
+                extranonce_prefix: extranonce_prefix
+                    .try_into()
+                    .expect("extranonce_prefix should be 32 bytes"),
+                group_channel_id: 0,
+            }),
+        );
+
+        let mut messages = vec![open_standard_mining_channel_success];
+        messages.extend(state.new_block_messages(channel_id));
+        Ok(Sv2ServerOutcome::Reply(messages))
+    }
+
+    async fn handle_open_extended_mining_channel(
+        &self,
+        _client_id: u32,
+        m: OpenExtendedMiningChannel<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        Ok(Sv2ServerOutcome::Reply(vec![AnyMessage::Mining(
+            Mining::OpenMiningChannelError(OpenMiningChannelError {
+                request_id: m.request_id,
+                error_code: str0255("unsupported-extended-channels".to_string())?,
+            }),
+        )]))
+    }
+
+    async fn handle_update_channel(
+        &self,
+        _client_id: u32,
+        _m: UpdateChannel<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        Ok(Sv2ServerOutcome::Ok)
+    }
+
+    async fn handle_close_channel(
+        &self,
+        client_id: u32,
+        m: CloseChannel<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        let mut state = self.state();
+        if state.channels.get(&m.channel_id) == Some(&client_id) {
+            state.remove_channel(m.channel_id);
+        }
+        Ok(Sv2ServerOutcome::Ok)
+    }
+
+    async fn handle_submit_shares_standard(
+        &self,
+        client_id: u32,
+        m: SubmitSharesStandard,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        let mut state = self.state();
+        let error_code = state.check_share(client_id, &m);
+        state.shares.push(Sv2MockPoolShare {
+            client_id,
+            share: m.clone(),
+            error_code: error_code.clone(),
+        });
+
+        let response = match error_code {
+            None => Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+                channel_id: m.channel_id,
+                last_sequence_number: m.sequence_number,
+                new_submits_accepted_count: 1,
+                new_shares_sum: 1,
+            }),
+            Some(error_code) => Mining::SubmitSharesError(SubmitSharesError {
+                channel_id: m.channel_id,
+                sequence_number: m.sequence_number,
+                error_code: str0255(error_code)?,
+            }),
+        };
+        Ok(Sv2ServerOutcome::Reply(vec![AnyMessage::Mining(response)]))
+    }
+
+    async fn handle_submit_shares_extended(
+        &self,
+        _client_id: u32,
+        _m: SubmitSharesExtended<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        // no extended channel is ever opened
+        Err(Sv2ServerEventError::UnsupportedMessage)
+    }
+
+    async fn handle_set_custom_mining_job(
+        &self,
+        _client_id: u32,
+        _m: SetCustomMiningJob<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        Err(Sv2ServerEventError::UnsupportedMessage)
+    }
+
+    async fn on_new_template(
+        &self,
+        _m: NewTemplate<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        Ok(Sv2ServerOutcome::Ok)
+    }
+
+    async fn on_set_new_prev_hash(
+        &self,
+        _m: SetNewPrevHash<'static>,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        Ok(Sv2ServerOutcome::Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockPoolHandler, MockPoolState, Sv2MockSharePolicy};
+    use crate::server::service::outcome::Sv2ServerOutcome;
+    use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
+    use std::sync::{Arc, Mutex};
+    use stratum_common::roles_logic_sv2::mining_sv2::{
+        OpenStandardMiningChannel, SubmitSharesStandard,
+    };
+    use stratum_common::roles_logic_sv2::parsers::{AnyMessage, Mining};
+
+    fn share(channel_id: u32, job_id: u32, nonce: u32) -> SubmitSharesStandard {
+        SubmitSharesStandard {
+            channel_id,
+            sequence_number: nonce,
+            job_id,
+            nonce,
+            ntime: 0,
+            version: 0x2000_0000,
+        }
+    }
+
+    // Returns the error code of the reply to a share, or `None` if it was accepted.
+    fn share_error_code(outcome: Sv2ServerOutcome<'static>) -> Option<String> {
+        let Sv2ServerOutcome::Reply(messages) = outcome else {
+            panic!("expected Reply");
+        };
+        match &messages[0] {
+            AnyMessage::Mining(Mining::SubmitSharesSuccess(_)) => None,
+            AnyMessage::Mining(Mining::SubmitSharesError(m)) => {
+                Some(String::from_utf8(m.error_code.to_vec()).unwrap())
+            }
+            _ => panic!("expected SubmitShares.Success or SubmitShares.Error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_pool_opens_channels_and_validates_shares() {
+        let handler = MockPoolHandler {
+            state: Arc::new(Mutex::new(MockPoolState::new(
+                [0xff; 32],
+                Sv2MockSharePolicy::Validate,
+            ))),
+        };
+
+        let outcome = handler
+            .handle_open_standard_mining_channel(
+                1,
+                OpenStandardMiningChannel {
+                    request_id: 7.into(),
+                    user_identity: "user".to_string().try_into().unwrap(),
+                    nominal_hash_rate: 1.0,
+                    max_target: [0xff; 32].into(),
+                },
+            )
+            .await
+            .unwrap();
+        let Sv2ServerOutcome::Reply(messages) = outcome else {
+            panic!("expected Reply");
+        };
+        let AnyMessage::Mining(Mining::OpenStandardMiningChannelSuccess(success)) = &messages[0]
+        else {
+            panic!("expected OpenStandardMiningChannel.Success");
+        };
+        let channel_id = success.channel_id;
+        let AnyMessage::Mining(Mining::SetNewPrevHash(set_new_prev_hash)) = &messages[2] else {
+            panic!("expected SetNewPrevHash");
+        };
+        let job_id = set_new_prev_hash.job_id;
+
+        let accepted = handler
+            .handle_submit_shares_standard(1, share(channel_id, job_id, 1))
+            .await
+            .unwrap();
+        assert_eq!(share_error_code(accepted), None);
+
+        let duplicate = handler
+            .handle_submit_shares_standard(1, share(channel_id, job_id, 1))
+            .await
+            .unwrap();
+        assert_eq!(
+            share_error_code(duplicate).as_deref(),
+            Some("duplicate-share")
+        );
+
+        let wrong_client = handler
+            .handle_submit_shares_standard(2, share(channel_id, job_id, 2))
+            .await
+            .unwrap();
+        assert_eq!(
+            share_error_code(wrong_client).as_deref(),
+            Some("invalid-channel-id")
+        );
+
+        let unknown_job = handler
+            .handle_submit_shares_standard(1, share(channel_id, job_id + 100, 3))
+            .await
+            .unwrap();
+        assert_eq!(
+            share_error_code(unknown_job).as_deref(),
+            Some("invalid-job-id")
+        );
+
+        // a new block makes the job stale
+        handler.state().new_block_messages(channel_id);
+        let stale = handler
+            .handle_submit_shares_standard(1, share(channel_id, job_id, 4))
+            .await
+            .unwrap();
+        assert_eq!(share_error_code(stale).as_deref(), Some("stale-share"));
+
+        assert_eq!(handler.state().shares.len(), 5);
+
+        // removing the client closes its channels
+        let mut handler = handler;
+        handler.remove_client(1).await;
+        assert!(handler.state().channels.is_empty());
+    }
+}
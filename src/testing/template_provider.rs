@@ -0,0 +1,692 @@
+//! A mock Template Provider, built on [`Sv2ServerService`].
+//!
+//! [`Sv2MockTemplateProvider`] speaks the Template Distribution subprotocol over the usual encrypted TCP transport,
+//! but instead of talking to a Bitcoin node, it serves templates of an empty regtest chain:
+//! - on `CoinbaseOutputConstraints`, it sends the current template (as a future template) followed by its `SetNewPrevHash`,
+//!   and records the constraints, see [`Sv2MockTemplateProvider::coinbase_output_constraints`]
+//! - on `RequestTransactionData`, it sends an empty transaction list, or an error for unknown or stale templates
+//! - every `SubmitSolution` is recorded, see [`Sv2MockTemplateProvider::submitted_solutions`]
+//!
+//! New templates and blocks are broadcast to every connected client on demand, via [`Sv2MockTemplateProvider::emit`]
+//! or [`Sv2MockTemplateProvider::play`].
+//!
+//! The data is regtest-shaped (BIP34 heights, halvings every 150 blocks, `nBits = 0x207fffff`), but fake:
+//! block hashes and the witness commitment are made up, so solutions can't be validated against them.
+
+use crate::layer::{Sv2BoxFuture, Sv2Layer, Sv2Next};
+use crate::server::service::client::{Sv2ClientFilter, Sv2MessagesToClient, Sv2MessagesToClients};
+use crate::server::service::config::{
+    Sv2ServerServiceConfig, Sv2ServerServiceTemplateDistributionConfig, Sv2ServerTcpConfig,
+};
+use crate::server::service::error::Sv2ServerServiceError;
+use crate::server::service::event::{Sv2MessageToServer, Sv2ServerEvent, Sv2ServerEventError};
+use crate::server::service::outcome::Sv2ServerOutcome;
+use crate::server::service::subprotocols::mining::handler::NullSv2MiningServerHandler;
+use crate::server::service::Sv2ServerService;
+use crate::Sv2Service;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::{Seq0255, Seq064K, B0255, B064K};
+use stratum_common::roles_logic_sv2::common_messages_sv2::Protocol;
+use stratum_common::roles_logic_sv2::parsers::{AnyMessage, TemplateDistribution};
+use stratum_common::roles_logic_sv2::template_distribution_sv2::{
+    NewTemplate, RequestTransactionDataError, RequestTransactionDataSuccess, SetNewPrevHash,
+    SubmitSolution,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+// the regtest genesis block hash, in internal byte order
+const REGTEST_GENESIS_HASH: [u8; 32] = [
+    0x06, 0x22, 0x6e, 0x46, 0x11, 0x1a, 0x0b, 0x59, 0xca, 0xaf, 0x12, 0x60, 0x43, 0xeb, 0x5b, 0xbf,
+    0x28, 0xc3, 0x4f, 0x3a, 0x5e, 0x33, 0x2a, 0x1f, 0xc7, 0xb2, 0xb7, 0x3c, 0xf1, 0x88, 0x91, 0x0f,
+];
+const REGTEST_GENESIS_TIMESTAMP: u32 = 1_296_688_602;
+const REGTEST_N_BITS: u32 = 0x207f_ffff;
+const REGTEST_HALVING_INTERVAL: u32 = 150;
+const INITIAL_SUBSIDY: u64 = 5_000_000_000;
+const BLOCK_INTERVAL_SECS: u32 = 600;
+
+/// A scripted step of a [`Sv2MockTemplateProvider`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sv2MockTemplateStep {
+    /// A new template on the current chain tip, as if the mempool changed.
+    NewTemplate,
+    /// A new block on the chain tip: a future template for the next height, followed by the `SetNewPrevHash` activating it.
+    NewBlock,
+}
+
+/// A Template Provider serving scripted, regtest-shaped templates. See [`crate::testing::template_provider`].
+///
+/// Clones share the same chain and recorded solutions.
+#[derive(Debug, Clone)]
+pub struct Sv2MockTemplateProvider {
+    service: Sv2ServerService<NullSv2MiningServerHandler>,
+    state: Arc<Mutex<MockTemplateProviderState>>,
+}
+
+impl Sv2MockTemplateProvider {
+    /// Creates a new [`Sv2MockTemplateProvider`], listening on `tcp_config.listen_address` once started.
+    pub fn new(
+        tcp_config: Sv2ServerTcpConfig,
+        cancellation_token: CancellationToken,
+    ) -> Result<Self, Sv2ServerServiceError> {
+        let config = Sv2ServerServiceConfig {
+            min_supported_version: 2,
+            max_supported_version: 2,
+            // the mock never drops idle clients
+            inactivity_limit: u64::MAX,
+            disconnect_on_protocol_violation: false,
+            tcp_config,
+            mining_config: None,
+            job_declaration_config: None,
+            template_distribution_config: Some(Sv2ServerServiceTemplateDistributionConfig {
+                supported_flags: 0,
+            }),
+        };
+
+        let state = Arc::new(Mutex::new(MockTemplateProviderState::new()));
+        let service =
+            Sv2ServerService::new(config, NullSv2MiningServerHandler, cancellation_token)?;
+        let service = service.clone().with_layer(MockTemplateProviderLayer {
+            service,
+            state: state.clone(),
+        });
+
+        Ok(Self { service, state })
+    }
+
+    /// Broadcasts the messages of `step` to every client connected under the Template Distribution protocol.
+    ///
+    /// Returns the [`Sv2ServerOutcome::Broadcasted`] of the broadcast.
+    pub async fn emit(
+        &mut self,
+        step: Sv2MockTemplateStep,
+    ) -> Result<Sv2ServerOutcome<'static>, Sv2ServerEventError> {
+        let messages = {
+            let mut state = self
+                .state
+                .lock()
+                .expect("mock template provider lock poisoned");
+            match step {
+                Sv2MockTemplateStep::NewTemplate => vec![state.new_template(false)],
+                Sv2MockTemplateStep::NewBlock => state.new_block(),
+            }
+        };
+        debug!("Sv2MockTemplateProvider emitting {:?}", step);
+
+        self.service
+            .handle(Sv2ServerEvent::Broadcast(Box::new(Sv2MessagesToClients {
+                filter: Sv2ClientFilter::Protocol(Protocol::TemplateDistributionProtocol),
+                messages,
+            })))
+            .await
+    }
+
+    /// Emits every step of `script` in order, waiting `interval` between them.
+    pub async fn play(
+        &mut self,
+        script: &[Sv2MockTemplateStep],
+        interval: Duration,
+    ) -> Result<(), Sv2ServerEventError> {
+        for (i, step) in script.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(interval).await;
+            }
+            self.emit(*step).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns every `SubmitSolution` received so far, in the order they arrived.
+    pub fn submitted_solutions(&self) -> Vec<SubmitSolution<'static>> {
+        self.state
+            .lock()
+            .expect("mock template provider lock poisoned")
+            .submitted_solutions
+            .clone()
+    }
+
+    /// Returns every `CoinbaseOutputConstraints` received so far, as (max_additional_size, max_additional_sigops),
+    /// in the order they arrived.
+    pub fn coinbase_output_constraints(&self) -> Vec<(u32, u16)> {
+        self.state
+            .lock()
+            .expect("mock template provider lock poisoned")
+            .coinbase_output_constraints
+            .clone()
+    }
+
+    /// Returns the height of the current chain tip.
+    pub fn height(&self) -> u32 {
+        self.state
+            .lock()
+            .expect("mock template provider lock poisoned")
+            .height
+    }
+
+    /// Returns the underlying [`Sv2ServerService`], e.g.: to look at its clients.
+    pub fn service(&self) -> &Sv2ServerService<NullSv2MiningServerHandler> {
+        &self.service
+    }
+}
+
+impl Sv2Service for Sv2MockTemplateProvider {
+    type Event = Sv2ServerEvent<'static>;
+    type Outcome = Sv2ServerOutcome<'static>;
+    type ServiceError = Sv2ServerServiceError;
+    type EventError = Sv2ServerEventError;
+
+    fn handle(
+        &mut self,
+        event: Sv2ServerEvent<'static>,
+    ) -> impl Future<Output = Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> + Send {
+        self.service.handle(event)
+    }
+
+    async fn start(&mut self) -> Result<(), Sv2ServerServiceError> {
+        self.service.start().await
+    }
+}
+
+// The chain as seen by the mock.
+#[derive(Debug)]
+struct MockTemplateProviderState {
+    // the height of the chain tip
+    height: u32,
+    prev_hash: [u8; 32],
+    header_timestamp: u32,
+    next_template_id: u64,
+    // ids of the templates built on the current chain tip
+    tip_template_ids: Vec<u64>,
+    // the latest template, and the SetNewPrevHash that activated the current chain tip
+    last_template: Option<NewTemplate<'static>>,
+    set_new_prev_hash: Option<SetNewPrevHash<'static>>,
+    coinbase_output_constraints: Vec<(u32, u16)>,
+    submitted_solutions: Vec<SubmitSolution<'static>>,
+}
+
+impl MockTemplateProviderState {
+    fn new() -> Self {
+        Self {
+            height: 0,
+            prev_hash: REGTEST_GENESIS_HASH,
+            header_timestamp: REGTEST_GENESIS_TIMESTAMP,
+            next_template_id: 1,
+            tip_template_ids: Vec::new(),
+            last_template: None,
+            set_new_prev_hash: None,
+            coinbase_output_constraints: Vec::new(),
+            submitted_solutions: Vec::new(),
+        }
+    }
+
+    // Builds a template for the block on top of the current chain tip.
+    fn new_template(&mut self, future_template: bool) -> AnyMessage<'static> {
+        let template_id = self.next_template_id;
+        self.next_template_id += 1;
+        self.tip_template_ids.push(template_id);
+
+        let height = self.height + 1;
+        let new_template = NewTemplate {
+            template_id,
+            future_template,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: B0255::Owned(bip34_height(height)),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: regtest_subsidy(height),
+            coinbase_tx_outputs_count: 1,
+            coinbase_tx_outputs: B064K::Owned(witness_commitment_output()),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(Vec::new()).expect("empty merkle path"),
+        };
+        self.last_template = Some(new_template.clone());
+
+        AnyMessage::TemplateDistribution(TemplateDistribution::NewTemplate(new_template))
+    }
+
+    // Activates `template_id` on the current chain tip.
+    fn set_new_prev_hash(&mut self, template_id: u64) -> AnyMessage<'static> {
+        let set_new_prev_hash = SetNewPrevHash {
+            template_id,
+            prev_hash: self.prev_hash.into(),
+            header_timestamp: self.header_timestamp,
+            n_bits: REGTEST_N_BITS,
+            target: regtest_target().into(),
+        };
+        self.set_new_prev_hash = Some(set_new_prev_hash.clone());
+
+        AnyMessage::TemplateDistribution(TemplateDistribution::SetNewPrevHash(set_new_prev_hash))
+    }
+
+    // Mines a block on the current chain tip.
+    fn new_block(&mut self) -> Vec<AnyMessage<'static>> {
+        self.height += 1;
+        self.prev_hash = fake_block_hash(self.height);
+        self.header_timestamp += BLOCK_INTERVAL_SECS;
+        self.tip_template_ids.clear();
+
+        self.activate_future_template()
+    }
+
+    // A future template, followed by the SetNewPrevHash activating it.
+    fn activate_future_template(&mut self) -> Vec<AnyMessage<'static>> {
+        let new_template = self.new_template(true);
+        let template_id = self.next_template_id - 1;
+        vec![new_template, self.set_new_prev_hash(template_id)]
+    }
+
+    // What a client gets after sending CoinbaseOutputConstraints: the current template, and the current chain tip.
+    fn current_tip(&mut self) -> Vec<AnyMessage<'static>> {
+        match (self.last_template.clone(), self.set_new_prev_hash.clone()) {
+            (Some(mut new_template), Some(set_new_prev_hash)) => {
+                new_template.future_template = true;
+                vec![
+                    AnyMessage::TemplateDistribution(TemplateDistribution::NewTemplate(
+                        new_template,
+                    )),
+                    AnyMessage::TemplateDistribution(TemplateDistribution::SetNewPrevHash(
+                        set_new_prev_hash,
+                    )),
+                ]
+            }
+            _ => self.activate_future_template(),
+        }
+    }
+
+    fn transaction_data(&self, template_id: u64) -> AnyMessage<'static> {
+        if self.tip_template_ids.contains(&template_id) {
+            // the mempool is always empty
+            AnyMessage::TemplateDistribution(TemplateDistribution::RequestTransactionDataSuccess(
+                RequestTransactionDataSuccess {
+                    template_id,
+                    excess_data: B064K::Owned(Vec::new()),
+                    transaction_list: Seq064K::new(Vec::new()).expect("empty transaction list"),
+                },
+            ))
+        } else {
+            let error_code = if template_id < self.next_template_id {
+                "stale-template-id"
+            } else {
+                "template-id-not-found"
+            };
+            AnyMessage::TemplateDistribution(TemplateDistribution::RequestTransactionDataError(
+                RequestTransactionDataError {
+                    template_id,
+                    error_code: error_code
+                        .to_string()
+                        .try_into()
+                        .expect("failed to encode string"),
+                },
+            ))
+        }
+    }
+}
+
+// Answers the Template Distribution messages of clients, in place of the (missing) template distribution handler.
+//
+// Only clients set up under the Template Distribution protocol are answered, messages from any other client go on
+// to the service, which rejects them.
+struct MockTemplateProviderLayer {
+    // the service this layer is part of, to look up the clients
+    service: Sv2ServerService<NullSv2MiningServerHandler>,
+    state: Arc<Mutex<MockTemplateProviderState>>,
+}
+
+impl MockTemplateProviderLayer {
+    // Whether `client_id` completed SetupConnection under the Template Distribution protocol.
+    async fn is_template_distribution_client(&self, client_id: u32) -> bool {
+        let Some(client) = self.service.get_client(client_id) else {
+            return false;
+        };
+        let connection = client.connection.read().await;
+        connection
+            .as_ref()
+            .is_some_and(|connection| connection.protocol == Protocol::TemplateDistributionProtocol)
+    }
+
+    // Returns the reply to `message`, if any.
+    fn respond(&self, message: &TemplateDistribution<'static>) -> Option<Vec<AnyMessage<'static>>> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("mock template provider lock poisoned");
+        match message {
+            TemplateDistribution::CoinbaseOutputConstraints(m) => {
+                state.coinbase_output_constraints.push((
+                    m.coinbase_output_max_additional_size,
+                    m.coinbase_output_max_additional_sigops,
+                ));
+                Some(state.current_tip())
+            }
+            TemplateDistribution::RequestTransactionData(m) => {
+                Some(vec![state.transaction_data(m.template_id)])
+            }
+            TemplateDistribution::SubmitSolution(m) => {
+                debug!(
+                    "Sv2MockTemplateProvider received a solution for template_id {}",
+                    m.template_id
+                );
+                state.submitted_solutions.push(m.clone());
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Sv2Layer<Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>
+    for MockTemplateProviderLayer
+{
+    fn handle<'a>(
+        &'a self,
+        event: Sv2ServerEvent<'static>,
+        next: Sv2Next<'a, Sv2ServerEvent<'static>, Sv2ServerOutcome<'static>, Sv2ServerEventError>,
+    ) -> Sv2BoxFuture<'a, Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>> {
+        Box::pin(async move {
+            let reply = match &event {
+                Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                    client_id: Some(client_id),
+                    message: AnyMessage::TemplateDistribution(message),
+                }) if self.is_template_distribution_client(*client_id).await => {
+                    self.respond(message).map(|messages| (*client_id, messages))
+                }
+                _ => None,
+            };
+
+            match reply {
+                Some((client_id, messages)) => {
+                    next.run(Sv2ServerEvent::SendMessagesToClient(Box::new(
+                        Sv2MessagesToClient {
+                            client_id,
+                            messages,
+                        },
+                    )))
+                    .await
+                }
+                None => next.run(event).await,
+            }
+        })
+    }
+}
+
+// The BIP34 height push that starts the coinbase script.
+fn bip34_height(height: u32) -> Vec<u8> {
+    match height {
+        0 => vec![0x00],
+        // OP_1 to OP_16
+        1..=16 => vec![0x50 + height as u8],
+        _ => {
+            let mut bytes = height.to_le_bytes().to_vec();
+            while bytes.last() == Some(&0) {
+                bytes.pop();
+            }
+            // keep the number positive
+            if bytes.last().is_some_and(|byte| byte & 0x80 != 0) {
+                bytes.push(0);
+            }
+            let mut script = vec![bytes.len() as u8];
+            script.extend(bytes);
+            script
+        }
+    }
+}
+
+// A serialized zero-value OP_RETURN output carrying a (made up) witness commitment.
+fn witness_commitment_output() -> Vec<u8> {
+    let mut script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+    script.extend([0u8; 32]);
+
+    let mut output = 0u64.to_le_bytes().to_vec();
+    output.push(script.len() as u8);
+    output.extend(script);
+    output
+}
+
+// The block subsidy at `height`, which runs out after 64 halvings.
+fn regtest_subsidy(height: u32) -> u64 {
+    INITIAL_SUBSIDY
+        .checked_shr(height / REGTEST_HALVING_INTERVAL)
+        .unwrap_or(0)
+}
+
+// The target encoded by REGTEST_N_BITS, as a little-endian U256.
+fn regtest_target() -> [u8; 32] {
+    let mut target = [0u8; 32];
+    target[29] = 0xff;
+    target[30] = 0xff;
+    target[31] = 0x7f;
+    target
+}
+
+fn fake_block_hash(height: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash[..4].copy_from_slice(&height.to_le_bytes());
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bip34_height, regtest_subsidy, Sv2MockTemplateProvider, Sv2MockTemplateStep};
+    use crate::client::tcp::encrypted::Sv2EncryptedTcpClient;
+    use crate::server::service::config::Sv2ServerTcpConfig;
+    use crate::server::service::event::{Sv2MessageToServer, Sv2ServerEvent};
+    use crate::server::service::outcome::Sv2ServerOutcome;
+    use crate::Sv2Service;
+    use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+    use stratum_common::roles_logic_sv2::codec_sv2::binary_sv2::B064K;
+    use stratum_common::roles_logic_sv2::common_messages_sv2::{Protocol, SetupConnection};
+    use stratum_common::roles_logic_sv2::parsers::{
+        AnyMessage, CommonMessages, TemplateDistribution,
+    };
+    use stratum_common::roles_logic_sv2::template_distribution_sv2::{
+        CoinbaseOutputConstraints, RequestTransactionData, SubmitSolution,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    fn get_available_address() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn template_distribution(message: TemplateDistribution<'static>) -> AnyMessage<'static> {
+        AnyMessage::TemplateDistribution(message)
+    }
+
+    #[test]
+    fn heights_are_bip34_encoded() {
+        assert_eq!(bip34_height(1), vec![0x51]);
+        assert_eq!(bip34_height(16), vec![0x60]);
+        assert_eq!(bip34_height(17), vec![0x01, 0x11]);
+        assert_eq!(bip34_height(128), vec![0x02, 0x80, 0x00]);
+        assert_eq!(bip34_height(300), vec![0x02, 0x2c, 0x01]);
+    }
+
+    #[test]
+    fn subsidy_runs_out() {
+        assert_eq!(regtest_subsidy(149), 5_000_000_000);
+        assert_eq!(regtest_subsidy(150), 2_500_000_000);
+        assert_eq!(regtest_subsidy(150 * 64), 0);
+        assert_eq!(regtest_subsidy(u32::MAX), 0);
+    }
+
+    #[tokio::test]
+    async fn mock_template_provider_ignores_clients_not_set_up() {
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: get_available_address(),
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+        let mut template_provider =
+            Sv2MockTemplateProvider::new(tcp_config, CancellationToken::new()).unwrap();
+
+        let result = template_provider
+            .handle(Sv2ServerEvent::IncomingMessage(Sv2MessageToServer {
+                client_id: Some(1),
+                message: template_distribution(TemplateDistribution::CoinbaseOutputConstraints(
+                    CoinbaseOutputConstraints {
+                        coinbase_output_max_additional_size: 100,
+                        coinbase_output_max_additional_sigops: 1,
+                    },
+                )),
+            }))
+            .await;
+        assert!(result.is_err());
+        assert!(template_provider.coinbase_output_constraints().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mock_template_provider_serves_templates() {
+        let address = get_available_address();
+        let tcp_config = Sv2ServerTcpConfig {
+            listen_address: address,
+            pub_key: Secp256k1PublicKey::try_from(
+                "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72".to_string(),
+            )
+            .expect("failed"),
+            priv_key: Secp256k1SecretKey::try_from(
+                "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n".to_string(),
+            )
+            .expect("failed"),
+            cert_validity: 3600,
+        };
+
+        let cancellation_token = CancellationToken::new();
+        let mut template_provider =
+            Sv2MockTemplateProvider::new(tcp_config, cancellation_token.clone()).unwrap();
+        let mut service = template_provider.clone();
+        tokio::spawn(async move { service.start().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = Sv2EncryptedTcpClient::new(address, None).await.unwrap();
+        let setup_connection = SetupConnection {
+            protocol: Protocol::TemplateDistributionProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        };
+        client
+            .io
+            .send_message(setup_connection.into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.io.recv_message().await.unwrap(),
+            AnyMessage::Common(CommonMessages::SetupConnectionSuccess(_))
+        ));
+
+        // CoinbaseOutputConstraints -> future template on top of genesis, then its SetNewPrevHash
+        client
+            .io
+            .send_message(template_distribution(
+                TemplateDistribution::CoinbaseOutputConstraints(CoinbaseOutputConstraints {
+                    coinbase_output_max_additional_size: 1,
+                    coinbase_output_max_additional_sigops: 1,
+                }),
+            ))
+            .await
+            .unwrap();
+        let AnyMessage::TemplateDistribution(TemplateDistribution::NewTemplate(new_template)) =
+            client.io.recv_message().await.unwrap()
+        else {
+            panic!("expected NewTemplate");
+        };
+        assert!(new_template.future_template);
+        assert_eq!(new_template.coinbase_tx_value_remaining, 5_000_000_000);
+        let AnyMessage::TemplateDistribution(TemplateDistribution::SetNewPrevHash(
+            set_new_prev_hash,
+        )) = client.io.recv_message().await.unwrap()
+        else {
+            panic!("expected SetNewPrevHash");
+        };
+        assert_eq!(set_new_prev_hash.template_id, new_template.template_id);
+        assert_eq!(set_new_prev_hash.n_bits, 0x207fffff);
+
+        // RequestTransactionData -> empty mempool
+        client
+            .io
+            .send_message(template_distribution(
+                TemplateDistribution::RequestTransactionData(RequestTransactionData {
+                    template_id: new_template.template_id,
+                }),
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.io.recv_message().await.unwrap(),
+            AnyMessage::TemplateDistribution(TemplateDistribution::RequestTransactionDataSuccess(m))
+                if m.template_id == new_template.template_id
+        ));
+
+        // a new block makes the first template stale
+        let outcome = template_provider
+            .emit(Sv2MockTemplateStep::NewBlock)
+            .await
+            .unwrap();
+        let Sv2ServerOutcome::Broadcasted(report) = outcome else {
+            panic!("expected Broadcasted");
+        };
+        assert_eq!(report.delivered.len(), 1);
+        assert_eq!(template_provider.height(), 1);
+        assert!(matches!(
+            client.io.recv_message().await.unwrap(),
+            AnyMessage::TemplateDistribution(TemplateDistribution::NewTemplate(m)) if m.future_template
+        ));
+        assert!(matches!(
+            client.io.recv_message().await.unwrap(),
+            AnyMessage::TemplateDistribution(TemplateDistribution::SetNewPrevHash(_))
+        ));
+
+        client
+            .io
+            .send_message(template_distribution(
+                TemplateDistribution::RequestTransactionData(RequestTransactionData {
+                    template_id: new_template.template_id,
+                }),
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.io.recv_message().await.unwrap(),
+            AnyMessage::TemplateDistribution(TemplateDistribution::RequestTransactionDataError(_))
+        ));
+
+        // solutions are recorded
+        client
+            .io
+            .send_message(template_distribution(TemplateDistribution::SubmitSolution(
+                SubmitSolution {
+                    template_id: 2,
+                    version: 0x2000_0000,
+                    header_timestamp: 0,
+                    header_nonce: 42,
+                    coinbase_tx: B064K::Owned(vec![]),
+                },
+            )))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let solutions = template_provider.submitted_solutions();
+        assert_eq!(solutions.len(), 1);
+        assert_eq!(solutions[0].header_nonce, 42);
+
+        cancellation_token.cancel();
+    }
+}
@@ -0,0 +1,293 @@
+//! Scheduled events for [`crate::Sv2Service`] implementers.
+//!
+//! Handlers can ask the service to fire some event later, or periodically, by returning a
+//! [`Sv2ScheduledEvent`] on the `Schedule` variant of their outcome
+//! (e.g.: [`crate::server::service::outcome::Sv2ServerOutcome::Schedule`]).
+//!
+//! Every [`Sv2ScheduledEvent`] carries a [`Sv2TimerHandle`], which the handler can keep in order to
+//! cancel the timer later. All timers are also cancelled when the service
+//! [`tokio_util::sync::CancellationToken`] is cancelled, so they never outlive the service.
+//!
+//! Fired events are handled by the service just like events coming from sibling services:
+//! on a dedicated task, concurrently with the messages coming from the connections.
+
+use crate::ChannelUsageWarning;
+use async_channel::{Receiver, Sender};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// How many fired events can wait to be handled before timers are paused.
+const TIMER_CHANNEL_CAPACITY: usize = 1024;
+
+static TIMER_ID_GENERATOR: AtomicU64 = AtomicU64::new(0);
+
+/// A handle to a scheduled timer.
+///
+/// Clones refer to the same timer.
+#[derive(Debug, Clone)]
+pub struct Sv2TimerHandle {
+    id: u64,
+    cancellation_token: CancellationToken,
+}
+
+impl Sv2TimerHandle {
+    fn new() -> Self {
+        Self {
+            id: TIMER_ID_GENERATOR.fetch_add(1, Ordering::Relaxed),
+            cancellation_token: CancellationToken::new(),
+        }
+    }
+
+    /// A unique identifier for the timer.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancels the timer. Events that were already fired are still handled.
+    pub fn cancel(&self) {
+        self.cancellation_token.cancel();
+    }
+
+    /// Returns whether the timer was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token.is_cancelled()
+    }
+}
+
+/// When a [`Sv2ScheduledEvent`] fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sv2TimerSchedule {
+    /// Fire once, after `delay`.
+    Once { delay: Duration },
+    /// Fire every `interval`, starting one `interval` from now.
+    ///
+    /// If the service falls behind, ticks are delayed instead of fired in a burst.
+    Every { interval: Duration },
+}
+
+/// An event to be fired by the service according to some [`Sv2TimerSchedule`].
+#[derive(Debug, Clone)]
+pub struct Sv2ScheduledEvent<E> {
+    schedule: Sv2TimerSchedule,
+    event: E,
+    handle: Sv2TimerHandle,
+}
+
+impl<E> Sv2ScheduledEvent<E> {
+    /// Fires `event` once, after `delay`.
+    pub fn once(delay: Duration, event: E) -> Self {
+        Self {
+            schedule: Sv2TimerSchedule::Once { delay },
+            event,
+            handle: Sv2TimerHandle::new(),
+        }
+    }
+
+    /// Fires `event` every `interval`, until cancelled.
+    ///
+    /// Fails with [`Sv2TimerError::ZeroInterval`] if `interval` is zero.
+    pub fn every(interval: Duration, event: E) -> Result<Self, Sv2TimerError> {
+        if interval.is_zero() {
+            return Err(Sv2TimerError::ZeroInterval);
+        }
+        Ok(Self {
+            schedule: Sv2TimerSchedule::Every { interval },
+            event,
+            handle: Sv2TimerHandle::new(),
+        })
+    }
+
+    /// The handle that cancels this timer.
+    pub fn handle(&self) -> Sv2TimerHandle {
+        self.handle.clone()
+    }
+
+    pub fn schedule(&self) -> Sv2TimerSchedule {
+        self.schedule
+    }
+
+    pub fn event(&self) -> &E {
+        &self.event
+    }
+}
+
+/// Errors that can occur when scheduling a [`Sv2ScheduledEvent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2TimerError {
+    /// A periodic timer was given an interval of 0.
+    ZeroInterval,
+}
+
+impl fmt::Display for Sv2TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sv2TimerError::ZeroInterval => write!(f, "Timer interval must be greater than 0"),
+        }
+    }
+}
+
+impl std::error::Error for Sv2TimerError {}
+
+/// The timers of a service.
+///
+/// Each timer runs on its own task, which only sleeps and forwards the event to a channel.
+/// The service listens on that channel (see [`Sv2Timers::recv`]) and handles the fired events,
+/// so timer tasks never hold a clone of the service.
+///
+/// Timers scheduled before the service starts listening fire as usual, and their events wait on the channel.
+#[derive(Debug, Clone)]
+pub(crate) struct Sv2Timers<E> {
+    tx: Sender<E>,
+    rx: Receiver<E>,
+    usage_warning: Arc<ChannelUsageWarning>,
+    cancellation_token: CancellationToken,
+}
+
+impl<E> Sv2Timers<E>
+where
+    E: Clone + Send + 'static,
+{
+    pub(crate) fn new(cancellation_token: CancellationToken) -> Self {
+        let (tx, rx) = async_channel::bounded(TIMER_CHANNEL_CAPACITY);
+        Self {
+            tx,
+            rx,
+            usage_warning: Arc::new(ChannelUsageWarning::default()),
+            cancellation_token,
+        }
+    }
+
+    /// Starts the timer, returning its handle.
+    pub(crate) fn schedule(&self, scheduled_event: Sv2ScheduledEvent<E>) -> Sv2TimerHandle {
+        let Sv2ScheduledEvent {
+            schedule,
+            event,
+            handle,
+        } = scheduled_event;
+
+        let tx = self.tx.clone();
+        let service_cancellation_token = self.cancellation_token.clone();
+        let timer_cancellation_token = handle.cancellation_token.clone();
+        let timer_id = handle.id;
+
+        debug!("Scheduling timer {}: {:?}", timer_id, schedule);
+
+        tokio::spawn(async move {
+            let mut deadline = match schedule {
+                Sv2TimerSchedule::Once { delay } => Instant::now() + delay,
+                Sv2TimerSchedule::Every { interval } => Instant::now() + interval,
+            };
+
+            loop {
+                tokio::select! {
+                    _ = service_cancellation_token.cancelled() => break,
+                    _ = timer_cancellation_token.cancelled() => break,
+                    _ = tokio::time::sleep_until(deadline) => {
+                        // waiting for room on the channel must not prevent cancellation
+                        tokio::select! {
+                            _ = service_cancellation_token.cancelled() => break,
+                            _ = timer_cancellation_token.cancelled() => break,
+                            result = tx.send(event.clone()) => {
+                                if result.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        match schedule {
+                            Sv2TimerSchedule::Once { .. } => break,
+                            // measured from the moment the event was delivered, so a slow service delays ticks
+                            Sv2TimerSchedule::Every { interval } => deadline = Instant::now() + interval,
+                        }
+                    }
+                }
+            }
+            debug!("Timer {} ended", timer_id);
+        });
+
+        handle
+    }
+
+    /// Waits for the next fired event.
+    ///
+    /// Returns `None` once the service is cancelled.
+    pub(crate) async fn recv(&self) -> Option<E> {
+        tokio::select! {
+            _ = self.cancellation_token.cancelled() => None,
+            result = self.rx.recv() => {
+                self.usage_warning
+                    .observe("Timer channel", self.rx.len(), self.rx.capacity());
+                result.ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2ScheduledEvent, Sv2TimerError, Sv2Timers};
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn one_shot_timer_fires_once() {
+        let timers = Sv2Timers::new(CancellationToken::new());
+        timers.schedule(Sv2ScheduledEvent::once(Duration::from_millis(10), 7u32));
+
+        let fired = tokio::time::timeout(Duration::from_secs(1), timers.recv()).await;
+        assert_eq!(fired.unwrap(), Some(7));
+
+        let fired_again = tokio::time::timeout(Duration::from_millis(100), timers.recv()).await;
+        assert!(fired_again.is_err());
+    }
+
+    #[tokio::test]
+    async fn periodic_timer_fires_until_cancelled() {
+        let timers = Sv2Timers::new(CancellationToken::new());
+        let handle =
+            timers.schedule(Sv2ScheduledEvent::every(Duration::from_millis(10), 7u32).unwrap());
+
+        for _ in 0..3 {
+            let fired = tokio::time::timeout(Duration::from_secs(1), timers.recv()).await;
+            assert_eq!(fired.unwrap(), Some(7));
+        }
+
+        handle.cancel();
+        assert!(handle.is_cancelled());
+        // drain an event that may have fired right before cancellation
+        let _ = tokio::time::timeout(Duration::from_millis(20), timers.recv()).await;
+
+        let fired = tokio::time::timeout(Duration::from_millis(100), timers.recv()).await;
+        assert!(fired.is_err());
+    }
+
+    #[test]
+    fn periodic_timers_need_an_interval() {
+        assert_eq!(
+            Sv2ScheduledEvent::every(Duration::ZERO, 7u32)
+                .map(|scheduled_event| *scheduled_event.event()),
+            Err(Sv2TimerError::ZeroInterval)
+        );
+    }
+
+    #[tokio::test]
+    async fn timers_are_cancelled_with_the_service() {
+        let cancellation_token = CancellationToken::new();
+        let timers = Sv2Timers::new(cancellation_token.clone());
+        let scheduled_event = Sv2ScheduledEvent::once(Duration::from_millis(50), 7u32);
+        let handle = scheduled_event.handle();
+        let other_handle = timers.schedule(scheduled_event);
+        assert_eq!(handle.id(), other_handle.id());
+
+        cancellation_token.cancel();
+
+        let fired = tokio::time::timeout(Duration::from_millis(200), timers.recv()).await;
+        assert_eq!(fired.unwrap(), None);
+        // only the service was cancelled, not the timer itself
+        assert!(!handle.is_cancelled());
+    }
+}
@@ -0,0 +1,227 @@
+//! Adapters from [`crate::Sv2Service`] implementers to [`tower::Service`].
+//!
+//! [`Sv2TowerService`] wraps a [`Sv2ServerService`] or a [`Sv2ClientService`], so that events can be sent
+//! through the tower ecosystem (e.g.: `Timeout`, `Buffer`, `LoadShed`, `ConcurrencyLimit`, `Retry`).
+//!
+//! [`tower::Service::poll_ready`] reflects:
+//! - backpressure: at most `max_in_flight` events are handled at the same time, further callers wait for a slot
+//! - shutdown: once the service [`tokio_util::sync::CancellationToken`] is cancelled, it fails with `ServiceShutDown`
+//! - connection state (client only): it fails with [`Sv2ClientEventError::IsNotConnected`] while the client
+//!   is not connected to any server
+//!
+//! The adapter is meant for a service that was already started: connections are still set up by
+//! [`crate::Sv2Service::start`].
+
+use crate::client::service::event::{Sv2ClientEvent, Sv2ClientEventError};
+use crate::client::service::outcome::Sv2ClientOutcome;
+use crate::client::service::subprotocols::mining::handler::Sv2MiningClientHandler;
+use crate::client::service::subprotocols::template_distribution::handler::Sv2TemplateDistributionClientHandler;
+use crate::client::service::Sv2ClientService;
+use crate::layer::Sv2BoxFuture;
+use crate::server::service::event::{Sv2ServerEvent, Sv2ServerEventError};
+use crate::server::service::outcome::Sv2ServerOutcome;
+use crate::server::service::subprotocols::mining::handler::Sv2MiningServerHandler;
+use crate::server::service::Sv2ServerService;
+use crate::Sv2Service;
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+
+/// A [`tower::Service`] over a [`Sv2ServerService`] or a [`Sv2ClientService`].
+///
+/// Clones share the same `max_in_flight` budget.
+#[derive(Debug)]
+pub struct Sv2TowerService<S> {
+    service: S,
+    semaphore: PollSemaphore,
+    // the slot reserved by `poll_ready` for the next `call`
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<S: Clone> Clone for Sv2TowerService<S> {
+    fn clone(&self) -> Self {
+        // a reserved slot belongs to the original only
+        Self {
+            service: self.service.clone(),
+            semaphore: self.semaphore.clone(),
+            permit: None,
+        }
+    }
+}
+
+impl<S> Sv2TowerService<S> {
+    /// Wraps `service`, allowing at most `max_in_flight` events to be handled at the same time.
+    ///
+    /// Fails with [`Sv2TowerServiceError::InvalidMaxInFlight`] if `max_in_flight` is zero,
+    /// since no event could ever be handled.
+    pub fn new(service: S, max_in_flight: usize) -> Result<Self, Sv2TowerServiceError> {
+        if max_in_flight == 0 {
+            return Err(Sv2TowerServiceError::InvalidMaxInFlight);
+        }
+        Ok(Self {
+            service,
+            semaphore: PollSemaphore::new(Arc::new(Semaphore::new(max_in_flight))),
+            permit: None,
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.service
+    }
+
+    pub fn into_inner(self) -> S {
+        self.service
+    }
+
+    // Reserves a slot for the next call, if none was reserved yet.
+    fn poll_permit(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.permit.is_none() {
+            match self.semaphore.poll_acquire(cx) {
+                Poll::Ready(permit) => {
+                    // the semaphore is owned by the adapter and never closed
+                    self.permit = permit;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(())
+    }
+
+    fn take_permit(&mut self) -> OwnedSemaphorePermit {
+        self.permit
+            .take()
+            .expect("poll_ready must return Ready before call")
+    }
+}
+
+/// Errors that can occur when creating a [`Sv2TowerService`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sv2TowerServiceError {
+    /// The adapter was created with a `max_in_flight` of 0.
+    InvalidMaxInFlight,
+}
+
+impl fmt::Display for Sv2TowerServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sv2TowerServiceError::InvalidMaxInFlight => {
+                write!(f, "max_in_flight must be greater than 0")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Sv2TowerServiceError {}
+
+impl<M> tower::Service<Sv2ServerEvent<'static>> for Sv2TowerService<Sv2ServerService<M>>
+where
+    M: Sv2MiningServerHandler + Clone + Send + Sync + 'static,
+{
+    type Response = Sv2ServerOutcome<'static>;
+    type Error = Sv2ServerEventError;
+    type Future = Sv2BoxFuture<'static, Result<Sv2ServerOutcome<'static>, Sv2ServerEventError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.service.is_shut_down() {
+            return Poll::Ready(Err(Sv2ServerEventError::ServiceShutDown));
+        }
+        self.poll_permit(cx).map(Ok)
+    }
+
+    fn call(&mut self, event: Sv2ServerEvent<'static>) -> Self::Future {
+        let permit = self.take_permit();
+        let mut service = self.service.clone();
+        Box::pin(async move {
+            let result = service.handle(event).await;
+            drop(permit);
+            result
+        })
+    }
+}
+
+impl<M, T> tower::Service<Sv2ClientEvent<'static>> for Sv2TowerService<Sv2ClientService<M, T>>
+where
+    M: Sv2MiningClientHandler + Clone + Send + Sync + 'static,
+    T: Sv2TemplateDistributionClientHandler + Clone + Send + Sync + 'static,
+{
+    type Response = Sv2ClientOutcome<'static>;
+    type Error = Sv2ClientEventError;
+    type Future = Sv2BoxFuture<'static, Result<Sv2ClientOutcome<'static>, Sv2ClientEventError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.service.is_shut_down() {
+            return Poll::Ready(Err(Sv2ClientEventError::ServiceShutDown));
+        }
+        if !self.service.try_is_connected_to_any() {
+            return Poll::Ready(Err(Sv2ClientEventError::IsNotConnected));
+        }
+        self.poll_permit(cx).map(Ok)
+    }
+
+    fn call(&mut self, event: Sv2ClientEvent<'static>) -> Self::Future {
+        let permit = self.take_permit();
+        let mut service = self.service.clone();
+        Box::pin(async move {
+            let result = service.handle(event).await;
+            drop(permit);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sv2TowerService, Sv2TowerServiceError};
+    use crate::server::service::event::{Sv2ServerEvent, Sv2ServerEventError};
+    use crate::testing::fixtures;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+    use tower::{Service, ServiceExt};
+
+    #[tokio::test]
+    async fn tower_service_handles_events() {
+        let server_service = fixtures::null_server_service(CancellationToken::new());
+        assert_eq!(
+            Sv2TowerService::new(server_service.clone(), 0).err(),
+            Some(Sv2TowerServiceError::InvalidMaxInFlight)
+        );
+        let mut tower_service = Sv2TowerService::new(server_service, 1).unwrap();
+
+        let result = tower_service
+            .ready()
+            .await
+            .unwrap()
+            .call(Sv2ServerEvent::DisconnectClient(1))
+            .await;
+        assert!(matches!(result, Err(Sv2ServerEventError::IdNotFound)));
+    }
+
+    #[tokio::test]
+    async fn tower_service_poll_ready_reflects_backpressure_and_shutdown() {
+        let cancellation_token = CancellationToken::new();
+        let mut tower_service =
+            Sv2TowerService::new(fixtures::null_server_service(cancellation_token.clone()), 1)
+                .unwrap();
+        let mut other_tower_service = tower_service.clone();
+
+        // the only slot is reserved by the first adapter
+        tower_service.ready().await.unwrap();
+        let ready =
+            tokio::time::timeout(Duration::from_millis(50), other_tower_service.ready()).await;
+        assert!(ready.is_err());
+
+        // completing the call frees the slot
+        let _ = tower_service
+            .call(Sv2ServerEvent::DisconnectClient(1))
+            .await;
+        let ready =
+            tokio::time::timeout(Duration::from_millis(50), other_tower_service.ready()).await;
+        assert!(ready.is_ok());
+
+        cancellation_token.cancel();
+        let ready = tower_service.ready().await;
+        assert!(matches!(ready, Err(Sv2ServerEventError::ServiceShutDown)));
+    }
+}